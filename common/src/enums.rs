@@ -4,4 +4,5 @@ use clap::ValueEnum;
 pub enum DataSource {
     Mysql,
     Postgres,
+    Sqlite,
 }
\ No newline at end of file
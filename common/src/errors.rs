@@ -50,6 +50,8 @@ impl std::fmt::Display for MegaError {
     }
 }
 
+impl std::error::Error for MegaError {}
+
 impl From<anyhow::Error> for MegaError {
     fn from(err: anyhow::Error) -> MegaError {
         MegaError::new(err, 101)
@@ -69,12 +71,91 @@ impl From<std::io::Error> for MegaError {
     }
 }
 
+impl From<crate::config::ConfigError> for MegaError {
+    fn from(err: crate::config::ConfigError) -> MegaError {
+        MegaError::new(err.into(), 1)
+    }
+}
+
 impl From<sea_orm::DbErr> for MegaError {
     fn from(err: sea_orm::DbErr) -> MegaError {
+        StorageError::from(err).into()
+    }
+}
+
+impl From<StorageError> for MegaError {
+    fn from(err: StorageError) -> MegaError {
         MegaError::new(err.into(), 1)
     }
 }
 
+impl MegaError {
+    /// The storage error this error was created from, for the servers to answer with a status
+    /// matching its kind.
+    pub fn storage_error(&self) -> Option<&StorageError> {
+        self.error.as_ref()?.downcast_ref::<StorageError>()
+    }
+}
+
+/// The errors of `ObjectStorage`, by kind.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum StorageError {
+    #[error("not found: {0}")]
+    NotFound(String),
+    /// A unique or foreign key constraint was violated, usually by a concurrent write.
+    #[error("conflict: {0}")]
+    Conflict(String),
+    /// The database can't be reached, the request may succeed once retried.
+    #[error("storage unavailable: {0}")]
+    Connection(String),
+    /// A value couldn't be converted from or to its column.
+    #[error("serialization error: {0}")]
+    Serialization(String),
+    #[error("storage error: {0}")]
+    Other(String),
+}
+
+impl From<sea_orm::DbErr> for StorageError {
+    fn from(err: sea_orm::DbErr) -> StorageError {
+        use sea_orm::{DbErr, SqlErr};
+
+        let message = err.to_string();
+        if let Some(
+            SqlErr::UniqueConstraintViolation(_) | SqlErr::ForeignKeyConstraintViolation(_),
+        ) = err.sql_err()
+        {
+            return StorageError::Conflict(message);
+        }
+        match err {
+            DbErr::RecordNotFound(_) | DbErr::RecordNotUpdated => StorageError::NotFound(message),
+            DbErr::ConnectionAcquire(_) | DbErr::Conn(_) => StorageError::Connection(message),
+            DbErr::TryIntoErr { .. }
+            | DbErr::ConvertFromU64(_)
+            | DbErr::UnpackInsertId
+            | DbErr::UpdateGetPrimaryKey
+            | DbErr::AttrNotSet(_)
+            | DbErr::Type(_)
+            | DbErr::Json(_) => StorageError::Serialization(message),
+            // a connection lost during a statement surfaces as an io error of the driver
+            DbErr::Exec(_) | DbErr::Query(_) if has_io_source(&err) => {
+                StorageError::Connection(message)
+            }
+            _ => StorageError::Other(message),
+        }
+    }
+}
+
+fn has_io_source(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if err.is::<std::io::Error>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
 #[derive(Error, Debug)]
 #[allow(unused)]
 pub enum GitLFSError {
@@ -83,4 +164,30 @@ pub enum GitLFSError {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use sea_orm::{DbErr, RuntimeErr};
+
+    use crate::errors::{MegaError, StorageError};
+
+    #[test]
+    fn test_storage_error_from_db_err() {
+        let err = StorageError::from(DbErr::RecordNotFound(String::from("refs")));
+        assert_eq!(
+            err,
+            StorageError::NotFound(String::from("RecordNotFound Error: refs"))
+        );
+        let err = StorageError::from(DbErr::Conn(RuntimeErr::Internal(String::from("refused"))));
+        assert!(matches!(err, StorageError::Connection(_)));
+        let err = StorageError::from(DbErr::AttrNotSet(String::from("data")));
+        assert!(matches!(err, StorageError::Serialization(_)));
+        let err = StorageError::from(DbErr::Custom(String::from("syntax error")));
+        assert!(matches!(err, StorageError::Other(_)));
+
+        let err = MegaError::from(DbErr::RecordNotUpdated);
+        assert!(matches!(
+            err.storage_error(),
+            Some(StorageError::NotFound(_))
+        ));
+        assert!(MegaError::with_message("other").storage_error().is_none());
+    }
+}
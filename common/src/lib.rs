@@ -1,4 +1,6 @@
+pub mod config;
 pub mod errors;
+pub mod lifecycle;
 pub mod utils;
 pub mod enums;
 pub mod model;
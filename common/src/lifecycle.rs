@@ -0,0 +1,133 @@
+//!
+//! The lifecycle of the mega process, shared by the services it runs: whether it is ready to
+//! serve, whether it is shutting down, and how many pushes are still being received.
+//!
+//! Shutting down stops admitting new pushes, so that the supervisor of `mega service start` can
+//! wait for the ones in progress before closing the listeners, see `Lifecycle::drain`.
+//!
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct State {
+    ready: bool,
+    stopping: bool,
+    in_flight: usize,
+}
+
+#[derive(Debug)]
+pub struct Lifecycle {
+    state: watch::Sender<State>,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Lifecycle {
+            state: watch::Sender::new(State::default()),
+        }
+    }
+}
+
+/// The lifecycle of this process.
+pub fn lifecycle() -> &'static Lifecycle {
+    static LIFECYCLE: OnceLock<Lifecycle> = OnceLock::new();
+    LIFECYCLE.get_or_init(Lifecycle::default)
+}
+
+impl Lifecycle {
+    /// Whether the services are started and not shutting down, reported by the health endpoint.
+    pub fn is_ready(&self) -> bool {
+        let state = self.state.borrow();
+        state.ready && !state.stopping
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.state.send_modify(|state| state.ready = ready);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.state.borrow().stopping
+    }
+
+    /// Starts shutting down, the pushes begun after this are refused.
+    pub fn stop(&self) {
+        self.state.send_modify(|state| state.stopping = true);
+    }
+
+    /// Resolves once `stop` has been called.
+    pub async fn stopped(&self) {
+        let mut state = self.state.subscribe();
+        let _ = state.wait_for(|state| state.stopping).await;
+    }
+
+    /// # Registers a push, which holds the shutdown until the returned guard is dropped.
+    ///
+    /// Returns `None` if the process is shutting down, the push should then be refused before
+    /// anything is written.
+    pub fn begin_transaction(&self) -> Option<Transaction<'_>> {
+        let admitted = self.state.send_if_modified(|state| {
+            if state.stopping {
+                return false;
+            }
+            state.in_flight += 1;
+            true
+        });
+        admitted.then(|| Transaction { lifecycle: self })
+    }
+
+    /// Number of pushes in progress.
+    pub fn in_flight(&self) -> usize {
+        self.state.borrow().in_flight
+    }
+
+    /// # Waits for the pushes in progress to finish, for at most `deadline`.
+    ///
+    /// Returns false if some are still running when the deadline expires.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        let mut state = self.state.subscribe();
+        let drained = state.wait_for(|state| state.in_flight == 0);
+        let drained = tokio::time::timeout(deadline, drained).await;
+        matches!(drained, Ok(Ok(_)))
+    }
+}
+
+/// A push in progress, see `Lifecycle::begin_transaction`.
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    lifecycle: &'a Lifecycle,
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        self.lifecycle
+            .state
+            .send_modify(|state| state.in_flight -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::lifecycle::Lifecycle;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let lifecycle = Lifecycle::default();
+        lifecycle.set_ready(true);
+        assert!(lifecycle.is_ready());
+
+        let transaction = lifecycle.begin_transaction().unwrap();
+        lifecycle.stop();
+        assert!(!lifecycle.is_ready());
+        assert!(lifecycle.begin_transaction().is_none());
+        assert!(!lifecycle.drain(Duration::from_millis(10)).await);
+
+        drop(transaction);
+        assert_eq!(lifecycle.in_flight(), 0);
+        assert!(lifecycle.drain(Duration::from_millis(10)).await);
+        lifecycle.stopped().await;
+    }
+}
@@ -1,2 +1,2 @@
 pub mod obj_service;
-pub mod router;
\ No newline at end of file
+pub mod router;
@@ -9,6 +9,7 @@ use git::internal::object::tree::Tree;
 use git::internal::object::ObjectT;
 use storage::driver::database::storage::ObjectStorage;
 
+use crate::errors::error_response;
 use crate::model::object_detail::{BlobObjects, Directories, Item};
 use crate::model::query::DirectoryQuery;
 
@@ -31,7 +32,8 @@ impl ObjectService {
                     return Err((StatusCode::NOT_FOUND, "Blob not found".to_string()));
                 }
             }
-            _ => return Err((StatusCode::NOT_FOUND, "Blob not found".to_string())),
+            Ok(_) => return Err((StatusCode::NOT_FOUND, "Blob not found".to_string())),
+            Err(e) => return Err(error_response(e)),
         };
 
         let row_data = match String::from_utf8(blob_data) {
@@ -63,14 +65,15 @@ impl ObjectService {
                 .storage
                 .get_directory_by_full_path(&repo_path)
                 .await
-                .unwrap();
+                .map_err(error_response)?;
             match directory {
                 Some(dir) => {
                     if dir.is_repo {
                         // find commit by path
                         let commit_id = match self.storage.search_refs(&repo_path).await {
                             Ok(refs) if !refs.is_empty() => refs[0].ref_git_id.clone(),
-                            _ => {
+                            Err(e) => return Err(error_response(e)),
+                            Ok(_) => {
                                 return Err((
                                     StatusCode::NOT_FOUND,
                                     "repo_path might not valid".to_string(),
@@ -80,11 +83,18 @@ impl ObjectService {
                         // find tree by commit
                         let tree_id = match self.storage.get_commit_by_hash(&commit_id).await {
                             Ok(Some(commit)) => commit.tree,
-                            _ => return Err((StatusCode::NOT_FOUND, "Tree not found".to_string())),
+                            Ok(_) => {
+                                return Err((StatusCode::NOT_FOUND, "Tree not found".to_string()))
+                            }
+                            Err(e) => return Err(error_response(e)),
                         };
                         self.get_tree_objects(&tree_id).await
                     } else {
-                        let dirs = self.storage.get_directory_by_pid(dir.id).await.unwrap();
+                        let dirs = self
+                            .storage
+                            .get_directory_by_pid(dir.id)
+                            .await
+                            .map_err(error_response)?;
                         let items = dirs.into_iter().map(|x| x.into()).collect();
                         let data = Directories { items };
                         Ok(Json(data))
@@ -110,7 +120,8 @@ impl ObjectService {
                     return Err((StatusCode::NOT_FOUND, "Tree not found".to_string()));
                 }
             }
-            _ => return Err((StatusCode::NOT_FOUND, "Tree not found".to_string())),
+            Ok(_) => return Err((StatusCode::NOT_FOUND, "Tree not found".to_string())),
+            Err(e) => return Err(error_response(e)),
         };
 
         let tree = Tree::new_from_data(tree_data);
@@ -120,7 +131,11 @@ impl ObjectService {
             .map(|tree_item| tree_item.id.to_plain_str())
             .collect();
 
-        let child_nodes = self.storage.get_nodes_by_hashes(child_ids).await.unwrap();
+        let child_nodes = self
+            .storage
+            .get_nodes_by_hashes(child_ids)
+            .await
+            .map_err(error_response)?;
 
         let mut items: Vec<Item> = child_nodes
             .iter()
@@ -131,7 +146,7 @@ impl ObjectService {
             .storage
             .get_commit_by_hashes(related_commit_ids)
             .await
-            .unwrap();
+            .map_err(error_response)?;
         let mut related_c_map: HashMap<String, Commit> = HashMap::new();
         for c in related_c {
             related_c_map.insert(c.git_id.clone(), c.into());
@@ -157,11 +172,13 @@ impl ObjectService {
     ) -> Result<Response, (StatusCode, String)> {
         let node = match self.storage.get_node_by_hash(object_id).await {
             Ok(Some(node)) => node,
-            _ => return Err((StatusCode::NOT_FOUND, "Blob not found".to_string())),
+            Ok(_) => return Err((StatusCode::NOT_FOUND, "Blob not found".to_string())),
+            Err(e) => return Err(error_response(e)),
         };
         let raw_data = match self.storage.get_obj_data_by_id(object_id).await {
             Ok(Some(model)) => model,
-            _ => return Err((StatusCode::NOT_FOUND, "Blob not found".to_string())),
+            Ok(_) => return Err((StatusCode::NOT_FOUND, "Blob not found".to_string())),
+            Err(e) => return Err(error_response(e)),
         };
         let file_name = format!("inline; filename=\"{}\"", node.name.unwrap());
         let res = Response::builder()
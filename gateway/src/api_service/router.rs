@@ -8,21 +8,31 @@ use axum::{
     Json, Router,
 };
 
+use chrono::DateTime;
+
+use storage::driver::database::storage::AuditLogQuery;
+
 use crate::{
     api_service::obj_service::ObjectService,
+    errors::error_response,
     model::{
+        audit::{AuditLogItem, AuditLogs},
         object_detail::{BlobObjects, Directories},
-        query::DirectoryQuery,
+        query::{AuditQuery, DirectoryQuery},
     },
 };
 
 use crate::AppState;
 
+const AUDIT_PAGE_SIZE: u64 = 100;
+const AUDIT_MAX_PAGE_SIZE: u64 = 1000;
+
 pub fn routers<S>(state: AppState) -> Router<S> {
     Router::new()
         .route("/blob", get(get_blob_object))
         .route("/tree", get(get_directories))
         .route("/object", get(get_origin_object))
+        .route("/audit", get(get_audit_logs))
         .with_state(state)
 }
 
@@ -57,3 +67,47 @@ async fn get_origin_object(
     };
     object_service.get_objects_data(object_id).await
 }
+
+async fn get_audit_logs(
+    Query(query): Query<AuditQuery>,
+    state: State<AppState>,
+) -> Result<Json<AuditLogs>, (StatusCode, String)> {
+    let parse_date = |date: Option<String>| match date {
+        Some(date) => DateTime::parse_from_rfc3339(&date)
+            .map(|date| Some(date.naive_utc()))
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid date {}: {}", date, e),
+                )
+            }),
+        None => Ok(None),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(AUDIT_PAGE_SIZE)
+        .clamp(1, AUDIT_MAX_PAGE_SIZE);
+    let audit_query = AuditLogQuery {
+        repo_path: query.repo_path,
+        actor: query.actor,
+        action: query.action,
+        since: parse_date(query.since)?,
+        until: parse_date(query.until)?,
+        before_id: query.cursor,
+        limit,
+    };
+    let logs = state
+        .storage
+        .get_audit_logs(&audit_query)
+        .await
+        .map_err(error_response)?;
+    // a full page may be followed by older entries
+    let next_cursor = logs
+        .last()
+        .filter(|_| logs.len() as u64 == limit)
+        .map(|log| log.id);
+    Ok(Json(AuditLogs {
+        items: logs.into_iter().map(AuditLogItem::from).collect(),
+        next_cursor,
+    }))
+}
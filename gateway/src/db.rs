@@ -0,0 +1,63 @@
+//!
+//! The schema of the database, created and upgraded by the migrations of the `migration` crate.
+//!
+//! `mega db migrate` applies the pending migrations, which `mega init` also does, `mega db
+//! rollback` reverts the last applied ones and `mega db status` lists them all. The applied
+//! migrations are recorded in the `seaql_migrations` table of the database.
+//!
+//! A SQLite database is migrated whenever it is opened, so it needs no `mega db migrate`.
+//!
+use clap::{Args, Subcommand};
+
+use common::config::Config;
+use common::enums::DataSource;
+use common::errors::MegaError;
+use migration::{Migrator, MigratorTrait};
+use storage::driver::database;
+
+#[derive(Args, Clone, Debug)]
+pub struct DbOptions {
+    #[arg(short, long, value_enum, default_value = "postgres", global = true)]
+    pub data_source: DataSource,
+
+    #[command(subcommand)]
+    pub command: DbCommand,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum DbCommand {
+    /// Apply the pending migrations
+    Migrate,
+    /// Revert the last applied migrations
+    Rollback {
+        /// Number of migrations to revert
+        #[arg(long, default_value_t = 1)]
+        steps: u32,
+    },
+    /// List the migrations and whether they are applied
+    Status,
+}
+
+/// Runs a `mega db` command.
+pub async fn exec_db_command(config: &Config, options: &DbOptions) -> Result<(), MegaError> {
+    let storage = database::init(config, &options.data_source).await;
+    let connection = storage.get_connection();
+    match &options.command {
+        DbCommand::Migrate => {
+            let pending = Migrator::get_pending_migrations(connection).await?;
+            Migrator::up(connection, None).await?;
+            println!("{} migrations applied", pending.len());
+        }
+        DbCommand::Rollback { steps } => {
+            let applied = Migrator::get_applied_migrations(connection).await?;
+            Migrator::down(connection, Some(*steps)).await?;
+            println!("{} migrations reverted", applied.len().min(*steps as usize));
+        }
+        DbCommand::Status => {
+            for migration in Migrator::get_migration_with_status(connection).await? {
+                println!("{}\t{}", migration.name(), migration.status());
+            }
+        }
+    }
+    Ok(())
+}
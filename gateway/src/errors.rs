@@ -0,0 +1,80 @@
+//!
+//! The status codes answered for the errors of the storage: a missing row is a 404, a write
+//! racing with another one a 409 and an unreachable database a 503, so that the clients and
+//! the load balancer can tell a transient failure from a broken request.
+//!
+use std::error::Error;
+
+use axum::http::StatusCode;
+
+use common::errors::{MegaError, StorageError};
+use git::errors::GitError;
+
+pub fn storage_status(err: &StorageError) -> StatusCode {
+    match err {
+        StorageError::NotFound(_) => StatusCode::NOT_FOUND,
+        StorageError::Conflict(_) => StatusCode::CONFLICT,
+        StorageError::Connection(_) => StatusCode::SERVICE_UNAVAILABLE,
+        StorageError::Serialization(_) | StorageError::Other(_) => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// # The status and the message answered for the error of a handler.
+///
+/// The storage error is looked up in the chain of `err`, where it may be wrapped in a
+/// `GitError` or a `MegaError`, any other error is an internal error.
+pub fn error_response(err: impl Into<anyhow::Error>) -> (StatusCode, String) {
+    let err = err.into();
+    let status = err
+        .chain()
+        .find_map(storage_error)
+        .map(storage_status)
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    if status.is_server_error() {
+        tracing::error!("{:#}", err);
+    }
+    (status, format!("{}\n", err))
+}
+
+fn storage_error<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a StorageError> {
+    if let Some(err) = err.downcast_ref::<StorageError>() {
+        return Some(err);
+    }
+    if let Some(GitError::StorageError(err)) = err.downcast_ref::<GitError>() {
+        return Some(err);
+    }
+    err.downcast_ref::<MegaError>()
+        .and_then(|err| err.storage_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use common::errors::{MegaError, StorageError};
+    use git::errors::GitError;
+
+    use crate::errors::error_response;
+
+    #[test]
+    fn test_error_response() {
+        let unavailable = StorageError::Connection(String::from("pool timed out"));
+        let (status, _) = error_response(MegaError::from(unavailable.clone()));
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let (status, _) = error_response(anyhow::Error::from(GitError::StorageError(unavailable)));
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let conflict = StorageError::Conflict(String::from("duplicate key"));
+        let (status, _) = error_response(anyhow::Error::from(MegaError::from(conflict)));
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, message) = error_response(StorageError::NotFound(String::from("refs")));
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(message, "not found: refs\n");
+
+        let (status, _) = error_response(GitError::InvalidPackHeader(String::from("PACX")));
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
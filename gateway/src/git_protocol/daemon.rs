@@ -0,0 +1,213 @@
+//!
+//! The git daemon protocol, served on `git://` URLs: the client opens a TCP connection and
+//! sends a single pkt-line naming the service and the repository, then talks the same protocol
+//! as over ssh. There is no authentication, so only anonymous fetches are served, and only from
+//! directories the anonymous user can read.
+//!
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use common::config::Config;
+use git::protocol::pack::build_error_line;
+use git::protocol::{PackProtocol, Protocol, ProtocolVersion, ServiceType};
+use storage::driver::database::storage::ObjectStorage;
+
+/// The request line sent by the client when it connects, such as
+/// `git-upload-pack /project.git\0host=example.com\0\0version=2\0`.
+#[derive(Debug, PartialEq)]
+pub struct DaemonRequest {
+    pub service: String,
+    pub path: PathBuf,
+    pub host: Option<String>,
+    pub version: ProtocolVersion,
+}
+
+impl DaemonRequest {
+    pub fn parse(line: &[u8]) -> Result<Self, String> {
+        let line = String::from_utf8_lossy(line);
+        let mut fields = line.trim_end_matches('\n').split('\0');
+        let (service, path) = fields
+            .next()
+            .and_then(|command| command.split_once(' '))
+            .ok_or_else(|| String::from("invalid request"))?;
+        if !path.starts_with('/') {
+            return Err(String::from("invalid request"));
+        }
+        let mut host = None;
+        let mut extra = vec![];
+        for field in fields.filter(|f| !f.is_empty()) {
+            match field.strip_prefix("host=") {
+                Some(value) => host = Some(value.to_owned()),
+                None => extra.push(field),
+            }
+        }
+        Ok(DaemonRequest {
+            service: service.to_owned(),
+            path: PathBuf::from(path.strip_suffix(".git").unwrap_or(path)),
+            host,
+            version: ProtocolVersion::from_git_protocol(&extra.join(":")),
+        })
+    }
+}
+
+/// # Serves a `git://` connection.
+///
+/// Reads the request line, then answers each message of the client, which ends with a flush
+/// packet or with `done`, until a pack is sent or the client disconnects.
+pub async fn handle_connection(
+    storage: Arc<dyn ObjectStorage>,
+    config: Arc<Config>,
+    mut stream: TcpStream,
+) {
+    let mut buf = BytesMut::new();
+    let line = loop {
+        if let Some(line) = split_pkt_line(&mut buf) {
+            break line;
+        }
+        if !read_more(&mut stream, &mut buf).await {
+            return;
+        }
+    };
+    let request = match DaemonRequest::parse(&line) {
+        Ok(request) => request,
+        Err(err) => return send_error(&mut stream, &err).await,
+    };
+    tracing::info!("git daemon: {} {:?}", request.service, request.path);
+    if request.service != "git-upload-pack" {
+        return send_error(&mut stream, "service not enabled").await;
+    }
+    let mut pack_protocol = PackProtocol::new(request.path, storage, Protocol::Git, &config);
+    pack_protocol.service_type = ServiceType::UploadPack;
+    pack_protocol.version = request.version;
+    if let Err(err) = pack_protocol.check_permission().await {
+        return send_error(&mut stream, &err.to_string()).await;
+    }
+    let refs = match pack_protocol.git_info_refs().await {
+        Ok(refs) => refs,
+        Err(err) => return send_error(&mut stream, &err.to_string()).await,
+    };
+    if stream.write_all(&refs).await.is_err() {
+        return;
+    }
+
+    loop {
+        while !is_complete_message(&buf) {
+            if !read_more(&mut stream, &mut buf).await {
+                return;
+            }
+        }
+        let mut message = buf.split().freeze();
+        let (pack_stream, reply) = match pack_protocol.git_upload_pack(&mut message).await {
+            Ok(result) => result,
+            Err(err) => return send_error(&mut stream, &err.to_string()).await,
+        };
+        if stream.write_all(&reply).await.is_err() {
+            return;
+        }
+        if let Some(mut pack_stream) = pack_stream {
+            while let Some(bytes) = pack_stream.recv().await {
+                if stream.write_all(&bytes).await.is_err() {
+                    return;
+                }
+            }
+            let _ = stream.shutdown().await;
+            return;
+        }
+    }
+}
+
+async fn read_more(stream: &mut TcpStream, buf: &mut BytesMut) -> bool {
+    matches!(stream.read_buf(buf).await, Ok(n) if n > 0)
+}
+
+async fn send_error(stream: &mut TcpStream, message: &str) {
+    tracing::info!("git daemon: {}", message);
+    let _ = stream.write_all(&build_error_line(message)).await;
+    let _ = stream.shutdown().await;
+}
+
+// takes the first pkt-line of `buf` once it is complete
+fn split_pkt_line(buf: &mut BytesMut) -> Option<Bytes> {
+    let length = pkt_length(buf, 0)?;
+    if length < 4 || buf.len() < length {
+        return None;
+    }
+    let line = buf.split_to(length);
+    Some(line.freeze().slice(4..))
+}
+
+fn pkt_length(buf: &[u8], pos: usize) -> Option<usize> {
+    let hex = std::str::from_utf8(buf.get(pos..pos + 4)?).ok()?;
+    usize::from_str_radix(hex, 16).ok()
+}
+
+// whether `buf` holds whole pkt-lines, the last one ending a message of the client
+fn is_complete_message(buf: &[u8]) -> bool {
+    let mut pos = 0;
+    let mut last = None;
+    while pos < buf.len() {
+        match pkt_length(buf, pos) {
+            Some(length) if length < 4 => {
+                last = Some(&buf[pos..pos + 4]);
+                pos += 4;
+            }
+            Some(length) if pos + length <= buf.len() => {
+                last = Some(&buf[pos..pos + length]);
+                pos += length;
+            }
+            _ => return false,
+        }
+    }
+    matches!(last, Some(b"0000") | Some(b"0009done\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bytes::BytesMut;
+    use git::protocol::ProtocolVersion;
+
+    use crate::git_protocol::daemon::{is_complete_message, split_pkt_line, DaemonRequest};
+
+    #[test]
+    fn test_parse_daemon_request() {
+        let request =
+            DaemonRequest::parse(b"git-upload-pack /projects/mega.git\0host=git.example.com\0")
+                .unwrap();
+        assert_eq!(request.service, "git-upload-pack");
+        assert_eq!(request.path, PathBuf::from("/projects/mega"));
+        assert_eq!(request.host.as_deref(), Some("git.example.com"));
+        assert_eq!(request.version, ProtocolVersion::V0);
+
+        let request =
+            DaemonRequest::parse(b"git-upload-pack /mega\0host=localhost:9418\0\0version=2\0")
+                .unwrap();
+        assert_eq!(request.path, PathBuf::from("/mega"));
+        assert_eq!(request.version, ProtocolVersion::V2);
+
+        assert!(DaemonRequest::parse(b"git-upload-pack").is_err());
+        assert!(DaemonRequest::parse(b"git-upload-pack mega.git\0").is_err());
+    }
+
+    #[test]
+    fn test_client_messages() {
+        let mut buf = BytesMut::from(&b"002dgit-upload-pack /mega.git\0host=localhost\x00003"[..]);
+        let line = split_pkt_line(&mut buf).unwrap();
+        assert_eq!(&line[..], b"git-upload-pack /mega.git\0host=localhost\0");
+        assert_eq!(&buf[..], b"003");
+        assert_eq!(split_pkt_line(&mut buf), None);
+
+        let want = b"0032want 0000000000000000000000000000000000000000\n";
+        assert!(!is_complete_message(want));
+        assert!(is_complete_message(&[&want[..], b"0000"].concat()));
+        assert!(is_complete_message(&[&want[..], b"0009done\n"].concat()));
+        assert!(!is_complete_message(&[&want[..], b"000"].concat()));
+        // protocol v2 commands have a delimiter before their arguments
+        assert!(is_complete_message(b"0014command=ls-refs\n00010000"));
+    }
+}
@@ -4,17 +4,19 @@
 //!
 //!
 use std::collections::HashMap;
+use std::convert::Infallible;
 
 use anyhow::Result;
 use axum::body::Body;
 use axum::http::response::Builder;
 use axum::http::{Request, Response, StatusCode};
-use bytes::{Bytes, BytesMut};
-use futures::TryStreamExt;
-use tokio::io::AsyncReadExt;
+use bytes::BytesMut;
+use futures::{future, stream, StreamExt, TryStreamExt};
 
-use git::protocol::{pack, PackProtocol, ServiceType};
+use git::errors::GitError;
+use git::protocol::{PackProtocol, ServiceType};
 
+use crate::errors::error_response;
 use crate::https_server::GetParams;
 
 // # Discovering Reference
@@ -29,8 +31,12 @@ pub async fn git_info_refs(
 ) -> Result<Response<Body>, (StatusCode, String)> {
     let service_name = params.service.unwrap();
     pack_protocol.service_type = service_name.parse::<ServiceType>().unwrap();
+    check_permission(&mut pack_protocol).await?;
     let resp = build_res_header(format!("application/x-{}-advertisement", service_name));
-    let pkt_line_stream = pack_protocol.git_info_refs().await;
+    let pkt_line_stream = pack_protocol
+        .git_info_refs()
+        .await
+        .map_err(error_response)?;
     let body = Body::from(pkt_line_stream.freeze());
     Ok(resp.body(body).unwrap())
 }
@@ -45,20 +51,21 @@ pub async fn git_info_refs(
 /// buffer.
 ///
 /// The `pack_protocol` is then used to process the `upload_request` using the `git_upload_pack` method.
-/// It returns the `send_pack_data` and `buf` containing the response data.
+/// It returns the `pack_stream` and `buf` containing the ACK/NAK lines.
 ///
 /// A response header is constructed using the `build_res_header` function with a content type of
-/// "application/x-git-upload-pack-result". The response body channel is created using `Body::channel()`.
-///
-/// The `buf` is sent as the initial data using the `sender` to establish the response body.
+/// "application/x-git-upload-pack-result".
 ///
-/// A new task is spawned to send the remaining `send_pack_data` using the `send_pack` function.
+/// The `buf` is sent first, followed by the pack read from `pack_stream` as it is generated, so
+/// the pack is never held in memory as a whole.
 ///
 /// Finally, the constructed response with the response body is returned.
 pub async fn git_upload_pack(
     req: Request<Body>,
     mut pack_protocol: PackProtocol,
 ) -> Result<Response<Body>, (StatusCode, String)> {
+    pack_protocol.service_type = ServiceType::UploadPack;
+    check_permission(&mut pack_protocol).await?;
     let upload_request: BytesMut = req
         .into_body()
         .into_data_stream()
@@ -69,35 +76,29 @@ pub async fn git_upload_pack(
         .await
         .unwrap();
 
-    let (send_pack_data, buf) = pack_protocol
+    let (pack_stream, buf) = pack_protocol
         .git_upload_pack(&mut upload_request.freeze())
         .await
-        .unwrap();
+        .map_err(error_response)?;
     tracing::info!("send ack/nak message buf: {:?}", buf);
-    let mut res_bytes = BytesMut::new();
-    res_bytes.extend(buf);
 
     let resp = build_res_header("application/x-git-upload-pack-result".to_owned());
 
     tracing::info!("send response");
 
-    let mut reader = send_pack_data.as_slice();
-    loop {
-        let mut temp = BytesMut::new();
-        temp.reserve(65500);
-        let length = reader.read_buf(&mut temp).await.unwrap();
-        if length == 0 {
-            let bytes_out = Bytes::from_static(pack::PKT_LINE_END_MARKER);
-            tracing::info!("send 0000:{:?}", bytes_out);
-            res_bytes.extend(bytes_out);
-            break;
-        }
-        let bytes_out = pack_protocol.build_side_band_format(temp, length);
-        tracing::info!("send pack file: length: {:?}", bytes_out.len());
-        res_bytes.extend(bytes_out);
-    }
-    let body = Body::from(res_bytes.freeze());
-    let resp = resp.body(body).unwrap();
+    // negotiation is not finished yet, the client will send another round of haves
+    let pack_stream = match pack_stream {
+        Some(pack_stream) => pack_stream,
+        None => return Ok(resp.body(Body::from(buf.freeze())).unwrap()),
+    };
+    let pack_stream = stream::unfold(pack_stream, |mut pack_stream| async move {
+        pack_stream
+            .recv()
+            .await
+            .map(|bytes| (Ok::<_, Infallible>(bytes), pack_stream))
+    });
+    let body = stream::once(future::ready(Ok(buf.freeze()))).chain(pack_stream);
+    let resp = resp.body(Body::from_stream(body)).unwrap();
     Ok(resp)
 }
 
@@ -125,6 +126,8 @@ pub async fn git_receive_pack(
     req: Request<Body>,
     mut pack_protocol: PackProtocol,
 ) -> Result<Response<Body>, (StatusCode, String)> {
+    pack_protocol.service_type = ServiceType::ReceivePack;
+    check_permission(&mut pack_protocol).await?;
     let combined_body_bytes: BytesMut = req
         .into_body()
         .into_data_stream()
@@ -135,17 +138,38 @@ pub async fn git_receive_pack(
         .await
         .unwrap();
 
-
     let parse_report = pack_protocol
         .git_receive_pack(combined_body_bytes.freeze())
         .await
-        .unwrap();
+        .map_err(error_response)?;
     tracing::info!("report status:{:?}", parse_report);
     let resp = build_res_header("application/x-git-receive-pack-result".to_owned());
     let resp = resp.body(Body::from(parse_report)).unwrap();
     Ok(resp)
 }
 
+/// # Checks the access policy before serving the request.
+///
+/// Anonymous requests are answered with 401 so that git asks for credentials and retries,
+/// authenticated users without the permission get 403. The policy failing to load is a
+/// storage error.
+pub async fn check_permission(
+    pack_protocol: &mut PackProtocol,
+) -> Result<(), (StatusCode, String)> {
+    match pack_protocol.check_permission().await {
+        Ok(()) => Ok(()),
+        Err(GitError::PermissionDenied(msg)) => {
+            let status = if pack_protocol.user.is_none() {
+                StatusCode::UNAUTHORIZED
+            } else {
+                StatusCode::FORBIDDEN
+            };
+            Err((status, format!("{}\n", GitError::PermissionDenied(msg))))
+        }
+        Err(err) => Err(error_response(err)),
+    }
+}
+
 /// # Build Response headers for Smart Server.
 /// Clients MUST NOT reuse or revalidate a cached response.
 /// Servers MUST include sufficient Cache-Control headers to prevent caching of the response.
@@ -1,2 +1,3 @@
+pub mod daemon;
 pub mod http;
-pub mod ssh;
\ No newline at end of file
+pub mod ssh;
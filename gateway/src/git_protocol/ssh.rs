@@ -5,23 +5,28 @@
 //!
 
 use async_trait::async_trait;
-use bytes::{Bytes, BytesMut};
-use chrono::{DateTime, Duration, Utc};
+use bytes::Bytes;
 use git::lfs::lfs_structs::Link;
+use git::lfs::transfer::LfsTransfer;
+use git::lfs::LfsConfig;
 use russh::server::{self, Auth, Msg, Session};
 use russh::{Channel, ChannelId};
 use russh_keys::key;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use tokio::io::AsyncReadExt;
 
+use common::config::{AuthConfig, Config};
 use storage::driver::database::storage::ObjectStorage;
+use storage::driver::file_storage;
 
-use git::protocol::pack::{self};
+use git::protocol::pack::build_error_line;
 use git::protocol::ServiceType;
-use git::protocol::{PackProtocol, Protocol};
+use git::protocol::{PackProtocol, Protocol, ProtocolVersion};
+
+use crate::token_registry::LfsGrant;
+use crate::{key_registry, token_registry};
 
 type ClientMap = HashMap<(usize, ChannelId), Channel<Msg>>;
 
@@ -31,14 +36,28 @@ pub struct SshServer {
     pub clients: Arc<Mutex<ClientMap>>,
     pub id: usize,
     pub storage: Arc<dyn ObjectStorage>,
+    pub config: Arc<Config>,
+    // set by the client through the GIT_PROTOCOL environment variable
+    pub protocol_version: ProtocolVersion,
+    // the channels on which upload-pack is streaming a pack
+    pub pack_sending: HashSet<ChannelId>,
+    // the owner of the key the client authenticated with
+    pub user: Option<String>,
+    // the address of the client, recorded in the audit log
+    pub client_addr: Option<String>,
+    // base URL of the http service, for the LFS links
+    pub lfs_url: String,
+    // the channels running git-lfs-transfer, which then handles their data
+    pub lfs_transfer: HashMap<ChannelId, LfsTransfer>,
     // TODO: consider is it a good choice to bind data here, find a better solution to bind data with ssh client
     pub pack_protocol: Option<PackProtocol>,
 }
 
 impl server::Server for SshServer {
     type Handler = Self;
-    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
-        let s = self.clone();
+    fn new_client(&mut self, peer_addr: Option<std::net::SocketAddr>) -> Self {
+        let mut s = self.clone();
+        s.client_addr = peer_addr.map(|addr| addr.to_string());
         self.id += 1;
         s
     }
@@ -84,6 +103,7 @@ impl server::Handler for SshServer {
         // Push: git-receive-pack '/path/to/repo.git'
         // Pull: git-upload-pack '/path/to/repo.git'
         // LFS HTTP Authenticate: git-lfs-authenticate '/path/to/repo.git' download/upload
+        // LFS SSH Transfer: git-lfs-transfer '/path/to/repo.git' download/upload
         let command: Vec<_> = data.split(' ').collect();
         let path = command[1];
         let end = path.len() - ".git'".len();
@@ -91,53 +111,148 @@ impl server::Handler for SshServer {
             PathBuf::from(&path[1..end]),
             self.storage.clone(),
             Protocol::Ssh,
+            &self.config,
         );
+        pack_protocol.version = self.protocol_version;
+        pack_protocol.client_addr = self.client_addr.clone();
+        pack_protocol.user = self.user.clone();
         match command[0] {
             "git-upload-pack" | "git-receive-pack" => {
                 pack_protocol.service_type = ServiceType::from_str(command[0]).unwrap();
-                let res = pack_protocol.git_info_refs().await;
-                self.pack_protocol = Some(pack_protocol);
-                session.data(channel, res.to_vec().into());
+                if let Err(err) = pack_protocol.check_permission().await {
+                    tracing::info!("{}", err);
+                    session.data(channel, build_error_line(&err.to_string()).to_vec().into());
+                    session.exit_status_request(channel, 1);
+                    session.close(channel);
+                    return Ok((self, session));
+                }
+                match pack_protocol.git_info_refs().await {
+                    Ok(res) => {
+                        self.pack_protocol = Some(pack_protocol);
+                        session.data(channel, res.to_vec().into());
+                    }
+                    Err(err) => send_error(&mut session, channel, &err.to_string()),
+                }
             }
-            //Note that currently mega does not support pure ssh to transfer files, still relay on the https server.
-            //see https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md for more details about pure ssh file transfer.
+            // pure ssh LFS, objects and locks are exchanged on the channel, see `LfsTransfer`
             "git-lfs-transfer" => {
-                session.data(channel, "not implemented yet".as_bytes().to_vec().into());
+                let operation = command.get(2).copied().unwrap_or("download");
+                pack_protocol.service_type = if operation == "upload" {
+                    ServiceType::ReceivePack
+                } else {
+                    ServiceType::UploadPack
+                };
+                if let Err(err) = pack_protocol.check_permission().await {
+                    tracing::info!("{}", err);
+                    session.extended_data(channel, 1, format!("{}\n", err).into_bytes().into());
+                    session.exit_status_request(channel, 1);
+                    session.close(channel);
+                    return Ok((self, session));
+                }
+                let config = LfsConfig {
+                    // the links of the http API aren't used over ssh
+                    host: String::new(),
+                    port: 0,
+                    storage: self.storage.clone(),
+                    fs_storage: file_storage::init(&self.config.storage, "lfs-files".to_owned())
+                        .await,
+                    user: self.user.clone(),
+                    protocol: Protocol::Ssh,
+                    repo_path: path[1..end].to_owned(),
+                    client_addr: self.client_addr.clone(),
+                };
+                self.lfs_transfer
+                    .insert(channel, LfsTransfer::new(config, operation));
+                session.data(channel, LfsTransfer::advertise().to_vec().into());
             }
             // When connecting over SSH, the first attempt will be made to use
             // `git-lfs-transfer`, the pure SSH protocol, and if it fails, Git LFS will fall
             // back to the hybrid protocol using `git-lfs-authenticate`.
             "git-lfs-authenticate" => {
-                let mut header = HashMap::new();
-                header.insert("Accept".to_string(), "application/vnd.git-lfs".to_string());
-                let link = Link {
-                    href: "http://localhost:8000".to_string(),
-                    header,
-                    expires_at: {
-                        let expire_time: DateTime<Utc> = Utc::now() + Duration::seconds(86400);
-                        expire_time.to_rfc3339()
-                    },
+                // downloads need to read the repository, uploads to write to it
+                let operation = match command.get(2) {
+                    Some(&"upload") => "upload",
+                    _ => "download",
+                };
+                pack_protocol.service_type = match operation {
+                    "upload" => ServiceType::ReceivePack,
+                    _ => ServiceType::UploadPack,
                 };
-                session.data(channel, serde_json::to_vec(&link).unwrap().into());
+                let user = match pack_protocol.check_permission().await {
+                    Ok(()) => self.user.clone(),
+                    Err(err) => {
+                        tracing::info!("{}", err);
+                        None
+                    }
+                };
+                match user {
+                    Some(user) => {
+                        let grant = LfsGrant {
+                            repo_path: path[1..end].to_owned(),
+                            operation: operation.to_owned(),
+                        };
+                        let link = lfs_link(&self.config.auth, &self.lfs_url, &grant, &user);
+                        session.data(channel, serde_json::to_vec(&link).unwrap().into());
+                        session.exit_status_request(channel, 0);
+                    }
+                    None => {
+                        session.extended_data(
+                            channel,
+                            1,
+                            format!("{} can't access {}\n", data, &path[1..end])
+                                .into_bytes()
+                                .into(),
+                        );
+                        session.exit_status_request(channel, 1);
+                    }
+                }
+                session.close(channel);
             }
             _ => println!("Not Supported command!"),
         }
         Ok((self, session))
     }
 
+    /// Git clients request the wire protocol version by sending `GIT_PROTOCOL` before the
+    /// exec request, which requires `AcceptEnv GIT_PROTOCOL` on OpenSSH servers.
+    async fn env_request(
+        mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        tracing::info!(
+            "env_request, channel:{:?}, {}={}",
+            channel,
+            variable_name,
+            variable_value
+        );
+        if variable_name == "GIT_PROTOCOL" {
+            self.protocol_version = ProtocolVersion::from_git_protocol(variable_value);
+        }
+        Ok((self, session))
+    }
+
     async fn auth_publickey(
-        self,
+        mut self,
         user: &str,
         public_key: &key::PublicKey,
     ) -> Result<(Self, Auth), Self::Error> {
-        tracing::info!("auth_publickey: {} / {:?}", user, public_key);
-        Ok((self, Auth::Accept))
-    }
-
-    async fn auth_password(self, user: &str, password: &str) -> Result<(Self, Auth), Self::Error> {
-        tracing::info!("auth_password: {} / {}", user, password);
-        // in this example implementation, any username/password combination is accepted
-        Ok((self, Auth::Accept))
+        tracing::info!("auth_publickey: {} / {}", user, public_key.fingerprint());
+        match key_registry::authenticate(self.storage.as_ref(), public_key).await {
+            Some(name) => {
+                // the key owner pushes, whatever the ssh user is, usually `git`
+                self.user = Some(name);
+                Ok((self, Auth::Accept))
+            }
+            None => Ok((
+                self,
+                Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            )),
+        }
     }
 
     async fn data(
@@ -146,6 +261,16 @@ impl server::Handler for SshServer {
         data: &[u8],
         mut session: Session,
     ) -> Result<(Self, Session), Self::Error> {
+        if let Some(lfs_transfer) = self.lfs_transfer.get_mut(&channel) {
+            let (output, quit) = lfs_transfer.receive(data).await;
+            session.data(channel, output.to_vec().into());
+            if quit {
+                self.lfs_transfer.remove(&channel);
+                session.exit_status_request(channel, 0);
+                session.close(channel);
+            }
+            return Ok((self, session));
+        }
         let pack_protocol = self.pack_protocol.as_mut().unwrap();
 
         match pack_protocol.service_type {
@@ -169,37 +294,85 @@ impl server::Handler for SshServer {
             let mut clients = self.clients.lock().unwrap();
             clients.remove(&(self.id, channel));
         }
-        session.exit_status_request(channel, 0000);
-        session.close(channel);
+        // the pack sending task closes the channel once the pack is complete
+        if !self.pack_sending.contains(&channel) {
+            session.exit_status_request(channel, 0000);
+            session.close(channel);
+        }
+
+        Ok((self, session))
+    }
 
+    async fn channel_close(
+        mut self,
+        channel: ChannelId,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        self.pack_sending.remove(&channel);
+        self.lfs_transfer.remove(&channel);
         Ok((self, session))
     }
 }
 
+// answers with an ERR pkt-line, which git shows to the user, and closes the channel
+fn send_error(session: &mut Session, channel: ChannelId, message: &str) {
+    tracing::error!("ssh channel {:?}: {}", channel, message);
+    session.data(channel, build_error_line(message).to_vec().into());
+    session.exit_status_request(channel, 1);
+    session.close(channel);
+}
+
+/// The LFS endpoint of the repository of `grant` on the http service, with a signed token of
+/// `user` restricted to it.
+pub fn lfs_link(config: &AuthConfig, lfs_url: &str, grant: &LfsGrant, user: &str) -> Link {
+    let (token, expires_at) = token_registry::issue_lfs_token(config, user, grant);
+    let mut header = HashMap::new();
+    header.insert("Accept".to_string(), "application/vnd.git-lfs".to_string());
+    header.insert("Authorization".to_string(), format!("Bearer {}", token));
+    Link {
+        href: format!(
+            "{}{}.git/info/lfs",
+            lfs_url.trim_end_matches('/'),
+            grant.repo_path
+        ),
+        header,
+        expires_at: expires_at.to_rfc3339(),
+    }
+}
+
 impl SshServer {
     async fn handle_upload_pack(&mut self, channel: ChannelId, data: &[u8], session: &mut Session) {
         let pack_protocol = self.pack_protocol.as_mut().unwrap();
 
-        let (send_pack_data, buf) = pack_protocol
+        let (pack_stream, buf) = match pack_protocol
             .git_upload_pack(&mut Bytes::copy_from_slice(data))
             .await
-            .unwrap();
+        {
+            Ok(res) => res,
+            Err(err) => return send_error(session, channel, &err.to_string()),
+        };
 
         tracing::info!("buf is {:?}", buf);
         session.data(channel, String::from_utf8(buf.to_vec()).unwrap().into());
 
-        let mut reader = send_pack_data.as_slice();
-        loop {
-            let mut temp = BytesMut::new();
-            temp.reserve(65500);
-            let length = reader.read_buf(&mut temp).await.unwrap();
-            if temp.is_empty() {
-                session.data(channel, pack::PKT_LINE_END_MARKER.to_vec().into());
-                return;
+        let mut pack_stream = match pack_stream {
+            Some(pack_stream) => pack_stream,
+            None => return,
+        };
+        // the pack is written through the session handle, which only sends more data once the
+        // client's window allows it, and the channel is closed when the pack is complete
+        self.pack_sending.insert(channel);
+        let handle = session.handle();
+        tokio::spawn(async move {
+            while let Some(bytes) = pack_stream.recv().await {
+                if handle.data(channel, bytes.to_vec().into()).await.is_err() {
+                    tracing::error!("ssh channel {:?} closed while sending pack", channel);
+                    return;
+                }
             }
-            let bytes_out = pack_protocol.build_side_band_format(temp, length);
-            session.data(channel, bytes_out.to_vec().into());
-        }
+            let _ = handle.exit_status_request(channel, 0).await;
+            let _ = handle.close(channel).await;
+        });
     }
 
     async fn handle_receive_pack(
@@ -210,11 +383,43 @@ impl SshServer {
     ) {
         let pack_protocol = self.pack_protocol.as_mut().unwrap();
 
-        let buf = pack_protocol
+        let buf = match pack_protocol
             .git_receive_pack(Bytes::from(data.to_vec()))
             .await
-            .unwrap();
+        {
+            Ok(buf) => buf,
+            Err(err) => return send_error(session, channel, &err.to_string()),
+        };
         tracing::info!("report status: {:?}", buf);
         session.data(channel, buf.to_vec().into());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use common::config::AuthConfig;
+
+    use crate::git_protocol::ssh::lfs_link;
+    use crate::token_registry::{verify_lfs_token, LfsGrant};
+
+    #[test]
+    fn test_lfs_link() {
+        let config = AuthConfig::default();
+        let grant = LfsGrant {
+            repo_path: String::from("/projects/mega"),
+            operation: String::from("upload"),
+        };
+        let link = lfs_link(&config, "https://git.example.com/", &grant, "alice");
+        assert_eq!(
+            link.href,
+            "https://git.example.com/projects/mega.git/info/lfs"
+        );
+        let token = link.header["Authorization"]
+            .strip_prefix("Bearer ")
+            .unwrap();
+        assert_eq!(
+            verify_lfs_token(&config, token),
+            Some((String::from("alice"), grant))
+        );
+    }
+}
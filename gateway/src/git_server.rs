@@ -0,0 +1,55 @@
+//!
+//! The git daemon service, which serves anonymous fetches on `git://` URLs, see
+//! `git_protocol::daemon`.
+//!
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use clap::Args;
+
+use common::config::Config;
+use common::model::CommonOptions;
+use storage::driver::database;
+
+use crate::git_protocol::daemon;
+
+#[derive(Args, Clone, Debug)]
+pub struct GitOptions {
+    #[clap(flatten)]
+    pub common: CommonOptions,
+
+    #[clap(flatten)]
+    pub custom: GitCustom,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct GitCustom {
+    #[arg(long, default_value_t = 9418)]
+    pub git_port: u16,
+}
+
+/// start a git daemon server
+pub async fn start_server(config: &Config, options: &GitOptions) {
+    let GitOptions {
+        common: CommonOptions { host, data_source },
+        custom: GitCustom { git_port },
+    } = options;
+    let storage = database::init(config, data_source).await;
+    let config = Arc::new(config.clone());
+    let addr = SocketAddr::from_str(&format!("{}:{}", host, git_port)).unwrap();
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tracing::info!("git daemon connection from {}", peer);
+                tokio::spawn(daemon::handle_connection(
+                    storage.clone(),
+                    config.clone(),
+                    stream,
+                ));
+            }
+            Err(err) => tracing::error!("git daemon accept failed: {}", err),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//!
+//! The endpoints probed by the deployment. `/healthz` answers as long as the process serves
+//! requests, `/readyz` also checks the database and the file storage and fails while the
+//! process is starting or shutting down, and `/metrics` exposes the metrics recorded by the
+//! gateway and the git crate in the Prometheus text format.
+//!
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{Request, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+use common::lifecycle::lifecycle;
+use storage::driver::database;
+
+use crate::https_server::{AppState, Route};
+
+// a readiness check slower than this fails, the probe of the deployment would time out anyway
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// unit second, from a ref advertisement to a large clone
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+pub fn routers() -> Router<AppState> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(render_metrics))
+}
+
+/// # Installs the Prometheus recorder of the process.
+///
+/// The metrics recorded before are lost, so it is called when the http service starts. It
+/// can be called again when the service is restarted.
+pub fn install_recorder() -> &'static PrometheusHandle {
+    static RECORDER: OnceLock<PrometheusHandle> = OnceLock::new();
+    RECORDER.get_or_init(|| {
+        PrometheusBuilder::new()
+            .set_buckets(DURATION_BUCKETS)
+            .unwrap()
+            .install_recorder()
+            .expect("failed to install the metrics recorder")
+    })
+}
+
+/// The route of a request in the metrics, the paths hold the repository so they can't be
+/// used as labels.
+pub fn route_label(uri: &Uri) -> &'static str {
+    let path = uri.path();
+    if path.starts_with("/api/") {
+        return "api";
+    }
+    if matches!(path, "/healthz" | "/readyz" | "/metrics") {
+        return "probe";
+    }
+    match Route::of(path) {
+        Some(Route::InfoRefs) => "info_refs",
+        Some(Route::UploadPack) => "git_upload_pack",
+        Some(Route::ReceivePack) => "git_receive_pack",
+        Some(Route::LfsBatch) => "lfs_batch",
+        Some(Route::LfsObject) => "lfs_object",
+        Some(Route::Locks | Route::VerifyLocks | Route::Unlock) => "lfs_locks",
+        None => "other",
+    }
+}
+
+/// # Counts the requests and records their latency, by method, route and status.
+///
+/// The latency is measured until the response headers, the body of a clone is streamed after.
+pub async fn track_metrics(req: Request<Body>, next: Next) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = route_label(req.uri());
+    let response = next.run(req).await;
+    let status = response.status().as_u16().to_string();
+    metrics::counter!(
+        "mega_http_requests_total",
+        "method" => method.clone(),
+        "route" => route,
+        "status" => status,
+    )
+    .increment(1);
+    metrics::histogram!(
+        "mega_http_request_duration_seconds",
+        "method" => method,
+        "route" => route,
+    )
+    .record(start.elapsed().as_secs_f64());
+    response
+}
+
+async fn healthz() -> &'static str {
+    "ok\n"
+}
+
+async fn readyz(State(state): State<AppState>) -> (StatusCode, String) {
+    if !lifecycle().is_ready() {
+        let reason = if lifecycle().is_stopping() {
+            "shutting down"
+        } else {
+            "starting"
+        };
+        return (StatusCode::SERVICE_UNAVAILABLE, format!("{}\n", reason));
+    }
+
+    let connection = state.storage.get_connection();
+    let database = tokio::time::timeout(CHECK_TIMEOUT, connection.ping()).await;
+    let database = match database {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(String::from("timed out")),
+    };
+    // the remote storage may wait on an unreachable endpoint
+    let lfs_storage = state.lfs_storage.clone();
+    let file_storage = tokio::spawn(async move { lfs_storage.check().await });
+    let file_storage = match tokio::time::timeout(CHECK_TIMEOUT, file_storage).await {
+        Ok(Ok(Ok(()))) => Ok(()),
+        Ok(Ok(Err(e))) => Err(e.to_string()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(String::from("timed out")),
+    };
+
+    let mut status = StatusCode::OK;
+    let mut body = String::new();
+    for (name, result) in [("database", database), ("file storage", file_storage)] {
+        match result {
+            Ok(()) => body.push_str(&format!("{}: ok\n", name)),
+            Err(e) => {
+                status = StatusCode::SERVICE_UNAVAILABLE;
+                body.push_str(&format!("{}: {}\n", name, e));
+            }
+        }
+    }
+    (status, body)
+}
+
+async fn render_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    // the pool is sampled when scraped instead of on each query
+    if let Some((size, idle)) = database::pool_stats(state.storage.get_connection()) {
+        metrics::gauge!("mega_db_pool_connections").set(size as f64);
+        metrics::gauge!("mega_db_pool_idle_connections").set(idle as f64);
+    }
+    metrics::gauge!("mega_db_pool_max_connections")
+        .set(state.config.database.max_connections as f64);
+
+    let recorder = install_recorder();
+    recorder.run_upkeep();
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        recorder.render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Uri;
+
+    use crate::health::route_label;
+
+    #[test]
+    fn test_route_label() {
+        let route = |uri: &str| route_label(&uri.parse::<Uri>().unwrap());
+        assert_eq!(
+            route("/mega.git/info/refs?service=git-upload-pack"),
+            "info_refs"
+        );
+        assert_eq!(
+            route("/third_parts/mega.git/git-receive-pack"),
+            "git_receive_pack"
+        );
+        assert_eq!(route("/objects/batch"), "lfs_batch");
+        assert_eq!(
+            route("/objects/4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393"),
+            "lfs_object"
+        );
+        assert_eq!(route("/locks/1234/unlock"), "lfs_locks");
+        assert_eq!(route("/api/v1/status"), "api");
+        assert_eq!(route("/readyz"), "probe");
+        assert_eq!(route("/mega.git/HEAD"), "other");
+    }
+}
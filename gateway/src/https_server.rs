@@ -4,32 +4,41 @@
 //!
 //!
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::OnceLock;
 use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::Result;
 use axum::body::Body;
-use axum::extract::{Query, State};
-use axum::http::{Request, StatusCode, Uri};
-use axum::response::Response;
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::header::WWW_AUTHENTICATE;
+use axum::http::{HeaderMap, Method, Request, StatusCode, Uri};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
-use axum::Router;
+use axum::{Extension, Router};
+use axum_server::tls_rustls::RustlsConfig;
 use clap::Args;
 
 use regex::Regex;
 use serde::Deserialize;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
 
+use common::config::Config;
 use common::model::CommonOptions;
 use git::lfs::LfsConfig;
-use git::protocol::{PackProtocol, Protocol};
-use storage::driver::database;
+use git::protocol::{PackProtocol, Protocol, ProtocolVersion};
 use storage::driver::database::storage::ObjectStorage;
+use storage::driver::file_storage::FileStorage;
+use storage::driver::{database, file_storage};
 use tower_http::trace::TraceLayer;
 
-use crate::{api_service, git_protocol, lfs};
+use crate::token_registry::{self, Credentials, Identity, Scope};
+use crate::{api_service, errors, git_protocol, health, lfs};
 
 #[derive(Args, Clone, Debug)]
 pub struct HttpOptions {
@@ -48,16 +57,25 @@ pub struct HttpCustom {
     #[arg(long, default_value_t = 443)]
     pub https_port: u16,
 
-    #[arg(long, value_name = "FILE")]
+    /// PEM private key, https is served on `https_port` when it is set with the certificate
+    #[arg(long, value_name = "FILE", requires = "https_cert_path")]
     https_key_path: Option<PathBuf>,
 
-    #[arg(long, value_name = "FILE")]
+    /// PEM certificate chain, reloaded with the key on SIGHUP
+    #[arg(long, value_name = "FILE", requires = "https_key_path")]
     https_cert_path: Option<PathBuf>,
+
+    /// Don't serve plain http on `http_port`
+    #[arg(long, requires = "https_cert_path")]
+    pub https_only: bool,
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub storage: Arc<dyn ObjectStorage>,
+    // the storage of the LFS objects, which the remote backend connects to once
+    pub lfs_storage: Arc<dyn FileStorage>,
+    pub config: Arc<Config>,
     pub options: HttpOptions,
 }
 
@@ -75,21 +93,217 @@ pub fn remove_git_suffix(uri: Uri, git_suffix: &str) -> PathBuf {
     PathBuf::from(uri.path().replace(".git", "").replace(git_suffix, ""))
 }
 
-pub async fn start_server(options: &HttpOptions) {
+/// The repo of an LFS request, empty if the LFS API is served from the root.
+pub fn lfs_repo_path(uri: &Uri) -> String {
+    match uri.path().find("/info/lfs") {
+        Some(end) => uri.path()[..end].replace(".git", ""),
+        None => String::new(),
+    }
+}
+
+/// Reads the wire protocol version requested through the `Git-Protocol` header.
+pub fn protocol_version(headers: &HeaderMap) -> ProtocolVersion {
+    headers
+        .get("Git-Protocol")
+        .and_then(|value| value.to_str().ok())
+        .map(ProtocolVersion::from_git_protocol)
+        .unwrap_or_default()
+}
+
+/// The routes of the git and LFS services, recognized by the end of the request path, after
+/// the path of the repository.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route {
+    InfoRefs,
+    UploadPack,
+    ReceivePack,
+    LfsBatch,
+    LfsObject,
+    Locks,
+    VerifyLocks,
+    Unlock,
+}
+
+impl Route {
+    const ALL: [Route; 8] = [
+        Route::InfoRefs,
+        Route::UploadPack,
+        Route::ReceivePack,
+        Route::LfsBatch,
+        Route::LfsObject,
+        Route::Locks,
+        Route::VerifyLocks,
+        Route::Unlock,
+    ];
+
+    fn pattern(self) -> &'static str {
+        match self {
+            Route::InfoRefs => r"/info/refs$",
+            Route::UploadPack => r"/git-upload-pack$",
+            Route::ReceivePack => r"/git-receive-pack$",
+            Route::LfsBatch => r"/objects/batch$",
+            Route::LfsObject => r"/objects/[a-z0-9]+$",
+            Route::Locks => r"/locks$",
+            Route::VerifyLocks => r"/locks/verify$",
+            Route::Unlock => r"/unlock$",
+        }
+    }
+
+    /// The route of a request, the batch API coming before the objects whose route also
+    /// matches it.
+    pub fn of(path: &str) -> Option<Route> {
+        Route::ALL.into_iter().find(|route| route.matches(path))
+    }
+
+    /// Whether `path` is a request to this route. The regexes are compiled on first use, not on
+    /// every request.
+    pub fn matches(self, path: &str) -> bool {
+        static REGEXES: OnceLock<Vec<Regex>> = OnceLock::new();
+        let regexes = REGEXES.get_or_init(|| {
+            Route::ALL
+                .iter()
+                .map(|route| Regex::new(route.pattern()).unwrap())
+                .collect()
+        });
+        regexes[self as usize].is_match(path)
+    }
+}
+
+/// The scope a route needs and whether anonymous requests may use it, `None` for routes
+/// that don't need authentication.
+pub fn required_scope(method: &Method, uri: &Uri) -> Option<(Scope, bool)> {
+    let path = uri.path();
+    let matches = |route: Route| route.matches(path);
+    match *method {
+        Method::GET if matches(Route::InfoRefs) => {
+            if uri
+                .query()
+                .unwrap_or_default()
+                .contains("service=git-receive-pack")
+            {
+                Some((Scope::Write, false))
+            } else {
+                Some((Scope::Read, true))
+            }
+        }
+        Method::GET if matches(Route::LfsObject) || matches(Route::Locks) => {
+            Some((Scope::Lfs, true))
+        }
+        // uploads are refused to anonymous users by the batch handler
+        Method::POST if matches(Route::LfsBatch) => Some((Scope::Lfs, true)),
+        Method::POST
+            if matches(Route::VerifyLocks) || matches(Route::Locks) || matches(Route::Unlock) =>
+        {
+            Some((Scope::Lfs, false))
+        }
+        Method::POST if matches(Route::UploadPack) => Some((Scope::Read, true)),
+        Method::POST if matches(Route::ReceivePack) => Some((Scope::Write, false)),
+        Method::PUT if matches(Route::LfsObject) => Some((Scope::Lfs, false)),
+        // the audit log shows who pushed to which repo, it isn't public
+        Method::GET if path == "/api/v1/audit" => Some((Scope::Read, false)),
+        _ => None,
+    }
+}
+
+/// The LFS operation of a route, `None` for the routes that aren't LFS routes and for the batch
+/// API, which has the operation in its body.
+pub fn lfs_operation(method: &Method, uri: &Uri) -> Option<&'static str> {
+    let path = uri.path();
+    let matches = |route: Route| route.matches(path);
+    match *method {
+        Method::GET if matches(Route::LfsObject) || matches(Route::Locks) => Some("download"),
+        Method::POST
+            if matches(Route::VerifyLocks) || matches(Route::Locks) || matches(Route::Unlock) =>
+        {
+            Some("upload")
+        }
+        Method::PUT if matches(Route::LfsObject) => Some("upload"),
+        _ => None,
+    }
+}
+
+/// Answers 403 for an authenticated user who isn't allowed to.
+pub fn forbidden(message: &str) -> Response {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::from(format!("{}\n", message)))
+        .unwrap()
+}
+
+/// Answers 401 with a challenge, for git and git-lfs to ask the user for credentials.
+pub fn unauthorized(message: &str) -> Response {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, "Basic realm=\"mega\"")
+        .body(Body::from(format!("{}\n", message)))
+        .unwrap()
+}
+
+/// # Authenticates the requests of the smart-HTTP and LFS routes.
+///
+/// The `Identity` of an authenticated request is added to its extensions. Requests with
+/// invalid credentials, or anonymous requests to routes needing a user, get a 401, and
+/// tokens without the scope of the route, or LFS tokens of another repository or operation,
+/// get a 403.
+pub async fn auth_middleware(
+    State(state): State<AppState>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    let identity = match Credentials::from_headers(req.headers()) {
+        Ok(credentials) => {
+            token_registry::authenticate(state.storage.as_ref(), &state.config.auth, credentials)
+                .await
+        }
+        Err(err) => Err(err),
+    };
+    let identity = match identity {
+        Ok(identity) => identity,
+        // the credentials can't be checked while the storage is failing
+        Err(err) if err.storage_error().is_some() => {
+            return errors::error_response(err).into_response()
+        }
+        Err(err) => return unauthorized(&err.to_string()),
+    };
+    if let Some((scope, anonymous)) = required_scope(req.method(), req.uri()) {
+        match &identity {
+            Some(identity) if !identity.has_scope(scope) => {
+                return forbidden(&format!("the token lacks the {} scope", scope));
+            }
+            None if !anonymous => return unauthorized("authentication required"),
+            _ => {}
+        }
+    }
+    if let (Some(identity), Some(operation)) = (&identity, lfs_operation(req.method(), req.uri())) {
+        let repo_path = lfs_repo_path(req.uri());
+        if !identity.allows_lfs(&repo_path, operation) {
+            return forbidden(&format!("the token can't {} in {}", operation, repo_path));
+        }
+    }
+    if let Some(identity) = identity {
+        req.extensions_mut().insert(identity);
+    }
+    next.run(req).await
+}
+
+pub async fn start_server(config: &Config, options: &HttpOptions) {
     let HttpOptions {
         common: CommonOptions { host, data_source },
         custom:
             HttpCustom {
-                https_key_path: _,
-                https_cert_path: _,
+                https_key_path,
+                https_cert_path,
                 http_port,
-                https_port: _,
+                https_port,
+                https_only,
             },
     } = options;
-    let server_url = format!("{}:{}", host, http_port);
 
+    health::install_recorder();
     let state = AppState {
-        storage: database::init(data_source).await,
+        storage: database::init(config, data_source).await,
+        lfs_storage: file_storage::init(&config.storage, String::from("lfs-files")).await,
+        config: Arc::new(config.clone()),
         options: options.to_owned(),
     };
     let app = Router::new()
@@ -100,38 +314,108 @@ pub async fn start_server(options: &HttpOptions) {
                 .post(post_method_router)
                 .put(put_method_router),
         )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ))
         .layer(ServiceBuilder::new().layer(CorsLayer::new().allow_origin(Any)))
+        // the probes don't need authentication
+        .merge(health::routers())
+        .layer(middleware::from_fn(health::track_metrics))
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
-    let addr = SocketAddr::from_str(&server_url).unwrap();
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app.into_make_service())
+    let tls_config = match (https_cert_path, https_key_path) {
+        (Some(cert), Some(key)) => Some(load_tls_config(cert, key).await),
+        _ => None,
+    };
+    let http = async {
+        if *https_only {
+            return Ok(());
+        }
+        let addr = SocketAddr::from_str(&format!("{}:{}", host, http_port)).unwrap();
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        // the address of the client is recorded in the audit log
+        let app = app
+            .clone()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(listener, app).await
+    };
+    let https = async {
+        let tls_config = match tls_config {
+            Some(tls_config) => tls_config,
+            None => return Ok(()),
+        };
+        let addr = SocketAddr::from_str(&format!("{}:{}", host, https_port)).unwrap();
+        axum_server::bind_rustls(addr, tls_config)
+            .serve(
+                app.clone()
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+    };
+    tokio::try_join!(http, https).unwrap();
+}
+
+/// Loads the certificate chain and the private key, and reloads them whenever the process
+/// receives SIGHUP, so that certificates can be rotated without restarting the server.
+pub async fn load_tls_config(cert_path: &Path, key_path: &Path) -> RustlsConfig {
+    // both the ring and aws-lc-rs providers are compiled in, rustls can't pick one by itself
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let config = RustlsConfig::from_pem_file(cert_path, key_path)
         .await
-        .unwrap();
+        .unwrap_or_else(|e| panic!("failed to load {}: {}", cert_path.display(), e));
+
+    #[cfg(unix)]
+    {
+        let config = config.clone();
+        let (cert_path, key_path) = (cert_path.to_owned(), key_path.to_owned());
+        let mut hangup = signal(SignalKind::hangup()).unwrap();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match config.reload_from_pem_file(&cert_path, &key_path).await {
+                    Ok(()) => tracing::info!("reloaded {}", cert_path.display()),
+                    // keeps serving the previous certificate
+                    Err(e) => tracing::error!("failed to reload {}: {}", cert_path.display(), e),
+                }
+            }
+        });
+    }
+    config
 }
 
 async fn get_method_router(
     state: State<AppState>,
     Query(params): Query<GetParams>,
+    identity: Option<Extension<Identity>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
     uri: Uri,
 ) -> Result<Response<Body>, (StatusCode, String)> {
+    let user = identity.map(|Extension(identity)| identity.user);
+    let client_addr = connect_info.map(|ConnectInfo(addr)| addr.to_string());
     let mut lfs_config: LfsConfig = state.deref().to_owned().into();
-    lfs_config.fs_storage = storage::driver::file_storage::init("lfs-files".to_owned()).await;
+    lfs_config.user = user.clone();
+    lfs_config.repo_path = lfs_repo_path(&uri);
+    lfs_config.client_addr = client_addr.clone();
+    if let Some(operation) = lfs_operation(&Method::GET, &uri) {
+        lfs::check_lfs_permission(&state, &lfs_config, operation).await?;
+    }
     // Routing LFS services.
-    if Regex::new(r"/objects/[a-z0-9]+$")
-        .unwrap()
-        .is_match(uri.path())
-    {
+    if Route::LfsObject.matches(uri.path()) {
         lfs::lfs_download_object(&lfs_config, uri.path()).await
-    } else if Regex::new(r"/locks$").unwrap().is_match(uri.path()) {
+    } else if Route::Locks.matches(uri.path()) {
         return lfs::lfs_retrieve_lock(&lfs_config, params).await;
-    } else if Regex::new(r"/info/refs$").unwrap().is_match(uri.path()) {
-        let pack_protocol = PackProtocol::new(
+    } else if Route::InfoRefs.matches(uri.path()) {
+        let mut pack_protocol = PackProtocol::new(
             remove_git_suffix(uri, "/info/refs"),
             state.storage.clone(),
             Protocol::Http,
+            &state.config,
         );
+        pack_protocol.version = protocol_version(&headers);
+        pack_protocol.user = user;
+        pack_protocol.client_addr = client_addr;
         return git_protocol::http::git_info_refs(params, pack_protocol).await;
     } else {
         return Err((
@@ -147,35 +431,47 @@ async fn post_method_router(
     req: Request<Body>,
 ) -> Result<Response, (StatusCode, String)> {
     let mut lfs_config: LfsConfig = state.deref().to_owned().into();
-    lfs_config.fs_storage = storage::driver::file_storage::init("lfs-files".to_owned()).await;
+    lfs_config.user = req
+        .extensions()
+        .get::<Identity>()
+        .map(|identity| identity.user.clone());
+    lfs_config.repo_path = lfs_repo_path(&uri);
+    lfs_config.client_addr = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.to_string());
+    if let Some(operation) = lfs_operation(req.method(), &uri) {
+        lfs::check_lfs_permission(&state, &lfs_config, operation).await?;
+    }
     // Routing LFS services.
-    if Regex::new(r"/locks/verify$").unwrap().is_match(uri.path()) {
+    if Route::VerifyLocks.matches(uri.path()) {
         lfs::lfs_verify_lock(state, &lfs_config, req).await
-    } else if Regex::new(r"/locks$").unwrap().is_match(uri.path()) {
+    } else if Route::Locks.matches(uri.path()) {
         return lfs::lfs_create_lock(state, &lfs_config, req).await;
-    } else if Regex::new(r"/unlock$").unwrap().is_match(uri.path()) {
+    } else if Route::Unlock.matches(uri.path()) {
         return lfs::lfs_delete_lock(state, &lfs_config, uri.path(), req).await;
-    } else if Regex::new(r"/objects/batch$").unwrap().is_match(uri.path()) {
+    } else if Route::LfsBatch.matches(uri.path()) {
         return lfs::lfs_process_batch(state, &lfs_config, req).await;
-    } else if Regex::new(r"/git-upload-pack$")
-        .unwrap()
-        .is_match(uri.path())
-    {
-        let pack_protocol = PackProtocol::new(
+    } else if Route::UploadPack.matches(uri.path()) {
+        let mut pack_protocol = PackProtocol::new(
             remove_git_suffix(uri, "/git-upload-pack"),
             state.storage.clone(),
             Protocol::Http,
+            &state.config,
         );
+        pack_protocol.version = protocol_version(req.headers());
+        pack_protocol.user = lfs_config.user.clone();
+        pack_protocol.client_addr = lfs_config.client_addr.clone();
         git_protocol::http::git_upload_pack(req, pack_protocol).await
-    } else if Regex::new(r"/git-receive-pack$")
-        .unwrap()
-        .is_match(uri.path())
-    {
-        let pack_protocol = PackProtocol::new(
+    } else if Route::ReceivePack.matches(uri.path()) {
+        let mut pack_protocol = PackProtocol::new(
             remove_git_suffix(uri, "/git-receive-pack"),
             state.storage.clone(),
             Protocol::Http,
+            &state.config,
         );
+        pack_protocol.user = lfs_config.user.clone();
+        pack_protocol.client_addr = lfs_config.client_addr.clone();
         git_protocol::http::git_receive_pack(req, pack_protocol).await
     } else {
         Err((
@@ -191,11 +487,19 @@ async fn put_method_router(
     req: Request<Body>,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     let mut lfs_config: LfsConfig = state.deref().to_owned().into();
-    lfs_config.fs_storage = storage::driver::file_storage::init("lfs-files".to_owned()).await;
-    if Regex::new(r"/objects/[a-z0-9]+$")
-        .unwrap()
-        .is_match(uri.path())
-    {
+    lfs_config.user = req
+        .extensions()
+        .get::<Identity>()
+        .map(|identity| identity.user.clone());
+    lfs_config.repo_path = lfs_repo_path(&uri);
+    lfs_config.client_addr = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.to_string());
+    if let Some(operation) = lfs_operation(req.method(), &uri) {
+        lfs::check_lfs_permission(&state, &lfs_config, operation).await?;
+    }
+    if Route::LfsObject.matches(uri.path()) {
         lfs::lfs_upload_object(&lfs_config, uri.path(), req).await
     } else {
         Err((
@@ -206,4 +510,90 @@ async fn put_method_router(
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use axum::http::{Method, Uri};
+
+    use crate::https_server::{lfs_operation, required_scope, Route};
+    use crate::token_registry::Scope;
+
+    #[test]
+    fn test_required_scope() {
+        let scope = |method, uri: &str| required_scope(&method, &uri.parse::<Uri>().unwrap());
+        assert_eq!(
+            scope(Method::GET, "/mega.git/info/refs?service=git-upload-pack"),
+            Some((Scope::Read, true))
+        );
+        assert_eq!(
+            scope(Method::GET, "/mega.git/info/refs?service=git-receive-pack"),
+            Some((Scope::Write, false))
+        );
+        assert_eq!(
+            scope(Method::POST, "/mega.git/git-receive-pack"),
+            Some((Scope::Write, false))
+        );
+        assert_eq!(
+            scope(Method::POST, "/objects/batch"),
+            Some((Scope::Lfs, true))
+        );
+        assert_eq!(
+            scope(
+                Method::PUT,
+                "/objects/4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393"
+            ),
+            Some((Scope::Lfs, false))
+        );
+        assert_eq!(
+            scope(Method::POST, "/locks/1234/unlock"),
+            Some((Scope::Lfs, false))
+        );
+        assert_eq!(scope(Method::GET, "/api/v1/status"), None);
+        assert_eq!(
+            scope(Method::GET, "/api/v1/audit?repo_path=/projects/mega"),
+            Some((Scope::Read, false))
+        );
+    }
+
+    #[test]
+    fn test_lfs_operation() {
+        let operation = |method, uri: &str| lfs_operation(&method, &uri.parse::<Uri>().unwrap());
+        assert_eq!(
+            operation(Method::GET, "/mega.git/info/lfs/locks"),
+            Some("download")
+        );
+        assert_eq!(
+            operation(Method::POST, "/mega.git/info/lfs/locks/1234/unlock"),
+            Some("upload")
+        );
+        assert_eq!(
+            operation(
+                Method::PUT,
+                "/mega.git/info/lfs/objects/4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393"
+            ),
+            Some("upload")
+        );
+        assert_eq!(
+            operation(Method::POST, "/mega.git/info/lfs/objects/batch"),
+            None
+        );
+        assert_eq!(operation(Method::POST, "/mega.git/git-upload-pack"), None);
+    }
+
+    #[test]
+    fn test_route() {
+        let paths = [
+            "/mega.git/info/refs",
+            "/mega.git/git-upload-pack",
+            "/mega.git/git-receive-pack",
+            "/mega.git/info/lfs/objects/batch",
+            "/mega.git/info/lfs/objects/4d7a214614ab2935c943f9e0ff69d22e",
+            "/mega.git/info/lfs/locks",
+            "/mega.git/info/lfs/locks/verify",
+            "/mega.git/info/lfs/locks/1234/unlock",
+        ];
+        for (route, path) in Route::ALL.iter().zip(paths) {
+            assert!(route.matches(path), "{:?} {}", route, path);
+        }
+        assert!(!Route::Locks.matches("/mega.git/info/lfs/locks/verify"));
+        assert!(!Route::InfoRefs.matches("/mega.git/info/refs/heads"));
+    }
+}
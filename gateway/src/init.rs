@@ -1,6 +1,8 @@
 use clap::Args;
 
+use common::config::Config;
 use common::enums::DataSource;
+use migration::{Migrator, MigratorTrait};
 use storage::driver::database;
 
 #[derive(Args, Clone, Debug)]
@@ -9,8 +11,12 @@ pub struct InitOptions {
     pub data_source: DataSource,
 }
 
-pub async fn init_dir(options: &InitOptions) -> Result<(), Box<dyn std::error::Error>> {
-    let storage = database::init(&options.data_source).await;
+pub async fn init_dir(
+    config: &Config,
+    options: &InitOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let storage = database::init(config, &options.data_source).await;
+    Migrator::up(storage.get_connection(), None).await?;
     storage.init_repo_dir().await.unwrap();
     Ok(())
 }
@@ -0,0 +1,216 @@
+//!
+//! Registry of the SSH public keys of the users, keys are looked up by their SHA256
+//! fingerprint when a client authenticates, and the key owner is the user of the session.
+//!
+use std::path::Path;
+
+use clap::{Args, Subcommand};
+use russh_keys::key::PublicKey;
+use russh_keys::PublicKeyBase64;
+use sea_orm::{NotSet, Set};
+
+use common::config::Config;
+use common::enums::DataSource;
+use common::errors::MegaError;
+use entity::{ssh_keys, users};
+use storage::driver::database;
+use storage::driver::database::storage::ObjectStorage;
+
+#[derive(Args, Clone, Debug)]
+pub struct KeyOptions {
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    pub data_source: DataSource,
+
+    #[command(subcommand)]
+    pub command: KeyCommand,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum KeyCommand {
+    /// Register a public key for a user, the user is created if needed
+    Add {
+        user: String,
+        /// A line of authorized_keys or the path of a .pub file
+        key: String,
+        #[arg(long)]
+        title: Option<String>,
+    },
+    /// List the keys of a user
+    List { user: String },
+    /// Revoke a key of a user
+    Revoke { user: String, id: i32 },
+}
+
+/// Runs a `mega key` command.
+pub async fn exec_key_command(config: &Config, options: &KeyOptions) -> Result<(), MegaError> {
+    let storage = database::init(config, &options.data_source).await;
+    match &options.command {
+        KeyCommand::Add { user, key, title } => {
+            let key = add_key(storage.as_ref(), user, key, title.clone()).await?;
+            println!("added key {} SHA256:{}", key.id, key.fingerprint);
+        }
+        KeyCommand::List { user } => {
+            for key in list_keys(storage.as_ref(), user).await? {
+                println!(
+                    "{}\tSHA256:{}\t{}\t{}",
+                    key.id, key.fingerprint, key.title, key.created_at
+                );
+            }
+        }
+        KeyCommand::Revoke { user, id } => {
+            if !revoke_key(storage.as_ref(), user, *id).await? {
+                return Err(MegaError::with_message(&format!(
+                    "{} has no key {}",
+                    user, id
+                )));
+            }
+            println!("revoked key {}", id);
+        }
+    }
+    Ok(())
+}
+
+/// A public key in the `<type> <base64>` form of authorized_keys, with its fingerprint.
+#[derive(Debug, PartialEq)]
+pub struct AuthorizedKey {
+    pub key: String,
+    pub fingerprint: String,
+    pub comment: Option<String>,
+}
+
+/// Parses a line of authorized_keys, options before the key type are not supported.
+pub fn parse_authorized_key(line: &str) -> Result<AuthorizedKey, MegaError> {
+    let mut parts = line.split_whitespace();
+    let (base64, comment) = match (parts.next(), parts.next()) {
+        (Some(_key_type), Some(base64)) => (base64, parts.collect::<Vec<_>>().join(" ")),
+        (Some(base64), None) => (base64, String::new()),
+        _ => return Err(MegaError::with_message("empty public key")),
+    };
+    let public_key = russh_keys::parse_public_key_base64(base64)
+        .map_err(|e| MegaError::with_message(&format!("invalid public key: {}", e)))?;
+    Ok(AuthorizedKey {
+        key: format!("{} {}", public_key.name(), public_key.public_key_base64()),
+        fingerprint: public_key.fingerprint(),
+        comment: (!comment.is_empty()).then_some(comment),
+    })
+}
+
+/// Registers a key, which is either a line of authorized_keys or a file holding one.
+pub async fn add_key(
+    storage: &dyn ObjectStorage,
+    user_name: &str,
+    key: &str,
+    title: Option<String>,
+) -> Result<ssh_keys::Model, MegaError> {
+    let line = if Path::new(key).is_file() {
+        std::fs::read_to_string(key)?
+    } else {
+        key.to_owned()
+    };
+    let parsed = parse_authorized_key(line.trim())?;
+    if storage
+        .get_ssh_key_by_fingerprint(&parsed.fingerprint)
+        .await?
+        .is_some()
+    {
+        return Err(MegaError::with_message("the key is already registered"));
+    }
+    let now = chrono::Utc::now().naive_utc();
+    let user_id = ensure_user(storage, user_name).await?;
+    let mut model = ssh_keys::Model {
+        id: 0,
+        user_id,
+        title: title.or(parsed.comment).unwrap_or_default(),
+        ssh_key: parsed.key,
+        fingerprint: parsed.fingerprint,
+        created_at: now,
+    };
+    model.id = storage
+        .save_ssh_key(ssh_keys::ActiveModel {
+            id: NotSet,
+            user_id: Set(model.user_id),
+            title: Set(model.title.clone()),
+            ssh_key: Set(model.ssh_key.clone()),
+            fingerprint: Set(model.fingerprint.clone()),
+            created_at: Set(model.created_at),
+        })
+        .await?;
+    Ok(model)
+}
+
+/// Returns the id of a user, creating the user if needed.
+pub async fn ensure_user(storage: &dyn ObjectStorage, user_name: &str) -> Result<i32, MegaError> {
+    if let Some(user) = storage.get_user_by_name(user_name).await? {
+        return Ok(user.id);
+    }
+    let now = chrono::Utc::now().naive_utc();
+    storage
+        .save_user(users::ActiveModel {
+            id: NotSet,
+            name: Set(user_name.to_owned()),
+            created_at: Set(now),
+            updated_at: Set(now),
+        })
+        .await
+}
+
+pub async fn list_keys(
+    storage: &dyn ObjectStorage,
+    user_name: &str,
+) -> Result<Vec<ssh_keys::Model>, MegaError> {
+    match storage.get_user_by_name(user_name).await? {
+        Some(user) => storage.list_ssh_keys(user.id).await,
+        None => Ok(vec![]),
+    }
+}
+
+/// Revokes a key of the user, returns false if the user has no such key.
+pub async fn revoke_key(
+    storage: &dyn ObjectStorage,
+    user_name: &str,
+    key_id: i32,
+) -> Result<bool, MegaError> {
+    match storage.get_user_by_name(user_name).await? {
+        Some(user) => storage.revoke_ssh_key(user.id, key_id).await,
+        None => Ok(false),
+    }
+}
+
+/// Returns the name of the user owning a key offered by a client, if it is registered.
+pub async fn authenticate(storage: &dyn ObjectStorage, public_key: &PublicKey) -> Option<String> {
+    let registered = storage
+        .get_ssh_key_by_fingerprint(&public_key.fingerprint())
+        .await
+        .ok()??;
+    if registered.ssh_key.split_whitespace().nth(1) != Some(&public_key.public_key_base64()) {
+        return None;
+    }
+    storage
+        .get_user_by_id(registered.user_id)
+        .await
+        .ok()?
+        .map(|user| user.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_registry::parse_authorized_key;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIJXPGlR7jRwaO6vUEDUmVEUDSdcXClvPKcSN76oJcegm";
+
+    #[test]
+    fn test_parse_authorized_key() {
+        let parsed = parse_authorized_key(&format!("ssh-ed25519 {} alice@laptop", KEY)).unwrap();
+        assert_eq!(parsed.key, format!("ssh-ed25519 {}", KEY));
+        // same as `ssh-keygen -l`
+        assert_eq!(
+            parsed.fingerprint,
+            "8mZNc369xAmsEgVb5a9C6F0AGIc0kHxZLeA5rWpCps0"
+        );
+        assert_eq!(parsed.comment.as_deref(), Some("alice@laptop"));
+
+        assert_eq!(parse_authorized_key(KEY).unwrap().comment, None);
+        assert!(parse_authorized_key("ssh-ed25519 not-a-key").is_err());
+        assert!(parse_authorized_key("").is_err());
+    }
+}
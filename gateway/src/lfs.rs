@@ -44,25 +44,57 @@
 use axum::{
     body::Body,
     extract::{FromRequest, State},
-    http::{Request, StatusCode},
+    http::{header::AUTHORIZATION, Request, StatusCode},
     response::Response,
     Json,
 };
 
+use std::path::PathBuf;
+
 use common::errors::GitLFSError;
 use futures::TryStreamExt;
 use git::lfs::{
     lfs_structs::{
-        BatchResponse, LockList, LockListQuery, LockRequest, LockResponse, RequestVars,
-        UnlockRequest, UnlockResponse, VerifiableLockRequest,
+        BatchRequest, BatchResponse, LockList, LockListQuery, LockRequest, LockResponse,
+        RequestVars, UnlockRequest, UnlockResponse, VerifiableLockRequest,
     },
     LfsConfig,
 };
+use git::protocol::{PackProtocol, Protocol, ServiceType};
 
-use crate::https_server::{AppState, GetParams};
+use crate::git_protocol::http::check_permission;
+use crate::https_server::{forbidden, unauthorized, AppState, GetParams};
+use crate::token_registry::Identity;
 
 const LFS_CONTENT_TYPE: &str = "application/vnd.git-lfs+json";
 
+/// # Checks the directory permissions of the repository for an LFS `operation`.
+///
+/// Downloads need `read` on the repository and uploads need `write`, as fetching and pushing
+/// it do. The LFS API served from the root is the one of the root directory.
+pub async fn check_lfs_permission(
+    state: &AppState,
+    config: &LfsConfig,
+    operation: &str,
+) -> Result<(), (StatusCode, String)> {
+    let repo_path = match config.repo_path.as_str() {
+        "" => "/",
+        repo_path => repo_path,
+    };
+    let mut pack_protocol = PackProtocol::new(
+        PathBuf::from(repo_path),
+        state.storage.clone(),
+        Protocol::Http,
+        &state.config,
+    );
+    pack_protocol.user = config.user.clone();
+    pack_protocol.service_type = match operation {
+        "upload" => ServiceType::ReceivePack,
+        _ => ServiceType::UploadPack,
+    };
+    check_permission(&mut pack_protocol).await
+}
+
 pub async fn lfs_retrieve_lock(
     config: &LfsConfig,
     params: GetParams,
@@ -191,10 +223,30 @@ pub async fn lfs_process_batch(
     config: &LfsConfig,
     req: Request<Body>,
 ) -> Result<Response<Body>, (StatusCode, String)> {
-    let request = Json::from_request(req, &state)
-        .await
-        .unwrap();
-    let result = git::lfs::handler::lfs_process_batch(config, request.0).await;
+    // the action links carry the credentials of the batch request
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let identity = req.extensions().get::<Identity>().cloned();
+    let Json(mut batch_request): Json<BatchRequest> =
+        Json::from_request(req, &state).await.unwrap();
+    if batch_request.operation == "upload" && config.user.is_none() {
+        return Ok(unauthorized("authentication required"));
+    }
+    if identity.is_some_and(|i| !i.allows_lfs(&config.repo_path, &batch_request.operation)) {
+        return Ok(forbidden(&format!(
+            "the token can't {} in {}",
+            batch_request.operation, config.repo_path
+        )));
+    }
+    check_lfs_permission(&state, config, &batch_request.operation).await?;
+    for object in batch_request.objects.iter_mut() {
+        object.authorization = authorization.clone();
+    }
+    let result = git::lfs::handler::lfs_process_batch(config, batch_request).await;
 
     match result {
         Ok(response_objects) => {
@@ -4,19 +4,23 @@
 //!
 //!
 
-use std::sync::Arc;
-
 use git::lfs::LfsConfig;
+use git::protocol::Protocol;
 use https_server::AppState;
-use storage::driver::file_storage::local_storage::LocalStorage;
 
 mod api_service;
+pub mod db;
+pub mod errors;
 mod git_protocol;
+pub mod git_server;
+pub mod health;
 pub mod https_server;
 pub mod init;
+pub mod key_registry;
 mod lfs;
 mod model;
 pub mod ssh_server;
+pub mod token_registry;
 
 impl From<AppState> for LfsConfig {
     fn from(value: AppState) -> Self {
@@ -24,7 +28,11 @@ impl From<AppState> for LfsConfig {
             host: value.options.common.host,
             port: value.options.custom.http_port,
             storage: value.storage,
-            fs_storage: Arc::new(LocalStorage::default()),
+            fs_storage: value.lfs_storage,
+            user: None,
+            protocol: Protocol::Http,
+            repo_path: String::new(),
+            client_addr: None,
         }
     }
 }
@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+
+use entity::audit_log;
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditLogs {
+    pub items: Vec<AuditLogItem>,
+    /// Set when there may be older entries, to pass as `cursor` for the next page.
+    pub next_cursor: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditLogItem {
+    pub id: i64,
+    pub action: String,
+    pub actor: Option<String>,
+    pub protocol: String,
+    pub repo_path: String,
+    pub target: String,
+    pub old_id: Option<String>,
+    pub new_id: Option<String>,
+    pub result: String,
+    pub client_addr: Option<String>,
+    pub created_at: String,
+}
+
+impl From<audit_log::Model> for AuditLogItem {
+    fn from(value: audit_log::Model) -> Self {
+        AuditLogItem {
+            id: value.id,
+            action: value.action,
+            actor: value.actor,
+            protocol: value.protocol,
+            repo_path: value.repo_path,
+            target: value.target,
+            old_id: value.old_id,
+            new_id: value.new_id,
+            result: value.result,
+            client_addr: value.client_addr,
+            // stored in UTC
+            created_at: value.created_at.and_utc().to_rfc3339(),
+        }
+    }
+}
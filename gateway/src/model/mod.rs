@@ -1,2 +1,3 @@
+pub mod audit;
 pub mod object_detail;
 pub mod query;
@@ -11,3 +11,16 @@ pub struct DirectoryQuery {
 fn default_path() -> String {
     "/".to_string()
 }
+
+/// The filters of `/api/v1/audit`, `since` and `until` are RFC 3339 dates and `cursor` is the
+/// `next_cursor` of the previous page.
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub repo_path: Option<String>,
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub cursor: Option<i64>,
+    pub limit: Option<u64>,
+}
@@ -1,12 +1,11 @@
 //!
 //!
 //!
-use std::collections::HashMap;
-use std::env;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
@@ -16,7 +15,9 @@ use clap::Args;
 use ed25519_dalek::{SigningKey, SIGNATURE_LENGTH};
 use russh_keys::key::KeyPair;
 
+use common::config::Config;
 use common::model::CommonOptions;
+use git::protocol::ProtocolVersion;
 use storage::driver::database;
 
 use crate::git_protocol::ssh::SshServer;
@@ -40,22 +41,27 @@ pub struct SshCustom {
 
     #[arg(long, value_name = "FILE")]
     ssh_cert_path: Option<PathBuf>,
+
+    /// Base URL of the http service, where git-lfs-authenticate sends LFS clients, defaults
+    /// to http on port 8000 of the host
+    #[arg(long, value_name = "URL")]
+    pub lfs_url: Option<String>,
 }
 
 /// start a ssh server
-pub async fn start_server(command: &SshOptions) {
+pub async fn start_server(config: &Config, command: &SshOptions) {
     // we need to persist the key to prevent key expired after server restart.
-    let client_key = load_key().await.unwrap();
+    let client_key = load_key(&config.ssh.root).await.unwrap();
     let client_pubkey = Arc::new(client_key.clone_public_key().unwrap());
 
-    let mut config = russh::server::Config {
+    let mut ssh_config = russh::server::Config {
         auth_rejection_time: std::time::Duration::from_secs(3),
         auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
+        // clients authenticate with a key of the registry, see `key_registry`
+        methods: russh::MethodSet::PUBLICKEY,
         ..Default::default()
     };
-    config.keys.push(client_key);
-
-    let config = Arc::new(config);
+    ssh_config.keys.push(client_key);
 
     let SshOptions {
         common: CommonOptions { host, data_source },
@@ -64,24 +70,36 @@ pub async fn start_server(command: &SshOptions) {
                 ssh_port,
                 ssh_key_path: _,
                 ssh_cert_path: _,
+                lfs_url,
             },
     } = command;
     let sh = SshServer {
         client_pubkey,
         clients: Arc::new(Mutex::new(HashMap::new())),
         id: 0,
-        storage: database::init(data_source).await,
+        storage: database::init(config, data_source).await,
+        config: Arc::new(config.clone()),
+        protocol_version: ProtocolVersion::default(),
+        pack_sending: HashSet::new(),
+        user: None,
+        client_addr: None,
+        lfs_url: lfs_url
+            .clone()
+            .unwrap_or_else(|| format!("http://{}:8000", host)),
+        lfs_transfer: HashMap::new(),
         pack_protocol: None,
     };
     let server_url = format!("{}:{}", host, ssh_port);
     let addr = SocketAddr::from_str(&server_url).unwrap();
-    russh::server::run(config, addr, sh).await.unwrap()
+    russh::server::run(Arc::new(ssh_config), addr, sh)
+        .await
+        .unwrap()
 }
 
 /// # Loads an SSH keypair.
 ///
 /// This function follows the following steps:
-/// 1. It retrieves the root directory for the SSH key from the environment variable SSH_ROOT using env::var.
+/// 1. It creates the root directory for the SSH key, `ssh.root` in the configuration, if needed.
 /// 2. It constructs the path to the SSH private key file by joining the root directory with the filename "id_rsa" using PathBuf.
 /// 3. It checks if the key file exists. If it doesn't, it generates a new Ed25519 keypair using KeyPair::generate_ed25519.
 /// - The generated keypair is then written to the key file.
@@ -91,9 +109,9 @@ pub async fn start_server(command: &SshOptions) {
 /// # Returns
 ///
 /// An asynchronous Result containing the loaded SSH keypair if successful, or an error if any of the steps fail.
-async fn load_key() -> Result<KeyPair> {
-    let key_root = env::var("SSH_ROOT").expect("SSH_ROOT is not set in .env file");
-    let key_path = PathBuf::from(key_root).join("id_rsa");
+async fn load_key(key_root: &Path) -> Result<KeyPair> {
+    std::fs::create_dir_all(key_root)?;
+    let key_path = key_root.join("id_rsa");
     if !key_path.exists() {
         // generate a keypair if not exists
         let keys = KeyPair::generate_ed25519().unwrap();
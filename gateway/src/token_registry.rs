@@ -0,0 +1,527 @@
+//!
+//! Access tokens of the users, which authenticate the smart-HTTP and LFS requests.
+//!
+//! A token is sent either as the password of HTTP Basic, with the name of its owner as user
+//! name, or as a `Bearer` token. Only the SHA256 of a token is stored, and a token grants the
+//! `read`, `write` and `lfs` scopes it was created with.
+//!
+//! The ssh service also hands short-lived LFS tokens out through `git-lfs-authenticate`, which
+//! aren't stored but signed with the HMAC secret `auth.lfs_token_secret` of the configuration,
+//! shared by the ssh and http services. Such a token only grants the LFS operation it was
+//! asked for on its repository.
+//!
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use axum::http::header::AUTHORIZATION;
+use axum::http::HeaderMap;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use clap::{Args, Subcommand};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::Rng;
+use sea_orm::{NotSet, Set};
+use serde::{Deserialize, Serialize};
+
+use common::config::{AuthConfig, Config};
+use common::enums::DataSource;
+use common::errors::MegaError;
+use entity::access_token;
+use storage::driver::database;
+use storage::driver::database::storage::ObjectStorage;
+
+use crate::key_registry::ensure_user;
+
+const TOKEN_PREFIX: &str = "mega_";
+
+/// Seconds an LFS token given by `git-lfs-authenticate` is valid.
+const LFS_TOKEN_LIFETIME: i64 = 600;
+
+#[derive(Args, Clone, Debug)]
+pub struct TokenOptions {
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    pub data_source: DataSource,
+
+    #[command(subcommand)]
+    pub command: TokenCommand,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum TokenCommand {
+    /// Create a token for a user, the user is created if needed
+    Add {
+        user: String,
+        name: String,
+        /// Comma separated scopes among read, write and lfs
+        #[arg(long, default_value = "read,write,lfs")]
+        scopes: String,
+        /// Number of days the token is valid, it never expires if not set
+        #[arg(long)]
+        expires_in_days: Option<i64>,
+    },
+    /// List the tokens of a user
+    List { user: String },
+    /// Revoke a token of a user
+    Revoke { user: String, id: i32 },
+}
+
+/// Runs a `mega token` command.
+pub async fn exec_token_command(config: &Config, options: &TokenOptions) -> Result<(), MegaError> {
+    let storage = database::init(config, &options.data_source).await;
+    match &options.command {
+        TokenCommand::Add {
+            user,
+            name,
+            scopes,
+            expires_in_days,
+        } => {
+            let scopes = parse_scopes(scopes)?;
+            let (model, token) =
+                add_token(storage.as_ref(), user, name, &scopes, *expires_in_days).await?;
+            println!("added token {}, it won't be shown again:", model.id);
+            println!("{}", token);
+        }
+        TokenCommand::List { user } => {
+            for token in list_tokens(storage.as_ref(), user).await? {
+                let expires_at = token
+                    .expires_at
+                    .map(|t| t.to_string())
+                    .unwrap_or_else(|| String::from("never"));
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    token.id, token.name, token.scopes, token.created_at, expires_at
+                );
+            }
+        }
+        TokenCommand::Revoke { user, id } => {
+            if !revoke_token(storage.as_ref(), user, *id).await? {
+                return Err(MegaError::with_message(&format!(
+                    "{} has no token {}",
+                    user, id
+                )));
+            }
+            println!("revoked token {}", id);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Read,
+    Write,
+    Lfs,
+}
+
+impl FromStr for Scope {
+    type Err = MegaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "lfs" => Ok(Scope::Lfs),
+            _ => Err(MegaError::with_message(&format!("unknown scope {}", s))),
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scope = match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Lfs => "lfs",
+        };
+        write!(f, "{}", scope)
+    }
+}
+
+/// Parses comma separated scopes, such as `read,lfs`.
+pub fn parse_scopes(scopes: &str) -> Result<Vec<Scope>, MegaError> {
+    scopes
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// The user authenticated by a request, with the scopes of the token it used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub user: String,
+    pub scopes: Vec<Scope>,
+    // the restriction of an LFS token given by `git-lfs-authenticate`
+    pub lfs_grant: Option<LfsGrant>,
+}
+
+impl Identity {
+    /// Whether the token grants `scope`, `write` implies `read`.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+            || (scope == Scope::Read && self.scopes.contains(&Scope::Write))
+    }
+
+    /// Whether the token grants the LFS `operation` on `repo_path`, which any token with the
+    /// `lfs` scope does unless it is restricted by an `LfsGrant`.
+    pub fn allows_lfs(&self, repo_path: &str, operation: &str) -> bool {
+        self.lfs_grant
+            .as_ref()
+            .is_none_or(|grant| grant.allows(repo_path, operation))
+    }
+}
+
+/// The repository and the operation, `download` or `upload`, of an LFS token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LfsGrant {
+    pub repo_path: String,
+    pub operation: String,
+}
+
+impl LfsGrant {
+    /// An `upload` token also downloads, as git-lfs checks the objects it pushes.
+    pub fn allows(&self, repo_path: &str, operation: &str) -> bool {
+        self.repo_path == repo_path && (self.operation == "upload" || operation == "download")
+    }
+}
+
+/// The credentials of an `Authorization` header.
+#[derive(Debug, PartialEq)]
+pub enum Credentials {
+    Basic { user: String, password: String },
+    Bearer(String),
+}
+
+impl Credentials {
+    /// Reads the `Authorization` header, `None` if there is none, an error if it is malformed.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, MegaError> {
+        let value = match headers.get(AUTHORIZATION) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| MegaError::with_message("malformed authorization header"))?,
+            None => return Ok(None),
+        };
+        if let Some(encoded) = value.strip_prefix("Basic ") {
+            let decoded = STANDARD
+                .decode(encoded.trim())
+                .ok()
+                .and_then(|d| String::from_utf8(d).ok())
+                .ok_or_else(|| MegaError::with_message("malformed basic credentials"))?;
+            let (user, password) = decoded
+                .split_once(':')
+                .ok_or_else(|| MegaError::with_message("malformed basic credentials"))?;
+            Ok(Some(Credentials::Basic {
+                user: user.to_owned(),
+                password: password.to_owned(),
+            }))
+        } else if let Some(token) = value.strip_prefix("Bearer ") {
+            Ok(Some(Credentials::Bearer(token.trim().to_owned())))
+        } else {
+            Err(MegaError::with_message("unsupported authorization scheme"))
+        }
+    }
+}
+
+/// The hex SHA256 of a token, which is what the database stores.
+pub fn hash_token(token: &str) -> String {
+    sha256::digest(token)
+}
+
+/// Generates a new random token.
+pub fn generate_token() -> String {
+    let bytes: [u8; 20] = rand::thread_rng().gen();
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", TOKEN_PREFIX, hex)
+}
+
+/// Claims of the LFS tokens given by `git-lfs-authenticate`.
+#[derive(Debug, Serialize, Deserialize)]
+struct LfsClaims {
+    sub: String,
+    exp: i64,
+    repo: String,
+    operation: String,
+}
+
+// without a configured secret, only the http service of the same process accepts the tokens
+fn lfs_token_secret(config: &AuthConfig) -> &[u8] {
+    static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+    match &config.lfs_token_secret {
+        Some(secret) => secret.as_bytes(),
+        None => SECRET.get_or_init(|| rand::thread_rng().gen::<[u8; 32]>().to_vec()),
+    }
+}
+
+/// Signs an LFS token for `user` granting `grant`, valid until the returned time.
+pub fn issue_lfs_token(
+    config: &AuthConfig,
+    user: &str,
+    grant: &LfsGrant,
+) -> (String, DateTime<Utc>) {
+    let expires_at = Utc::now() + chrono::Duration::seconds(LFS_TOKEN_LIFETIME);
+    (
+        encode_lfs_token(config, user, grant, expires_at.timestamp()),
+        expires_at,
+    )
+}
+
+fn encode_lfs_token(config: &AuthConfig, user: &str, grant: &LfsGrant, exp: i64) -> String {
+    let claims = LfsClaims {
+        sub: user.to_owned(),
+        exp,
+        repo: grant.repo_path.clone(),
+        operation: grant.operation.clone(),
+    };
+    jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(lfs_token_secret(config)),
+    )
+    .unwrap()
+}
+
+/// Returns the user and the grant of an LFS token, if its signature is valid and it hasn't
+/// expired.
+pub fn verify_lfs_token(config: &AuthConfig, token: &str) -> Option<(String, LfsGrant)> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.leeway = 0;
+    jsonwebtoken::decode::<LfsClaims>(
+        token,
+        &DecodingKey::from_secret(lfs_token_secret(config)),
+        &validation,
+    )
+    .ok()
+    .map(|data| {
+        let grant = LfsGrant {
+            repo_path: data.claims.repo,
+            operation: data.claims.operation,
+        };
+        (data.claims.sub, grant)
+    })
+}
+
+/// Creates a token, returns the stored token and the token itself, which isn't stored.
+pub async fn add_token(
+    storage: &dyn ObjectStorage,
+    user_name: &str,
+    name: &str,
+    scopes: &[Scope],
+    expires_in_days: Option<i64>,
+) -> Result<(access_token::Model, String), MegaError> {
+    if scopes.is_empty() {
+        return Err(MegaError::with_message("a token needs at least one scope"));
+    }
+    let user_id = ensure_user(storage, user_name).await?;
+    let token = generate_token();
+    let now = chrono::Utc::now().naive_utc();
+    let mut model = access_token::Model {
+        id: 0,
+        user_id,
+        name: name.to_owned(),
+        token_hash: hash_token(&token),
+        scopes: scopes
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+        expires_at: expires_in_days.map(|days| now + chrono::Duration::days(days)),
+        created_at: now,
+    };
+    model.id = storage
+        .save_access_token(access_token::ActiveModel {
+            id: NotSet,
+            user_id: Set(model.user_id),
+            name: Set(model.name.clone()),
+            token_hash: Set(model.token_hash.clone()),
+            scopes: Set(model.scopes.clone()),
+            expires_at: Set(model.expires_at),
+            created_at: Set(model.created_at),
+        })
+        .await?;
+    Ok((model, token))
+}
+
+pub async fn list_tokens(
+    storage: &dyn ObjectStorage,
+    user_name: &str,
+) -> Result<Vec<access_token::Model>, MegaError> {
+    match storage.get_user_by_name(user_name).await? {
+        Some(user) => storage.list_access_tokens(user.id).await,
+        None => Ok(vec![]),
+    }
+}
+
+/// Revokes a token of the user, returns false if the user has no such token.
+pub async fn revoke_token(
+    storage: &dyn ObjectStorage,
+    user_name: &str,
+    token_id: i32,
+) -> Result<bool, MegaError> {
+    match storage.get_user_by_name(user_name).await? {
+        Some(user) => storage.revoke_access_token(user.id, token_id).await,
+        None => Ok(false),
+    }
+}
+
+/// # Authenticates the credentials of a request.
+///
+/// Returns `None` for anonymous requests, and an error if the token is unknown, expired,
+/// or if the Basic user name isn't the name of its owner.
+pub async fn authenticate(
+    storage: &dyn ObjectStorage,
+    config: &AuthConfig,
+    credentials: Option<Credentials>,
+) -> Result<Option<Identity>, MegaError> {
+    let (user, token) = match credentials {
+        Some(Credentials::Basic { user, password }) => (Some(user), password),
+        Some(Credentials::Bearer(token)) => (None, token),
+        None => return Ok(None),
+    };
+    let invalid = || MegaError::with_message("invalid credentials");
+    if user.is_none() && !token.starts_with(TOKEN_PREFIX) {
+        let (user, grant) = verify_lfs_token(config, &token).ok_or_else(invalid)?;
+        return Ok(Some(Identity {
+            user,
+            scopes: vec![Scope::Lfs],
+            lfs_grant: Some(grant),
+        }));
+    }
+    let registered = storage
+        .get_access_token_by_hash(&hash_token(&token))
+        .await?
+        .ok_or_else(invalid)?;
+    if registered
+        .expires_at
+        .is_some_and(|t| t <= chrono::Utc::now().naive_utc())
+    {
+        return Err(MegaError::with_message("the token has expired"));
+    }
+    let owner = storage
+        .get_user_by_id(registered.user_id)
+        .await?
+        .ok_or_else(invalid)?;
+    if user.is_some_and(|user| user != owner.name) {
+        return Err(invalid());
+    }
+    Ok(Some(Identity {
+        user: owner.name,
+        scopes: parse_scopes(&registered.scopes)?,
+        lfs_grant: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::AUTHORIZATION;
+    use axum::http::HeaderMap;
+
+    use common::config::AuthConfig;
+
+    use crate::token_registry::{
+        encode_lfs_token, generate_token, hash_token, issue_lfs_token, parse_scopes,
+        verify_lfs_token, Credentials, Identity, LfsGrant, Scope,
+    };
+
+    #[test]
+    fn test_credentials_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Credentials::from_headers(&headers).unwrap(), None);
+        // alice:secret
+        headers.insert(AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0".parse().unwrap());
+        assert_eq!(
+            Credentials::from_headers(&headers).unwrap(),
+            Some(Credentials::Basic {
+                user: String::from("alice"),
+                password: String::from("secret"),
+            })
+        );
+        headers.insert(AUTHORIZATION, "Bearer mega_0123".parse().unwrap());
+        assert_eq!(
+            Credentials::from_headers(&headers).unwrap(),
+            Some(Credentials::Bearer(String::from("mega_0123")))
+        );
+        headers.insert(AUTHORIZATION, "Basic not base64".parse().unwrap());
+        assert!(Credentials::from_headers(&headers).is_err());
+        headers.insert(AUTHORIZATION, "Digest username=alice".parse().unwrap());
+        assert!(Credentials::from_headers(&headers).is_err());
+    }
+
+    #[test]
+    fn test_token_hash_and_scopes() {
+        let token = generate_token();
+        assert!(token.starts_with("mega_"));
+        assert_eq!(token.len(), 45);
+        assert_ne!(token, generate_token());
+        assert_eq!(
+            hash_token("secret"),
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+        );
+
+        let scopes = parse_scopes("write, lfs").unwrap();
+        assert_eq!(scopes, vec![Scope::Write, Scope::Lfs]);
+        assert!(parse_scopes("read,admin").is_err());
+        let identity = Identity {
+            user: String::from("alice"),
+            scopes,
+            lfs_grant: None,
+        };
+        assert!(identity.has_scope(Scope::Read));
+        assert!(identity.has_scope(Scope::Lfs));
+        let identity = Identity {
+            user: String::from("alice"),
+            scopes: vec![Scope::Read],
+            lfs_grant: None,
+        };
+        assert!(!identity.has_scope(Scope::Write));
+    }
+
+    #[test]
+    fn test_lfs_token() {
+        let config = AuthConfig::default();
+        let grant = LfsGrant {
+            repo_path: String::from("/projects/mega"),
+            operation: String::from("download"),
+        };
+        let alice = Some((String::from("alice"), grant.clone()));
+        let (token, expires_at) = issue_lfs_token(&config, "alice", &grant);
+        assert!(expires_at > chrono::Utc::now());
+        assert_eq!(verify_lfs_token(&config, &token), alice);
+
+        let mut tampered = token.clone();
+        tampered.insert(token.find('.').unwrap() + 1, 'x');
+        assert_eq!(verify_lfs_token(&config, &tampered), None);
+        let expired =
+            encode_lfs_token(&config, "alice", &grant, chrono::Utc::now().timestamp() - 1);
+        assert_eq!(verify_lfs_token(&config, &expired), None);
+
+        let shared = AuthConfig {
+            lfs_token_secret: Some(String::from("shared secret")),
+        };
+        let (token, _) = issue_lfs_token(&shared, "alice", &grant);
+        assert_eq!(verify_lfs_token(&config, &token), None);
+        assert_eq!(verify_lfs_token(&shared, &token), alice);
+    }
+
+    #[test]
+    fn test_lfs_grant() {
+        let mut identity = Identity {
+            user: String::from("alice"),
+            scopes: vec![Scope::Lfs],
+            lfs_grant: None,
+        };
+        assert!(identity.allows_lfs("/projects/other", "upload"));
+        identity.lfs_grant = Some(LfsGrant {
+            repo_path: String::from("/projects/mega"),
+            operation: String::from("download"),
+        });
+        assert!(identity.allows_lfs("/projects/mega", "download"));
+        assert!(!identity.allows_lfs("/projects/mega", "upload"));
+        assert!(!identity.allows_lfs("/projects/other", "download"));
+        identity.lfs_grant.as_mut().unwrap().operation = String::from("upload");
+        assert!(identity.allows_lfs("/projects/mega", "download"));
+        assert!(identity.allows_lfs("/projects/mega", "upload"));
+    }
+}
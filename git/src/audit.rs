@@ -0,0 +1,189 @@
+//!
+//! The audit log of the changes made through mega: the ref updates of the pushes, the LFS
+//! uploads and the lock operations. Each entry records who made the change, over which
+//! protocol and from which address, and whether it was applied.
+//!
+//! The ref updates are saved in the transaction that applies them, so the log can't miss a
+//! moved ref. The other entries are saved once the change is done and only logged on failure.
+//!
+use std::collections::HashMap;
+
+use chrono::Utc;
+use sea_orm::{ActiveValue::NotSet, Set};
+
+use entity::audit_log;
+use storage::driver::database::storage::ObjectStorage;
+
+use crate::protocol::{PackProtocol, Protocol, RefCommand};
+use crate::structure::subrepo::TrunkUpdate;
+
+pub const RESULT_OK: &str = "ok";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AuditAction {
+    RefUpdate,
+    LfsUpload,
+    LockCreate,
+    LockDelete,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::RefUpdate => "ref_update",
+            AuditAction::LfsUpload => "lfs_upload",
+            AuditAction::LockCreate => "lock_create",
+            AuditAction::LockDelete => "lock_delete",
+        }
+    }
+}
+
+/// A change to record, `target` is the ref name, the LFS object id or the locked path.
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub action: AuditAction,
+    pub actor: Option<String>,
+    pub protocol: Protocol,
+    pub repo_path: String,
+    pub target: String,
+    pub old_id: Option<String>,
+    pub new_id: Option<String>,
+    /// `ok`, or the reason the change was rejected.
+    pub result: String,
+    pub client_addr: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn into_active_model(self) -> audit_log::ActiveModel {
+        audit_log::ActiveModel {
+            id: NotSet,
+            action: Set(self.action.as_str().to_owned()),
+            actor: Set(self.actor),
+            protocol: Set(self.protocol.as_str().to_owned()),
+            repo_path: Set(self.repo_path),
+            target: Set(self.target),
+            old_id: Set(self.old_id),
+            new_id: Set(self.new_id),
+            result: Set(self.result),
+            client_addr: Set(self.client_addr),
+            created_at: Set(Utc::now().naive_utc()),
+        }
+    }
+}
+
+/// Saves `entries` outside of any transaction. The changes they describe are already done, so
+/// a failure is only logged.
+pub async fn record(storage: &dyn ObjectStorage, entries: Vec<AuditEntry>) {
+    let models = entries
+        .into_iter()
+        .map(AuditEntry::into_active_model)
+        .collect();
+    if let Err(e) = storage.save_audit_logs(None, models).await {
+        tracing::error!("failed to save the audit log: {}", e);
+    }
+}
+
+impl PackProtocol {
+    /// # The entries of the ref commands of a push, with their status.
+    ///
+    /// A branch pushed to a sub-directory repo also moves the trunk branch, which gets its own
+    /// entry when the command succeeded.
+    pub fn ref_audit_entries(
+        &self,
+        trunk_updates: &HashMap<String, TrunkUpdate>,
+    ) -> Vec<AuditEntry> {
+        let path = self.path.to_str().unwrap();
+        let mut entries = vec![];
+        for command in &self.command_list {
+            entries.push(self.ref_audit_entry(path, command, &command.old_id, &command.new_id));
+            match trunk_updates.get(&command.ref_name) {
+                Some(update) if command.is_ok() && update.old_id != update.new_id => {
+                    entries.push(self.ref_audit_entry(
+                        &update.trunk_path,
+                        command,
+                        &update.old_id,
+                        &update.new_id,
+                    ));
+                }
+                _ => {}
+            }
+        }
+        entries
+    }
+
+    fn ref_audit_entry(
+        &self,
+        repo_path: &str,
+        command: &RefCommand,
+        old_id: &str,
+        new_id: &str,
+    ) -> AuditEntry {
+        AuditEntry {
+            action: AuditAction::RefUpdate,
+            actor: self.user.clone(),
+            protocol: self.protocol,
+            repo_path: repo_path.to_owned(),
+            target: command.ref_name.clone(),
+            old_id: Some(old_id.to_owned()),
+            new_id: Some(new_id.to_owned()),
+            result: if command.is_ok() {
+                RESULT_OK.to_owned()
+            } else {
+                command.error_msg.clone()
+            },
+            client_addr: self.client_addr.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use crate::audit::{AuditAction, RESULT_OK};
+    use crate::protocol::{PackProtocol, Protocol, RefCommand};
+
+    const OLD_ID: &str = "7bdc783132575d5b3e78400ace9971970ff43a18";
+    const NEW_ID: &str = "8f19cdbd7e5f3f9d2c3a4f6e0a4ae2e0b0e9a5b1";
+
+    #[test]
+    fn test_ref_audit_entries() {
+        let mut mock = PackProtocol::mock();
+        mock.protocol = Protocol::Ssh;
+        mock.path = PathBuf::from("/projects/mega");
+        mock.user = Some(String::from("alice"));
+        mock.client_addr = Some(String::from("127.0.0.1:50022"));
+        mock.command_list = vec![
+            RefCommand::new(
+                OLD_ID.to_owned(),
+                NEW_ID.to_owned(),
+                String::from("refs/heads/main"),
+            ),
+            RefCommand::new(
+                NEW_ID.to_owned(),
+                OLD_ID.to_owned(),
+                String::from("refs/heads/dev"),
+            ),
+        ];
+        mock.command_list[1].failed(String::from("non-fast-forward"));
+
+        let entries = mock.ref_audit_entries(&HashMap::new());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, AuditAction::RefUpdate);
+        assert_eq!(entries[0].repo_path, "/projects/mega");
+        assert_eq!(entries[0].target, "refs/heads/main");
+        assert_eq!(entries[0].old_id.as_deref(), Some(OLD_ID));
+        assert_eq!(entries[0].new_id.as_deref(), Some(NEW_ID));
+        assert_eq!(entries[0].result, RESULT_OK);
+        assert_eq!(entries[1].result, "non-fast-forward");
+
+        let model = entries[0].clone().into_active_model();
+        assert_eq!(model.protocol.unwrap(), "ssh");
+        assert_eq!(model.actor.unwrap().as_deref(), Some("alice"));
+        assert_eq!(
+            model.client_addr.unwrap().as_deref(),
+            Some("127.0.0.1:50022")
+        );
+    }
+}
@@ -7,6 +7,8 @@ use std::string::FromUtf8Error;
 
 use thiserror::Error;
 
+use common::errors::{MegaError, StorageError};
+
 #[derive(Error, Debug)]
 #[allow(unused)]
 pub enum GitError {
@@ -43,6 +45,9 @@ pub enum GitError {
     #[error("The `{0}` is not a valid pack header.")]
     InvalidPackHeader(String),
 
+    #[error("The `{0}` is not a valid filter-spec.")]
+    InvalidFilterSpec(String),
+
     #[error("The {0} is not a valid Hash value ")]
     InvalidHashValue(String),
 
@@ -61,8 +66,35 @@ pub enum GitError {
     #[error("Can't encode the object which id [{0}] to bytes")]
     EncodeObjectError(String),
 
+    #[error("Can't send the pack data, {0}")]
+    SendPackError(String),
+
+    #[error("Can't update the refs, {0}")]
+    RefUpdateError(String),
+
+    #[error("Permission denied, {0}")]
+    PermissionDenied(String),
+
     #[error("UTF-8 conversion error: {0}")]
     ConversionError(String),
+
+    #[error("Storage error, {0}")]
+    StorageError(StorageError),
+}
+
+impl From<MegaError> for GitError {
+    fn from(err: MegaError) -> Self {
+        match err.storage_error() {
+            Some(err) => GitError::StorageError(err.clone()),
+            None => GitError::StorageError(StorageError::Other(err.to_string())),
+        }
+    }
+}
+
+impl From<sea_orm::DbErr> for GitError {
+    fn from(err: sea_orm::DbErr) -> Self {
+        GitError::StorageError(err.into())
+    }
 }
 
 impl From<FromUtf8Error> for GitError {
@@ -51,11 +51,16 @@ impl Display for GitObjects {
 }
 
 pub fn from_model(model: objects::Model) -> Arc<dyn ObjectT> {
+    fn with_hash<T: ObjectT + 'static>(mut obj: T, id: Hash) -> Arc<dyn ObjectT> {
+        obj.set_hash(id);
+        Arc::new(obj)
+    }
+    let id = Hash::new_from_str(&model.git_id);
     let obj: Arc<dyn ObjectT> = match &model.object_type as &str {
-        "blob" => Arc::new(Blob::new_from_data(model.data)),
-        "commit" => Arc::new(Commit::new_from_data(model.data)),
-        "tag" => Arc::new(Tag::new_from_data(model.data)),
-        "tree" => Arc::new(Tree::new_from_data(model.data)),
+        "blob" => with_hash(Blob::new_from_data(model.data), id),
+        "commit" => with_hash(Commit::new_from_data(model.data), id),
+        "tag" => with_hash(Tag::new_from_data(model.data), id),
+        "tree" => with_hash(Tree::new_from_data(model.data), id),
         &_ => todo!(),
     };
     
@@ -2,6 +2,8 @@ use std::{collections::HashMap, num::NonZeroUsize, cell::RefCell};
 
 use lru::LruCache;
 
+use common::config::PackConfig;
+
 use crate::hash::Hash;
 #[derive(Hash, Clone, PartialEq, Eq)]
 struct OffHash {
@@ -12,6 +14,10 @@ struct OffHash {
 pub trait _Cache {
     type T ;
     fn new(size: Option<usize>) -> Self where Self: Sized;
+    /// The cache of a decoding thread.
+    fn from_config(config: &PackConfig) -> Self where Self: Sized {
+        Self::new(Some(config.decode_cache_size))
+    }
     fn get_hash(&self, offset: usize) -> Option<Hash>;
     fn get(&self, offset: usize) -> Option<Self::T>;
     fn put(&self, offset: usize, hash: Hash, obj: Self::T);
@@ -114,6 +120,8 @@ pub mod kvstore{
     use kvcache::connector::redis::RedisClient;
     use kvcache::KVCache;
 
+    use common::config::PackConfig;
+
     use crate::internal::pack::Hash;
     use crate::internal::pack::_Cache;
 
@@ -137,6 +145,12 @@ pub mod kvstore{
         fn new(_size: Option<usize>) -> Self {
            Self::default()
         }
+        fn from_config(config: &PackConfig) -> Self {
+            Self {
+                ioffset: RefCell::new(HashMap::new()),
+                inner: KVCache::with_connector(RedisClient::open(&config.redis_url)),
+            }
+        }
         fn get_hash(&self, offset: usize) -> Option<Hash> {
             self.ioffset.borrow().get(&offset).copied()
         }
@@ -1,4 +1,5 @@
 use std::fmt::Display;
+use std::time::Duration;
 /// A Counter for counting git object types
 #[derive(Default,Clone, Copy)]
 pub struct GitTypeCounter{
@@ -69,7 +70,16 @@ impl DecodeCounter{
     pub fn count_depth(&mut self, depth :usize){
         self.delta_depth+=depth;
     }
-    
+
+    /// Adds the counts of a decoded pack to the `mega_pack_decode_*` metrics, the decode
+    /// throughput is the rate of the objects over the rate of the duration.
+    pub fn record_metrics(&self, elapsed: Duration) {
+        metrics::counter!("mega_pack_decode_objects_total", "type" => "base").increment(self.base as u64);
+        metrics::counter!("mega_pack_decode_objects_total", "type" => "delta").increment(self.delta as u64);
+        metrics::counter!("mega_pack_decode_cache_hits_total").increment(self.cache_hit as u64);
+        metrics::counter!("mega_pack_decode_storage_lookups_total").increment(self.db_look as u64);
+        metrics::histogram!("mega_pack_decode_duration_seconds").record(elapsed.as_secs_f64());
+    }
 }
 impl Display for DecodeCounter{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
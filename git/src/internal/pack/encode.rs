@@ -1,14 +1,19 @@
-use std::io::{Cursor, Error, Write};
+use std::collections::VecDeque;
+use std::io::{Cursor, Error, ErrorKind, Write};
 use std::sync::Arc;
 
 use sha1::{Digest, Sha1};
 
+use common::config::PackConfig;
 use delta;
 use entity::objects;
 
+use crate::hash::Hash;
 use crate::internal::object::ObjectT;
 use crate::internal::pack::header::EntryHeader;
 use crate::internal::zlib::stream::deflate::Write as Writer;
+use crate::internal::ObjectType;
+use crate::utils;
 
 const SLID_WINDWOS: usize = 20;
 
@@ -101,6 +106,234 @@ pub fn pack_encode(obj_vec: Vec<Arc<dyn ObjectT>>) -> Result<Vec<u8>, Error> {
     Ok(out_data)
 }
 
+/// Tuning of the delta compression done by `PackWriter`, from the `pack.delta_*` keys of the
+/// configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeltaOptions {
+    /// Number of previously written objects tried as delta base.
+    pub window: usize,
+    /// Maximum length of a delta chain, deep chains are slow to resolve for the client.
+    pub max_depth: usize,
+    /// Minimum `delta::encode_rate` of a base, poorer bases are not even tried.
+    pub min_rate: f64,
+    /// Objects larger than this are never deltified nor used as base.
+    pub max_size: usize,
+}
+
+impl Default for DeltaOptions {
+    fn default() -> Self {
+        DeltaOptions {
+            window: 10,
+            max_depth: 50,
+            min_rate: 0.5,
+            max_size: 1024 * 1024,
+        }
+    }
+}
+
+impl DeltaOptions {
+    pub fn from_config(config: &PackConfig) -> Self {
+        DeltaOptions {
+            window: config.delta_window,
+            max_depth: config.delta_max_depth,
+            min_rate: config.delta_min_rate,
+            // the copy instructions of a delta can't address more than 16MiB
+            max_size: config.delta_max_size.min(0xffffff),
+        }
+    }
+}
+
+/// Encodes a pack one object at a time, so that each entry can be sent as soon as it is
+/// deflated instead of building the whole pack in memory. The number of objects has to be
+/// known up front, since it is part of the pack header.
+pub struct PackWriter {
+    hash: Sha1,
+    remaining: usize,
+    // number of bytes written so far, the offset of the next entry
+    offset: usize,
+    delta: Option<DeltaWindow>,
+}
+
+// the trees and blobs last written, candidates as delta base for the next ones
+struct DeltaWindow {
+    options: DeltaOptions,
+    ofs_delta: bool,
+    entries: VecDeque<WindowEntry>,
+}
+
+struct WindowEntry {
+    id: Hash,
+    offset: usize,
+    obj_type: ObjectType,
+    data: Vec<u8>,
+    depth: usize,
+}
+
+impl DeltaWindow {
+    /// Picks the base with the best `encode_rate` among the objects of the same type and of
+    /// similar size, and returns its index with the delta if it is smaller than the object.
+    fn find_delta(&self, obj_type: ObjectType, data: &[u8]) -> Option<(usize, Vec<u8>)> {
+        if !matches!(obj_type, ObjectType::Tree | ObjectType::Blob)
+            || data.is_empty()
+            || data.len() > self.options.max_size
+        {
+            return None;
+        }
+        let mut best = None;
+        let mut best_rate = self.options.min_rate;
+        for (i, base) in self.entries.iter().enumerate().rev() {
+            if base.obj_type != obj_type
+                || base.depth >= self.options.max_depth
+                || base.data.len() > data.len() * 2
+                || data.len() > base.data.len() * 2
+            {
+                continue;
+            }
+            let rate = delta::encode_rate(&base.data, data);
+            if rate > best_rate {
+                best = Some(i);
+                best_rate = rate;
+            }
+        }
+        let i = best?;
+        let delta = delta::encode(&self.entries[i].data, data);
+        if delta.len() < data.len() {
+            Some((i, delta))
+        } else {
+            None
+        }
+    }
+
+    fn push(&mut self, entry: WindowEntry) {
+        if self.options.window == 0 || entry.data.len() > self.options.max_size {
+            return;
+        }
+        if self.entries.len() == self.options.window {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+impl PackWriter {
+    /// Returns the writer and the pack header to send first.
+    pub fn new(object_number: usize) -> (Self, Vec<u8>) {
+        let header_data = encode_header(object_number);
+        let mut hash = Sha1::new();
+        hash.update(&header_data);
+        let writer = PackWriter {
+            hash,
+            remaining: object_number,
+            offset: header_data.len(),
+            delta: None,
+        };
+        (writer, header_data)
+    }
+
+    /// # Returns a writer that deltifies trees and blobs against the last written ones.
+    ///
+    /// Objects should be written grouped by path and by decreasing size, as `git pack-objects`
+    /// does, for the window to find good bases. Deltas are written as `OFS_DELTA` if the client
+    /// supports `ofs-delta`, as `REF_DELTA` otherwise.
+    pub fn with_delta(
+        object_number: usize,
+        options: DeltaOptions,
+        ofs_delta: bool,
+    ) -> (Self, Vec<u8>) {
+        let (mut writer, header_data) = PackWriter::new(object_number);
+        writer.delta = Some(DeltaWindow {
+            options,
+            ofs_delta,
+            entries: VecDeque::new(),
+        });
+        (writer, header_data)
+    }
+
+    /// Returns the encoded entry of `obj`.
+    pub fn write_object(&mut self, obj: Arc<dyn ObjectT>) -> Result<Vec<u8>, Error> {
+        if self.remaining == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "more objects than announced in the pack header",
+            ));
+        }
+        self.remaining -= 1;
+        let obj_data = match self.delta.as_mut() {
+            None => encode_one_object(obj)?,
+            Some(window) => {
+                let obj_type = obj.get_type();
+                let data = obj.get_raw();
+                let (obj_data, depth) = match window.find_delta(obj_type, &data) {
+                    Some((i, delta)) => {
+                        let base = &window.entries[i];
+                        let mut obj_data = if window.ofs_delta {
+                            let mut header = entry_header(6, delta.len());
+                            header.extend(utils::write_offset_encoding(
+                                (self.offset - base.offset) as u64,
+                            ));
+                            header
+                        } else {
+                            let mut header = entry_header(7, delta.len());
+                            header.extend_from_slice(&base.id.0);
+                            header
+                        };
+                        obj_data.extend(deflate(&delta)?);
+                        (obj_data, base.depth + 1)
+                    }
+                    None => (
+                        encode_one_ojbect(obj_type.type2number(), data.len(), &data)?,
+                        0,
+                    ),
+                };
+                if matches!(obj_type, ObjectType::Tree | ObjectType::Blob) {
+                    window.push(WindowEntry {
+                        id: obj.get_hash(),
+                        offset: self.offset,
+                        obj_type,
+                        data,
+                        depth,
+                    });
+                }
+                obj_data
+            }
+        };
+        self.offset += obj_data.len();
+        self.hash.update(&obj_data);
+        Ok(obj_data)
+    }
+
+    /// # Writes entries copied as they are from another pack.
+    ///
+    /// `entries` are `object_number` whole entries, whose deltas refer to the same entries or
+    /// to objects of this pack. They are not used as delta base by the next objects.
+    pub fn write_raw(&mut self, object_number: usize, entries: &[u8]) -> Result<(), Error> {
+        if object_number > self.remaining {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "more objects than announced in the pack header",
+            ));
+        }
+        self.remaining -= object_number;
+        self.offset += entries.len();
+        self.hash.update(entries);
+        Ok(())
+    }
+
+    /// Returns the trailing checksum, fails if fewer objects were written than announced.
+    pub fn finish(self) -> Result<Vec<u8>, Error> {
+        if self.remaining != 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "{} objects announced in the pack header are missing",
+                    self.remaining
+                ),
+            ));
+        }
+        Ok(self.hash.finalize().to_vec())
+    }
+}
+
 fn encode_header(object_number: usize) -> Vec<u8> {
     let mut result: Vec<u8> = vec![
         b'P', b'A', b'C', b'K', // The logotype of the Pack File
@@ -115,55 +348,32 @@ fn encode_header(object_number: usize) -> Vec<u8> {
 }
 
 fn encode_one_object(obj: Arc<dyn ObjectT>) -> Result<Vec<u8>, Error> {
-    let mut out = Writer::new(Vec::new());
     let obj_data = obj.get_raw();
-    let size = obj_data.len();
-    let mut header_data = vec![(0x80 | (obj.get_type().type2number() << 4)) + (size & 0x0f) as u8];
-    let mut _size = size >> 4;
-    if _size > 0 {
-        while _size > 0 {
-            if _size >> 7 > 0 {
-                header_data.push((0x80 | _size) as u8);
-                _size >>= 7;
-            } else {
-                header_data.push((_size) as u8);
-                break;
-            }
-        }
-    } else {
-        header_data.push(0);
-    }
+    encode_one_ojbect(obj.get_type().type2number(), obj_data.len(), &obj_data)
+}
 
-    if let Err(err) = std::io::copy(&mut Cursor::new(obj_data), &mut out) {
-        match err.kind() {
-            std::io::ErrorKind::Other => return Err(err),
-            err => {
-                unreachable!("Should never see other errors than zlib, but got {:?}", err,)
-            }
-        }
-    };
-    out.flush().expect("zlib flush should never fail");
-    header_data.append(&mut out.into_inner());
+fn encode_one_ojbect(git_type: u8, size: usize, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut header_data = entry_header(git_type, size);
+    header_data.append(&mut deflate(data)?);
     Ok(header_data)
 }
 
-fn encode_one_ojbect(git_type: u8, size: usize, data: &[u8]) -> Result<Vec<u8>, Error> {
-    let mut out = Writer::new(Vec::new());
-    let mut header_data = vec![(0x80 | (git_type << 4)) + (size & 0x0f) as u8];
+/// The type and size header of a pack entry, the size being the inflated size of the data.
+fn entry_header(git_type: u8, size: usize) -> Vec<u8> {
+    // the continuation bit is only set if more size bytes follow, as readers count the header
+    // length from the size
+    let mut header_data = vec![(git_type << 4) | (size & 0x0f) as u8];
     let mut _size = size >> 4;
-    if _size > 0 {
-        while _size > 0 {
-            if _size >> 7 > 0 {
-                header_data.push((0x80 | _size) as u8);
-                _size >>= 7;
-            } else {
-                header_data.push((_size) as u8);
-                break;
-            }
-        }
-    } else {
-        header_data.push(0);
+    while _size > 0 {
+        *header_data.last_mut().unwrap() |= 0x80;
+        header_data.push((_size & 0x7f) as u8);
+        _size >>= 7;
     }
+    header_data
+}
+
+fn deflate(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut out = Writer::new(Vec::new());
     if let Err(err) = std::io::copy(&mut Cursor::new(data), &mut out) {
         match err.kind() {
             std::io::ErrorKind::Other => return Err(err),
@@ -173,8 +383,7 @@ fn encode_one_ojbect(git_type: u8, size: usize, data: &[u8]) -> Result<Vec<u8>,
         }
     };
     out.flush().expect("zlib flush should never fail");
-    header_data.append(&mut out.into_inner());
-    Ok(header_data)
+    Ok(out.into_inner())
 }
 
 fn u32_vec(value: u32) -> Vec<u8> {
@@ -196,7 +405,7 @@ mod tests {
     use crate::hash::Hash;
     use crate::internal::object::blob::Blob;
     use crate::internal::object::ObjectT;
-    use crate::internal::pack::encode::{pack_encode, Encoder};
+    use crate::internal::pack::encode::{pack_encode, DeltaOptions, Encoder, PackWriter};
     use crate::internal::pack::Pack;
 
     #[test]
@@ -215,6 +424,81 @@ mod tests {
         block_on(Pack::decode(&mut buff)).unwrap();
     }
 
+    #[test]
+    fn test_pack_writer() {
+        let id = Hash([0u8; 20]);
+        let (mut writer, mut pack_data) = PackWriter::new(2);
+        for data in ["hello,1", "hello,2"] {
+            let blob = Blob {
+                id,
+                data: data.as_bytes().to_vec(),
+            };
+            pack_data.extend(writer.write_object(Arc::new(blob)).unwrap());
+        }
+        pack_data.extend(writer.finish().unwrap());
+        let mut buff = Cursor::new(pack_data);
+        block_on(Pack::decode(&mut buff)).unwrap();
+    }
+
+    // writes similar blobs and returns the pack, which must decode
+    fn write_similar_blobs(writer: PackWriter, mut pack_data: Vec<u8>) -> Vec<u8> {
+        let mut writer = writer;
+        for i in 0..3 {
+            let mut data = String::from("fn main() {\n").repeat(50);
+            data.push_str(&format!("    println!(\"{}\");\n}}\n", i));
+            let mut blob = Blob::new_from_data(data.into_bytes());
+            let raw = [format!("blob {}\0", blob.data.len()).as_bytes(), &blob.data].concat();
+            blob.set_hash(Hash::new(&raw));
+            pack_data.extend(writer.write_object(Arc::new(blob)).unwrap());
+        }
+        pack_data.extend(writer.finish().unwrap());
+        let mut buff = Cursor::new(pack_data.clone());
+        block_on(Pack::decode(&mut buff)).unwrap();
+        pack_data
+    }
+
+    #[test]
+    fn test_pack_writer_delta() {
+        let (writer, header) = PackWriter::new(3);
+        let full = write_similar_blobs(writer, header);
+        for ofs_delta in [true, false] {
+            let (writer, header) = PackWriter::with_delta(3, DeltaOptions::default(), ofs_delta);
+            let deltified = write_similar_blobs(writer, header);
+            assert!(deltified.len() < full.len());
+        }
+
+        // chains are cut at the maximum depth
+        let options = DeltaOptions {
+            max_depth: 0,
+            ..Default::default()
+        };
+        let (writer, header) = PackWriter::with_delta(3, options, true);
+        assert_eq!(write_similar_blobs(writer, header).len(), full.len());
+    }
+
+    #[test]
+    fn test_pack_writer_raw() {
+        let (writer, header) = PackWriter::with_delta(3, DeltaOptions::default(), true);
+        let reused = write_similar_blobs(writer, header);
+        let entries = &reused[12..reused.len() - 20];
+
+        let (mut writer, mut pack_data) = PackWriter::with_delta(4, DeltaOptions::default(), true);
+        let blob = Blob::new_from_data(b"another blob".to_vec());
+        pack_data.extend(writer.write_object(Arc::new(blob)).unwrap());
+        writer.write_raw(3, entries).unwrap();
+        pack_data.extend_from_slice(entries);
+        assert!(writer.write_raw(1, entries).is_err());
+        pack_data.extend(writer.finish().unwrap());
+        let mut buff = Cursor::new(pack_data);
+        block_on(Pack::decode(&mut buff)).unwrap();
+    }
+
+    #[test]
+    fn test_pack_writer_missing_objects() {
+        let (writer, _) = PackWriter::new(1);
+        assert!(writer.finish().is_err());
+    }
+
     #[test]
     fn test_pack_encoder() {
         let id = Hash([0u8; 20]);
@@ -13,8 +13,9 @@ use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use tokio::sync::{RwLock, RwLockReadGuard};
 
+use common::config::{DecodeCacheType, PackConfig};
 use delta;
-use entity::{mr, objects};
+use entity::{mr, objects, pack_index};
 use storage::{driver::database::storage::ObjectStorage, utils::id_generator::generate_id};
 
 use crate::internal::pack::cache::{kvstore::ObjectCache as kvObjectCache, ObjectCache, _Cache};
@@ -59,6 +60,13 @@ impl Entry {
             link: Set(None),
         }
     }
+    fn convert_to_index_model(&self, pack_id: &str) -> pack_index::ActiveModel {
+        pack_index::ActiveModel {
+            git_id: Set(self.hash.unwrap().to_plain_str()),
+            pack_id: Set(pack_id.to_owned()),
+            pack_offset: Set(self.offset as i64),
+        }
+    }
 }
 impl ToRedisArgs for Entry {
     fn write_redis_args<W>(&self, out: &mut W)
@@ -192,6 +200,10 @@ impl PackPreload {
     pub fn is_empty(&self) -> bool {
         self.entries.is_empty()
     }
+    /// The offsets of all the entries in the pack, in order.
+    pub fn offsets(&self) -> Vec<u64> {
+        self.entries.iter().map(|e| e.offset as u64).collect()
+    }
 }
 
 /// Decide the preloaded objects, and store it .
@@ -204,13 +216,24 @@ impl PackPreload {
 ///
 /// - `p`: A `PackPreload` struct representing the data to be decoded and loaded.
 /// - `storage`: An `Arc<dyn ObjectStorage>` trait object providing storage capabilities.
+/// - `config`: The `pack` configuration, choosing the cache of the decoding threads.
+/// - `pack_id`: The id of the pack in the pack store, if the pack is kept there. The objects
+///   are then saved in the `pack_index` table, with the offset of their entry, instead of the
+///   `objects` table.
 ///
 /// # Returns
 ///
-/// The function returns a `Result<i64, GitError>`, where the `i64` represents the `mr_id`
+/// The function returns a `Result<(i64, Vec<(String, u64)>), GitError>`, where the `i64`
+/// represents the `mr_id`, the `Vec` the objects indexed in the pack with their offset,
 /// and `GitError` represents any potential error that might occur during the process.
 ///
-pub async fn decode_load(p: PackPreload, storage: Arc<dyn ObjectStorage>) -> Result<i64, GitError> {
+pub async fn decode_load(
+    p: PackPreload,
+    storage: Arc<dyn ObjectStorage>,
+    config: &PackConfig,
+    pack_id: Option<String>,
+) -> Result<(i64, Vec<(String, u64)>), GitError> {
+    let start = Instant::now();
     let decode_counter: Arc<Mutex<DecodeCounter>> = Arc::new(Mutex::new(DecodeCounter::default()));
     let all_len = p.len();
     tracing::info!("Decode the preload git object\n{}", p.counter);
@@ -218,10 +241,7 @@ pub async fn decode_load(p: PackPreload, storage: Arc<dyn ObjectStorage>) -> Res
     tracing::info!("Deal with the object using {} threads. ", cpu_number);
     let share: Arc<RwLock<PackPreload>> = Arc::new(RwLock::new(p));
     let mr_id = generate_id();
-    
-    let mut cache_type: String= String::new();
-    utils::get_env_number("GIT_INTERNAL_DECODE_CACHE_TYEP", &mut cache_type);
-    
+
     let producer_handles: Vec<_> = (0..cpu_number)
         .map(|i| {
             let shard_clone = Arc::clone(&share);
@@ -233,35 +253,35 @@ pub async fn decode_load(p: PackPreload, storage: Arc<dyn ObjectStorage>) -> Res
             } else {
                 (i + 1) * chunk
             };
-            match &cache_type as &str {
-                "redis" => 
+            let config = config.clone();
+            let pack_id = pack_id.clone();
+            match config.decode_cache_type {
+                DecodeCacheType::Redis =>
                 tokio::spawn(async move {
-                    produce_object::<kvObjectCache<Entry>>(shard_clone, st_clone, begin, end, counter_clone, mr_id).await
+                    produce_object::<kvObjectCache<Entry>>(shard_clone, st_clone, config, begin, end, counter_clone, mr_id, pack_id).await
                 }),
-                "lru" =>
+                DecodeCacheType::Lru =>
                 tokio::spawn(async move {
-                    produce_object::<ObjectCache<Entry>>(shard_clone, st_clone, begin, end, counter_clone, mr_id).await
-                }),
-                _ =>
-                tokio::spawn(async move {
-                    produce_object::<ObjectCache<Entry>>(shard_clone, st_clone, begin, end, counter_clone, mr_id).await
+                    produce_object::<ObjectCache<Entry>>(shard_clone, st_clone, config, begin, end, counter_clone, mr_id, pack_id).await
                 }),
             }
         })
         .collect();
 
     let mut batch_success = true;
+    let mut indexed = Vec::new();
     for handle in producer_handles {
-        let res = handle.await.unwrap();
-        if res.is_err() {
-            batch_success = false;
+        match handle.await.unwrap() {
+            Ok(objects) => indexed.extend(objects),
+            Err(_) => batch_success = false,
         }
     }
     assert!(batch_success);
     let re = decode_counter.lock().unwrap();
     tracing::info!("Summary : {}", re);
+    re.record_metrics(start.elapsed());
 
-    Ok(mr_id)
+    Ok((mr_id, indexed))
 }
 
 use crate::internal::pack::counter::CounterType::*;
@@ -274,10 +294,12 @@ use crate::internal::pack::counter::CounterType::*;
 /// - `txn`: An `Arc` containing the database transaction for database operations.
 /// - `data`: A shared `Arc<RwLock<PackPreload>>` containing the preload data.
 /// - `storage`: A shared `Arc<dyn ObjectStorage>` trait object providing storage capabilities.
+/// - `config`: The cache and batch sizes of the `pack` configuration.
 /// - `range_begin`: The starting index of the range of entries to process.
 /// - `range_end`: The ending index of the range of entries to process.
 /// - `counter`: A shared `Arc<Mutex<DecodeCounter>>` for counting decode operations.
 /// - `mr_id`: An identifier for the produced Git objects.
+/// - `pack_id`: The pack of the pack store the objects are indexed in, `None` to save them in the `objects` table.
 ///
 /// # Generic Type Parameter
 ///
@@ -286,38 +308,36 @@ use crate::internal::pack::counter::CounterType::*;
 /// # Returns
 ///
 /// This function returns a `Result`:
-/// - `Ok(())` if the Git objects are produced successfully.
+/// - `Ok(objects)` if the Git objects are produced successfully, with the objects indexed in the pack and their offset.
 /// - `Err(GitError)` in case of any errors during the operation.
 ///
+#[allow(clippy::too_many_arguments)]
 async fn produce_object<TC>(
     data: Arc<RwLock<PackPreload>>,
     storage: Arc<dyn ObjectStorage>,
+    config: PackConfig,
     range_begin: usize,
     range_end: usize,
     counter: Arc<Mutex<DecodeCounter>>,
     mr_id: i64,
-)  -> Result<(), GitError> where TC: _Cache<T = Entry> {
+    pack_id: Option<String>,
+)  -> Result<Vec<(String, u64)>, GitError> where TC: _Cache<T = Entry> {
 
     let thread_id: u16 = rand::thread_rng().gen();
     tracing::info!("thread begin : {}", thread_id);
     let mut mr_to_obj_model = Vec::<mr::ActiveModel>::with_capacity(1001);
     let mut git_obj_model = Vec::<objects::ActiveModel>::with_capacity(1001);
+    let mut index_model = Vec::<pack_index::ActiveModel>::with_capacity(1001);
+    let mut indexed = Vec::new();
 
-    let mut object_cache_size = 1000;
-    utils::get_env_number("GIT_INTERNAL_DECODE_CACHE_SIZE", &mut object_cache_size);
-
-    let cache= TC::new(Some(object_cache_size));
+    let cache = TC::from_config(&config);
 
     let start = Instant::now();
     let mut db_cost = 0;
-    let mut batch_size = 10000;  
-    utils::get_env_number("GIT_INTERNAL_DECODE_STORAGE_BATCH_SIZE", &mut batch_size);
+    let batch_size = config.decode_storage_batch_size;
 
-    let mut save_task_wait_number = 10; // the most await save thread amount
-    utils::get_env_number(
-        "GIT_INTERNAL_DECODE_STORAGE_TQUEUE_SIZE",
-        &mut save_task_wait_number,
-    );
+    // the most await save thread amount, config.decode_storage_tqueue_size
+    // let save_task_wait_number = config.decode_storage_tqueue_size;
 
     // let mut save_queue: CircularQueue<_> = CircularQueue::new(save_task_wait_number);
     //let mut save_handler: Option<tokio::task::JoinHandle<()>> = None;
@@ -397,7 +417,13 @@ async fn produce_object<TC>(
         // tracing::info!("thread id:{},HEADER TYPE: {} offset :{}, HASH :{}",thread_id,result_entity.header,result_entity.offset,result_entity.hash.unwrap());
         //
         mr_to_obj_model.push(result_entity.convert_to_mr_model(mr_id));
-        git_obj_model.push(result_entity.convert_to_data_model());
+        match &pack_id {
+            Some(pack_id) => {
+                indexed.push((result_entity.hash.unwrap().to_plain_str(), e.offset as u64));
+                index_model.push(result_entity.convert_to_index_model(pack_id));
+            }
+            None => git_obj_model.push(result_entity.convert_to_data_model()),
+        }
         cache.put(e.offset, result_entity.hash.unwrap(), result_entity);
 
         //save to storage
@@ -408,7 +434,12 @@ async fn produce_object<TC>(
             let stc = storage.clone();
             // let h = tokio::spawn(async move {
                 stc.save_mr_objects(None, mr_to_obj_model).await.unwrap();
-                stc.save_obj_data(None, git_obj_model).await.unwrap();
+                if !git_obj_model.is_empty() {
+                    stc.save_obj_data(None, git_obj_model).await.unwrap();
+                }
+                if !index_model.is_empty() {
+                    stc.save_pack_index(None, index_model).await.unwrap();
+                }
             // });
             let cost = db_start.elapsed().as_millis();
             db_cost += cost;
@@ -427,6 +458,7 @@ async fn produce_object<TC>(
             // }
             mr_to_obj_model = Vec::with_capacity(batch_size);
             git_obj_model = Vec::with_capacity(batch_size);
+            index_model = Vec::with_capacity(batch_size);
         }
     }
     let db_start = Instant::now();
@@ -436,6 +468,9 @@ async fn produce_object<TC>(
     if !git_obj_model.is_empty() {
         storage.save_obj_data(None, git_obj_model).await.unwrap();
     }
+    if !index_model.is_empty() {
+        storage.save_pack_index(None, index_model).await.unwrap();
+    }
     let cost = db_start.elapsed().as_millis();
     db_cost += cost;
     // await the remaining threads
@@ -448,7 +483,7 @@ async fn produce_object<TC>(
     // }
     let end = start.elapsed().as_millis();
     tracing::info!("Git Object Produce thread one  time cost:{} ms, db_time_cost:{}", end, db_cost);
-    Ok(())
+    Ok(indexed)
 }
 
 /// Asynchronous function to perform delta offset operation.
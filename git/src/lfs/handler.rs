@@ -16,11 +16,12 @@ use entity::{locks, meta};
 use storage::driver::database::storage::ObjectStorage;
 use storage::driver::file_storage::local_storage::MetaObject;
 
+use crate::audit::{self, AuditAction, AuditEntry, RESULT_OK};
 use crate::lfs::lfs_structs::{
     BatchRequest, LockList, LockRequest, ObjectError, UnlockRequest, VerifiableLockList,
     VerifiableLockRequest,
 };
-use crate::lfs::lfs_structs::{Link, Lock, LockListQuery, Representation, RequestVars};
+use crate::lfs::lfs_structs::{Link, Lock, LockListQuery, Representation, RequestVars, User};
 use crate::lfs::LfsConfig;
 
 pub async fn lfs_retrieve_lock(
@@ -78,7 +79,7 @@ pub async fn lfs_verify_lock(
             lock_list.next_cursor = next_cursor;
 
             for lock in locks.iter() {
-                if Option::is_none(&lock.owner) {
+                if is_lock_owner(lock, config.user.as_deref()) {
                     lock_list.ours.push(lock.clone());
                 } else {
                     lock_list.theirs.push(lock.clone());
@@ -91,6 +92,13 @@ pub async fn lfs_verify_lock(
 }
 
 pub async fn lfs_create_lock(config: &LfsConfig, req: LockRequest) -> Result<Lock, GitLFSError> {
+    let path = req.path.clone();
+    let res = lfs_try_create_lock(config, req).await;
+    record_audit(config, AuditAction::LockCreate, path, &res).await;
+    res
+}
+
+async fn lfs_try_create_lock(config: &LfsConfig, req: LockRequest) -> Result<Lock, GitLFSError> {
     let res = lfs_get_filtered_locks(
         config.storage.clone(),
         &req.refs.name,
@@ -123,7 +131,7 @@ pub async fn lfs_create_lock(config: &LfsConfig, req: LockRequest) -> Result<Loc
             random_num
         },
         path: req.path.to_owned(),
-        owner: None,
+        owner: config.user.clone().map(|name| User { name }),
         locked_at: {
             let locked_at: DateTime<Utc> = Utc::now();
             locked_at.to_rfc3339()
@@ -142,6 +150,21 @@ pub async fn lfs_delete_lock(
     config: &LfsConfig,
     id: &str,
     unlock_request: UnlockRequest,
+) -> Result<Lock, GitLFSError> {
+    let res = lfs_try_delete_lock(config, id, unlock_request).await;
+    // the path of the lock is only known once it is deleted
+    let target = match &res {
+        Ok(lock) => lock.path.clone(),
+        Err(_) => id.to_owned(),
+    };
+    record_audit(config, AuditAction::LockDelete, target, &res).await;
+    res
+}
+
+async fn lfs_try_delete_lock(
+    config: &LfsConfig,
+    id: &str,
+    unlock_request: UnlockRequest,
 ) -> Result<Lock, GitLFSError> {
     if id.is_empty() {
         return Err(GitLFSError::GeneralError("Invalid lock id!".to_string()));
@@ -149,7 +172,7 @@ pub async fn lfs_delete_lock(
     let res = delete_lock(
         config.storage.clone(),
         &unlock_request.refs.name,
-        None,
+        config.user.clone(),
         id,
         unlock_request.force.unwrap_or(false),
     )
@@ -176,12 +199,8 @@ pub async fn lfs_delete_lock(
 
 pub async fn lfs_process_batch(
     config: &LfsConfig,
-    mut batch_vars: BatchRequest,
+    batch_vars: BatchRequest,
 ) -> Result<Vec<Representation>, GitLFSError> {
-    let bvo = &mut batch_vars.objects;
-    for request in bvo {
-        request.authorization = "".to_string();
-    }
     let mut response_objects = Vec::<Representation>::new();
     let server_url = format!("http://{}:{}", config.host, config.port);
 
@@ -190,7 +209,7 @@ pub async fn lfs_process_batch(
         // Found
         let found = meta.is_ok();
         let mut meta = meta.unwrap_or_default();
-        if found && config.fs_storage.exist(&meta.oid) {
+        if found && config.fs_storage.exist(&meta.oid).await.unwrap_or(false) {
             response_objects.push(represent(object, &meta, true, false, false, &server_url).await);
             continue;
         }
@@ -219,10 +238,21 @@ pub async fn lfs_upload_object(
     config: &LfsConfig,
     request_vars: &RequestVars,
     body_bytes: &[u8],
+) -> Result<(), GitLFSError> {
+    let res = lfs_try_upload_object(config, request_vars, body_bytes).await;
+    let oid = request_vars.oid.clone();
+    record_audit(config, AuditAction::LfsUpload, oid, &res).await;
+    res
+}
+
+async fn lfs_try_upload_object(
+    config: &LfsConfig,
+    request_vars: &RequestVars,
+    body_bytes: &[u8],
 ) -> Result<(), GitLFSError> {
     let meta = lfs_get_meta(config.storage.clone(), request_vars)
         .await
-        .unwrap();
+        .map_err(|_| GitLFSError::GeneralError(String::from("Object not found!")))?;
     let res = config
         .fs_storage
         .put(&meta.oid, meta.size, body_bytes)
@@ -235,6 +265,8 @@ pub async fn lfs_upload_object(
             "Header not acceptable!",
         )));
     }
+    metrics::counter!("mega_lfs_bytes_total", "direction" => "in")
+        .increment(body_bytes.len() as u64);
     Ok(())
 }
 
@@ -244,11 +276,35 @@ pub async fn lfs_download_object(
 ) -> Result<Bytes, GitLFSError> {
     let meta = lfs_get_meta(config.storage.clone(), request_vars)
         .await
-        .unwrap();
-    let bytes = config.fs_storage.get(&meta.oid).await.unwrap();
+        .map_err(|_| GitLFSError::GeneralError(String::from("Object not found!")))?;
+    let bytes = config
+        .fs_storage
+        .get(&meta.oid)
+        .await
+        .map_err(|_| GitLFSError::GeneralError(String::from("Object not found!")))?;
+    metrics::counter!("mega_lfs_bytes_total", "direction" => "out").increment(bytes.len() as u64);
     Ok(bytes)
 }
 
+/// Checks an uploaded object is stored with the size the client expects.
+pub async fn lfs_verify_object(
+    config: &LfsConfig,
+    request_vars: &RequestVars,
+) -> Result<(), GitLFSError> {
+    match lfs_get_meta(config.storage.clone(), request_vars).await {
+        Ok(meta) if config.fs_storage.exist(&meta.oid).await.unwrap_or(false) => {
+            if meta.size == request_vars.size {
+                Ok(())
+            } else {
+                Err(GitLFSError::GeneralError(String::from(
+                    "Object size doesn't match!",
+                )))
+            }
+        }
+        _ => Err(GitLFSError::GeneralError(String::from("Object not found!"))),
+    }
+}
+
 pub async fn represent(
     rv: &RequestVars,
     meta: &MetaObject,
@@ -303,6 +359,31 @@ pub async fn represent(
     rep
 }
 
+// records a change requested through the LFS API in the audit log
+async fn record_audit<T>(
+    config: &LfsConfig,
+    action: AuditAction,
+    target: String,
+    res: &Result<T, GitLFSError>,
+) {
+    let result = match res {
+        Ok(_) => RESULT_OK.to_owned(),
+        Err(GitLFSError::GeneralError(msg)) => msg.clone(),
+    };
+    let entry = AuditEntry {
+        action,
+        actor: config.user.clone(),
+        protocol: config.protocol,
+        repo_path: config.repo_path.clone(),
+        target,
+        old_id: None,
+        new_id: None,
+        result,
+        client_addr: config.client_addr.clone(),
+    };
+    audit::record(config.storage.as_ref(), vec![entry]).await;
+}
+
 fn create_link(href: &str, header: &HashMap<String, String>) -> Link {
     Link {
         href: href.to_string(),
@@ -448,6 +529,14 @@ async fn lfs_add_lock(
     }
 }
 
+// locks created by anonymous users belong to everyone
+fn is_lock_owner(lock: &Lock, user: Option<&str>) -> bool {
+    match &lock.owner {
+        Some(owner) => user == Some(owner.name.as_str()),
+        None => true,
+    }
+}
+
 async fn lfs_get_meta(
     storage: Arc<dyn ObjectStorage>,
     v: &RequestVars,
@@ -514,7 +603,7 @@ async fn lfs_delete_meta(
 async fn delete_lock(
     storage: Arc<dyn ObjectStorage>,
     repo: &str,
-    _user: Option<String>,
+    user: Option<String>,
     id: &str,
     force: bool,
 ) -> Result<Lock, GitLFSError> {
@@ -543,7 +632,7 @@ async fn delete_lock(
 
             for lock in locks_from_data.iter() {
                 if lock.id == *id {
-                    if Option::is_some(&lock.owner) && !force {
+                    if !is_lock_owner(lock, user.as_deref()) && !force {
                         return Err(GitLFSError::GeneralError("".to_string()));
                     }
                     lock_to_delete.id = lock.id.to_owned();
@@ -565,8 +654,10 @@ async fn delete_lock(
 
             // No locks remains, delete the repo from database.
             if new_locks.is_empty() {
-                storage.delete_lock_by_id(repo.to_owned()).await;
-                return Ok(lock_to_delete);
+                return match storage.delete_lock_by_id(repo.to_owned()).await {
+                    Ok(()) => Ok(lock_to_delete),
+                    Err(e) => Err(GitLFSError::GeneralError(e.to_string())),
+                };
             }
 
             // Update remaining locks.
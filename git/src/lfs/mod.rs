@@ -2,8 +2,11 @@ use std::sync::Arc;
 
 use storage::driver::{database::storage::ObjectStorage, file_storage::FileStorage};
 
+use crate::protocol::Protocol;
+
 pub mod handler;
 pub mod lfs_structs;
+pub mod transfer;
 
 #[derive(Clone)]
 pub struct LfsConfig {
@@ -14,4 +17,14 @@ pub struct LfsConfig {
     pub storage: Arc<dyn ObjectStorage>,
 
     pub fs_storage: Arc<dyn FileStorage>,
+
+    /// The authenticated user of the request, `None` if anonymous.
+    pub user: Option<String>,
+
+    /// The protocol, repo and client address of the request, recorded in the audit log.
+    pub protocol: Protocol,
+
+    pub repo_path: String,
+
+    pub client_addr: Option<String>,
 }
@@ -0,0 +1,520 @@
+//!
+//! The pure SSH transfer protocol of git-lfs, run by `git-lfs-transfer <repo> <operation>`,
+//! which moves LFS objects and locks over the ssh channel in pkt-lines instead of through the
+//! http API.
+//!
+//! A request is a command line, then `key=value` arguments, then optionally a delimiter
+//! packet followed by data, and a flush packet. Responses have the same shape, starting with
+//! `status <code>`. The server advertises `version=1` and the client must negotiate it first.
+//!
+//! See <https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md>.
+//!
+use std::collections::HashMap;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use common::errors::GitLFSError;
+
+use crate::lfs::handler;
+use crate::lfs::lfs_structs::{
+    BatchRequest, Lock, LockListQuery, LockRequest, Ref, RequestVars, UnlockRequest,
+};
+use crate::lfs::LfsConfig;
+use crate::protocol::pack::{add_pkt_line_string, PKT_LINE_DELIM_MARKER, PKT_LINE_END_MARKER};
+
+// the largest payload of a pkt-line
+const MAX_PKT_DATA: usize = 65516;
+
+/// A request of the client, its data is the content of the packets after the delimiter.
+#[derive(Debug, Default, PartialEq)]
+pub struct TransferRequest {
+    pub command: String,
+    pub args: HashMap<String, String>,
+    pub data: Option<Bytes>,
+}
+
+impl TransferRequest {
+    /// Parses the first complete request of `buf`, with the number of bytes it spans, or `None`
+    /// if more data is needed.
+    pub fn parse(buf: &[u8]) -> Result<Option<(Self, usize)>, String> {
+        // objects come in many ssh packets, only the lengths are read until the request is
+        // complete
+        let mut pos = 0;
+        loop {
+            match pkt_length(buf, pos)? {
+                None => return Ok(None),
+                Some(0) => break,
+                Some(1) => pos += 4,
+                Some(length) if buf.len() < pos + length => return Ok(None),
+                Some(length) => pos += length,
+            }
+        }
+
+        let mut pos = 0;
+        let mut request = TransferRequest::default();
+        let mut data: Option<BytesMut> = None;
+        loop {
+            let length = pkt_length(buf, pos)?.unwrap();
+            match length {
+                0 => {
+                    if request.command.is_empty() {
+                        return Err(String::from("missing command"));
+                    }
+                    request.data = data.map(BytesMut::freeze);
+                    return Ok(Some((request, pos + 4)));
+                }
+                1 => {
+                    if data.is_some() {
+                        return Err(String::from("unexpected delimiter"));
+                    }
+                    data = Some(BytesMut::new());
+                    pos += 4;
+                }
+                _ => {
+                    let payload = &buf[pos + 4..pos + length];
+                    if let Some(data) = data.as_mut() {
+                        data.put(payload);
+                    } else {
+                        let line = String::from_utf8_lossy(payload)
+                            .trim_end_matches('\n')
+                            .to_owned();
+                        if request.command.is_empty() {
+                            request.command = line;
+                        } else {
+                            let (key, value) = line.split_once('=').unwrap_or((&line, ""));
+                            request.args.insert(key.to_owned(), value.to_owned());
+                        }
+                    }
+                    pos += length;
+                }
+            }
+        }
+    }
+
+    fn arg(&self, key: &str) -> String {
+        self.args.get(key).cloned().unwrap_or_default()
+    }
+}
+
+// the length of the pkt-line at `pos`, `None` if the buffer ends before its header
+fn pkt_length(buf: &[u8], pos: usize) -> Result<Option<usize>, String> {
+    if buf.len() < pos + 4 {
+        return Ok(None);
+    }
+    match std::str::from_utf8(&buf[pos..pos + 4])
+        .ok()
+        .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+    {
+        Some(length) if length != 2 && length != 3 && length != 4 => Ok(Some(length)),
+        _ => Err(String::from("invalid pkt-line length")),
+    }
+}
+
+/// A response to a request, encoded as pkt-lines by `to_bytes`.
+#[derive(Debug, PartialEq)]
+pub struct TransferResponse {
+    pub status: u16,
+    pub args: Vec<String>,
+    pub data: Option<Bytes>,
+}
+
+impl TransferResponse {
+    pub fn new(status: u16, args: Vec<String>, data: Option<Bytes>) -> Self {
+        TransferResponse { status, args, data }
+    }
+
+    /// An error response, the message is sent as data.
+    pub fn error(status: u16, message: &str) -> Self {
+        TransferResponse::new(status, vec![], Some(Bytes::from(format!("{}\n", message))))
+    }
+
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        add_pkt_line_string(&mut buf, format!("status {}\n", self.status));
+        for arg in &self.args {
+            add_pkt_line_string(&mut buf, format!("{}\n", arg));
+        }
+        if let Some(data) = &self.data {
+            buf.put(&PKT_LINE_DELIM_MARKER[..]);
+            for chunk in data.chunks(MAX_PKT_DATA) {
+                buf.put(format!("{:04x}", chunk.len() + 4).as_bytes());
+                buf.put(chunk);
+            }
+        }
+        buf.put(&PKT_LINE_END_MARKER[..]);
+        buf
+    }
+}
+
+/// The state of a `git-lfs-transfer` session.
+#[derive(Clone)]
+pub struct LfsTransfer {
+    config: LfsConfig,
+    // `upload` or `download`, as requested by the command line
+    operation: String,
+    negotiated: bool,
+    buffer: BytesMut,
+}
+
+impl LfsTransfer {
+    pub fn new(config: LfsConfig, operation: &str) -> Self {
+        LfsTransfer {
+            config,
+            operation: operation.to_owned(),
+            negotiated: false,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// The capabilities the server sends when the command starts.
+    pub fn advertise() -> BytesMut {
+        let mut buf = BytesMut::new();
+        add_pkt_line_string(&mut buf, String::from("version=1\n"));
+        buf.put(&PKT_LINE_END_MARKER[..]);
+        buf
+    }
+
+    /// # Handles data sent by the client.
+    ///
+    /// Requests can span several ssh packets, so the data is buffered until a request is
+    /// complete. Returns the responses to the complete requests, and whether the session is
+    /// over, either because the client quit or because it sent malformed data.
+    pub async fn receive(&mut self, data: &[u8]) -> (BytesMut, bool) {
+        self.buffer.put(data);
+        let mut output = BytesMut::new();
+        loop {
+            match TransferRequest::parse(&self.buffer) {
+                Ok(Some((request, consumed))) => {
+                    let _ = self.buffer.split_to(consumed);
+                    let quit = request.command == "quit";
+                    output.put(self.handle(request).await.to_bytes());
+                    if quit {
+                        return (output, true);
+                    }
+                }
+                Ok(None) => return (output, false),
+                Err(message) => {
+                    output.put(TransferResponse::error(400, &message).to_bytes());
+                    return (output, true);
+                }
+            }
+        }
+    }
+
+    pub async fn handle(&mut self, request: TransferRequest) -> TransferResponse {
+        let (command, argument) = match request.command.split_once(' ') {
+            Some((command, argument)) => (command, argument.to_owned()),
+            None => (request.command.as_str(), String::new()),
+        };
+        if !self.negotiated && command != "version" && command != "quit" {
+            return TransferResponse::error(400, "version not negotiated");
+        }
+        match command {
+            "version" if argument == "1" => {
+                self.negotiated = true;
+                TransferResponse::new(200, vec![], None)
+            }
+            "version" => TransferResponse::error(400, "unsupported version"),
+            "batch" => self.batch(&request).await,
+            "get-object" => self.get_object(&argument).await,
+            "put-object" => self.put_object(&argument, &request).await,
+            "verify-object" => self.verify_object(&argument, &request).await,
+            "lock" => self.lock(&request).await,
+            "list-lock" => self.list_lock(&request).await,
+            "unlock" => self.unlock(&argument, &request).await,
+            "quit" => TransferResponse::new(200, vec![], None),
+            _ => TransferResponse::error(400, &format!("unknown command {}", command)),
+        }
+    }
+
+    // each `<oid> <size>` line is answered with the action to take, `noop` if there is none
+    async fn batch(&self, request: &TransferRequest) -> TransferResponse {
+        let hash_algo = request.args.get("hash-algo").map(String::as_str);
+        if hash_algo.is_some_and(|algo| algo != "sha256") {
+            return TransferResponse::error(400, "unsupported hash algorithm");
+        }
+        let mut objects = vec![];
+        let data = request.data.clone().unwrap_or_default();
+        for line in String::from_utf8_lossy(&data).lines() {
+            let parsed = line
+                .split_once(' ')
+                .and_then(|(oid, size)| Some((oid, size.parse::<i64>().ok()?)));
+            match parsed {
+                Some((oid, size)) => objects.push(RequestVars {
+                    oid: oid.to_owned(),
+                    size,
+                    ..Default::default()
+                }),
+                None => return TransferResponse::error(400, &format!("invalid object {}", line)),
+            }
+        }
+        let batch_request = BatchRequest {
+            operation: self.operation.clone(),
+            transfers: vec![String::from("basic")],
+            objects,
+            hash_algo: String::from("sha256"),
+        };
+        let representations = match handler::lfs_process_batch(&self.config, batch_request).await {
+            Ok(representations) => representations,
+            Err(err) => return TransferResponse::error(500, &lfs_error_message(err)),
+        };
+        let mut lines = String::new();
+        for rep in representations {
+            let has_action = rep
+                .actions
+                .as_ref()
+                .is_some_and(|actions| actions.contains_key(&self.operation));
+            let action = if has_action {
+                self.operation.as_str()
+            } else {
+                "noop"
+            };
+            lines.push_str(&format!("{} {} {}\n", rep.oid, rep.size, action));
+        }
+        TransferResponse::new(
+            200,
+            vec![String::from("transfer=basic")],
+            Some(Bytes::from(lines)),
+        )
+    }
+
+    async fn get_object(&self, oid: &str) -> TransferResponse {
+        let request_vars = RequestVars {
+            oid: oid.to_owned(),
+            ..Default::default()
+        };
+        match handler::lfs_download_object(&self.config, &request_vars).await {
+            Ok(bytes) => {
+                TransferResponse::new(200, vec![format!("size={}", bytes.len())], Some(bytes))
+            }
+            Err(err) => TransferResponse::error(404, &lfs_error_message(err)),
+        }
+    }
+
+    async fn put_object(&self, oid: &str, request: &TransferRequest) -> TransferResponse {
+        if self.operation != "upload" {
+            return TransferResponse::error(403, "objects can only be put by an upload");
+        }
+        let data = request.data.clone().unwrap_or_default();
+        let size = match request.arg("size").parse::<i64>() {
+            Ok(size) if size == data.len() as i64 => size,
+            _ => return TransferResponse::error(400, "size doesn't match the data"),
+        };
+        let request_vars = RequestVars {
+            oid: oid.to_owned(),
+            size,
+            ..Default::default()
+        };
+        match handler::lfs_upload_object(&self.config, &request_vars, &data).await {
+            Ok(()) => TransferResponse::new(200, vec![], None),
+            Err(err) => TransferResponse::error(500, &lfs_error_message(err)),
+        }
+    }
+
+    async fn verify_object(&self, oid: &str, request: &TransferRequest) -> TransferResponse {
+        let request_vars = RequestVars {
+            oid: oid.to_owned(),
+            size: request.arg("size").parse().unwrap_or(-1),
+            ..Default::default()
+        };
+        match handler::lfs_verify_object(&self.config, &request_vars).await {
+            Ok(()) => TransferResponse::new(200, vec![], None),
+            Err(err) => TransferResponse::error(404, &lfs_error_message(err)),
+        }
+    }
+
+    async fn lock(&self, request: &TransferRequest) -> TransferResponse {
+        if self.operation != "upload" {
+            return TransferResponse::error(403, "locks can only be created by an upload");
+        }
+        let lock_request = LockRequest {
+            path: request.arg("path"),
+            refs: Ref {
+                name: request.arg("refname"),
+            },
+        };
+        match handler::lfs_create_lock(&self.config, lock_request).await {
+            Ok(lock) => TransferResponse::new(201, lock_args(&lock), None),
+            Err(err) => {
+                // a conflict is answered with the existing lock
+                let query = LockListQuery {
+                    path: request.arg("path"),
+                    id: String::new(),
+                    cursor: String::new(),
+                    limit: String::from("1"),
+                    refspec: request.arg("refname"),
+                };
+                match handler::lfs_retrieve_lock(&self.config, query).await {
+                    Ok(list) if !list.locks.is_empty() => {
+                        TransferResponse::new(409, lock_args(&list.locks[0]), None)
+                    }
+                    _ => TransferResponse::error(500, &lfs_error_message(err)),
+                }
+            }
+        }
+    }
+
+    async fn list_lock(&self, request: &TransferRequest) -> TransferResponse {
+        let query = LockListQuery {
+            path: request.arg("path"),
+            id: request.arg("id"),
+            cursor: request.arg("cursor"),
+            limit: request.arg("limit"),
+            refspec: request.arg("refname"),
+        };
+        let list = match handler::lfs_retrieve_lock(&self.config, query).await {
+            Ok(list) => list,
+            Err(err) => return TransferResponse::error(400, &lfs_error_message(err)),
+        };
+        let id = request.arg("id");
+        let mut lines = String::new();
+        for lock in list.locks.iter().filter(|l| id.is_empty() || l.id == id) {
+            lines.push_str(&format!("lock {}\n", lock.id));
+            lines.push_str(&format!("path {} {}\n", lock.id, lock.path));
+            lines.push_str(&format!("locked-at {} {}\n", lock.id, lock.locked_at));
+            if let Some(owner) = &lock.owner {
+                lines.push_str(&format!("ownername {} {}\n", lock.id, owner.name));
+            }
+            let ours = match &lock.owner {
+                Some(owner) => self.config.user.as_deref() == Some(owner.name.as_str()),
+                None => true,
+            };
+            lines.push_str(&format!(
+                "owner {} {}\n",
+                lock.id,
+                if ours { "ours" } else { "theirs" }
+            ));
+        }
+        let mut args = vec![];
+        if !list.next_cursor.is_empty() {
+            args.push(format!("next-cursor={}", list.next_cursor));
+        }
+        TransferResponse::new(200, args, Some(Bytes::from(lines)))
+    }
+
+    async fn unlock(&self, id: &str, request: &TransferRequest) -> TransferResponse {
+        if self.operation != "upload" {
+            return TransferResponse::error(403, "locks can only be deleted by an upload");
+        }
+        let unlock_request = UnlockRequest {
+            force: Some(request.arg("force") == "true"),
+            refs: Ref {
+                name: request.arg("refname"),
+            },
+        };
+        match handler::lfs_delete_lock(&self.config, id, unlock_request).await {
+            Ok(lock) => TransferResponse::new(200, lock_args(&lock), None),
+            Err(_) => TransferResponse::error(403, &format!("unable to unlock {}", id)),
+        }
+    }
+}
+
+fn lock_args(lock: &Lock) -> Vec<String> {
+    let mut args = vec![
+        format!("id={}", lock.id),
+        format!("path={}", lock.path),
+        format!("locked-at={}", lock.locked_at),
+    ];
+    if let Some(owner) = &lock.owner {
+        args.push(format!("ownername={}", owner.name));
+    }
+    args
+}
+
+fn lfs_error_message(err: GitLFSError) -> String {
+    match err {
+        GitLFSError::GeneralError(message) if !message.is_empty() => message,
+        err => err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use tokio_test::block_on;
+
+    use storage::driver::database::sqlite_storage::SqliteStorage;
+    use storage::driver::file_storage::local_storage::LocalStorage;
+
+    use crate::lfs::transfer::{LfsTransfer, TransferRequest, TransferResponse};
+    use crate::lfs::LfsConfig;
+    use crate::protocol::Protocol;
+
+    #[test]
+    fn test_parse_request() {
+        let put = b"0020put-object 1234567890abcdef\n000bsize=5\n00010009hello0000";
+        let (request, consumed) = TransferRequest::parse(put).unwrap().unwrap();
+        assert_eq!(consumed, put.len());
+        assert_eq!(request.command, "put-object 1234567890abcdef");
+        assert_eq!(request.args["size"], "5");
+        assert_eq!(request.data, Some(Bytes::from_static(b"hello")));
+
+        // incomplete requests wait for more data
+        assert_eq!(TransferRequest::parse(&put[..put.len() - 2]).unwrap(), None);
+        assert_eq!(TransferRequest::parse(b"00").unwrap(), None);
+
+        let (request, consumed) = TransferRequest::parse(b"000eversion 1\n0000quit")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.command, "version 1");
+        assert_eq!(request.data, None);
+        assert_eq!(consumed, 18);
+
+        assert!(TransferRequest::parse(b"0000").is_err());
+        assert!(TransferRequest::parse(b"zzzz").is_err());
+    }
+
+    #[test]
+    fn test_encode_response() {
+        assert_eq!(&LfsTransfer::advertise()[..], b"000eversion=1\n0000");
+        let response = TransferResponse::new(
+            200,
+            vec![String::from("transfer=basic")],
+            Some(Bytes::from_static(b"abc 3 noop\n")),
+        );
+        assert_eq!(
+            &response.to_bytes()[..],
+            b"000fstatus 200\n0013transfer=basic\n0001000fabc 3 noop\n0000"
+        );
+        assert_eq!(
+            &TransferResponse::error(404, "not found").to_bytes()[..],
+            b"000fstatus 404\n0001000enot found\n0000"
+        );
+
+        let data = Bytes::from(vec![0u8; 70000]);
+        let bytes = TransferResponse::new(200, vec![], Some(data)).to_bytes();
+        let (request, _) = TransferRequest::parse(&[&b"0008get\n"[..], &bytes[15..]].concat())
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.data.unwrap().len(), 70000);
+    }
+
+    #[test]
+    fn test_download_cant_lock() {
+        block_on(async {
+            let config = LfsConfig {
+                host: String::new(),
+                port: 0,
+                storage: Arc::new(SqliteStorage::memory().await.unwrap()),
+                fs_storage: Arc::new(LocalStorage::init(
+                    std::env::temp_dir().join("lfs-transfer-test"),
+                )),
+                user: Some(String::from("alice")),
+                protocol: Protocol::Ssh,
+                repo_path: String::from("/projects/mega"),
+                client_addr: None,
+            };
+            let mut transfer = LfsTransfer::new(config, "download");
+            let (output, _) = transfer.receive(b"000eversion 1\n0000").await;
+            assert_eq!(&output[..], b"000fstatus 200\n0000");
+
+            let (output, quit) = transfer.receive(b"0009lock\n000fpath=a.bin\n0000").await;
+            assert!(!quit);
+            assert!(output.starts_with(b"000fstatus 403\n"));
+            let (output, _) = transfer.receive(b"000dunlock 1\n0000").await;
+            assert!(output.starts_with(b"000fstatus 403\n"));
+        });
+    }
+}
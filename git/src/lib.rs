@@ -3,6 +3,7 @@
 //!
 //!
 //!
+pub mod audit;
 pub mod errors;
 pub mod hash;
 pub mod internal;
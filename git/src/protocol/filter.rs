@@ -0,0 +1,243 @@
+//!
+//! Partial clone object filters, sent by the client with the `filter <filter-spec>` line, see
+//! the `--filter` option of https://git-scm.com/docs/git-rev-list
+//!
+//! Filtered out blobs and trees are left out of the packfile, and the client fetches them
+//! lazily later by wanting their ids directly.
+//!
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::errors::GitError;
+use crate::protocol::PackProtocol;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ObjectFilter {
+    // blob:none, omits all blobs
+    BlobNone,
+    // blob:limit=<n>[kmg], omits blobs of at least n bytes
+    BlobLimit(usize),
+    // tree:<depth>, omits blobs and trees whose depth from the root tree is at least depth
+    TreeDepth(usize),
+    // sparse:oid=<blob-ish>, omits blobs outside the sparse-checkout spec stored in the blob
+    SparseOid(String),
+}
+
+impl FromStr for ObjectFilter {
+    type Err = GitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || GitError::InvalidFilterSpec(s.to_owned());
+        if s == "blob:none" {
+            Ok(ObjectFilter::BlobNone)
+        } else if let Some(limit) = s.strip_prefix("blob:limit=") {
+            let (number, unit) = match limit.char_indices().last() {
+                Some((i, 'k')) | Some((i, 'K')) => (&limit[..i], 1024),
+                Some((i, 'm')) | Some((i, 'M')) => (&limit[..i], 1024 * 1024),
+                Some((i, 'g')) | Some((i, 'G')) => (&limit[..i], 1024 * 1024 * 1024),
+                _ => (limit, 1),
+            };
+            let number: usize = number.parse().map_err(|_| invalid())?;
+            Ok(ObjectFilter::BlobLimit(number * unit))
+        } else if let Some(depth) = s.strip_prefix("tree:") {
+            Ok(ObjectFilter::TreeDepth(
+                depth.parse().map_err(|_| invalid())?,
+            ))
+        } else if let Some(oid) = s.strip_prefix("sparse:oid=") {
+            Ok(ObjectFilter::SparseOid(oid.to_owned()))
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+/// Patterns of a sparse-checkout file, in the gitignore-like syntax used by
+/// `git sparse-checkout`. The last matching pattern decides, and `!` negates a pattern.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SparsePatterns {
+    patterns: Vec<SparsePattern>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct SparsePattern {
+    negated: bool,
+    // a trailing slash only matches directories
+    dir_only: bool,
+    segments: Vec<String>,
+}
+
+impl SparsePatterns {
+    pub fn parse(content: &str) -> Self {
+        let mut patterns = vec![];
+        for line in content.lines() {
+            let mut line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let negated = line.starts_with('!');
+            if negated {
+                line = &line[1..];
+            }
+            let dir_only = line.ends_with('/');
+            let segments = line
+                .trim_matches('/')
+                .split('/')
+                .map(|s| s.to_owned())
+                .collect();
+            patterns.push(SparsePattern {
+                negated,
+                dir_only,
+                segments,
+            });
+        }
+        SparsePatterns { patterns }
+    }
+
+    /// Checks whether the blob at `path`, relative to the root tree, is part of the spec.
+    pub fn includes(&self, path: &Path) -> bool {
+        let components: Vec<&str> = path.iter().filter_map(|c| c.to_str()).collect();
+        let mut included = false;
+        for pattern in &self.patterns {
+            let len = pattern.segments.len();
+            let matched = (len < components.len()
+                || (!pattern.dir_only && len == components.len()))
+                && pattern
+                    .segments
+                    .iter()
+                    .zip(&components)
+                    .all(|(p, c)| glob_match(p.as_bytes(), c.as_bytes()));
+            if matched {
+                included = !pattern.negated;
+            }
+        }
+        included
+    }
+}
+
+// matches a single path component against a pattern with `*` and `?` wildcards
+pub(crate) fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// An object filter ready to be applied while walking the trees, with the sparse-checkout
+/// spec already loaded.
+#[derive(Debug, Default, Clone)]
+pub struct TreeFilter {
+    pub filter: Option<ObjectFilter>,
+    pub sparse: Option<SparsePatterns>,
+}
+
+impl TreeFilter {
+    /// Trees are only filtered by depth, the root tree is at depth 0.
+    pub fn includes_tree(&self, depth: usize) -> bool {
+        !matches!(self.filter, Some(ObjectFilter::TreeDepth(max)) if depth >= max)
+    }
+
+    /// Only `blob:limit` needs the blob data to decide.
+    pub fn needs_blob_size(&self) -> bool {
+        matches!(self.filter, Some(ObjectFilter::BlobLimit(_)))
+    }
+
+    pub fn includes_blob(&self, depth: usize, path: &Path, size: usize) -> bool {
+        match &self.filter {
+            None => true,
+            Some(ObjectFilter::BlobNone) => false,
+            Some(ObjectFilter::BlobLimit(limit)) => size < *limit,
+            Some(ObjectFilter::TreeDepth(max)) => depth < *max,
+            Some(ObjectFilter::SparseOid(_)) => self
+                .sparse
+                .as_ref()
+                .map(|sparse| sparse.includes(path))
+                .unwrap_or(true),
+        }
+    }
+}
+
+impl PackProtocol {
+    /// # Resolves the filter requested by the client.
+    ///
+    /// For `sparse:oid` the sparse-checkout spec is read from the blob, which must be given by
+    /// id, `<ref>:<path>` expressions are not supported.
+    pub async fn tree_filter(&self) -> Result<TreeFilter, GitError> {
+        let sparse = match &self.filter {
+            Some(ObjectFilter::SparseOid(oid)) => {
+                let blob = self
+                    .storage
+                    .get_obj_data_by_id(oid)
+                    .await?
+                    .ok_or(GitError::NotFountHashValue(oid.to_owned()))?;
+                Some(SparsePatterns::parse(&String::from_utf8_lossy(&blob.data)))
+            }
+            _ => None,
+        };
+        Ok(TreeFilter {
+            filter: self.filter.clone(),
+            sparse,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::protocol::filter::{ObjectFilter, SparsePatterns, TreeFilter};
+
+    #[test]
+    fn test_parse_filter_spec() {
+        assert_eq!(
+            "blob:none".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::BlobNone
+        );
+        assert_eq!(
+            "blob:limit=1k".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::BlobLimit(1024)
+        );
+        assert_eq!(
+            "blob:limit=100".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::BlobLimit(100)
+        );
+        assert_eq!(
+            "tree:0".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::TreeDepth(0)
+        );
+        assert_eq!(
+            "sparse:oid=7bdc783132575d5b3e78400ace9971970ff43a18"
+                .parse::<ObjectFilter>()
+                .unwrap(),
+            ObjectFilter::SparseOid(String::from("7bdc783132575d5b3e78400ace9971970ff43a18"))
+        );
+        assert!("blob:limit=k".parse::<ObjectFilter>().is_err());
+        assert!("combine:blob:none+tree:1".parse::<ObjectFilter>().is_err());
+    }
+
+    #[test]
+    fn test_sparse_patterns_cone_mode() {
+        let sparse = SparsePatterns::parse("/*\n!/*/\n/docs/\n!/docs/*/\n/src/lib/\n");
+        assert!(sparse.includes(Path::new("README.md")));
+        assert!(!sparse.includes(Path::new("tests/data.txt")));
+        assert!(sparse.includes(Path::new("docs/index.md")));
+        assert!(!sparse.includes(Path::new("docs/images/logo.png")));
+        assert!(sparse.includes(Path::new("src/lib/mod.rs")));
+        assert!(!sparse.includes(Path::new("src/main.rs")));
+    }
+
+    #[test]
+    fn test_tree_depth_filter() {
+        let filter = TreeFilter {
+            filter: Some(ObjectFilter::TreeDepth(1)),
+            sparse: None,
+        };
+        assert!(filter.includes_tree(0));
+        assert!(!filter.includes_tree(1));
+        assert!(!filter.includes_blob(1, Path::new("README.md"), 10));
+    }
+}
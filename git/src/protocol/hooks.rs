@@ -0,0 +1,500 @@
+//!
+//! Server-side hooks of receive-pack, see https://git-scm.com/docs/githooks
+//!
+//! Hooks run once the pushed objects are stored and before any ref is updated:
+//! - `pre-receive` sees every command at once and rejects the whole push.
+//! - `update` runs for each ref and rejects only that ref.
+//! - `post-receive` runs after the refs are updated, with the commands that succeeded.
+//!
+//! In-process hooks implement `ReceiveHook`. External executables found in the directory set
+//! by `hooks.path` in the configuration are run like git runs them, with the `<old> <new> <ref>` lines on
+//! stdin for `pre-receive` and `post-receive`, and `<ref> <old> <new>` as arguments for
+//! `update`. Their output is relayed to the pusher on side-band 2.
+//!
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use common::config::HooksConfig;
+use storage::driver::database::storage::ObjectStorage;
+
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tree::Tree;
+use crate::protocol::policy::PolicyHook;
+use crate::protocol::stream::{put_side_band, side_band_max_payload};
+use crate::protocol::{PackProtocol, RefCommand, SideBind};
+use crate::structure::subrepo::TrunkUpdate;
+
+/// `Err` rejects the push, or the ref for `update`, with the message as reason.
+pub type HookResult = Result<(), String>;
+
+/// What a hook knows about the push.
+pub struct HookContext {
+    pub repo_path: PathBuf,
+    pub user: Option<String>,
+    pub storage: Arc<dyn ObjectStorage>,
+    pub pending: PendingObjects,
+}
+
+/// The commits and trees that are only stored along with the ref updates, such as the trunk
+/// commits replayed from a sub-directory push. Hooks look them up before the storage.
+#[derive(Clone, Default)]
+pub struct PendingObjects {
+    pub commits: HashMap<String, Commit>,
+    pub trees: HashMap<String, Tree>,
+}
+
+/// An in-process receive-pack hook, every method accepts by default. Messages pushed to
+/// `output` are shown to the pusher as `remote:` lines.
+#[async_trait]
+pub trait ReceiveHook: Send + Sync {
+    async fn pre_receive(
+        &self,
+        _ctx: &HookContext,
+        _commands: &[RefCommand],
+        _output: &mut Vec<String>,
+    ) -> HookResult {
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        _ctx: &HookContext,
+        _command: &RefCommand,
+        _output: &mut Vec<String>,
+    ) -> HookResult {
+        Ok(())
+    }
+
+    /// Runs once the refs are updated, it can't reject anything anymore.
+    async fn post_receive(
+        &self,
+        _ctx: &HookContext,
+        _commands: &[RefCommand],
+        _output: &mut Vec<String>,
+    ) {
+    }
+}
+
+/// The hooks run by receive-pack, in registration order.
+#[derive(Clone, Default)]
+pub struct ReceiveHooks {
+    hooks: Vec<Arc<dyn ReceiveHook>>,
+}
+
+impl ReceiveHooks {
+    /// Registers the external hooks of `hooks.path` if it is set.
+    pub fn from_config(config: &HooksConfig) -> Self {
+        let mut hooks = ReceiveHooks::default();
+        if let Some(dir) = &config.path {
+            hooks.register(Arc::new(ExternalHooks::new(dir.clone())));
+        }
+        hooks
+    }
+
+    /// Enforces the access policy first, then runs the external hooks of `hooks.path`.
+    pub fn with_policy(config: &HooksConfig) -> Self {
+        let mut hooks = ReceiveHooks::default();
+        hooks.register(Arc::new(PolicyHook));
+        hooks.hooks.extend(ReceiveHooks::from_config(config).hooks);
+        hooks
+    }
+
+    pub fn register(&mut self, hook: Arc<dyn ReceiveHook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+}
+
+/// Runs the hooks executables of a directory, missing executables are skipped.
+pub struct ExternalHooks {
+    dir: PathBuf,
+}
+
+impl ExternalHooks {
+    pub fn new(dir: PathBuf) -> Self {
+        ExternalHooks { dir }
+    }
+
+    // runs the hook if it exists, returns whether it exited successfully
+    async fn run(
+        &self,
+        name: &str,
+        ctx: &HookContext,
+        args: &[&str],
+        stdin: &str,
+        output: &mut Vec<String>,
+    ) -> bool {
+        let program = self.dir.join(name);
+        if !program.is_file() {
+            return true;
+        }
+        let child = Command::new(&program)
+            .args(args)
+            .current_dir(&self.dir)
+            .env("MEGA_REPO_PATH", &ctx.repo_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                tracing::error!("failed to run hook {:?}: {}", program, err);
+                output.push(format!("error: cannot run {} hook", name));
+                return false;
+            }
+        };
+        // the input is written while the output is read, a hook writing its output before
+        // reading all its input would block on a full pipe otherwise
+        if let Some(mut child_stdin) = child.stdin.take() {
+            let input = stdin.as_bytes().to_vec();
+            tokio::spawn(async move {
+                // the hook may exit without reading its input
+                let _ = child_stdin.write_all(&input).await;
+            });
+        }
+        match child.wait_with_output().await {
+            Ok(result) => {
+                for stream in [&result.stdout, &result.stderr] {
+                    output.extend(String::from_utf8_lossy(stream).lines().map(String::from));
+                }
+                result.status.success()
+            }
+            Err(err) => {
+                tracing::error!("failed to wait for hook {:?}: {}", program, err);
+                false
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ReceiveHook for ExternalHooks {
+    async fn pre_receive(
+        &self,
+        ctx: &HookContext,
+        commands: &[RefCommand],
+        output: &mut Vec<String>,
+    ) -> HookResult {
+        if self
+            .run("pre-receive", ctx, &[], &stdin_lines(commands), output)
+            .await
+        {
+            Ok(())
+        } else {
+            Err(String::from("pre-receive hook declined"))
+        }
+    }
+
+    async fn update(
+        &self,
+        ctx: &HookContext,
+        command: &RefCommand,
+        output: &mut Vec<String>,
+    ) -> HookResult {
+        let args = [
+            command.ref_name.as_str(),
+            command.old_id.as_str(),
+            command.new_id.as_str(),
+        ];
+        if self.run("update", ctx, &args, "", output).await {
+            Ok(())
+        } else {
+            Err(String::from("hook declined"))
+        }
+    }
+
+    async fn post_receive(
+        &self,
+        ctx: &HookContext,
+        commands: &[RefCommand],
+        output: &mut Vec<String>,
+    ) {
+        self.run("post-receive", ctx, &[], &stdin_lines(commands), output)
+            .await;
+    }
+}
+
+// the `<old> <new> <ref>` lines given to pre-receive and post-receive
+fn stdin_lines(commands: &[RefCommand]) -> String {
+    commands
+        .iter()
+        .map(|c| format!("{} {} {}\n", c.old_id, c.new_id, c.ref_name))
+        .collect()
+}
+
+impl PackProtocol {
+    /// # Runs the pre-receive hooks, then the update hooks of each remaining ref.
+    ///
+    /// Only the commands not rejected yet are given to the hooks. A pre-receive rejection
+    /// rejects every command with the hook message, an update rejection only its ref.
+    pub async fn run_receive_hooks(&mut self, output: &mut Vec<String>) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let ctx = self.hook_context();
+        let hooks = self.hooks.hooks.clone();
+        let commands: Vec<RefCommand> = self
+            .command_list
+            .iter()
+            .filter(|c| c.is_ok())
+            .cloned()
+            .collect();
+        for hook in &hooks {
+            if let Err(msg) = hook.pre_receive(&ctx, &commands, output).await {
+                for command in self.command_list.iter_mut().filter(|c| c.is_ok()) {
+                    command.failed(msg.clone());
+                }
+                return;
+            }
+        }
+        for command in self.command_list.iter_mut().filter(|c| c.is_ok()) {
+            for hook in &hooks {
+                if let Err(msg) = hook.update(&ctx, command, output).await {
+                    command.failed(msg);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// # Runs the update hooks of the trunk branches moved by a sub-directory push.
+    ///
+    /// The hooks see the trunk repo and the trunk ids, so that the protected refs and path
+    /// owners of the trunk also apply to pushes to its sub-directories. A rejection rejects
+    /// the sub-directory ref and drops its trunk update.
+    pub async fn run_trunk_update_hooks(
+        &mut self,
+        updates: &mut HashMap<String, TrunkUpdate>,
+        output: &mut Vec<String>,
+    ) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let hooks = self.hooks.hooks.clone();
+        for command in self.command_list.iter_mut().filter(|c| c.is_ok()) {
+            let update = match updates.get(&command.ref_name) {
+                Some(update) if update.old_id != update.new_id => update,
+                _ => continue,
+            };
+            let ctx = HookContext {
+                repo_path: PathBuf::from(&update.trunk_path),
+                user: self.user.clone(),
+                storage: self.storage.clone(),
+                pending: update.pending_objects(),
+            };
+            let trunk_command = RefCommand::new(
+                update.old_id.clone(),
+                update.new_id.clone(),
+                command.ref_name.clone(),
+            );
+            for hook in &hooks {
+                if let Err(msg) = hook.update(&ctx, &trunk_command, output).await {
+                    command.failed(format!("{}: {}", update.trunk_path, msg));
+                    break;
+                }
+            }
+            if !command.is_ok() {
+                updates.remove(&command.ref_name);
+            }
+        }
+    }
+
+    /// # Runs the post-receive hooks with the commands that were applied.
+    pub async fn run_post_receive_hooks(&self, output: &mut Vec<String>) {
+        let commands: Vec<RefCommand> = self
+            .command_list
+            .iter()
+            .filter(|c| c.is_ok())
+            .cloned()
+            .collect();
+        if self.hooks.is_empty() || commands.is_empty() {
+            return;
+        }
+        let ctx = self.hook_context();
+        for hook in &self.hooks.hooks {
+            hook.post_receive(&ctx, &commands, output).await;
+        }
+    }
+
+    /// # Writes the hook output as `remote:` lines on side-band 2.
+    ///
+    /// The lines longer than a side-band packet are split. Without side-band there is no
+    /// channel for it, the output is only logged.
+    pub fn write_hook_output(&self, buf: &mut BytesMut, output: &[String]) {
+        let max_payload = side_band_max_payload(&self.capabilities);
+        for line in output {
+            tracing::info!("hook output: {}", line);
+            if let Some(max_payload) = max_payload {
+                let line = format!("{}\n", line);
+                put_side_band(buf, SideBind::ProgressInfo, max_payload, line.as_bytes());
+            }
+        }
+    }
+
+    fn hook_context(&self) -> HookContext {
+        HookContext {
+            repo_path: self.path.clone(),
+            user: self.user.clone(),
+            storage: self.storage.clone(),
+            pending: PendingObjects::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use bytes::BytesMut;
+    use tokio_test::block_on;
+
+    use crate::protocol::hooks::{ExternalHooks, HookContext, HookResult, ReceiveHook};
+    use crate::protocol::{Capability, PackProtocol, RefCommand};
+
+    const OLD_ID: &str = "7bdc783132575d5b3e78400ace9971970ff43a18";
+    const NEW_ID: &str = "8f19cdbd7e5f3f9d2c3a4f6e0a4ae2e0b0e9a5b1";
+
+    struct ProtectMain;
+
+    #[async_trait]
+    impl ReceiveHook for ProtectMain {
+        async fn update(
+            &self,
+            _ctx: &HookContext,
+            command: &RefCommand,
+            output: &mut Vec<String>,
+        ) -> HookResult {
+            if command.ref_name == "refs/heads/main" {
+                output.push(String::from("main is protected"));
+                return Err(String::from("protected branch"));
+            }
+            Ok(())
+        }
+    }
+
+    fn mock_push() -> PackProtocol {
+        let mut mock = PackProtocol::mock();
+        for ref_name in ["refs/heads/main", "refs/heads/dev"] {
+            mock.command_list.push(RefCommand::new(
+                OLD_ID.to_owned(),
+                NEW_ID.to_owned(),
+                ref_name.to_owned(),
+            ));
+        }
+        mock
+    }
+
+    #[test]
+    fn test_update_hook_rejects_one_ref() {
+        let mut mock = mock_push();
+        mock.hooks.register(Arc::new(ProtectMain));
+        let mut output = vec![];
+        block_on(mock.run_receive_hooks(&mut output));
+        assert_eq!(
+            mock.command_list[0].get_status(),
+            "ng refs/heads/main protected branch"
+        );
+        assert!(mock.command_list[1].is_ok());
+
+        mock.capabilities.push(Capability::SideBand64k);
+        let mut buf = BytesMut::new();
+        mock.write_hook_output(&mut buf, &output);
+        assert_eq!(&buf[..], b"0017\x02main is protected\n");
+    }
+
+    // a hooks directory of its own holding `script` as the `name` hook, removed on drop
+    #[cfg(unix)]
+    fn external_hook(name: &str, script: &str) -> (tempfile::TempDir, ExternalHooks) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let program = dir.path().join(name);
+        std::fs::write(&program, script).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let hooks = ExternalHooks::new(dir.path().to_path_buf());
+        (dir, hooks)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_external_pre_receive_hook() {
+        let (_dir, hooks) = external_hook(
+            "pre-receive",
+            "#!/bin/sh\nwhile read old new ref; do echo \"rejected $ref\"; done\nexit 1\n",
+        );
+
+        let mut mock = mock_push();
+        mock.hooks.register(Arc::new(hooks));
+        let mut output = vec![];
+        block_on(mock.run_receive_hooks(&mut output));
+
+        assert_eq!(
+            output,
+            vec!["rejected refs/heads/main", "rejected refs/heads/dev"]
+        );
+        assert!(mock
+            .command_list
+            .iter()
+            .all(|c| c.error_msg == "pre-receive hook declined"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_external_hook_large_io() {
+        // more output than a pipe holds before the input is read
+        let (_dir, hooks) = external_hook(
+            "pre-receive",
+            "#!/bin/sh\nhead -c 200000 /dev/zero | tr '\\0' x\necho\nwc -c\n",
+        );
+        let mut mock = PackProtocol::mock();
+        let ctx = mock.hook_context();
+        let stdin = "y".repeat(300000);
+        let mut output = vec![];
+        assert!(block_on(hooks.run(
+            "pre-receive",
+            &ctx,
+            &[],
+            &stdin,
+            &mut output
+        )));
+        assert_eq!(output[0].len(), 200000);
+        assert_eq!(output[1].trim(), "300000");
+
+        // the long line is split in side-band packets
+        for (capability, max_len) in [
+            (Capability::SideBand, 1000),
+            (Capability::SideBand64k, 65520),
+        ] {
+            mock.capabilities = vec![capability];
+            let mut buf = BytesMut::new();
+            mock.write_hook_output(&mut buf, &output);
+            let mut lines = vec![];
+            let mut rest = &buf[..];
+            while !rest.is_empty() {
+                let len =
+                    usize::from_str_radix(std::str::from_utf8(&rest[..4]).unwrap(), 16).unwrap();
+                assert!(len <= max_len);
+                assert_eq!(rest[4], 2);
+                lines.extend_from_slice(&rest[5..len]);
+                rest = &rest[len..];
+            }
+            assert_eq!(
+                lines,
+                format!("{}\n{}\n", output[0], output[1]).into_bytes()
+            );
+        }
+    }
+}
@@ -11,20 +11,48 @@ use std::{
 
 use sea_orm::{ActiveValue::NotSet, Set};
 
-use common::{errors::MegaError, utils::ZERO_ID};
+use common::{
+    config::{Config, PackConfig},
+    errors::MegaError,
+    utils::ZERO_ID,
+};
 use entity::{mr_info, refs};
 use storage::driver::{database::mysql_storage::MysqlStorage, database::storage::ObjectStorage};
 
+use crate::protocol::filter::ObjectFilter;
+use crate::protocol::hooks::ReceiveHooks;
+use crate::protocol::negotiation::Negotiation;
 use crate::protocol::pack::SP;
+use crate::protocol::shallow::ShallowRequest;
 
+pub mod filter;
+pub mod hooks;
+pub mod negotiation;
 pub mod pack;
+pub mod policy;
+pub mod receive;
+pub mod shallow;
+pub mod stream;
+pub mod v2;
 #[derive(Clone)]
 pub struct PackProtocol {
     pub protocol: Protocol,
+    pub version: ProtocolVersion,
     pub capabilities: Vec<Capability>,
     pub path: PathBuf,
     pub storage: Arc<dyn ObjectStorage>,
     pub command_list: Vec<RefCommand>,
+    pub negotiation: Negotiation,
+    pub shallow: ShallowRequest,
+    pub filter: Option<ObjectFilter>,
+    pub hooks: ReceiveHooks,
+    pub pack_config: PackConfig,
+    // the authenticated user, checked against the access policy
+    pub user: Option<String>,
+    // the address of the client, or the peer id on p2p, recorded in the audit log
+    pub client_addr: Option<String>,
+    // the sub-directories the user can't read, left out of the served packs
+    pub denied_dirs: Vec<PathBuf>,
     // only needed in ssh protocal
     pub service_type: ServiceType,
 }
@@ -39,6 +67,45 @@ pub enum Protocol {
     P2p,
 }
 
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Local => "local",
+            Protocol::Http => "http",
+            Protocol::Ssh => "ssh",
+            Protocol::Git => "git",
+            Protocol::P2p => "p2p",
+        }
+    }
+}
+
+/// Wire protocol version requested by the client, through the `Git-Protocol` header on HTTP
+/// or the `GIT_PROTOCOL` environment variable on SSH.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ProtocolVersion {
+    #[default]
+    V0,
+    V1,
+    V2,
+}
+
+impl ProtocolVersion {
+    /// Parses a colon separated `Git-Protocol` value such as `version=2:object-format=sha1`,
+    /// the highest requested version wins and unknown keys are ignored.
+    pub fn from_git_protocol(value: &str) -> Self {
+        value
+            .split(':')
+            .filter_map(|param| param.trim().strip_prefix("version="))
+            .map(|v| match v {
+                "2" => ProtocolVersion::V2,
+                "1" => ProtocolVersion::V1,
+                _ => ProtocolVersion::V0,
+            })
+            .max_by_key(|v| *v as u8)
+            .unwrap_or_default()
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ServiceType {
     UploadPack,
@@ -77,11 +144,16 @@ pub enum Capability {
     NoDone,
     SideBand,
     SideBand64k,
+    NoProgress,
     ReportStatus,
     ReportStatusv2,
     OfsDelta,
+    Shallow,
     DeepenSince,
     DeepenNot,
+    DeepenRelative,
+    Filter,
+    Atomic,
 }
 
 impl FromStr for Capability {
@@ -93,12 +165,17 @@ impl FromStr for Capability {
             "report-status-v2" => Ok(Capability::ReportStatusv2),
             "side-band" => Ok(Capability::SideBand),
             "side-band-64k" => Ok(Capability::SideBand64k),
+            "no-progress" => Ok(Capability::NoProgress),
             "ofs-delta" => Ok(Capability::OfsDelta),
             "multi_ack" => Ok(Capability::MultiAck),
             "multi_ack_detailed" => Ok(Capability::MultiAckDetailed),
             "no-done" => Ok(Capability::NoDone),
+            "shallow" => Ok(Capability::Shallow),
             "deepen-since" => Ok(Capability::DeepenSince),
             "deepen-not" => Ok(Capability::DeepenNot),
+            "deepen-relative" => Ok(Capability::DeepenRelative),
+            "filter" => Ok(Capability::Filter),
+            "atomic" => Ok(Capability::Atomic),
             _ => Err(()),
         }
     }
@@ -194,6 +271,10 @@ impl RefCommand {
         }
     }
 
+    pub fn is_ok(&self) -> bool {
+        RefCommand::OK_STATUS == self.status
+    }
+
     pub fn failed(&mut self, msg: String) {
         self.status = RefCommand::FAILED_STATUS.to_owned();
         self.error_msg = msg;
@@ -210,21 +291,25 @@ impl RefCommand {
         }
     }
 
-    pub async fn update_refs(&self, storage: Arc<dyn ObjectStorage>, path: &Path) {
+    pub async fn update_refs(
+        &self,
+        storage: Arc<dyn ObjectStorage>,
+        path: &Path,
+    ) -> Result<(), MegaError> {
         match self.command_type {
             CommandType::Create => {
                 storage
                     .save_refs(vec![self.convert_to_model(path.to_str().unwrap())])
-                    .await
-                    .unwrap();
+                    .await?;
             }
-            CommandType::Delete => storage.delete_refs(self.old_id.clone(), path).await,
+            CommandType::Delete => storage.delete_refs(self.old_id.clone(), path).await?,
             CommandType::Update => {
                 storage
                     .update_refs(self.old_id.clone(), self.new_id.clone(), path)
-                    .await;
+                    .await?
             }
         }
+        Ok(())
     }
 }
 
@@ -240,13 +325,27 @@ pub fn new_mr_info(mr_id: i64) -> mr_info::ActiveModel {
 }
 
 impl PackProtocol {
-    pub fn new(path: PathBuf, storage: Arc<dyn ObjectStorage>, protocol: Protocol) -> Self {
+    pub fn new(
+        path: PathBuf,
+        storage: Arc<dyn ObjectStorage>,
+        protocol: Protocol,
+        config: &Config,
+    ) -> Self {
         PackProtocol {
             protocol,
+            version: ProtocolVersion::default(),
             capabilities: Vec::new(),
             path,
             storage,
             command_list: Vec::new(),
+            negotiation: Negotiation::default(),
+            shallow: ShallowRequest::default(),
+            filter: None,
+            hooks: ReceiveHooks::with_policy(&config.hooks),
+            pack_config: config.pack.clone(),
+            user: None,
+            client_addr: None,
+            denied_dirs: Vec::new(),
             service_type: ServiceType::ReceivePack,
         }
     }
@@ -254,14 +353,42 @@ impl PackProtocol {
     pub fn mock() -> Self {
         PackProtocol {
             protocol: Protocol::default(),
+            version: ProtocolVersion::default(),
             capabilities: Vec::new(),
             path: PathBuf::new(),
             storage: Arc::new(MysqlStorage::default()),
             command_list: Vec::new(),
+            negotiation: Negotiation::default(),
+            shallow: ShallowRequest::default(),
+            filter: None,
+            hooks: ReceiveHooks::default(),
+            pack_config: PackConfig::default(),
+            user: None,
+            client_addr: None,
+            denied_dirs: Vec::new(),
             service_type: ServiceType::ReceivePack,
         }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use crate::protocol::ProtocolVersion;
+
+    #[test]
+    fn test_protocol_version_from_git_protocol() {
+        assert_eq!(
+            ProtocolVersion::from_git_protocol("version=2"),
+            ProtocolVersion::V2
+        );
+        assert_eq!(
+            ProtocolVersion::from_git_protocol("object-format=sha1:version=1"),
+            ProtocolVersion::V1
+        );
+        assert_eq!(
+            ProtocolVersion::from_git_protocol("version=1:version=2"),
+            ProtocolVersion::V2
+        );
+        assert_eq!(ProtocolVersion::from_git_protocol(""), ProtocolVersion::V0);
+    }
+}
@@ -0,0 +1,314 @@
+//!
+//! Packfile negotiation for upload-pack, see the "Packfile Negotiation" section of
+//! https://git-scm.com/docs/pack-protocol
+//!
+//! The client sends the commits it has in rounds, and the server acknowledges the common ones
+//! (`multi_ack`, `multi_ack_detailed`, or a single `ACK` without either capability) until it is
+//! ready to send a packfile containing only the objects not reachable from the common commits.
+//!
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use bytes::BytesMut;
+
+use crate::errors::GitError;
+use crate::hash::Hash;
+use crate::internal::object::commit::Commit;
+use crate::protocol::pack::add_pkt_line_string;
+use crate::protocol::{Capability, PackProtocol};
+
+/// The acknowledgement flavour selected by the client capabilities.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AckMode {
+    Single,
+    MultiAck,
+    MultiAckDetailed,
+}
+
+/// Negotiation state of an upload-pack session.
+///
+/// HTTP is stateless, so a new state is built for each request from the haves the client
+/// re-sends. On SSH the same `PackProtocol` serves every round and keeps the state.
+#[derive(Debug, Clone, Default)]
+pub struct Negotiation {
+    pub want: HashSet<String>,
+    pub common: HashSet<String>,
+    pub last_common: Option<String>,
+    // the want section is terminated by the first flush-pkt
+    pub wants_received: bool,
+    got_common: bool,
+    got_other: bool,
+    sent_ready: bool,
+    // cached `ok_to_give_up` result, invalidated when a new common commit is found
+    give_up: Option<bool>,
+}
+
+impl PackProtocol {
+    pub fn ack_mode(&self) -> AckMode {
+        if self.capabilities.contains(&Capability::MultiAckDetailed) {
+            AckMode::MultiAckDetailed
+        } else if self.capabilities.contains(&Capability::MultiAck) {
+            AckMode::MultiAck
+        } else {
+            AckMode::Single
+        }
+    }
+
+    /// # Handles a `have` line of the current round.
+    ///
+    /// A have is common only if the commit exists in the `commit` table. Unknown haves are
+    /// answered with `ready` (or `continue`) once every want already reaches a common commit,
+    /// which tells the client it can stop sending haves.
+    pub async fn process_have(&mut self, hash: &str, buf: &mut BytesMut) -> Result<(), GitError> {
+        let mode = self.ack_mode();
+        if let Ok(Some(_)) = self.storage.get_commit_by_hash(hash).await {
+            if self.negotiation.common.insert(hash.to_owned()) {
+                self.negotiation.give_up = None;
+            }
+            self.negotiation.got_common = true;
+            self.negotiation.last_common = Some(hash.to_owned());
+            match mode {
+                AckMode::MultiAckDetailed => {
+                    add_pkt_line_string(buf, format!("ACK {} common\n", hash))
+                }
+                AckMode::MultiAck => add_pkt_line_string(buf, format!("ACK {} continue\n", hash)),
+                AckMode::Single => {
+                    if self.negotiation.common.len() == 1 {
+                        add_pkt_line_string(buf, format!("ACK {}\n", hash))
+                    }
+                }
+            }
+        } else {
+            self.negotiation.got_other = true;
+            if mode != AckMode::Single && self.ok_to_give_up().await? {
+                if mode == AckMode::MultiAckDetailed {
+                    self.negotiation.sent_ready = true;
+                    add_pkt_line_string(buf, format!("ACK {} ready\n", hash));
+                } else {
+                    add_pkt_line_string(buf, format!("ACK {} continue\n", hash));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// # Handles the flush-pkt closing a round of haves.
+    ///
+    /// Returns true if the packfile should follow immediately, which only happens when the
+    /// client supports `no-done` and the server has already signaled `ready`.
+    pub async fn end_negotiation_round(&mut self, buf: &mut BytesMut) -> Result<bool, GitError> {
+        let mode = self.ack_mode();
+        if mode == AckMode::MultiAckDetailed
+            && self.negotiation.got_common
+            && !self.negotiation.got_other
+            && self.ok_to_give_up().await?
+        {
+            self.negotiation.sent_ready = true;
+            add_pkt_line_string(
+                buf,
+                format!(
+                    "ACK {} ready\n",
+                    self.negotiation.last_common.as_ref().unwrap()
+                ),
+            );
+        }
+        if self.negotiation.common.is_empty() || mode != AckMode::Single {
+            add_pkt_line_string(buf, String::from("NAK\n"));
+        }
+        if self.capabilities.contains(&Capability::NoDone) && self.negotiation.sent_ready {
+            add_pkt_line_string(
+                buf,
+                format!("ACK {}\n", self.negotiation.last_common.as_ref().unwrap()),
+            );
+            return Ok(true);
+        }
+        self.negotiation.got_common = false;
+        self.negotiation.got_other = false;
+        Ok(false)
+    }
+
+    /// # Handles the `done` line, after which the packfile is always sent.
+    pub fn finish_negotiation(&self, buf: &mut BytesMut) {
+        match &self.negotiation.last_common {
+            Some(last) if !self.negotiation.common.is_empty() => {
+                if self.ack_mode() != AckMode::Single {
+                    add_pkt_line_string(buf, format!("ACK {}\n", last));
+                }
+            }
+            _ => add_pkt_line_string(buf, String::from("NAK\n")),
+        }
+    }
+
+    /// # Checks whether every want can reach one of the common commits.
+    ///
+    /// The ancestry walk is cut off at the oldest common commit, since an older commit can't
+    /// lead to it.
+    pub async fn ok_to_give_up(&mut self) -> Result<bool, GitError> {
+        if let Some(give_up) = self.negotiation.give_up {
+            return Ok(give_up);
+        }
+        let common = self.negotiation.common.clone();
+        let give_up = if common.is_empty() {
+            false
+        } else {
+            let cutoff = self
+                .load_commits(common.iter().cloned().collect())
+                .await?
+                .iter()
+                .map(|c| c.committer.timestamp)
+                .min()
+                .unwrap_or_default();
+            let mut reaches_all = true;
+            for want in self.negotiation.want.clone() {
+                if !self.reaches_common(&want, &common, cutoff).await? {
+                    reaches_all = false;
+                    break;
+                }
+            }
+            reaches_all
+        };
+        self.negotiation.give_up = Some(give_up);
+        Ok(give_up)
+    }
+
+    pub(crate) async fn reaches_common(
+        &self,
+        want: &str,
+        common: &HashSet<String>,
+        cutoff: usize,
+    ) -> Result<bool, GitError> {
+        let mut visited = HashSet::new();
+        let mut frontier = vec![want.to_owned()];
+        while !frontier.is_empty() {
+            if frontier.iter().any(|id| common.contains(id)) {
+                return Ok(true);
+            }
+            visited.extend(frontier.iter().cloned());
+            frontier = self
+                .load_commits(frontier)
+                .await?
+                .into_iter()
+                .filter(|c| c.committer.timestamp >= cutoff)
+                .flat_map(|c| c.parent_tree_ids)
+                .map(|p| p.to_plain_str())
+                .filter(|p| !visited.contains(p))
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect();
+        }
+        Ok(false)
+    }
+
+    /// # Lists the commits reachable from `want` but not from `have`.
+    ///
+    /// Walks the ancestry from the `commit` table in committer date order, the haves and their
+    /// ancestors are marked uninteresting and the walk stops once only uninteresting commits
+    /// are left in the queue.
+    pub async fn rev_list(
+        &self,
+        want: &HashSet<String>,
+        have: &HashSet<String>,
+    ) -> Result<Vec<Commit>, GitError> {
+        let mut loaded: HashMap<Hash, Commit> = HashMap::new();
+        let mut uninteresting: HashSet<Hash> = HashSet::new();
+        let mut seen: HashSet<Hash> = HashSet::new();
+        let mut queue: BinaryHeap<(usize, Hash)> = BinaryHeap::new();
+
+        let starts = want.iter().chain(have.iter()).cloned().collect();
+        for commit in self.load_commits(starts).await? {
+            if have.contains(&commit.id.to_plain_str()) {
+                uninteresting.insert(commit.id);
+            }
+            seen.insert(commit.id);
+            queue.push((commit.committer.timestamp, commit.id));
+            loaded.insert(commit.id, commit);
+        }
+
+        let mut result = vec![];
+        while queue.iter().any(|(_, id)| !uninteresting.contains(id)) {
+            let (_, id) = queue.pop().unwrap();
+            let commit = loaded.get(&id).unwrap().clone();
+            let is_uninteresting = uninteresting.contains(&id);
+
+            let missing = commit
+                .parent_tree_ids
+                .iter()
+                .filter(|p| !loaded.contains_key(p))
+                .map(|p| p.to_plain_str())
+                .collect();
+            for parent in self.load_commits(missing).await? {
+                loaded.insert(parent.id, parent);
+            }
+            for p_id in &commit.parent_tree_ids {
+                if is_uninteresting {
+                    uninteresting.insert(*p_id);
+                }
+                if let Some(parent) = loaded.get(p_id) {
+                    if seen.insert(*p_id) {
+                        queue.push((parent.committer.timestamp, *p_id));
+                    }
+                }
+            }
+            if !is_uninteresting {
+                result.push(commit);
+            }
+        }
+        // clock skew can mark a commit uninteresting after it has been popped
+        result.retain(|c| !uninteresting.contains(&c.id));
+        Ok(result)
+    }
+
+    pub(crate) async fn load_commits(&self, ids: Vec<String>) -> Result<Vec<Commit>, GitError> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .storage
+            .get_commit_by_hashes(ids)
+            .await
+            .map_err(|e| GitError::NotFountHashValue(e.to_string()))?
+            .into_iter()
+            .map(|m| m.into())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use crate::protocol::negotiation::AckMode;
+    use crate::protocol::{Capability, PackProtocol};
+
+    #[test]
+    fn test_ack_mode() {
+        let mut mock = PackProtocol::mock();
+        assert_eq!(mock.ack_mode(), AckMode::Single);
+        mock.capabilities.push(Capability::MultiAck);
+        assert_eq!(mock.ack_mode(), AckMode::MultiAck);
+        mock.capabilities.push(Capability::MultiAckDetailed);
+        assert_eq!(mock.ack_mode(), AckMode::MultiAckDetailed);
+    }
+
+    #[test]
+    fn test_finish_negotiation_without_common() {
+        let mock = PackProtocol::mock();
+        let mut buf = BytesMut::new();
+        mock.finish_negotiation(&mut buf);
+        assert_eq!(&buf[..], b"0008NAK\n");
+    }
+
+    #[test]
+    fn test_finish_negotiation_with_common() {
+        let mut mock = PackProtocol::mock();
+        mock.capabilities.push(Capability::MultiAckDetailed);
+        let hash = String::from("7bdc783132575d5b3e78400ace9971970ff43a18");
+        mock.negotiation.common.insert(hash.clone());
+        mock.negotiation.last_common = Some(hash);
+        let mut buf = BytesMut::new();
+        mock.finish_negotiation(&mut buf);
+        assert_eq!(
+            &buf[..],
+            b"0031ACK 7bdc783132575d5b3e78400ace9971970ff43a18\n"
+        );
+    }
+}
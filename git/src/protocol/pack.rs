@@ -3,16 +3,23 @@
 //!
 //!
 
+use std::collections::HashMap;
 use std::io::Write;
-use std::{collections::HashSet, io::Cursor, sync::Arc};
+use std::{io::Cursor, sync::Arc};
 
 use anyhow::Result;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
+use common::config::{ObjStore, PackConfig};
+use common::lifecycle::lifecycle;
 use storage::driver::database::storage::ObjectStorage;
+use storage::driver::pack_store::{idx::PackIdx, PackStore};
 
+use crate::audit;
+use crate::protocol::stream::PackStream;
 use crate::protocol::{
-    new_mr_info, Capability, PackProtocol, Protocol, RefCommand, ServiceType, SideBind,
+    new_mr_info, Capability, CommandType, PackProtocol, Protocol, ProtocolVersion, RefCommand,
+    ServiceType, SideBind,
 };
 use crate::protocol::{RefsType, ZERO_ID};
 use crate::structure::conversion;
@@ -32,6 +39,9 @@ const NUL: char = '\0';
 
 pub const PKT_LINE_END_MARKER: &[u8; 4] = b"0000";
 
+// Separates sections of a protocol v2 request or response.
+pub const PKT_LINE_DELIM_MARKER: &[u8; 4] = b"0001";
+
 // The atomic, report-status, report-status-v2, delete-refs, quiet,
 // and push-cert capabilities are sent and recognized by the receive-pack (push to server) process.
 const RECEIVE_CAP_LIST: &str = "report-status report-status-v2 delete-refs quiet atomic ";
@@ -42,7 +52,7 @@ const CAP_LIST: &str = "side-band-64k ofs-delta agent=mega/0.0.1";
 
 // All other capabilities are only recognized by the upload-pack (fetch from server) process.
 const UPLOAD_CAP_LIST: &str =
-    "shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done include-tag ";
+    "shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done include-tag filter allow-reachable-sha1-in-want ";
 
 impl PackProtocol {
     /// # Retrieves the information about Git references (refs) for the specified service type.
@@ -69,10 +79,14 @@ impl PackProtocol {
     /// Tracing information is logged regarding the response packet line stream.
     ///
     /// Finally, the constructed packet line stream is returned.
-    pub async fn git_info_refs(&mut self) -> BytesMut {
+    pub async fn git_info_refs(&mut self) -> Result<BytesMut, GitError> {
         let service_type = self.service_type;
+        // receive-pack has no version 2, the server falls back to the original protocol
+        if service_type == ServiceType::UploadPack && self.version == ProtocolVersion::V2 {
+            return Ok(self.git_capability_advertisement());
+        }
         // The stream MUST include capability declarations behind a NUL on the first ref.
-        let object_id = self.get_head_object_id(&self.path).await;
+        let object_id = self.get_head_object_id(&self.path).await?;
         let name = if object_id == ZERO_ID {
             "capabilities^{}"
         } else {
@@ -84,113 +98,111 @@ impl PackProtocol {
             // _ => CAP_LIST.to_owned(),
         };
         let pkt_line = format!("{}{}{}{}{}{}", object_id, SP, name, NUL, cap_list, LF);
-        let mut ref_list = vec![pkt_line];
+        let mut ref_list = vec![];
+        if self.version == ProtocolVersion::V1 {
+            ref_list.push(format!("version 1{}", LF));
+        }
+        ref_list.push(pkt_line);
 
         let git_refs = self
             .storage
             .get_all_refs_by_path(self.path.to_str().unwrap())
-            .await
-            .unwrap();
+            .await?;
         for git_ref in git_refs {
             let pkt_line = format!("{}{}{}{}", git_ref.ref_git_id, SP, git_ref.ref_name, LF);
             ref_list.push(pkt_line);
         }
         let pkt_line_stream = self.build_smart_reply(&ref_list, service_type.to_string());
         tracing::debug!("git_info_refs response: {:?}", pkt_line_stream);
-        pkt_line_stream
+        Ok(pkt_line_stream)
     }
 
+    /// # Handles an upload-pack request and negotiates the objects to send.
+    ///
+    /// Reads the want section, answered with the `shallow`/`unshallow` boundary if the client
+    /// is shallow or asks to deepen, then the rounds of haves. Each round is answered with
+    /// `ACK`/`NAK` lines according to the `multi_ack`/`multi_ack_detailed` capability. Once the
+    /// client sends `done` (or the server is ready and `no-done` is supported) the packfile
+    /// holds only the objects not reachable from the common commits.
+    ///
+    /// Returns the pack stream and the pkt-lines to send before it. There is no pack stream as
+    /// long as the negotiation is not finished, in which case nothing should follow the
+    /// pkt-lines.
     pub async fn git_upload_pack(
         &mut self,
         upload_request: &mut Bytes,
-    ) -> Result<(Vec<u8>, BytesMut)> {
-        let mut want: HashSet<String> = HashSet::new();
-        let mut have: HashSet<String> = HashSet::new();
+    ) -> Result<(Option<PackStream>, BytesMut)> {
+        if self.version == ProtocolVersion::V2 {
+            return self.git_command_v2(upload_request).await;
+        }
+        let mut buf = BytesMut::new();
+        let mut send_pack = false;
 
-        let mut read_first_line = false;
-        loop {
-            tracing::debug!("loop start");
+        while !upload_request.is_empty() {
             let (bytes_take, pkt_line) = read_pkt_line(upload_request);
-            // read 0000 to continue and read empty str to break
             if bytes_take == 0 {
-                if upload_request.is_empty() {
+                if !self.negotiation.wants_received {
+                    self.negotiation.wants_received = true;
+                    if self.shallow.is_requested() {
+                        self.compute_shallow_boundary().await?;
+                        self.write_shallow_info(&mut buf, false);
+                    }
+                } else if self.end_negotiation_round(&mut buf).await? {
+                    send_pack = true;
                     break;
-                } else {
-                    continue;
                 }
+                continue;
             }
             tracing::debug!("read line: {:?}", pkt_line);
             let dst = pkt_line.to_vec();
             let commands = &dst[0..4];
 
             match commands {
-                b"want" => want.insert(String::from_utf8(dst[5..45].to_vec()).unwrap()),
-                b"have" => have.insert(String::from_utf8(dst[5..45].to_vec()).unwrap()),
-                b"done" => break,
+                b"want" => {
+                    self.negotiation
+                        .want
+                        .insert(String::from_utf8(dst[5..45].to_vec()).unwrap());
+                    if dst.len() > 46 {
+                        // the first want line contains the capabilities
+                        self.parse_capabilities(std::str::from_utf8(&dst[46..]).unwrap());
+                    }
+                }
+                b"have" => {
+                    let hash = String::from_utf8(dst[5..45].to_vec()).unwrap();
+                    self.process_have(&hash, &mut buf).await?;
+                }
+                b"done" => {
+                    self.finish_negotiation(&mut buf);
+                    send_pack = true;
+                    break;
+                }
+                b"filt" => {
+                    let spec = String::from_utf8_lossy(&dst[7..]);
+                    self.filter = Some(spec.trim_end().parse()?);
+                }
                 other => {
-                    tracing::error!(
-                        "unsupported command: {:?}",
-                        String::from_utf8(other.to_vec())
-                    );
-                    continue;
+                    // shallow, deepen, deepen-since and deepen-not lines
+                    if !self.shallow.parse_line(&String::from_utf8_lossy(&dst)) {
+                        tracing::error!(
+                            "unsupported command: {:?}",
+                            String::from_utf8(other.to_vec())
+                        );
+                    }
                 }
             };
-            if !read_first_line {
-                self.parse_capabilities(&String::from_utf8(dst[46..].to_vec()).unwrap());
-                read_first_line = true;
-            }
         }
 
         tracing::info!(
-            "want commands: {:?}\n have commans: {:?}\n caps:{:?}",
-            want,
-            have,
+            "want commands: {:?}\n common commits: {:?}\n caps:{:?}",
+            self.negotiation.want,
+            self.negotiation.common,
             self.capabilities
         );
 
-        let mut send_pack_data = vec![];
-        let mut buf = BytesMut::new();
-
-        if have.is_empty() {
-            send_pack_data = self.get_full_pack_data(&self.path).await.unwrap();
-            add_pkt_line_string(&mut buf, String::from("NAK\n"));
-        } else {
-            if self.capabilities.contains(&Capability::MultiAckDetailed) {
-                // multi_ack_detailed mode, the server will differentiate the ACKs where it is signaling that
-                // it is ready to send data with ACK obj-id ready lines,
-                // and signals the identified common commits with ACK obj-id common lines
-                for hash in &have {
-                    if self.storage.get_commit_by_hash(hash).await.is_ok() {
-                        add_pkt_line_string(&mut buf, format!("ACK {} common\n", hash));
-                    }
-                    // no need to send NAK in this mode if missing commit?
-                }
-
-                send_pack_data = self
-                    .get_incremental_pack_data(&self.path, &want, &have)
-                    .await
-                    .unwrap();
-
-                for hash in &want {
-                    if self.storage.get_commit_by_hash(hash).await.is_ok() {
-                        add_pkt_line_string(&mut buf, format!("ACK {} common\n", hash));
-                    }
-                    if self.capabilities.contains(&Capability::NoDone) {
-                        // If multi_ack_detailed and no-done are both present, then the sender is free to immediately send a pack
-                        // following its first "ACK obj-id ready" message.
-                        add_pkt_line_string(&mut buf, format!("ACK {} ready\n", hash));
-                    }
-                }
-            } else {
-                tracing::error!("capability unsupported");
-            }
-            // TODO: hard-code here
-            add_pkt_line_string(
-                &mut buf,
-                format!("ACK {} \n", "27dd8d4cf39f3868c6eee38b601bc9e9939304f5"),
-            );
+        if !send_pack {
+            return Ok((None, buf));
         }
-        Ok((send_pack_data, buf))
+        Ok((Some(self.spawn_pack_stream()), buf))
     }
 
     pub async fn git_receive_pack(&mut self, mut body_bytes: Bytes) -> Result<Bytes> {
@@ -209,46 +221,72 @@ impl PackProtocol {
             }
         }
         // handles situation when client send b"0000"
-        if body_bytes.is_empty() {
+        if self.command_list.is_empty() {
             return Ok(body_bytes);
         }
-        // After receiving the pack data from the sender, the receiver sends a report
-        let mut report_status = BytesMut::new();
+        // holds the shutdown until the refs are updated, a push arriving after it began is
+        // refused before anything is written
+        let _transaction = match lifecycle().begin_transaction() {
+            Some(transaction) => transaction,
+            None => {
+                let reason = "the server is shutting down";
+                for command in self.command_list.iter_mut() {
+                    command.failed(reason.to_owned());
+                }
+                let entries = self.ref_audit_entries(&HashMap::new());
+                audit::record(self.storage.as_ref(), entries).await;
+                return Ok(self.build_report_status(reason, &[]));
+            }
+        };
 
-        //1. unpack progress
-        let mr_id = unpack(self.storage.clone(), &mut body_bytes).await?;
-        // write "unpack ok\n to report"
-        add_pkt_line_string(&mut report_status, "unpack ok\n".to_owned());
-        //2. parse progress
-        let parse_obj_result =
+        // no pack follows the commands if they only delete refs
+        let parse_obj_result = body_bytes.is_empty() || {
+            //1. unpack progress
+            let mr_id = unpack(self.storage.clone(), &self.pack_config, &mut body_bytes).await?;
+            //2. parse progress
             conversion::save_node_from_mr(self.storage.clone(), mr_id, &self.path)
                 .await
-                .is_ok();
-
-        //3. update each refs and build report
-        for mut command in self.command_list.clone() {
-            if command.refs_type == RefsType::Tag {
-                // just update if refs type is tag
-                command.update_refs(self.storage.clone(), &self.path).await;
-            } else {
-                // TODO: Updates can be unsuccessful for a number of reasons.
-                // a.The reference can have changed since the reference discovery phase was originally sent, meaning someone pushed in the meantime.
-                // b.The reference being pushed could be a non-fast-forward reference and the update hooks or configuration could be set to not allow that, etc.
-                // c.Also, some references can be updated while others can be rejected.
-                if parse_obj_result {
-                    command.update_refs(self.storage.clone(), &self.path).await;
-                    self.handle_directory().await.unwrap()
-                } else {
-                    command.failed(String::from("parse commit tree from obj failed"));
-                }
+                .is_ok()
+        };
+
+        //3. check and update each refs, then build report
+        if !parse_obj_result {
+            for command in self
+                .command_list
+                .iter_mut()
+                .filter(|c| c.refs_type == RefsType::Branch)
+            {
+                command.failed(String::from("parse commit tree from obj failed"));
             }
+        }
+        self.check_ref_commands().await?;
+        let mut hook_output = vec![];
+        self.run_receive_hooks(&mut hook_output).await;
+        self.apply_ref_commands(&mut hook_output).await?;
+        if self.command_list.iter().any(|c| {
+            c.is_ok() && c.refs_type == RefsType::Branch && c.command_type != CommandType::Delete
+        }) {
+            self.handle_directory().await?;
+        }
+        self.run_post_receive_hooks(&mut hook_output).await;
+        Ok(self.build_report_status("ok", &hook_output))
+    }
+
+    // After receiving the pack data from the sender, the receiver sends a report with the
+    // unpack status and the status of each ref command
+    fn build_report_status(&self, unpack_status: &str, hook_output: &[String]) -> Bytes {
+        let mut report_status = BytesMut::new();
+        add_pkt_line_string(&mut report_status, format!("unpack {}\n", unpack_status));
+        for command in &self.command_list {
             add_pkt_line_string(&mut report_status, command.get_status());
         }
         report_status.put(&PKT_LINE_END_MARKER[..]);
         let length = report_status.len();
-        let mut buf = self.build_side_band_format(report_status, length);
+        let mut buf = BytesMut::new();
+        self.write_hook_output(&mut buf, hook_output);
+        buf.put(self.build_side_band_format(report_status, length));
         buf.put(&PKT_LINE_END_MARKER[..]);
-        Ok(buf.into())
+        buf.freeze()
     }
 
     /// # Builds the packet data in the sideband format if the SideBand/64k capability is enabled.
@@ -319,6 +357,7 @@ impl PackProtocol {
 
 pub async fn unpack(
     storage: Arc<dyn ObjectStorage>,
+    config: &PackConfig,
     pack_file: &mut Bytes,
 ) -> Result<i64, GitError> {
     let count_hash: bool = true;
@@ -329,11 +368,28 @@ pub async fn unpack(
         output.write_all(pack_file).unwrap();
     }
 
-    let curosr_pack = Cursor::new(pack_file);
+    // the pack is stored before its objects are indexed, so that the index never points to a
+    // missing pack
+    let pack_store = match storage.get_config().obj_store {
+        ObjStore::Pack => {
+            let pack_store = PackStore::init(storage.get_config()).await;
+            let pack_id = pack_store.put_pack(pack_file).await?;
+            Some((pack_store, pack_id))
+        }
+        ObjStore::Database => None,
+    };
+
+    let curosr_pack = Cursor::new(&pack_file[..]);
     let reader = HashCounter::new(curosr_pack, count_hash);
     let p = PackPreload::new(reader);
-    let mr_id = decode_load(p, storage.clone()).await?;
-    storage.save_mr_info(new_mr_info(mr_id)).await.unwrap();
+    let offsets = p.offsets();
+    let pack_id = pack_store.as_ref().map(|(_, pack_id)| pack_id.clone());
+    let (mr_id, indexed) = decode_load(p, storage.clone(), config, pack_id).await?;
+    if let Some((pack_store, pack_id)) = pack_store {
+        let idx = PackIdx::new(pack_file, &offsets, &indexed)?;
+        pack_store.put_index(&pack_id, &idx).await?;
+    }
+    storage.save_mr_info(new_mr_info(mr_id)).await?;
     Ok(mr_id)
 }
 
@@ -349,11 +405,19 @@ fn read_until_white_space(bytes: &mut Bytes) -> String {
     String::from_utf8(buf).unwrap()
 }
 
-fn add_pkt_line_string(pkt_line_stream: &mut BytesMut, buf_str: String) {
+pub(crate) fn add_pkt_line_string(pkt_line_stream: &mut BytesMut, buf_str: String) {
     let buf_str_length = buf_str.len() + 4;
     pkt_line_stream.put(Bytes::from(format!("{buf_str_length:04x}")));
     pkt_line_stream.put(buf_str.as_bytes());
 }
+
+/// Builds the `ERR <message>` pkt-line a server sends instead of its reply when it refuses
+/// the request, which the client shows as `remote error: <message>`.
+pub fn build_error_line(message: &str) -> BytesMut {
+    let mut buf = BytesMut::new();
+    add_pkt_line_string(&mut buf, format!("ERR {}\n", message));
+    buf
+}
 /// Read a single pkt-format line from the `bytes` buffer and return the line length and line bytes.
 ///
 /// If the `bytes` buffer is empty, indicating no more data is available, the function returns a line length of 0 and an empty `Bytes` object.
@@ -364,6 +428,8 @@ fn add_pkt_line_string(pkt_line_stream: &mut BytesMut, buf_str: String) {
 ///
 /// If the resulting line length is 0, indicating an empty line, the function returns a line length of 0 and an empty `Bytes` object.
 ///
+/// The protocol v2 delimiter (`0001`) and response-end (`0002`) packets carry no content, their length is returned with an empty `Bytes` object.
+///
 /// If the line length is non-zero, the function extracts the line content from the `bytes` buffer. The extracted line content is returned as a `Bytes` object.
 /// Note that this operation modifies the `bytes` buffer, consuming the bytes up to the end of the line.
 ///
@@ -397,6 +463,9 @@ pub fn read_pkt_line(bytes: &mut Bytes) -> (usize, Bytes) {
     if pkt_length == 0 {
         return (0, Bytes::new());
     }
+    if pkt_length < 4 {
+        return (pkt_length, Bytes::new());
+    }
     // this operation will change the original bytes
     let pkt_line = bytes.copy_to_bytes(pkt_length - 4);
 
@@ -418,6 +487,16 @@ pub mod test {
         assert_eq!(&pkt_line[..], b"# service=git-upload-pack\n");
     }
 
+    #[test]
+    pub fn test_read_delim_pkt_line() {
+        let mut bytes = Bytes::from_static(b"00010009peel\n0000");
+        assert_eq!(read_pkt_line(&mut bytes), (1, Bytes::new()));
+        let (pkt_length, pkt_line) = read_pkt_line(&mut bytes);
+        assert_eq!(pkt_length, 9);
+        assert_eq!(&pkt_line[..], b"peel\n");
+        assert_eq!(read_pkt_line(&mut bytes), (0, Bytes::new()));
+    }
+
     #[test]
     pub fn test_build_smart_reply() {
         let mock = PackProtocol::mock();
@@ -0,0 +1,657 @@
+//!
+//! Access policy of the monorepo, stored in the `protected_ref`, `path_owner` and
+//! `dir_permission` tables.
+//!
+//! - Directory permissions grant `read` or `write` on a subtree of `repo_directory.full_path`
+//!   to a user. A subtree without any permission row on itself or its ancestors stays open to
+//!   everyone, as soon as one exists only the granted users can fetch, or push with `write`.
+//!   The rows below a repository apply as well: the unreadable sub-directories are left out
+//!   of the served packs and a push changing a sub-directory without `write` is rejected.
+//!   Since their parent trees still point to them, only a partial clone, fetching with
+//!   `--filter`, accepts such a pack: a fetch without filter is rejected, naming the first
+//!   unreadable sub-directory.
+//! - Protected refs can't be deleted. Force pushes are rejected for every ref by receive-pack.
+//! - Path owners work like a CODEOWNERS file: the deepest rule above a changed path lists the
+//!   users who must review it, so a push to a protected ref changing that path is only
+//!   accepted from one of them, other changes have to go through a pull request.
+//!
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use async_recursion::async_recursion;
+use async_trait::async_trait;
+
+use common::utils::ZERO_ID;
+use entity::{dir_permission, path_owner};
+
+use crate::errors::GitError;
+use crate::hash::Hash;
+use crate::internal::object::tree::{Tree, TreeItemMode};
+use crate::internal::object::ObjectT;
+use crate::protocol::filter::glob_match;
+use crate::protocol::hooks::{HookContext, HookResult, ReceiveHook};
+use crate::protocol::{CommandType, PackProtocol, RefCommand, ServiceType};
+use crate::structure::conversion::head_ref;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+impl FromStr for Permission {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Permission::Read),
+            "write" => Ok(Permission::Write),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Checks `user` has `needed` on a directory given the permission rows of the directory and
+/// of its ancestors, `write` implies `read`.
+pub fn is_permitted(
+    rows: &[dir_permission::Model],
+    user: Option<&str>,
+    needed: Permission,
+) -> bool {
+    if rows.is_empty() {
+        return true;
+    }
+    let user = match user {
+        Some(user) => user,
+        None => return false,
+    };
+    rows.iter().any(|row| {
+        row.user_name == user
+            && row
+                .permission
+                .parse::<Permission>()
+                .is_ok_and(|granted| granted >= needed)
+    })
+}
+
+/// The directory permissions found at, above and below a repository path.
+pub struct DirPermissions {
+    rows: Vec<dir_permission::Model>,
+}
+
+impl DirPermissions {
+    pub fn new(rows: Vec<dir_permission::Model>) -> Self {
+        DirPermissions { rows }
+    }
+
+    /// Checks `user` has `needed` on `path` given the rows of `path` and of its ancestors.
+    pub fn is_permitted(&self, path: &Path, user: Option<&str>, needed: Permission) -> bool {
+        let rows: Vec<dir_permission::Model> = self
+            .rows
+            .iter()
+            .filter(|row| path.starts_with(&row.path))
+            .cloned()
+            .collect();
+        is_permitted(&rows, user, needed)
+    }
+
+    /// Whether a row is set strictly below `path`, in which case a changed directory has to be
+    /// walked to know the permissions of its content.
+    pub fn has_rows_below(&self, path: &Path) -> bool {
+        self.rows
+            .iter()
+            .any(|row| Path::new(&row.path).starts_with(path) && Path::new(&row.path) != path)
+    }
+
+    /// The sub-directories strictly below `path` on which `user` doesn't have `needed`,
+    /// without the ones inside another of them.
+    pub fn denied_below(
+        &self,
+        path: &Path,
+        user: Option<&str>,
+        needed: Permission,
+    ) -> Vec<PathBuf> {
+        let dirs: BTreeSet<&Path> = self
+            .rows
+            .iter()
+            .map(|row| Path::new(&row.path))
+            .filter(|dir| dir.starts_with(path) && *dir != path)
+            .collect();
+        let denied: Vec<&Path> = dirs
+            .into_iter()
+            .filter(|dir| !self.is_permitted(dir, user, needed))
+            .collect();
+        denied
+            .iter()
+            .filter(|dir| {
+                !denied
+                    .iter()
+                    .any(|other| other != *dir && dir.starts_with(other))
+            })
+            .map(|dir| dir.to_path_buf())
+            .collect()
+    }
+}
+
+/// The owner rules found at, above and below a repository path.
+pub struct OwnerRules {
+    rules: Vec<path_owner::Model>,
+}
+
+impl OwnerRules {
+    pub fn new(rules: Vec<path_owner::Model>) -> Self {
+        OwnerRules { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The deepest rule containing `path`, if it lists any owner.
+    pub fn owner_of(&self, path: &Path) -> Option<&path_owner::Model> {
+        self.rules
+            .iter()
+            .filter(|rule| path.starts_with(&rule.path))
+            .max_by_key(|rule| Path::new(&rule.path).components().count())
+            .filter(|rule| !rule.owners.trim().is_empty())
+    }
+
+    /// Whether a rule is set strictly below `path`, in which case a changed directory has to
+    /// be walked to know the owners of its content.
+    pub fn has_rule_below(&self, path: &Path) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| Path::new(&rule.path).starts_with(path) && Path::new(&rule.path) != path)
+    }
+}
+
+/// Whether `user` is listed in the space separated owners of a rule, with or without `@`.
+pub fn is_owner(rule: &path_owner::Model, user: Option<&str>) -> bool {
+    user.is_some_and(|user| {
+        rule.owners
+            .split_whitespace()
+            .any(|owner| owner.trim_start_matches('@') == user)
+    })
+}
+
+impl PackProtocol {
+    /// # Checks the user can run the requested service on the repository.
+    ///
+    /// Fetching needs `read` and pushing needs `write` on the repository directory. The
+    /// sub-directories the user can't read are recorded in `denied_dirs` to be left out of the
+    /// packs of fetches with a filter, see `check_denied_dirs`, the pushed changes are checked
+    /// against the sub-directories by `PolicyHook`.
+    pub async fn check_permission(&mut self) -> Result<(), GitError> {
+        let needed = match self.service_type {
+            ServiceType::UploadPack => Permission::Read,
+            ServiceType::ReceivePack => Permission::Write,
+        };
+        let permissions = DirPermissions::new(
+            self.storage
+                .get_dir_permissions(self.path.to_str().unwrap())
+                .await?,
+        );
+        let user = self.user.as_deref();
+        if permissions.is_permitted(&self.path, user, needed) {
+            self.denied_dirs = permissions.denied_below(&self.path, user, Permission::Read);
+            Ok(())
+        } else {
+            Err(GitError::PermissionDenied(format!(
+                "{} can't {} {}",
+                self.user.as_deref().unwrap_or("anonymous user"),
+                if needed == Permission::Read {
+                    "read"
+                } else {
+                    "write to"
+                },
+                self.path.display()
+            )))
+        }
+    }
+}
+
+/// Enforces the directory permissions, the protected refs and the path owners on each pushed
+/// ref.
+pub struct PolicyHook;
+
+#[async_trait]
+impl ReceiveHook for PolicyHook {
+    async fn update(
+        &self,
+        ctx: &HookContext,
+        command: &RefCommand,
+        _output: &mut Vec<String>,
+    ) -> HookResult {
+        check_dir_permissions(ctx, command).await?;
+        let repo_path = ctx.repo_path.to_str().unwrap();
+        let protected = ctx
+            .storage
+            .get_protected_refs(repo_path)
+            .await
+            .map_err(|e| e.to_string())?
+            .iter()
+            .any(|r| glob_match(r.ref_pattern.as_bytes(), command.ref_name.as_bytes()));
+        if !protected {
+            return Ok(());
+        }
+        if command.command_type == CommandType::Delete {
+            return Err(String::from("protected ref can't be deleted"));
+        }
+
+        let rules = OwnerRules::new(
+            ctx.storage
+                .get_path_owners(repo_path)
+                .await
+                .map_err(|e| e.to_string())?,
+        );
+        if rules.is_empty() {
+            return Ok(());
+        }
+        let old_tree = commit_tree(ctx, &command.old_id).await?;
+        let new_tree = commit_tree(ctx, &command.new_id).await?;
+        let mut owned = HashMap::new();
+        diff_owned_paths(
+            ctx,
+            &rules,
+            old_tree,
+            new_tree,
+            ctx.repo_path.clone(),
+            &mut owned,
+        )
+        .await?;
+        let mut missing: Vec<&path_owner::Model> = owned
+            .values()
+            .filter(|rule| !is_owner(rule, ctx.user.as_deref()))
+            .collect();
+        missing.sort_by(|a, b| a.path.cmp(&b.path));
+        match missing.first() {
+            Some(rule) => Err(format!(
+                "changes to {} require review from {}",
+                rule.path, rule.owners
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+// rejects the changes to the sub-directories the user can't write to, a new branch is compared
+// with the branch HEAD points to
+async fn check_dir_permissions(ctx: &HookContext, command: &RefCommand) -> HookResult {
+    if command.command_type == CommandType::Delete {
+        return Ok(());
+    }
+    let repo_path = ctx.repo_path.to_str().unwrap();
+    let permissions = DirPermissions::new(
+        ctx.storage
+            .get_dir_permissions(repo_path)
+            .await
+            .map_err(|e| e.to_string())?,
+    );
+    let denied = permissions.denied_below(&ctx.repo_path, ctx.user.as_deref(), Permission::Write);
+    if denied.is_empty() {
+        return Ok(());
+    }
+    let old_id = match command.command_type {
+        CommandType::Create => head_ref(
+            &ctx.storage
+                .get_all_refs_by_path(repo_path)
+                .await
+                .map_err(|e| e.to_string())?,
+        )
+        .map(|r| r.ref_git_id.clone())
+        .unwrap_or_else(|| ZERO_ID.to_owned()),
+        _ => command.old_id.clone(),
+    };
+    let old_tree = commit_tree(ctx, &old_id).await?;
+    let new_tree = commit_tree(ctx, &command.new_id).await?;
+    let path =
+        diff_unwritable_path(ctx, &permissions, old_tree, new_tree, ctx.repo_path.clone()).await?;
+    match path {
+        Some(path) => Err(format!(
+            "{} can't write to {}",
+            ctx.user.as_deref().unwrap_or("anonymous user"),
+            path.display()
+        )),
+        None => Ok(()),
+    }
+}
+
+async fn commit_tree(ctx: &HookContext, commit_id: &str) -> Result<Option<Tree>, String> {
+    if commit_id == ZERO_ID {
+        return Ok(None);
+    }
+    if let Some(commit) = ctx.pending.commits.get(commit_id) {
+        return load_tree(ctx, &commit.tree_id.to_plain_str()).await;
+    }
+    let tree_id = match ctx
+        .storage
+        .get_commit_by_hash(commit_id)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        Some(commit) => commit.tree,
+        None => return Ok(None),
+    };
+    load_tree(ctx, &tree_id).await
+}
+
+async fn load_tree(ctx: &HookContext, tree_id: &str) -> Result<Option<Tree>, String> {
+    if let Some(tree) = ctx.pending.trees.get(tree_id) {
+        return Ok(Some(tree.clone()));
+    }
+    Ok(ctx
+        .storage
+        .get_obj_data_by_id(tree_id)
+        .await
+        .map_err(|e| e.to_string())?
+        .map(|model| Tree::new_from_data(model.data)))
+}
+
+fn tree_entries(tree: &Option<Tree>) -> HashMap<String, (Hash, TreeItemMode)> {
+    tree.iter()
+        .flat_map(|t| &t.tree_items)
+        .map(|item| (item.name.clone(), (item.id, item.mode)))
+        .collect()
+}
+
+async fn load_subtree(
+    ctx: &HookContext,
+    entry: Option<&(Hash, TreeItemMode)>,
+) -> Result<Option<Tree>, String> {
+    match entry {
+        Some((id, TreeItemMode::Tree)) => load_tree(ctx, &id.to_plain_str()).await,
+        _ => Ok(None),
+    }
+}
+
+// the first path changed between two trees that the user can't write to, only walking into
+// the directories that have permissions below them
+#[async_recursion]
+async fn diff_unwritable_path(
+    ctx: &HookContext,
+    permissions: &DirPermissions,
+    old_tree: Option<Tree>,
+    new_tree: Option<Tree>,
+    dir: PathBuf,
+) -> Result<Option<PathBuf>, String> {
+    let old_entries = tree_entries(&old_tree);
+    let new_entries = tree_entries(&new_tree);
+    let names: BTreeSet<&String> = old_entries.keys().chain(new_entries.keys()).collect();
+    for name in names {
+        let old = old_entries.get(name);
+        let new = new_entries.get(name);
+        if old.map(|(id, _)| id) == new.map(|(id, _)| id) {
+            continue;
+        }
+        let path = dir.join(name);
+        if permissions.has_rows_below(&path) {
+            let old_sub = load_subtree(ctx, old).await?;
+            let new_sub = load_subtree(ctx, new).await?;
+            // the change of a directory is the change of its content
+            if old_sub.is_some() || new_sub.is_some() {
+                let denied = diff_unwritable_path(ctx, permissions, old_sub, new_sub, path).await?;
+                if denied.is_some() {
+                    return Ok(denied);
+                }
+                continue;
+            }
+        }
+        if !permissions.is_permitted(&path, ctx.user.as_deref(), Permission::Write) {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+// collects the owner rules of the paths changed between two trees, only walking into the
+// directories that have rules below them
+#[async_recursion]
+async fn diff_owned_paths(
+    ctx: &HookContext,
+    rules: &OwnerRules,
+    old_tree: Option<Tree>,
+    new_tree: Option<Tree>,
+    dir: PathBuf,
+    owned: &mut HashMap<String, path_owner::Model>,
+) -> Result<(), String> {
+    let old_entries = tree_entries(&old_tree);
+    let new_entries = tree_entries(&new_tree);
+    let names: HashSet<&String> = old_entries.keys().chain(new_entries.keys()).collect();
+    for name in names {
+        let old = old_entries.get(name);
+        let new = new_entries.get(name);
+        if old.map(|(id, _)| id) == new.map(|(id, _)| id) {
+            continue;
+        }
+        let path = dir.join(name);
+        if rules.has_rule_below(&path) {
+            let old_sub = load_subtree(ctx, old).await?;
+            let new_sub = load_subtree(ctx, new).await?;
+            if old_sub.is_some() || new_sub.is_some() {
+                diff_owned_paths(ctx, rules, old_sub, new_sub, path.clone(), owned).await?;
+            }
+        }
+        if let Some(rule) = rules.owner_of(&path) {
+            owned.insert(rule.path.clone(), rule.clone());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use sea_orm::{ActiveModelTrait, NotSet, Set};
+    use tokio_test::block_on;
+
+    use entity::{dir_permission, path_owner};
+    use storage::driver::database::sqlite_storage::SqliteStorage;
+    use storage::driver::database::storage::ObjectStorage;
+
+    use crate::hash::Hash;
+    use crate::internal::object::commit::Commit;
+    use crate::internal::object::meta::Meta;
+    use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use crate::internal::object::ObjectT;
+    use crate::internal::ObjectType;
+    use crate::protocol::hooks::{HookContext, PendingObjects, ReceiveHook};
+    use crate::protocol::policy::{
+        is_owner, is_permitted, DirPermissions, OwnerRules, Permission, PolicyHook,
+    };
+    use crate::protocol::RefCommand;
+
+    fn permission(path: &str, user: &str, permission: &str) -> dir_permission::Model {
+        dir_permission::Model {
+            id: 0,
+            path: path.to_owned(),
+            user_name: user.to_owned(),
+            permission: permission.to_owned(),
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    fn owner(path: &str, owners: &str) -> path_owner::Model {
+        path_owner::Model {
+            id: 0,
+            path: path.to_owned(),
+            owners: owners.to_owned(),
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn test_is_permitted() {
+        assert!(is_permitted(&[], None, Permission::Write));
+        let rows = vec![
+            permission("/projects", "alice", "write"),
+            permission("/projects/mega", "bob", "read"),
+        ];
+        assert!(is_permitted(&rows, Some("alice"), Permission::Write));
+        assert!(is_permitted(&rows, Some("bob"), Permission::Read));
+        assert!(!is_permitted(&rows, Some("bob"), Permission::Write));
+        assert!(!is_permitted(&rows, Some("carol"), Permission::Read));
+        assert!(!is_permitted(&rows, None, Permission::Read));
+    }
+
+    #[test]
+    fn test_owner_rules() {
+        let rules = OwnerRules::new(vec![
+            owner("/projects/mega", "@alice"),
+            owner("/projects/mega/docs", "bob carol"),
+            owner("/projects/mega/third_party", ""),
+        ]);
+        let rule = rules.owner_of(Path::new("/projects/mega/docs/README.md"));
+        assert_eq!(rule.unwrap().path, "/projects/mega/docs");
+        assert!(is_owner(rule.unwrap(), Some("carol")));
+        assert!(!is_owner(rule.unwrap(), Some("alice")));
+
+        let rule = rules
+            .owner_of(Path::new("/projects/mega/src/lib.rs"))
+            .unwrap();
+        assert!(is_owner(rule, Some("alice")));
+        assert!(rules
+            .owner_of(Path::new("/projects/mega/third_party/lib.rs"))
+            .is_none());
+        assert!(rules.owner_of(Path::new("/projects/other")).is_none());
+
+        assert!(rules.has_rule_below(Path::new("/projects/mega")));
+        assert!(!rules.has_rule_below(Path::new("/projects/mega/docs")));
+    }
+
+    #[test]
+    fn test_denied_below() {
+        let permissions = DirPermissions::new(vec![
+            permission("/projects", "alice", "write"),
+            permission("/projects/mega/secret", "bob", "read"),
+            permission("/projects/mega/secret/deeper", "bob", "write"),
+            permission("/projects/mega/docs", "carol", "write"),
+        ]);
+        let mega = Path::new("/projects/mega");
+        let (docs, secret) = (
+            PathBuf::from("/projects/mega/docs"),
+            PathBuf::from("/projects/mega/secret"),
+        );
+        assert!(permissions
+            .denied_below(mega, Some("alice"), Permission::Write)
+            .is_empty());
+        assert_eq!(
+            permissions.denied_below(mega, Some("bob"), Permission::Read),
+            vec![docs.clone()]
+        );
+        assert_eq!(
+            permissions.denied_below(mega, Some("bob"), Permission::Write),
+            vec![docs, secret]
+        );
+        assert!(permissions.is_permitted(
+            Path::new("/projects/mega/secret/deeper/a.txt"),
+            Some("bob"),
+            Permission::Write
+        ));
+        assert!(permissions.has_rows_below(Path::new("/projects/mega/secret")));
+        assert!(!permissions.has_rows_below(Path::new("/projects/mega/secret/deeper")));
+    }
+
+    fn tree(items: &[(&str, TreeItemMode, Hash)]) -> Tree {
+        let mut tree = Tree {
+            id: Hash::default(),
+            tree_items: items
+                .iter()
+                .map(|(name, mode, id)| TreeItem::new(*mode, *id, name.to_string()))
+                .collect(),
+        };
+        tree.id = Meta::calculate_id(ObjectType::Tree, &tree.to_data().unwrap());
+        tree
+    }
+
+    fn blob_id(content: &str) -> Hash {
+        Meta::calculate_id(ObjectType::Blob, &content.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_push_checks_dir_permissions() {
+        block_on(async {
+            let storage = Arc::new(SqliteStorage::memory().await.unwrap());
+            for (path, permission) in [
+                ("/projects/mega/secret", "read"),
+                ("/projects/mega/secret/deeper", "write"),
+            ] {
+                let now = chrono::Utc::now().naive_utc();
+                dir_permission::ActiveModel {
+                    id: NotSet,
+                    path: Set(path.to_owned()),
+                    user_name: Set(String::from("bob")),
+                    permission: Set(permission.to_owned()),
+                    created_at: Set(now),
+                    updated_at: Set(now),
+                }
+                .insert(storage.get_connection())
+                .await
+                .unwrap();
+            }
+
+            // a version of the repo from the contents of README, secret/notes.txt and
+            // secret/deeper/file.txt
+            let mut pending = PendingObjects::default();
+            let mut version = |readme: &str, notes: &str, file: &str| {
+                let deeper = tree(&[("file.txt", TreeItemMode::Blob, blob_id(file))]);
+                let secret = tree(&[
+                    ("deeper", TreeItemMode::Tree, deeper.id),
+                    ("notes.txt", TreeItemMode::Blob, blob_id(notes)),
+                ]);
+                let root = tree(&[
+                    ("README", TreeItemMode::Blob, blob_id(readme)),
+                    ("secret", TreeItemMode::Tree, secret.id),
+                ]);
+                let signature = "Alice <alice@example.com> 1700000000 +0000";
+                let data = format!(
+                    "tree {}\nauthor {}\ncommitter {}\n\nversion\n",
+                    root.id, signature, signature
+                );
+                let mut commit = Commit::new_from_data(data.into_bytes());
+                commit.id = Meta::calculate_id(ObjectType::Commit, &commit.to_data().unwrap());
+                let commit_id = commit.id.to_plain_str();
+                for t in [deeper, secret, root] {
+                    pending.trees.insert(t.id.to_plain_str(), t);
+                }
+                pending.commits.insert(commit_id.clone(), commit);
+                commit_id
+            };
+            let old = version("r0", "n0", "f0");
+            let readme = version("r1", "n0", "f0");
+            let notes = version("r0", "n1", "f0");
+            let file = version("r0", "n0", "f1");
+
+            for (user, new, result) in [
+                ("bob", &readme, Ok(())),
+                ("bob", &file, Ok(())),
+                (
+                    "bob",
+                    &notes,
+                    Err("bob can't write to /projects/mega/secret/notes.txt"),
+                ),
+                ("alice", &readme, Ok(())),
+                (
+                    "alice",
+                    &file,
+                    Err("alice can't write to /projects/mega/secret/deeper"),
+                ),
+            ] {
+                let ctx = HookContext {
+                    repo_path: PathBuf::from("/projects/mega"),
+                    user: Some(user.to_owned()),
+                    storage: storage.clone(),
+                    pending: pending.clone(),
+                };
+                let command =
+                    RefCommand::new(old.clone(), new.clone(), String::from("refs/heads/main"));
+                let hook_result = PolicyHook.update(&ctx, &command, &mut vec![]).await;
+                assert_eq!(hook_result, result.map_err(String::from));
+            }
+        });
+    }
+}
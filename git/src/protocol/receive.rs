@@ -0,0 +1,324 @@
+//!
+//! Ref updates of receive-pack, see the "Reference Update Request and Packfile Transfer"
+//! section of https://git-scm.com/docs/pack-protocol
+//!
+//! Every command is checked against the current value of its ref and, for branches, against
+//! the ancestry of the pushed commit before anything is written. The updates are then applied
+//! as compare-and-swap statements in a single database transaction, so a push racing with
+//! another one is rejected instead of overwriting it. With the `atomic` capability, a single
+//! rejected ref rejects the whole push.
+//!
+//! Branches pushed to a sub-directory repo also move the trunk branch of the same name, in the
+//! same transaction, see `structure::subrepo`.
+//!
+//! Every command is recorded in the audit log, in the same transaction when it is applied.
+//!
+use std::collections::{HashMap, HashSet};
+
+use sea_orm::TransactionTrait;
+
+use common::utils::ZERO_ID;
+
+use crate::audit::{self, AuditEntry};
+use crate::errors::GitError;
+use crate::protocol::{Capability, CommandType, PackProtocol, RefsType};
+
+// committer dates can go slightly backwards between a commit and its parent when clocks are
+// skewed, so the ancestry walk goes that far below the date of the old commit
+const CLOCK_SKEW_SLOP: usize = 24 * 60 * 60;
+
+const STALE_INFO: &str = "stale info";
+const NON_FAST_FORWARD: &str = "non-fast-forward";
+const ATOMIC_FAILED: &str = "atomic transaction failed";
+
+impl PackProtocol {
+    /// # Rejects the commands that can't be applied.
+    ///
+    /// A command is rejected as stale if its ref doesn't point to `old_id` anymore, and as
+    /// non-fast-forward if it moves a branch to a commit that doesn't descend from `old_id`.
+    /// Deletions and tag updates only need the ref to be unchanged.
+    pub async fn check_ref_commands(&mut self) -> Result<(), GitError> {
+        let current: HashMap<String, String> = self
+            .storage
+            .get_all_refs_by_path(self.path.to_str().unwrap())
+            .await?
+            .into_iter()
+            .map(|r| (r.ref_name, r.ref_git_id))
+            .collect();
+        let mut commands = std::mem::take(&mut self.command_list);
+        for command in commands.iter_mut().filter(|c| c.is_ok()) {
+            let expected = current
+                .get(&command.ref_name)
+                .map(|id| id.as_str())
+                .unwrap_or(ZERO_ID);
+            if expected != command.old_id {
+                command.failed(String::from(STALE_INFO));
+            } else if command.command_type == CommandType::Update
+                && command.refs_type == RefsType::Branch
+                && !self.is_ancestor(&command.old_id, &command.new_id).await?
+            {
+                command.failed(String::from(NON_FAST_FORWARD));
+            }
+        }
+        self.command_list = commands;
+        Ok(())
+    }
+
+    /// # Applies the accepted commands in one transaction.
+    ///
+    /// Each ref is compare-and-swapped, so a ref moved by a concurrent push after the checks
+    /// is rejected as stale. With `atomic`, nothing is written unless every command succeeds.
+    /// The update hooks also run on the trunk branches moved by the commands, their output is
+    /// added to `hook_output`.
+    pub async fn apply_ref_commands(
+        &mut self,
+        hook_output: &mut Vec<String>,
+    ) -> Result<(), GitError> {
+        let mut trunk_updates = self.prepare_trunk_updates().await?;
+        self.run_trunk_update_hooks(&mut trunk_updates, hook_output)
+            .await;
+        let atomic = self.capabilities.contains(&Capability::Atomic);
+        if atomic && self.reject_all_if_any_failed() {
+            audit::record(
+                self.storage.as_ref(),
+                self.ref_audit_entries(&trunk_updates),
+            )
+            .await;
+            return Ok(());
+        }
+        let path = self.path.to_str().unwrap().to_owned();
+        let txn = self.storage.get_connection().begin().await?;
+        let mut commands = std::mem::take(&mut self.command_list);
+        for command in commands.iter_mut().filter(|c| c.is_ok()) {
+            let mut swapped = self
+                .storage
+                .compare_and_swap_ref(
+                    &txn,
+                    &path,
+                    &command.ref_name,
+                    &command.old_id,
+                    &command.new_id,
+                )
+                .await?;
+            if let Some(update) = trunk_updates.get(&command.ref_name).filter(|_| swapped) {
+                swapped = self.apply_trunk_update(&txn, command, update).await?;
+            }
+            if !swapped {
+                command.failed(String::from(STALE_INFO));
+            }
+        }
+        self.command_list = commands;
+
+        if atomic && self.reject_all_if_any_failed() {
+            txn.rollback().await?;
+            // the rejections are recorded with the reason of the rejected command
+            let entries = self.ref_audit_entries(&trunk_updates);
+            audit::record(self.storage.as_ref(), entries).await;
+            return Ok(());
+        }
+        // the refs don't move unless their audit entries are saved
+        let models = self
+            .ref_audit_entries(&trunk_updates)
+            .into_iter()
+            .map(AuditEntry::into_active_model)
+            .collect();
+        self.storage.save_audit_logs(Some(&txn), models).await?;
+        txn.commit().await.map_err(GitError::from)
+    }
+
+    /// Checks whether `ancestor` is reachable from `descendant` in the `commit` table.
+    pub async fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool, GitError> {
+        let cutoff = self
+            .load_commits(vec![ancestor.to_owned()])
+            .await?
+            .first()
+            .map(|c| c.committer.timestamp.saturating_sub(CLOCK_SKEW_SLOP))
+            .unwrap_or_default();
+        let target = HashSet::from([ancestor.to_owned()]);
+        self.reaches_common(descendant, &target, cutoff).await
+    }
+
+    // rejects the remaining commands once one of them is rejected, returns whether it did
+    fn reject_all_if_any_failed(&mut self) -> bool {
+        if self.command_list.iter().all(|c| c.is_ok()) {
+            return false;
+        }
+        for command in self.command_list.iter_mut().filter(|c| c.is_ok()) {
+            command.failed(String::from(ATOMIC_FAILED));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use tokio_test::block_on;
+
+    use common::utils::ZERO_ID;
+    use storage::driver::database::sqlite_storage::SqliteStorage;
+    use storage::driver::database::storage::{AuditLogQuery, ObjectStorage};
+
+    use crate::internal::object::commit::Commit;
+    use crate::internal::object::meta::Meta;
+    use crate::internal::object::ObjectT;
+    use crate::internal::ObjectType;
+    use crate::protocol::pack::add_pkt_line_string;
+    use crate::protocol::{PackProtocol, RefCommand};
+
+    const OLD_ID: &str = "7bdc783132575d5b3e78400ace9971970ff43a18";
+    const NEW_ID: &str = "8f19cdbd7e5f3f9d2c3a4f6e0a4ae2e0b0e9a5b1";
+    const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+    fn commit(parents: &[&Commit], message: &str) -> Commit {
+        let parents: String = parents
+            .iter()
+            .map(|p| format!("parent {}\n", p.id.to_plain_str()))
+            .collect();
+        let signature = "Alice <alice@example.com> 1700000000 +0000";
+        let data = format!(
+            "tree {}\n{}author {}\ncommitter {}\n\n{}\n",
+            EMPTY_TREE, parents, signature, signature, message
+        );
+        let mut commit = Commit::new_from_data(data.into_bytes());
+        commit.id = Meta::calculate_id(ObjectType::Commit, &commit.to_data().unwrap());
+        commit
+    }
+
+    async fn push(mock: &mut PackProtocol, old: Option<&Commit>, new: &Commit) -> String {
+        mock.command_list = vec![RefCommand::new(
+            old.map_or(ZERO_ID.to_owned(), |c| c.id.to_plain_str()),
+            new.id.to_plain_str(),
+            String::from("refs/heads/main"),
+        )];
+        mock.check_ref_commands().await.unwrap();
+        mock.apply_ref_commands(&mut vec![]).await.unwrap();
+        mock.command_list[0].get_status()
+    }
+
+    #[test]
+    fn test_reject_all_if_any_failed() {
+        let mut mock = PackProtocol::mock();
+        mock.command_list = vec![
+            RefCommand::new(
+                OLD_ID.to_owned(),
+                NEW_ID.to_owned(),
+                String::from("refs/heads/main"),
+            ),
+            RefCommand::new(
+                OLD_ID.to_owned(),
+                NEW_ID.to_owned(),
+                String::from("refs/heads/dev"),
+            ),
+        ];
+        assert!(!mock.reject_all_if_any_failed());
+
+        mock.command_list[1].failed(String::from("non-fast-forward"));
+        assert!(mock.reject_all_if_any_failed());
+        assert_eq!(
+            mock.command_list[0].get_status(),
+            "ng refs/heads/main atomic transaction failed"
+        );
+        assert_eq!(
+            mock.command_list[1].get_status(),
+            "ng refs/heads/dev non-fast-forward"
+        );
+    }
+
+    #[test]
+    fn test_apply_ref_commands_on_sqlite() {
+        // the in-memory database is dropped with the runtime of its connection
+        block_on(async {
+            let storage = Arc::new(SqliteStorage::memory().await.unwrap());
+            let path = PathBuf::from("/projects/mega");
+            let base = commit(&[], "base");
+            let next = commit(&[&base], "next");
+            let other = commit(&[], "other");
+            let models = [&base, &next, &other]
+                .iter()
+                .map(|c| c.convert_to_model(&path))
+                .collect();
+            storage.save_commits(None, models).await.unwrap();
+
+            let mut mock = PackProtocol::mock();
+            mock.storage = storage.clone();
+            mock.path = path;
+            assert_eq!(push(&mut mock, None, &base).await, "ok refs/heads/main");
+            assert_eq!(
+                push(&mut mock, Some(&base), &next).await,
+                "ok refs/heads/main"
+            );
+            assert_eq!(
+                push(&mut mock, Some(&next), &other).await,
+                "ng refs/heads/main non-fast-forward"
+            );
+            assert_eq!(
+                push(&mut mock, Some(&base), &next).await,
+                "ng refs/heads/main stale info"
+            );
+
+            let refs = storage
+                .get_all_refs_by_path("/projects/mega")
+                .await
+                .unwrap();
+            assert_eq!(refs.len(), 1);
+            assert_eq!(refs[0].ref_git_id, next.id.to_plain_str());
+            let query = AuditLogQuery {
+                limit: 10,
+                ..Default::default()
+            };
+            let logs = storage.get_audit_logs(&query).await.unwrap();
+            let results: Vec<&str> = logs.iter().map(|l| l.result.as_str()).collect();
+            assert_eq!(results, vec!["stale info", "non-fast-forward", "ok", "ok"]);
+        });
+    }
+
+    #[test]
+    fn test_delete_only_push() {
+        block_on(async {
+            let storage = Arc::new(SqliteStorage::memory().await.unwrap());
+            let path = PathBuf::from("/projects/mega");
+            let base = commit(&[], "base");
+            storage
+                .save_commits(None, vec![base.convert_to_model(&path)])
+                .await
+                .unwrap();
+            let mut mock = PackProtocol::mock();
+            mock.storage = storage.clone();
+            mock.path = path;
+            assert_eq!(push(&mut mock, None, &base).await, "ok refs/heads/main");
+
+            // git sends no pack when every command deletes a ref
+            let mut body = bytes::BytesMut::new();
+            add_pkt_line_string(
+                &mut body,
+                format!(
+                    "{} {} refs/heads/main\0report-status delete-refs\n",
+                    base.id, ZERO_ID
+                ),
+            );
+            body.extend_from_slice(b"0000");
+            mock.command_list.clear();
+            let report = mock.git_receive_pack(body.freeze()).await.unwrap();
+            assert_eq!(
+                report,
+                Bytes::from("000eunpack ok\n0016ok refs/heads/main00000000")
+            );
+            let refs = storage
+                .get_all_refs_by_path("/projects/mega")
+                .await
+                .unwrap();
+            assert!(refs.is_empty());
+            let query = AuditLogQuery {
+                limit: 10,
+                ..Default::default()
+            };
+            let logs = storage.get_audit_logs(&query).await.unwrap();
+            assert_eq!(logs[0].old_id, Some(base.id.to_plain_str()));
+            assert_eq!(logs[0].result, "ok");
+        });
+    }
+}
@@ -0,0 +1,280 @@
+//!
+//! Shallow clone support for upload-pack, see the "Shallow Clone" section of
+//! https://git-scm.com/docs/pack-protocol
+//!
+//! The client truncates the history with `deepen <depth>`, `deepen-since <timestamp>` or
+//! `deepen-not <ref>`, and tells the server which commits are already shallow on its side with
+//! `shallow <oid>` lines. The server answers with the new `shallow`/`unshallow` boundary and
+//! leaves every commit beyond it out of the packfile.
+//!
+use std::collections::HashSet;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::errors::GitError;
+use crate::internal::object::commit::Commit;
+use crate::protocol::pack::{add_pkt_line_string, PKT_LINE_END_MARKER};
+use crate::protocol::{Capability, PackProtocol};
+
+/// The history truncation requested by the client in the want section.
+#[derive(Debug, Clone, Default)]
+pub struct ShallowRequest {
+    pub client_shallows: HashSet<String>,
+    pub depth: Option<usize>,
+    pub deepen_since: Option<usize>,
+    pub deepen_not: Vec<String>,
+    // computed once the want section is read
+    pub boundary: Option<ShallowBoundary>,
+}
+
+/// The commits to send and the new shallow boundary of the client.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShallowBoundary {
+    pub commits: HashSet<String>,
+    pub shallow: Vec<String>,
+    pub unshallow: Vec<String>,
+}
+
+impl ShallowRequest {
+    /// Parses a `shallow`, `deepen`, `deepen-since` or `deepen-not` line, returns false if the
+    /// line is none of them.
+    pub fn parse_line(&mut self, line: &str) -> bool {
+        let line = line.trim_end();
+        if let Some(id) = line.strip_prefix("shallow ") {
+            self.client_shallows.insert(id.to_owned());
+        } else if let Some(depth) = line.strip_prefix("deepen ") {
+            self.depth = depth.parse().ok();
+        } else if let Some(since) = line.strip_prefix("deepen-since ") {
+            self.deepen_since = since.parse().ok();
+        } else if let Some(rev) = line.strip_prefix("deepen-not ") {
+            self.deepen_not.push(rev.to_owned());
+        } else {
+            return false;
+        }
+        true
+    }
+
+    pub fn is_deepen(&self) -> bool {
+        self.depth.is_some() || self.deepen_since.is_some() || !self.deepen_not.is_empty()
+    }
+
+    /// The client expects a shallow section whenever it is shallow or asks to deepen.
+    pub fn is_requested(&self) -> bool {
+        self.is_deepen() || !self.client_shallows.is_empty()
+    }
+}
+
+impl PackProtocol {
+    /// # Computes the shallow boundary of the wanted history.
+    ///
+    /// Walks the ancestry of the wants through the `commit` table:
+    /// - `deepen <depth>` keeps the commits less than `depth` generations away from the wants,
+    ///   or from the client shallow commits with `deepen-relative`.
+    /// - `deepen-since` keeps the commits committed at or after the timestamp.
+    /// - `deepen-not` drops the commits reachable from the given refs.
+    ///
+    /// Without a deepen request the walk stops at the client shallow commits, so a shallow
+    /// client never gets the history it has cut off. Kept commits with a parent left out become
+    /// the new shallow commits, and client shallow commits whose parents are now sent are
+    /// unshallowed.
+    pub async fn compute_shallow_boundary(&mut self) -> Result<(), GitError> {
+        let request = self.shallow.clone();
+        let want = self.negotiation.want.clone();
+        let no_stop = HashSet::new();
+
+        let commits = if !request.deepen_not.is_empty() || request.deepen_since.is_some() {
+            let mut excluded = HashSet::new();
+            for rev in &request.deepen_not {
+                excluded.insert(self.resolve_rev(rev).await?);
+            }
+            let since = request.deepen_since.unwrap_or_default();
+            self.rev_list(&want, &excluded)
+                .await?
+                .into_iter()
+                .filter(|c| c.committer.timestamp >= since || want.contains(&c.id.to_plain_str()))
+                .collect()
+        } else if let Some(depth) = request.depth {
+            if self.capabilities.contains(&Capability::DeepenRelative) {
+                let mut commits = self
+                    .walk_commits(&want, &request.client_shallows, None)
+                    .await?;
+                let roots = commits
+                    .iter()
+                    .map(|c| c.id.to_plain_str())
+                    .filter(|id| request.client_shallows.contains(id))
+                    .collect();
+                commits.extend(self.walk_commits(&roots, &no_stop, Some(depth + 1)).await?);
+                commits
+            } else {
+                self.walk_commits(&want, &no_stop, Some(depth)).await?
+            }
+        } else {
+            self.walk_commits(&want, &request.client_shallows, None)
+                .await?
+        };
+
+        let kept: HashSet<String> = commits.iter().map(|c| c.id.to_plain_str()).collect();
+        let mut boundary = ShallowBoundary::default();
+        let mut seen = HashSet::new();
+        for commit in &commits {
+            let id = commit.id.to_plain_str();
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            let is_shallow = commit
+                .parent_tree_ids
+                .iter()
+                .any(|p| !kept.contains(&p.to_plain_str()));
+            if is_shallow && !request.client_shallows.contains(&id) {
+                boundary.shallow.push(id);
+            } else if !is_shallow && request.client_shallows.contains(&id) {
+                boundary.unshallow.push(id);
+            }
+        }
+        boundary.commits = kept;
+        self.shallow.boundary = Some(boundary);
+        Ok(())
+    }
+
+    /// # Writes the `shallow`/`unshallow` lines of the computed boundary.
+    ///
+    /// In the original protocol the list is terminated by a flush-pkt, in version 2 it becomes
+    /// the `shallow-info` section.
+    pub fn write_shallow_info(&self, buf: &mut BytesMut, v2: bool) {
+        let boundary = match &self.shallow.boundary {
+            Some(boundary) => boundary,
+            None => return,
+        };
+        if v2 {
+            add_pkt_line_string(buf, String::from("shallow-info\n"));
+        }
+        for id in &boundary.shallow {
+            add_pkt_line_string(buf, format!("shallow {}\n", id));
+        }
+        for id in &boundary.unshallow {
+            add_pkt_line_string(buf, format!("unshallow {}\n", id));
+        }
+        if !v2 {
+            buf.put(&PKT_LINE_END_MARKER[..]);
+        }
+    }
+
+    /// # Lists the commits of the shallow boundary missing on the client.
+    ///
+    /// The client history is walked from the `have` commits down to its shallow commits, so
+    /// the history uncovered by a deepen request is sent even though it is reachable from the
+    /// haves.
+    pub async fn shallow_pack_commits(
+        &self,
+        have: &HashSet<String>,
+    ) -> Result<Vec<Commit>, GitError> {
+        let boundary = match &self.shallow.boundary {
+            Some(boundary) => boundary,
+            None => return Ok(vec![]),
+        };
+        let client_commits: HashSet<String> = self
+            .walk_commits(have, &self.shallow.client_shallows, None)
+            .await?
+            .into_iter()
+            .map(|c| c.id.to_plain_str())
+            .collect();
+        let missing = boundary
+            .commits
+            .iter()
+            .filter(|id| !client_commits.contains(*id))
+            .cloned()
+            .collect();
+        self.load_commits(missing).await
+    }
+
+    /// Resolves a `deepen-not` argument, either a commit id or a ref name of the repository.
+    async fn resolve_rev(&self, rev: &str) -> Result<String, GitError> {
+        if rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(rev.to_owned());
+        }
+        self.storage
+            .get_all_refs_by_path(self.path.to_str().unwrap())
+            .await?
+            .into_iter()
+            .find(|r| r.ref_name == rev || r.ref_name.ends_with(&format!("/{}", rev)))
+            .map(|r| r.ref_git_id)
+            .ok_or(GitError::NotFountHashValue(rev.to_owned()))
+    }
+
+    /// Walks the ancestry of `starts` generation by generation, without going past the `stop`
+    /// commits, and keeps at most `limit` generations.
+    async fn walk_commits(
+        &self,
+        starts: &HashSet<String>,
+        stop: &HashSet<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Commit>, GitError> {
+        let mut visited: HashSet<String> = starts.clone();
+        let mut frontier: Vec<String> = starts.iter().cloned().collect();
+        let mut result = vec![];
+        let mut generation = 0;
+        while !frontier.is_empty() && limit.is_none_or(|limit| generation < limit) {
+            let mut next = vec![];
+            for commit in self.load_commits(frontier).await? {
+                if !stop.contains(&commit.id.to_plain_str()) {
+                    for parent in &commit.parent_tree_ids {
+                        if visited.insert(parent.to_plain_str()) {
+                            next.push(parent.to_plain_str());
+                        }
+                    }
+                }
+                result.push(commit);
+            }
+            frontier = next;
+            generation += 1;
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use crate::protocol::shallow::{ShallowBoundary, ShallowRequest};
+    use crate::protocol::PackProtocol;
+
+    #[test]
+    fn test_parse_shallow_lines() {
+        let mut request = ShallowRequest::default();
+        assert!(request.parse_line("shallow 7bdc783132575d5b3e78400ace9971970ff43a18\n"));
+        assert!(request.parse_line("deepen 1"));
+        assert!(request.parse_line("deepen-since 1700000000"));
+        assert!(request.parse_line("deepen-not refs/heads/old"));
+        assert!(!request.parse_line("done"));
+        assert_eq!(request.depth, Some(1));
+        assert_eq!(request.deepen_since, Some(1700000000));
+        assert_eq!(request.deepen_not, vec![String::from("refs/heads/old")]);
+        assert!(request
+            .client_shallows
+            .contains("7bdc783132575d5b3e78400ace9971970ff43a18"));
+        assert!(request.is_deepen());
+    }
+
+    #[test]
+    fn test_write_shallow_info() {
+        let mut mock = PackProtocol::mock();
+        mock.shallow.boundary = Some(ShallowBoundary {
+            shallow: vec![String::from("7bdc783132575d5b3e78400ace9971970ff43a18")],
+            ..Default::default()
+        });
+        let mut buf = BytesMut::new();
+        mock.write_shallow_info(&mut buf, false);
+        assert_eq!(
+            &buf[..],
+            b"0035shallow 7bdc783132575d5b3e78400ace9971970ff43a18\n0000"
+        );
+
+        let mut buf = BytesMut::new();
+        mock.write_shallow_info(&mut buf, true);
+        assert_eq!(
+            &buf[..],
+            b"0011shallow-info\n0035shallow 7bdc783132575d5b3e78400ace9971970ff43a18\n"
+        );
+    }
+}
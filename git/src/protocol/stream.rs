@@ -0,0 +1,280 @@
+//!
+//! Streaming of the packfile sent by upload-pack.
+//!
+//! The pack is generated in a background task and handed to the transport through a bounded
+//! channel, multiplexed on side-band 1 when the client asked for `side-band`/`side-band-64k`.
+//! Progress messages go to side-band 2 and errors to side-band 3, so the gateway never holds
+//! more than a few packets of the pack in memory.
+//!
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::sync::mpsc;
+
+use crate::errors::GitError;
+use crate::protocol::pack::PKT_LINE_END_MARKER;
+use crate::protocol::{Capability, PackProtocol, SideBind};
+
+/// The pkt-lines of the pack, ready to be written to the HTTP body or the SSH channel.
+pub type PackStream = mpsc::Receiver<Bytes>;
+
+// number of packets buffered between the pack generation and the transport
+const PACK_STREAM_BUFFER: usize = 16;
+
+// a side-band packet is at most 1000 bytes, or 65520 bytes with side-band-64k, including the
+// 4 bytes length and the band byte
+const SIDE_BAND_MAX_PAYLOAD: usize = 995;
+const SIDE_BAND_64K_MAX_PAYLOAD: usize = 65515;
+
+/// Writes pack data, progress and errors to a `PackStream`.
+pub struct SideBandWriter {
+    tx: mpsc::Sender<Bytes>,
+    // `None` if the client didn't ask for side-band, the pack is then sent as is
+    max_payload: Option<usize>,
+    progress: bool,
+    // whether the bytes are counted in `mega_upload_pack_bytes_total`
+    served: bool,
+}
+
+/// The largest payload of a side-band packet, `None` if the client didn't ask for side-band.
+pub fn side_band_max_payload(capabilities: &[Capability]) -> Option<usize> {
+    if capabilities.contains(&Capability::SideBand64k) {
+        Some(SIDE_BAND_64K_MAX_PAYLOAD)
+    } else if capabilities.contains(&Capability::SideBand) {
+        Some(SIDE_BAND_MAX_PAYLOAD)
+    } else {
+        None
+    }
+}
+
+/// Appends `data` to `buf` on side-band `band`, split in packets of at most `max_payload`.
+pub fn put_side_band(buf: &mut BytesMut, band: SideBind, max_payload: usize, data: &[u8]) {
+    for chunk in data.chunks(max_payload) {
+        buf.put(Bytes::from(format!("{:04x}", chunk.len() + 5)));
+        buf.put_u8(band.value());
+        buf.put(chunk);
+    }
+}
+
+impl SideBandWriter {
+    pub fn new(tx: mpsc::Sender<Bytes>, capabilities: &[Capability]) -> Self {
+        let max_payload = side_band_max_payload(capabilities);
+        SideBandWriter {
+            tx,
+            max_payload,
+            progress: max_payload.is_some() && !capabilities.contains(&Capability::NoProgress),
+            served: true,
+        }
+    }
+
+    /// A writer without side-band, for callers that want the bare pack.
+    pub fn raw(tx: mpsc::Sender<Bytes>) -> Self {
+        SideBandWriter {
+            tx,
+            max_payload: None,
+            progress: false,
+            served: false,
+        }
+    }
+
+    pub async fn data(&self, data: &[u8]) -> Result<(), GitError> {
+        match self.max_payload {
+            Some(max_payload) => {
+                for chunk in data.chunks(max_payload) {
+                    self.send_band(SideBind::PackfileData, chunk).await?;
+                }
+                Ok(())
+            }
+            None => self.send(Bytes::copy_from_slice(data)).await,
+        }
+    }
+
+    /// Sends a progress message, which the client prints to stderr as is.
+    pub async fn progress(&self, message: &str) -> Result<(), GitError> {
+        if self.progress {
+            self.send_band(SideBind::ProgressInfo, message.as_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn error(&self, message: &str) -> Result<(), GitError> {
+        if self.max_payload.is_some() {
+            self.send_band(SideBind::Error, message.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Ends the side-band stream with a flush-pkt.
+    pub async fn flush(&self) -> Result<(), GitError> {
+        if self.max_payload.is_some() {
+            self.send(Bytes::from_static(PKT_LINE_END_MARKER)).await?;
+        }
+        Ok(())
+    }
+
+    // only called with side-band, a payload longer than a packet is split
+    async fn send_band(&self, band: SideBind, payload: &[u8]) -> Result<(), GitError> {
+        let max_payload = self.max_payload.unwrap_or(SIDE_BAND_MAX_PAYLOAD);
+        let mut buf = BytesMut::with_capacity(payload.len() + 5);
+        put_side_band(&mut buf, band, max_payload, payload);
+        self.send(buf.freeze()).await
+    }
+
+    async fn send(&self, bytes: Bytes) -> Result<(), GitError> {
+        if self.served {
+            metrics::counter!("mega_upload_pack_bytes_total").increment(bytes.len() as u64);
+        }
+        self.tx
+            .send(bytes)
+            .await
+            .map_err(|_| GitError::SendPackError(String::from("the receiver was closed")))
+    }
+}
+
+/// Progress lines in the format printed by `git pack-objects`, only sent when the percentage
+/// changes.
+pub struct Progress {
+    title: &'static str,
+    total: usize,
+    done: usize,
+    percent: usize,
+}
+
+impl Progress {
+    pub fn new(title: &'static str, total: usize) -> Self {
+        Progress {
+            title,
+            total,
+            done: 0,
+            percent: 0,
+        }
+    }
+
+    pub async fn inc(&mut self, writer: &SideBandWriter) -> Result<(), GitError> {
+        self.inc_by(1, writer).await
+    }
+
+    pub async fn inc_by(&mut self, n: usize, writer: &SideBandWriter) -> Result<(), GitError> {
+        self.done += n;
+        let percent = self.done * 100 / self.total.max(1);
+        if percent != self.percent && self.done != self.total {
+            self.percent = percent;
+            writer
+                .progress(&format!(
+                    "{}: {:3}% ({}/{})\r",
+                    self.title, percent, self.done, self.total
+                ))
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(&self, writer: &SideBandWriter) -> Result<(), GitError> {
+        writer
+            .progress(&format!(
+                "{}: 100% ({}/{}), done.\n",
+                self.title, self.done, self.total
+            ))
+            .await
+    }
+}
+
+impl PackProtocol {
+    /// # Starts sending the packfile negotiated so far.
+    ///
+    /// Counting the objects, loading and deflating them all happen in a spawned task, the pack
+    /// is read from the returned stream and ends with a flush-pkt when side-band is used. If
+    /// the pack generation fails, the error is reported to the client on side-band 3.
+    pub fn spawn_pack_stream(&self) -> PackStream {
+        let (tx, rx) = mpsc::channel(PACK_STREAM_BUFFER);
+        let protocol = self.clone();
+        tokio::spawn(async move {
+            let writer = SideBandWriter::new(tx, &protocol.capabilities);
+            if let Err(err) = protocol.stream_pack(&writer).await {
+                tracing::error!("failed to send pack: {}", err);
+                let _ = writer.error(&format!("{}\n", err)).await;
+            }
+        });
+        rx
+    }
+
+    async fn stream_pack(&self, writer: &SideBandWriter) -> Result<(), GitError> {
+        let objects = if self.negotiation.common.is_empty() {
+            self.collect_full_pack_objects(&self.path).await?
+        } else {
+            self.collect_incremental_pack_objects(&self.negotiation.want, &self.negotiation.common)
+                .await?
+        };
+        writer
+            .progress(&format!("Counting objects: {}, done.\n", objects.len()))
+            .await?;
+        self.write_pack(objects, writer).await?;
+        writer.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::sync::mpsc;
+    use tokio_test::block_on;
+
+    use crate::protocol::stream::SideBandWriter;
+    use crate::protocol::Capability;
+
+    fn collect(mut rx: mpsc::Receiver<Bytes>) -> Vec<u8> {
+        let mut data = vec![];
+        while let Ok(bytes) = rx.try_recv() {
+            data.extend(bytes);
+        }
+        data
+    }
+
+    #[test]
+    fn test_side_band_writer() {
+        let (tx, rx) = mpsc::channel(16);
+        let writer = SideBandWriter::new(tx, &[Capability::SideBand]);
+        block_on(async {
+            writer
+                .progress("Counting objects: 1, done.\n")
+                .await
+                .unwrap();
+            writer.data(&[b'P'; 1000]).await.unwrap();
+            writer.flush().await.unwrap();
+        });
+        drop(writer);
+        let data = collect(rx);
+        assert!(data.starts_with(b"0020\x02Counting objects: 1, done.\n03e8\x01"));
+        assert!(data.ends_with(b"000a\x01PPPPP0000"));
+    }
+
+    #[test]
+    fn test_side_band_writer_no_progress() {
+        let (tx, rx) = mpsc::channel(16);
+        let writer = SideBandWriter::new(tx, &[Capability::SideBand64k, Capability::NoProgress]);
+        block_on(async {
+            writer
+                .progress("Counting objects: 1, done.\n")
+                .await
+                .unwrap();
+            writer.data(b"PACK").await.unwrap();
+        });
+        drop(writer);
+        assert_eq!(collect(rx), b"0009\x01PACK");
+    }
+
+    #[test]
+    fn test_raw_writer() {
+        let (tx, rx) = mpsc::channel(16);
+        let writer = SideBandWriter::raw(tx);
+        block_on(async {
+            writer
+                .progress("Counting objects: 1, done.\n")
+                .await
+                .unwrap();
+            writer.data(b"PACK").await.unwrap();
+            writer.flush().await.unwrap();
+        });
+        drop(writer);
+        assert_eq!(collect(rx), b"PACK");
+    }
+}
@@ -0,0 +1,376 @@
+//!
+//! Git wire protocol version 2, see https://git-scm.com/docs/protocol-v2
+//!
+//! In version 2 the server first advertises its capabilities, and the client then issues
+//! commands (`ls-refs`, `fetch`, `object-info`), each one in its own request. Only upload-pack
+//! speaks version 2, receive-pack always uses the original protocol.
+//!
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+
+use common::utils::ZERO_ID;
+
+use crate::errors::GitError;
+use crate::internal::object::tag::Tag;
+use crate::protocol::pack::{
+    add_pkt_line_string, read_pkt_line, PKT_LINE_DELIM_MARKER, PKT_LINE_END_MARKER,
+};
+use crate::protocol::stream::PackStream;
+use crate::protocol::{Capability, PackProtocol};
+
+const LF: char = '\n';
+
+const AGENT: &str = "agent=mega/0.0.1";
+
+// Commands (and their features) supported by this server, sent after the `version 2` line.
+const V2_CAP_LIST: [&str; 5] = [
+    "ls-refs",
+    "fetch=shallow filter",
+    "server-option",
+    "object-format=sha1",
+    "object-info",
+];
+
+/// A single protocol v2 command request: `command=<name>`, the capability lines, and the
+/// arguments that follow the delimiter packet.
+#[derive(Debug, Default, PartialEq)]
+pub struct CommandRequest {
+    pub command: String,
+    pub capabilities: Vec<String>,
+    pub args: Vec<String>,
+}
+
+impl CommandRequest {
+    /// Reads one command request from `bytes`, stopping at the terminating flush packet.
+    /// Returns `None` if the request does not contain a `command=` line.
+    pub fn parse(bytes: &mut Bytes) -> Option<Self> {
+        let mut request = CommandRequest::default();
+        let mut in_args = false;
+        while !bytes.is_empty() {
+            let (pkt_length, pkt_line) = read_pkt_line(bytes);
+            match pkt_length {
+                0 => break,
+                1 => in_args = true,
+                2 | 3 => break,
+                _ => {
+                    let line = String::from_utf8_lossy(&pkt_line)
+                        .trim_end_matches(LF)
+                        .to_owned();
+                    if in_args {
+                        request.args.push(line);
+                    } else if let Some(command) = line.strip_prefix("command=") {
+                        request.command = command.to_owned();
+                    } else {
+                        request.capabilities.push(line);
+                    }
+                }
+            }
+        }
+        if request.command.is_empty() {
+            None
+        } else {
+            Some(request)
+        }
+    }
+}
+
+impl PackProtocol {
+    /// # Builds the protocol v2 capability advertisement.
+    ///
+    /// Sent in place of the ref advertisement when the client asked for `version=2`, refs are
+    /// only listed later on demand through the `ls-refs` command.
+    pub fn git_capability_advertisement(&self) -> BytesMut {
+        let mut cap_list = vec![format!("version 2{}", LF), format!("{}{}", AGENT, LF)];
+        for cap in V2_CAP_LIST {
+            cap_list.push(format!("{}{}", cap, LF));
+        }
+        let pkt_line_stream = self.build_smart_reply(&cap_list, self.service_type.to_string());
+        tracing::debug!(
+            "git_capability_advertisement response: {:?}",
+            pkt_line_stream
+        );
+        pkt_line_stream
+    }
+
+    /// # Handles a protocol v2 command request.
+    ///
+    /// Returns the pack stream (only if a `fetch` produced a packfile) and the pkt-lines to send
+    /// before it. Like the version 0 `git_upload_pack`, the pack stream is terminated by a
+    /// flush packet, and so are the responses without a packfile.
+    pub async fn git_command_v2(
+        &mut self,
+        request_bytes: &mut Bytes,
+    ) -> Result<(Option<PackStream>, BytesMut)> {
+        let request = match CommandRequest::parse(request_bytes) {
+            Some(request) => request,
+            None => return Ok((None, BytesMut::new())),
+        };
+        tracing::info!("protocol v2 request: {:?}", request);
+        match request.command.as_str() {
+            "ls-refs" => Ok((None, self.ls_refs(&request.args).await?)),
+            "fetch" => self.fetch(&request.args).await,
+            "object-info" => Ok((None, self.object_info(&request.args).await?)),
+            other => Err(anyhow::anyhow!(
+                "unsupported protocol v2 command: {}",
+                other
+            )),
+        }
+    }
+
+    /// # Lists the refs of the repository.
+    ///
+    /// Supports the `symrefs`, `peel` and `ref-prefix` arguments. Prefixes are pushed down to
+    /// the storage query, so clients that only care about a few refs don't pay for listing
+    /// every ref of the monorepo path.
+    pub async fn ls_refs(&self, args: &[String]) -> Result<BytesMut, GitError> {
+        let mut symrefs = false;
+        let mut peel = false;
+        let mut prefixes = vec![];
+        for arg in args {
+            match arg.as_str() {
+                "symrefs" => symrefs = true,
+                "peel" => peel = true,
+                _ => {
+                    if let Some(prefix) = arg.strip_prefix("ref-prefix ") {
+                        prefixes.push(prefix.to_owned());
+                    }
+                }
+            }
+        }
+        let matches_prefix =
+            |name: &str| prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p));
+
+        // generates the ref of a monorepo sub-directory if it doesn't exist yet
+        let head_id = self.get_head_object_id(&self.path).await?;
+        let path_str = self.path.to_str().unwrap();
+        let git_refs = if prefixes.is_empty() {
+            self.storage.get_all_refs_by_path(path_str).await?
+        } else {
+            self.storage
+                .get_refs_by_prefixes(path_str, prefixes.clone())
+                .await?
+        };
+
+        let mut buf = BytesMut::new();
+        if head_id != ZERO_ID && matches_prefix("HEAD") {
+            let mut pkt_line = format!("{} HEAD", head_id);
+            if symrefs {
+                let head_branch = self
+                    .storage
+                    .get_all_refs_by_path(path_str)
+                    .await?
+                    .into_iter()
+                    .find(|r| r.ref_git_id == head_id && r.ref_name.starts_with("refs/heads/"));
+                if let Some(branch) = head_branch {
+                    pkt_line.push_str(&format!(" symref-target:{}", branch.ref_name));
+                }
+            }
+            add_pkt_line_string(&mut buf, format!("{}{}", pkt_line, LF));
+        }
+
+        let peeled: Vec<Tag> = if peel {
+            let tag_ids = git_refs
+                .iter()
+                .filter(|r| r.ref_name.starts_with("refs/tags/"))
+                .map(|r| r.ref_git_id.clone())
+                .collect();
+            self.storage
+                .get_obj_data_by_ids(tag_ids)
+                .await?
+                .into_iter()
+                .filter(|o| o.object_type == "tag")
+                .map(|o| o.into())
+                .collect()
+        } else {
+            vec![]
+        };
+
+        for git_ref in git_refs {
+            let mut pkt_line = format!("{} {}", git_ref.ref_git_id, git_ref.ref_name);
+            if let Some(tag) = peeled
+                .iter()
+                .find(|t| t.id.to_plain_str() == git_ref.ref_git_id)
+            {
+                pkt_line.push_str(&format!(" peeled:{}", tag.object_hash.to_plain_str()));
+            }
+            add_pkt_line_string(&mut buf, format!("{}{}", pkt_line, LF));
+        }
+        buf.put(&PKT_LINE_END_MARKER[..]);
+        Ok(buf)
+    }
+
+    /// # Negotiates and sends a packfile.
+    ///
+    /// Without `done`, the server answers with an `acknowledgments` section and, once every want
+    /// reaches a common commit, goes straight on with the packfile after `ready`. A shallow or
+    /// deepening client gets a `shallow-info` section before the packfile. The packfile section
+    /// is always multiplexed on side-band 1 in version 2.
+    pub async fn fetch(&mut self, args: &[String]) -> Result<(Option<PackStream>, BytesMut)> {
+        let mut have = vec![];
+        let mut done = false;
+        for arg in args {
+            if let Some(id) = arg.strip_prefix("want ") {
+                self.negotiation.want.insert(id.to_owned());
+            } else if let Some(id) = arg.strip_prefix("have ") {
+                have.push(id.to_owned());
+            } else if arg == "done" {
+                done = true;
+            } else if let Some(spec) = arg.strip_prefix("filter ") {
+                self.filter = Some(spec.parse()?);
+            } else if self.shallow.parse_line(arg) {
+                // shallow, deepen, deepen-since and deepen-not arguments
+            } else if let Ok(cap) = arg.parse::<Capability>() {
+                self.capabilities.push(cap);
+            }
+        }
+        self.capabilities.push(Capability::SideBand64k);
+
+        let mut acks = vec![];
+        for hash in have {
+            if let Ok(Some(_)) = self.storage.get_commit_by_hash(&hash).await {
+                self.negotiation.common.insert(hash.clone());
+                acks.push(hash);
+            }
+        }
+
+        let mut buf = BytesMut::new();
+        if !done {
+            add_pkt_line_string(&mut buf, format!("acknowledgments{}", LF));
+            if acks.is_empty() {
+                add_pkt_line_string(&mut buf, format!("NAK{}", LF));
+            }
+            for hash in &acks {
+                add_pkt_line_string(&mut buf, format!("ACK {}{}", hash, LF));
+            }
+            if !self.ok_to_give_up().await? {
+                // keep negotiating, the client will send more haves or give up with done
+                buf.put(&PKT_LINE_END_MARKER[..]);
+                return Ok((None, buf));
+            }
+            add_pkt_line_string(&mut buf, format!("ready{}", LF));
+            buf.put(&PKT_LINE_DELIM_MARKER[..]);
+        }
+        if self.shallow.is_requested() {
+            self.compute_shallow_boundary().await?;
+            self.write_shallow_info(&mut buf, true);
+            buf.put(&PKT_LINE_DELIM_MARKER[..]);
+        }
+        add_pkt_line_string(&mut buf, format!("packfile{}", LF));
+
+        Ok((Some(self.spawn_pack_stream()), buf))
+    }
+
+    /// # Reports the size of the requested objects.
+    ///
+    /// The sizes are the stored ones, the objects aren't read. The objects that aren't found
+    /// are left out of the response.
+    pub async fn object_info(&self, args: &[String]) -> Result<BytesMut, GitError> {
+        let size = args.iter().any(|arg| arg == "size");
+        let oids: Vec<String> = args
+            .iter()
+            .filter_map(|arg| arg.strip_prefix("oid "))
+            .map(|oid| oid.to_owned())
+            .collect();
+        let sizes = self.storage.get_obj_sizes(oids.clone()).await?;
+
+        let mut buf = BytesMut::new();
+        if size {
+            add_pkt_line_string(&mut buf, format!("size{}", LF));
+        }
+        for oid in oids {
+            if let Some(obj_size) = sizes.get(&oid) {
+                let mut pkt_line = oid.clone();
+                if size {
+                    pkt_line.push_str(&format!(" {}", obj_size));
+                }
+                add_pkt_line_string(&mut buf, format!("{}{}", pkt_line, LF));
+            }
+        }
+        buf.put(&PKT_LINE_END_MARKER[..]);
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use sea_orm::Set;
+    use tokio_test::block_on;
+
+    use entity::objects;
+    use storage::driver::database::sqlite_storage::SqliteStorage;
+    use storage::driver::database::storage::ObjectStorage;
+
+    use crate::protocol::v2::CommandRequest;
+    use crate::protocol::{PackProtocol, ProtocolVersion, ServiceType};
+
+    #[test]
+    fn test_parse_command_request() {
+        let mut bytes = Bytes::from_static(
+            b"0014command=ls-refs\n0015agent=git/2.43.0\n00010009peel\n000csymrefs\n001bref-prefix refs/heads/\n0000",
+        );
+        let request = CommandRequest::parse(&mut bytes).unwrap();
+        assert_eq!(
+            request,
+            CommandRequest {
+                command: String::from("ls-refs"),
+                capabilities: vec![String::from("agent=git/2.43.0")],
+                args: vec![
+                    String::from("peel"),
+                    String::from("symrefs"),
+                    String::from("ref-prefix refs/heads/"),
+                ],
+            }
+        );
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_parse_command_request_without_command() {
+        let mut bytes = Bytes::from_static(b"0000");
+        assert_eq!(CommandRequest::parse(&mut bytes), None);
+    }
+
+    #[test]
+    fn test_capability_advertisement() {
+        let mut mock = PackProtocol::mock();
+        mock.version = ProtocolVersion::V2;
+        mock.service_type = ServiceType::UploadPack;
+        let pkt_line_stream = mock.git_capability_advertisement();
+        assert_eq!(&pkt_line_stream[..], b"001e# service=git-upload-pack\n0000000eversion 2\n0015agent=mega/0.0.1\n000cls-refs\n0019fetch=shallow filter\n0012server-option\n0017object-format=sha1\n0010object-info\n0000");
+    }
+
+    #[test]
+    fn test_object_info() {
+        // the in-memory database is dropped with the runtime of its connection
+        block_on(async {
+            let storage = Arc::new(SqliteStorage::memory().await.unwrap());
+            let git_id = "5e1c309dae7f45e0f39b1bf3ac3cd9db12e7d689";
+            let model = objects::ActiveModel {
+                id: Set(1),
+                git_id: Set(git_id.to_owned()),
+                object_type: Set(String::from("blob")),
+                data: Set(b"Hello World".to_vec()),
+                link: Set(None),
+            };
+            storage.save_obj_data(None, vec![model]).await.unwrap();
+
+            let mut mock = PackProtocol::mock();
+            mock.storage = storage;
+            let unknown = "0000000000000000000000000000000000000001";
+            let args = vec![
+                String::from("size"),
+                format!("oid {}", unknown),
+                format!("oid {}", git_id),
+            ];
+            let buf = mock.object_info(&args).await.unwrap();
+            assert_eq!(
+                &buf[..],
+                format!("0009size\n0030{} 11\n0000", git_id).as_bytes()
+            );
+        });
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::path::{Component, Components, Path, PathBuf};
 use std::{collections::HashSet, sync::Arc};
 
@@ -7,21 +7,119 @@ use async_recursion::async_recursion;
 use itertools::Itertools;
 use sea_orm::ActiveValue::NotSet;
 use sea_orm::{DbErr, Set, TransactionTrait};
+use tokio::sync::mpsc;
 
+use common::config::ObjStore;
+use common::errors::{MegaError, StorageError};
 use common::utils::ZERO_ID;
 use entity::{objects, refs, repo_directory};
 use storage::driver::database::storage::ObjectStorage;
+use storage::driver::pack_store::{self, object_count, parse_header, EntryKind, PackStore};
 
 use crate::errors::GitError;
 use crate::hash::Hash;
 use crate::internal::object::blob::Blob;
 use crate::internal::object::commit::Commit;
-use crate::internal::object::tag::Tag;
-use crate::internal::object::tree::Tree;
-use crate::internal::object::ObjectT;
-use crate::internal::pack::encode::pack_encode;
-use crate::protocol::PackProtocol;
+use crate::internal::object::tree::{Tree, TreeItemMode};
+use crate::internal::object::{from_model, ObjectT};
+use crate::internal::pack::encode::{DeltaOptions, PackWriter};
+use crate::internal::ObjectType;
+use crate::protocol::filter::TreeFilter;
+use crate::protocol::stream::{Progress, SideBandWriter};
+use crate::protocol::{Capability, PackProtocol};
 use crate::structure::nodes::NodeBuilder;
+use crate::structure::subrepo::new_subrepo_commit;
+
+// number of objects loaded from storage at once while a pack is written
+const PACK_BATCH_SIZE: usize = 100;
+
+/// The objects of a pack, collected by id only. Their content is loaded in batches while the
+/// pack is written, so serving a large clone doesn't need the whole repository in memory.
+#[derive(Debug, Default)]
+pub struct PackObjects {
+    pub commits: Vec<String>,
+    pub tags: Vec<String>,
+    pub trees: Vec<String>,
+    pub blobs: Vec<String>,
+    seen: HashSet<Hash>,
+    // hash of the path of the trees and blobs, to write objects of the same path next to
+    // each other so that they deltify against each other
+    name_hashes: HashMap<String, u32>,
+    // the sub-directories left out because the user can't read them, their parent trees
+    // still point to them
+    denied_dirs: BTreeSet<PathBuf>,
+}
+
+impl PackObjects {
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    pub fn contains(&self, id: &Hash) -> bool {
+        self.seen.contains(id)
+    }
+
+    /// Adds an object to the pack, returns false if it is already there.
+    pub fn insert(&mut self, id: Hash, object_type: ObjectType) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        let ids = match object_type {
+            ObjectType::Commit => &mut self.commits,
+            ObjectType::Tag => &mut self.tags,
+            ObjectType::Tree => &mut self.trees,
+            _ => &mut self.blobs,
+        };
+        ids.push(id.to_plain_str());
+        true
+    }
+
+    /// Adds a tree or blob found at `path` in the tree walk.
+    pub fn insert_with_path(&mut self, id: Hash, object_type: ObjectType, path: &Path) -> bool {
+        if !self.insert(id, object_type) {
+            return false;
+        }
+        self.name_hashes
+            .insert(id.to_plain_str(), pack_name_hash(path));
+        true
+    }
+
+    /// Removes objects from the pack, once they are sent by other means.
+    pub fn remove(&mut self, ids: &HashSet<String>) {
+        for id in ids {
+            self.seen.remove(&Hash::new_from_str(id));
+        }
+        for list in [
+            &mut self.commits,
+            &mut self.tags,
+            &mut self.trees,
+            &mut self.blobs,
+        ] {
+            list.retain(|id| !ids.contains(id));
+        }
+    }
+
+    fn name_hash(&self, id: &str) -> u32 {
+        self.name_hashes.get(id).copied().unwrap_or_default()
+    }
+}
+
+// same as `pack_name_hash` of git, sorts by the last characters of the path so that files
+// with the same name or extension are close to each other
+fn pack_name_hash(path: &Path) -> u32 {
+    let mut hash: u32 = 0;
+    for c in path.to_string_lossy().bytes() {
+        if c.is_ascii_whitespace() {
+            continue;
+        }
+        hash = (hash >> 2).wrapping_add((c as u32) << 24);
+    }
+    hash
+}
 
 impl PackProtocol {
     /// Asynchronously retrieves the full pack data for the specified repository path.
@@ -36,167 +134,503 @@ impl PackProtocol {
     /// * `Result<Vec<u8>, GitError>` - The packed binary data as a vector of bytes.
     ///
     pub async fn get_full_pack_data(&self, repo_path: &Path) -> Result<Vec<u8>, GitError> {
-        // container for reserve all commit,blob and tree objs
-        let mut hash_meta: HashMap<Hash, Arc<dyn ObjectT>> = HashMap::new();
+        let objects = self.collect_full_pack_objects(repo_path).await?;
+        self.encode_pack(objects).await
+    }
+
+    /// Asynchronously retrieves the pack data for a fetch, containing the commits reachable
+    /// from `want` but not from `have`, and the trees and blobs of those commits that are not
+    /// already in the trees of the `have` commits.
+    ///
+    /// # Arguments
+    /// * `want` - The commits requested by the client.
+    /// * `have` - The commits both sides have in common.
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>, GitError>` - The packed binary data as a vector of bytes.
+    ///
+    pub async fn get_incremental_pack_data(
+        &self,
+        _repo_path: &Path,
+        want: &HashSet<String>,
+        have: &HashSet<String>,
+    ) -> Result<Vec<u8>, GitError> {
+        let objects = self.collect_incremental_pack_objects(want, have).await?;
+        self.encode_pack(objects).await
+    }
+
+    /// Collects the objects of a clone of `repo_path`. For a shallow clone only the commits
+    /// inside the shallow boundary are collected, and the object filter of a partial clone is
+    /// applied to the trees.
+    pub async fn collect_full_pack_objects(
+        &self,
+        repo_path: &Path,
+    ) -> Result<PackObjects, GitError> {
+        let filter = self.tree_filter().await?;
+        if let Some(objects) = self.collect_want_objects(&filter).await? {
+            return Ok(objects);
+        }
+        let mut objects = PackObjects::default();
         let all_commits: Vec<Commit> = self
             .storage
             .get_all_commits_by_path(repo_path.to_str().unwrap())
-            .await
-            .unwrap()
+            .await?
             .into_iter()
             .map(|m| m.into())
+            .filter(|c: &Commit| match &self.shallow.boundary {
+                Some(boundary) => boundary.commits.contains(&c.id.to_plain_str()),
+                None => true,
+            })
             .collect();
-        let all_tree_ids = all_commits
-            .iter()
-            .map(|c| c.tree_id.to_plain_str())
-            .collect();
-        let all_trees: HashMap<String, objects::Model> = self
-            .storage
-            .get_obj_data_by_ids(all_tree_ids)
-            .await
-            .unwrap()
-            .into_iter()
-            .map(|m| (m.git_id.clone(), m))
-            .collect();
-        for c in all_commits {
-            self.traverse_want_trees(
-                all_trees.get(&c.tree_id.to_plain_str()).unwrap(),
-                &mut hash_meta,
-                &HashSet::new(),
-            )
-            .await;
-            hash_meta.insert(c.id, Arc::new(c));
-        }
+        self.collect_commits(all_commits, &mut objects, &HashSet::new(), &filter)
+            .await?;
+        self.check_denied_dirs(&objects)?;
 
         let tag_ids = self
             .storage
             .get_all_refs_by_path(repo_path.to_str().unwrap())
-            .await
-            .unwrap()
+            .await?
             .into_iter()
             .map(|r| r.ref_git_id)
             .collect_vec();
-        self.get_all_tags(tag_ids, &mut hash_meta).await;
-
-        let meta_vec: Vec<Arc<dyn ObjectT>> = hash_meta.into_values().collect();
-        let result: Vec<u8> = pack_encode(meta_vec).unwrap();
-        Ok(result)
+        self.get_all_tags(tag_ids, &mut objects).await?;
+        Ok(objects)
     }
 
-    pub async fn get_incremental_pack_data(
+    /// Collects the objects of a fetch, see `get_incremental_pack_data`. For a shallow fetch
+    /// the commits come from the shallow boundary instead.
+    pub async fn collect_incremental_pack_objects(
         &self,
-        _repo_path: &Path,
         want: &HashSet<String>,
         have: &HashSet<String>,
-    ) -> Result<Vec<u8>, GitError> {
-        let mut hash_meta: HashMap<Hash, Arc<dyn ObjectT>> = HashMap::new();
+    ) -> Result<PackObjects, GitError> {
+        let mut objects = PackObjects::default();
         let mut have_objs = HashSet::new();
-        let want_commits: Vec<Commit> = self
+        let filter = self.tree_filter().await?;
+
+        let have_commits = self
             .storage
-            .get_commit_by_hashes(want.iter().cloned().collect())
-            .await
-            .unwrap()
+            .get_commit_by_hashes(have.iter().cloned().collect())
+            .await?;
+        for have_c in have_commits {
+            let have_tree = self
+                .storage
+                .get_obj_data_by_id(&have_c.tree)
+                .await?
+                .ok_or_else(|| missing_object(&have_c.tree))?;
+            self.update_have_objs(&have_tree, &mut have_objs).await?;
+        }
+
+        let want_commits = if self.shallow.boundary.is_some() {
+            self.shallow_pack_commits(have).await?
+        } else {
+            self.rev_list(want, have).await?
+        };
+        self.collect_commits(want_commits, &mut objects, &have_objs, &filter)
+            .await?;
+        self.check_denied_dirs(&objects)?;
+        self.get_all_tags(want.iter().map(|x| x.to_owned()).collect(), &mut objects)
+            .await?;
+        Ok(objects)
+    }
+
+    // adds the commits and their trees, the root trees are loaded a batch at a time
+    async fn collect_commits(
+        &self,
+        commits: Vec<Commit>,
+        objects: &mut PackObjects,
+        have_objs: &HashSet<Hash>,
+        filter: &TreeFilter,
+    ) -> Result<(), GitError> {
+        for batch in commits.chunks(PACK_BATCH_SIZE) {
+            let tree_ids = batch.iter().map(|c| c.tree_id.to_plain_str()).collect();
+            let trees: HashMap<String, objects::Model> = self
+                .storage
+                .get_obj_data_by_ids(tree_ids)
+                .await?
+                .into_iter()
+                .map(|m| (m.git_id.clone(), m))
+                .collect();
+            for c in batch {
+                let tree_id = c.tree_id.to_plain_str();
+                self.traverse_want_trees(
+                    trees
+                        .get(&tree_id)
+                        .ok_or_else(|| missing_object(&tree_id))?,
+                    objects,
+                    have_objs,
+                    filter,
+                    0,
+                    PathBuf::new(),
+                )
+                .await?;
+                objects.insert(c.id, ObjectType::Commit);
+            }
+        }
+        Ok(())
+    }
+
+    // git checks that the trees of a pack don't point to missing objects, unless the client
+    // asked for a filter: the pack is then a promisor pack which is allowed to miss some
+    fn check_denied_dirs(&self, objects: &PackObjects) -> Result<(), GitError> {
+        match objects.denied_dirs.first() {
+            Some(dir) if self.filter.is_none() => Err(GitError::PermissionDenied(format!(
+                "{} can't read {}, fetch with --filter to leave it out",
+                self.user.as_deref().unwrap_or("anonymous user"),
+                dir.display()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Collects the objects when the client only wants trees and blobs, which is how a partial
+    /// clone fetches the objects left out by its filter. Returns `None` if any want is not a
+    /// tree or a blob. As advertised by `allow-reachable-sha1-in-want`, the wants have to be
+    /// reachable from the refs of the repository.
+    async fn collect_want_objects(
+        &self,
+        filter: &TreeFilter,
+    ) -> Result<Option<PackObjects>, GitError> {
+        if self.negotiation.want.is_empty() {
+            return Ok(None);
+        }
+        let want_objs: Vec<objects::Model> = self
+            .storage
+            .get_obj_data_by_ids(self.negotiation.want.iter().cloned().collect())
+            .await?
             .into_iter()
-            .map(|m| m.into())
+            .filter(|o| o.object_type == "tree" || o.object_type == "blob")
             .collect();
-        let want_tree_ids = want_commits
-            .iter()
-            .map(|c| c.tree_id.to_plain_str())
-            .collect();
-        let want_trees: HashMap<String, objects::Model> = self
+        if want_objs.len() != self.negotiation.want.len() {
+            return Ok(None);
+        }
+        let (tree_paths, unreachable) = self
+            .reachable_objects(
+                want_objs
+                    .iter()
+                    .map(|o| Hash::new_from_str(&o.git_id))
+                    .collect(),
+            )
+            .await?;
+        if let Some(id) = unreachable.iter().min() {
+            return Err(GitError::PermissionDenied(format!("not our ref {}", id)));
+        }
+        let mut objects = PackObjects::default();
+        for obj in want_objs {
+            if obj.object_type == "tree" {
+                // the sub-directories the user can't read are found from the path of the tree
+                let path = tree_paths[&Hash::new_from_str(&obj.git_id)].clone();
+                self.traverse_want_trees(&obj, &mut objects, &HashSet::new(), filter, 0, path)
+                    .await?;
+            } else {
+                objects.insert(Hash::new_from_str(&obj.git_id), ObjectType::Blob);
+            }
+        }
+        Ok(Some(objects))
+    }
+
+    // leaves the trees and blobs reachable from the refs out of `ids`. Returns the path in the
+    // repo of each reachable tree of `ids` and the unreachable ids.
+    //
+    // A partial clone mostly fetches the objects of the commits it checks out, so the trees of
+    // the tips are walked first, the history is only walked for the ids they don't reach,
+    // newest commits first, until all are found.
+    async fn reachable_objects(
+        &self,
+        mut ids: HashSet<Hash>,
+    ) -> Result<(HashMap<Hash, PathBuf>, HashSet<Hash>), GitError> {
+        let tips: HashSet<String> = self
             .storage
-            .get_obj_data_by_ids(want_tree_ids)
-            .await
-            .unwrap()
+            .get_all_refs_by_path(self.path.to_str().unwrap())
+            .await?
+            .into_iter()
+            .map(|r| r.ref_git_id)
+            .collect();
+        let mut seen = HashSet::new();
+        let mut tree_paths = HashMap::new();
+        let tip_trees = self
+            .load_commits(tips.iter().cloned().collect())
+            .await?
             .into_iter()
-            .map(|m| (m.git_id.clone(), m))
+            .map(|c| c.tree_id)
             .collect();
+        self.walk_reachable(tip_trees, &mut ids, &mut seen, &mut tree_paths)
+            .await?;
+        if ids.is_empty() {
+            return Ok((tree_paths, ids));
+        }
+        let commits = self.rev_list(&tips, &HashSet::new()).await?;
+        for batch in commits.chunks(PACK_BATCH_SIZE) {
+            let trees = batch.iter().map(|c| c.tree_id).collect();
+            self.walk_reachable(trees, &mut ids, &mut seen, &mut tree_paths)
+                .await?;
+            if ids.is_empty() {
+                break;
+            }
+        }
+        Ok((tree_paths, ids))
+    }
 
-        for c in want_commits {
-            let has_parent_c_id: Vec<String> = c
-                .parent_tree_ids
-                .clone()
+    // walks the `roots` trees a level at a time without the sub-directories the user can't
+    // read, until all `ids` are reached, the reached trees of `ids` are moved to `tree_paths`
+    async fn walk_reachable(
+        &self,
+        roots: Vec<Hash>,
+        ids: &mut HashSet<Hash>,
+        seen: &mut HashSet<Hash>,
+        tree_paths: &mut HashMap<Hash, PathBuf>,
+    ) -> Result<(), GitError> {
+        let mut level: Vec<(Hash, PathBuf)> =
+            roots.into_iter().map(|id| (id, PathBuf::new())).collect();
+        while !level.is_empty() && !ids.is_empty() {
+            let paths: HashMap<String, PathBuf> = level
                 .into_iter()
-                .filter(|p_id| have.contains(&p_id.to_plain_str()))
-                .map(|hash| hash.to_plain_str())
+                .filter(|(id, _)| seen.insert(*id))
+                .map(|(id, path)| (id.to_plain_str(), path))
                 .collect();
-            let have_commits = self
+            if paths.is_empty() {
+                break;
+            }
+            let trees = self
                 .storage
-                .get_commit_by_hashes(has_parent_c_id)
-                .await
-                .unwrap();
-
-            for have_c in have_commits {
-                let have_tree = self
-                    .storage
-                    .get_obj_data_by_id(&have_c.tree)
-                    .await
-                    .unwrap()
-                    .unwrap();
-                self.update_have_objs(&have_tree, &mut have_objs).await;
+                .get_obj_data_by_ids(paths.keys().cloned().collect())
+                .await?;
+            level = vec![];
+            for model in trees {
+                let path = &paths[&model.git_id];
+                let id = Hash::new_from_str(&model.git_id);
+                if ids.remove(&id) {
+                    tree_paths.insert(id, path.clone());
+                }
+                for item in Tree::new_from_data(model.data).tree_items {
+                    let item_path = path.join(&item.name);
+                    match item.mode {
+                        TreeItemMode::Tree
+                            if self.denied_dirs.contains(&self.path.join(&item_path)) => {}
+                        TreeItemMode::Tree => level.push((item.id, item_path)),
+                        TreeItemMode::Commit => {}
+                        _ => {
+                            ids.remove(&item.id);
+                        }
+                    }
+                }
             }
-
-            self.traverse_want_trees(
-                want_trees.get(&c.tree_id.to_plain_str()).unwrap(),
-                &mut hash_meta,
-                &have_objs,
-            )
-            .await;
-            hash_meta.insert(c.id, Arc::new(c));
         }
-        self.get_all_tags(want.iter().map(|x| x.to_owned()).collect(), &mut hash_meta)
-            .await;
-
-        let meta_vec: Vec<Arc<dyn ObjectT>> = hash_meta.into_values().collect();
-        let result: Vec<u8> = pack_encode(meta_vec).unwrap();
-        Ok(result)
+        Ok(())
     }
 
     pub async fn get_all_tags(
         &self,
         tag_ids: Vec<String>,
-        hash_meta: &mut HashMap<Hash, Arc<dyn ObjectT>>,
-    ) {
-        let tag_objs: Vec<Tag> = self
+        objects: &mut PackObjects,
+    ) -> Result<(), GitError> {
+        let tag_ids = self
             .storage
             .get_obj_data_by_ids(tag_ids)
-            .await
-            .unwrap()
+            .await?
             .into_iter()
             .filter(|o| o.object_type == "tag")
-            .map(|o| o.into())
-            .collect();
-        for tag in tag_objs {
-            hash_meta.insert(tag.id, Arc::new(tag));
+            .map(|o| Hash::new_from_str(&o.git_id));
+        for tag_id in tag_ids {
+            objects.insert(tag_id, ObjectType::Tag);
         }
+        Ok(())
     }
 
-    pub async fn get_head_object_id(&self, repo_path: &Path) -> String {
-        let path_str = repo_path.to_str().unwrap();
-        let refs_list = self.storage.search_refs(path_str).await.unwrap();
+    /// # Writes the pack of the collected objects.
+    ///
+    /// Objects are loaded from storage in batches and each entry is written as soon as it is
+    /// deflated, with the "Compressing objects" progress on side-band 2. Trees and blobs are
+    /// written grouped by path hash and by decreasing size, and deltified against the previous
+    /// objects of the window.
+    ///
+    /// With the pack store, the received packs whose objects are all wanted are first copied
+    /// as they are, see `take_reusable_packs`.
+    pub async fn write_pack(
+        &self,
+        mut objects: PackObjects,
+        writer: &SideBandWriter,
+    ) -> Result<(), GitError> {
+        let pack_store = match self.storage.get_config().obj_store {
+            ObjStore::Pack => Some(PackStore::init(self.storage.get_config()).await),
+            ObjStore::Database => None,
+        };
+        let reused = match &pack_store {
+            Some(pack_store) => self.take_reusable_packs(pack_store, &mut objects).await?,
+            None => vec![],
+        };
+        let total = objects.len() + reused.iter().map(|(_, count)| count).sum::<usize>();
+        let (mut pack_writer, header) = PackWriter::with_delta(
+            total,
+            DeltaOptions::from_config(&self.pack_config),
+            self.capabilities.contains(&Capability::OfsDelta),
+        );
+        writer.data(&header).await?;
+        let mut progress = Progress::new("Compressing objects", total);
 
-        if refs_list.is_empty() {
-            ZERO_ID.to_string()
-        } else {
-            for refs in &refs_list {
-                if repo_path.to_str().unwrap() == refs.repo_path {
-                    return refs.ref_git_id.clone();
+        if let Some(pack_store) = &pack_store {
+            for (pack_id, count) in reused {
+                let pack = pack_store.get_pack(&pack_id).await?;
+                let entries = pack_store::entries(&pack);
+                pack_writer
+                    .write_raw(count, &entries)
+                    .map_err(|e| GitError::EncodeObjectError(e.to_string()))?;
+                writer.data(&entries).await?;
+                progress.inc_by(count, writer).await?;
+            }
+        }
+
+        for batch in objects.commits.chunks(PACK_BATCH_SIZE) {
+            let commits = self.storage.get_commit_by_hashes(batch.to_vec()).await?;
+            for model in commits.into_iter().unique_by(|m| m.git_id.clone()) {
+                let commit: Commit = model.into();
+                let entry = pack_writer
+                    .write_object(Arc::new(commit))
+                    .map_err(|e| GitError::EncodeObjectError(e.to_string()))?;
+                writer.data(&entry).await?;
+                progress.inc(writer).await?;
+            }
+        }
+        let mut trees = std::mem::take(&mut objects.trees);
+        let mut blobs = std::mem::take(&mut objects.blobs);
+        trees.sort_by_cached_key(|id| objects.name_hash(id));
+        blobs.sort_by_cached_key(|id| objects.name_hash(id));
+        for ids in [&objects.tags, &trees, &blobs] {
+            for batch in ids.chunks(PACK_BATCH_SIZE) {
+                let mut models = self.storage.get_obj_data_by_ids(batch.to_vec()).await?;
+                models.sort_by_key(|m| {
+                    (
+                        objects.name_hash(&m.git_id),
+                        std::cmp::Reverse(m.data.len()),
+                    )
+                });
+                for model in models.into_iter().unique_by(|m| m.git_id.clone()) {
+                    let entry = pack_writer
+                        .write_object(from_model(model))
+                        .map_err(|e| GitError::EncodeObjectError(e.to_string()))?;
+                    writer.data(&entry).await?;
+                    progress.inc(writer).await?;
                 }
             }
-            for refs in &refs_list {
-                // if repo_path is subdirectory of some commit, we should generae a fake commit
-                if repo_path.starts_with(refs.repo_path.clone()) {
-                    return self.generate_subdir_commit(refs, repo_path).await;
+        }
+        progress.finish(writer).await?;
+        let checksum = pack_writer
+            .finish()
+            .map_err(|e| GitError::UnCompletedPackObject(e.to_string()))?;
+        writer.data(&checksum).await
+    }
+
+    /// # Takes out of `objects` the packs of the pack store that can be sent as they are.
+    ///
+    /// A pack is reused if all its objects are wanted and none is in another reused pack, the
+    /// bases of its `REF_DELTA` entries are sent too, and it has no `OFS_DELTA` entry if the
+    /// client doesn't support `ofs-delta`. Returns the packs with their number of objects.
+    async fn take_reusable_packs(
+        &self,
+        pack_store: &PackStore,
+        objects: &mut PackObjects,
+    ) -> Result<Vec<(String, usize)>, GitError> {
+        let wanted: HashSet<String> = [
+            &objects.commits,
+            &objects.tags,
+            &objects.trees,
+            &objects.blobs,
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+        let pack_ids: BTreeSet<String> = self
+            .storage
+            .get_pack_index(wanted.iter().cloned().collect())
+            .await?
+            .into_iter()
+            .map(|index| index.pack_id)
+            .collect();
+        let ofs_delta = self.capabilities.contains(&Capability::OfsDelta);
+        let mut taken = HashSet::new();
+        let mut reused = vec![];
+        for pack_id in pack_ids {
+            let idx = pack_store.get_index(&pack_id).await?;
+            let ids: HashSet<String> = idx.entries.iter().map(|e| e.git_id()).collect();
+            if ids
+                .iter()
+                .any(|id| !wanted.contains(id) || taken.contains(id))
+            {
+                continue;
+            }
+            let pack = pack_store.get_pack(&pack_id).await?;
+            // the entries that failed to decode on push are in the pack but not in its index
+            if object_count(&pack) != Some(idx.entries.len() as u32) {
+                continue;
+            }
+            let self_contained = idx.entries.iter().all(|e| {
+                let header = pack
+                    .get(e.offset as usize..)
+                    .and_then(|data| parse_header(data, e.offset));
+                match header {
+                    Some((EntryKind::Object(_), ..)) => true,
+                    Some((EntryKind::OfsDelta(_), ..)) => ofs_delta,
+                    Some((EntryKind::RefDelta(base_id), ..)) => wanted.contains(&base_id),
+                    None => false,
                 }
+            });
+            if self_contained {
+                reused.push((pack_id, ids.len()));
+                taken.extend(ids);
             }
-            //situation: repo_path: root/repotest2/src, commit: root/repotest
-            ZERO_ID.to_string()
         }
+        objects.remove(&taken);
+        Ok(reused)
+    }
+
+    // writes the pack into a buffer, for callers that need the whole pack at once
+    async fn encode_pack(&self, objects: PackObjects) -> Result<Vec<u8>, GitError> {
+        let (tx, mut rx) = mpsc::channel(PACK_BATCH_SIZE);
+        let encode = async move {
+            let writer = SideBandWriter::raw(tx);
+            self.write_pack(objects, &writer).await
+        };
+        let collect = async {
+            let mut data = vec![];
+            while let Some(bytes) = rx.recv().await {
+                data.extend(bytes);
+            }
+            data
+        };
+        let (result, data) = futures::join!(encode, collect);
+        result.map(|_| data)
+    }
+
+    pub async fn get_head_object_id(&self, repo_path: &Path) -> Result<String, GitError> {
+        // a sub-directory of a repo follows the trunk commit of the trunk HEAD
+        let trunk_refs = self.get_trunk_refs(repo_path).await?;
+        if let Some(trunk_ref) = head_ref(&trunk_refs) {
+            if let Some(subrepo_commit) = self
+                .storage
+                .get_subrepo_commit_by_trunk(repo_path.to_str().unwrap(), &trunk_ref.ref_git_id)
+                .await?
+            {
+                return Ok(subrepo_commit.commit_id);
+            }
+            return self.generate_subdir_commit(trunk_ref, repo_path).await;
+        }
+        let refs_list = self
+            .storage
+            .get_all_refs_by_path(repo_path.to_str().unwrap())
+            .await?;
+        Ok(head_ref(&refs_list)
+            .map(|r| r.ref_git_id.clone())
+            .unwrap_or_else(|| ZERO_ID.to_string()))
     }
 
     // get all objects id from have tree
     #[async_recursion]
-    async fn update_have_objs(&self, have_tree: &objects::Model, have_objects: &mut HashSet<Hash>) {
+    async fn update_have_objs(
+        &self,
+        have_tree: &objects::Model,
+        have_objects: &mut HashSet<Hash>,
+    ) -> Result<(), GitError> {
         let mut t = Tree::new_from_data(have_tree.data.clone());
         t.set_hash(Hash::new_from_str(&have_tree.git_id));
 
@@ -206,55 +640,88 @@ impl PackProtocol {
                 search_child_ids.push(item.id.to_plain_str());
             }
         }
-        let objs = self
-            .storage
-            .get_obj_data_by_ids(search_child_ids)
-            .await
-            .unwrap();
+        let objs = self.storage.get_obj_data_by_ids(search_child_ids).await?;
         for obj in objs {
             if obj.object_type == "tree" {
-                self.update_have_objs(&obj, have_objects).await;
+                self.update_have_objs(&obj, have_objects).await?;
             } else {
                 let blob_id = Hash::new_from_str(&obj.git_id.clone());
                 have_objects.insert(blob_id);
             }
         }
         have_objects.insert(t.id);
+        Ok(())
     }
 
-    // retrieve all sub trees recursively
+    // retrieve all sub trees recursively, leaving out the trees and blobs rejected by the filter
     #[async_recursion]
     async fn traverse_want_trees(
         &self,
         want_t: &objects::Model,
-        all_objects: &mut HashMap<Hash, Arc<dyn ObjectT>>,
+        objects: &mut PackObjects,
         have_objs: &HashSet<Hash>,
-    ) {
-        let mut t = Tree::new_from_data(want_t.data.clone());
-        t.set_hash(Hash::new_from_str(&want_t.git_id));
+        filter: &TreeFilter,
+        depth: usize,
+        path: PathBuf,
+    ) -> Result<(), GitError> {
+        if !filter.includes_tree(depth) {
+            return Ok(());
+        }
+        let t = Tree::new_from_data(want_t.data.clone());
+        if !objects.insert_with_path(Hash::new_from_str(&want_t.git_id), ObjectType::Tree, &path) {
+            return Ok(());
+        }
 
         let mut search_child_ids = vec![];
+        let mut child_paths = HashMap::new();
+        let mut blobs = vec![];
         for item in &t.tree_items {
-            if !all_objects.contains_key(&item.id) && !have_objs.contains(&item.id) {
-                search_child_ids.push(item.id.to_plain_str());
+            if objects.contains(&item.id) || have_objs.contains(&item.id) {
+                continue;
+            }
+            let dir = self.path.join(path.join(&item.name));
+            match item.mode {
+                // the sub-directories the user can't read are left out
+                TreeItemMode::Tree if self.denied_dirs.contains(&dir) => {
+                    objects.denied_dirs.insert(dir);
+                }
+                TreeItemMode::Tree => {
+                    search_child_ids.push(item.id.to_plain_str());
+                    child_paths.insert(item.id.to_plain_str(), path.join(&item.name));
+                }
+                // submodules point to commits of another repository
+                TreeItemMode::Commit => {}
+                _ => blobs.push((item.id, path.join(&item.name))),
             }
         }
-        let objs = self
-            .storage
-            .get_obj_data_by_ids(search_child_ids)
-            .await
-            .unwrap();
-        for obj in objs {
-            if obj.object_type == "tree" {
-                self.traverse_want_trees(&obj, all_objects, have_objs).await;
-            } else {
-                let mut blob = Blob::new_from_data(obj.data.clone());
-                let blob_id = Hash::new_from_str(&obj.git_id.clone());
-                blob.set_hash(blob_id);
-                all_objects.insert(blob_id, Arc::new(blob));
+
+        let blob_sizes: HashMap<String, usize> = if filter.needs_blob_size() && !blobs.is_empty() {
+            self.storage
+                .get_obj_data_by_ids(blobs.iter().map(|(id, _)| id.to_plain_str()).collect())
+                .await?
+                .into_iter()
+                .map(|m| (m.git_id, m.data.len()))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        for (blob_id, blob_path) in blobs {
+            let size = blob_sizes
+                .get(&blob_id.to_plain_str())
+                .copied()
+                .unwrap_or_default();
+            if filter.includes_blob(depth + 1, &blob_path, size) {
+                objects.insert_with_path(blob_id, ObjectType::Blob, &blob_path);
             }
         }
-        all_objects.insert(t.id, Arc::new(t));
+
+        let sub_trees = self.storage.get_obj_data_by_ids(search_child_ids).await?;
+        for obj in sub_trees {
+            let child_path = child_paths.remove(&obj.git_id).unwrap_or_default();
+            self.traverse_want_trees(&obj, objects, have_objs, filter, depth + 1, child_path)
+                .await?;
+        }
+        Ok(())
     }
 
     // TODO: Consider the scenario of deleting a repo
@@ -271,8 +738,7 @@ impl PackProtocol {
                     let repo_dir = self
                         .storage
                         .get_directory_by_full_path(current_path.to_str().unwrap())
-                        .await
-                        .unwrap();
+                        .await?;
                     match repo_dir {
                         Some(dir) => {
                             pid = Some(dir.id);
@@ -292,8 +758,7 @@ impl PackProtocol {
                                     created_at: Set(chrono::Utc::now().naive_utc()),
                                     updated_at: Set(chrono::Utc::now().naive_utc()),
                                 })
-                                .await
-                                .unwrap();
+                                .await?;
                             pid = Some(inserted_pid);
                         }
                     }
@@ -303,78 +768,125 @@ impl PackProtocol {
         Ok(())
     }
 
-    /// Generates a new commit for a subdirectory of the original project directory.
+    /// Returns the commit of a subdirectory matching the trunk commit of `refs`, which has no
+    /// subdirectory commit recorded yet: the trunk commit was neither pushed through the
+    /// subdirectory nor already fetched from it.
     /// Steps:
-    /// 1. Retrieve the root commit based on the provided reference's Git ID.
-    /// 2. If a root tree is found by searching for the repository path:
-    ///    a. Construct a child commit using the retrieved root commit and the root tree.
-    ///    b. Save the child commit.
-    ///    c. Obtain the commit ID of the child commit.
-    ///    d. Construct a child reference with the repository path, reference name, commit ID, and other relevant information.
-    ///    e. Save the child reference in the database.
-    /// 3. Return the commit ID of the child commit if successful; otherwise, return a default ID.
-    pub async fn generate_subdir_commit(&self, refs: &refs::Model, repo_path: &Path) -> String {
+    /// 1. Search the subdirectory tree in the trunk commit, there is no commit if it's missing.
+    /// 2. If the subdirectory ref has the same tree, the trunk only changed elsewhere and the
+    ///    ref is kept. Otherwise construct a child commit from the trunk commit with the
+    ///    subdirectory tree, its parent being the current subdirectory commit so that fetching
+    ///    it again is a fast-forward.
+    /// 3. Move the subdirectory ref to the commit, then store the commit and record the pair of
+    ///    commits in the same transaction.
+    pub async fn generate_subdir_commit(
+        &self,
+        refs: &refs::Model,
+        repo_path: &Path,
+    ) -> Result<String, GitError> {
+        let path_str = repo_path.to_str().unwrap();
         let root_commit: Commit = self
             .storage
-            .get_commit_by_hash(&refs.ref_git_id.clone())
-            .await
-            .unwrap()
-            .unwrap()
+            .get_commit_by_hash(&refs.ref_git_id)
+            .await?
+            .ok_or_else(|| missing_object(&refs.ref_git_id))?
             .into();
+        let current_ref = self
+            .storage
+            .get_all_refs_by_path(path_str)
+            .await?
+            .into_iter()
+            .find(|r| r.ref_name == refs.ref_name);
+        let current_id = current_ref
+            .map(|r| r.ref_git_id)
+            .unwrap_or_else(|| ZERO_ID.to_string());
 
-        let relative_path = PathBuf::from(&repo_path.to_str().unwrap()[refs.repo_path.len()..]);
-        let mut comp = relative_path.components();
-        // skip the first root dir
-        comp.next();
-        let t_id = self
-            .search_dir_from_tree(&root_commit.tree_id.to_plain_str(), comp)
-            .await;
-
-        let child_commit =
-            Commit::subdir_commit(root_commit.to_data().unwrap(), Hash::new_from_str(&t_id));
-        let child_c_model = child_commit.convert_to_model(repo_path);
-        self.storage
-            .save_commits(None, vec![child_c_model.clone()])
-            .await
-            .unwrap();
-        let commit_id = child_commit.id.to_plain_str();
-        let child_refs = refs::ActiveModel {
-            id: NotSet,
-            repo_path: Set(repo_path.to_str().unwrap().to_string()),
-            ref_name: Set(refs.ref_name.clone()),
-            ref_git_id: Set(commit_id.clone()),
-            created_at: Set(chrono::Utc::now().naive_utc()),
-            updated_at: Set(chrono::Utc::now().naive_utc()),
+        let relative_path = repo_path.strip_prefix(&refs.repo_path).unwrap();
+        let t_id = match self
+            .search_dir_from_tree(
+                &root_commit.tree_id.to_plain_str(),
+                relative_path.components(),
+            )
+            .await?
+        {
+            Some(t_id) => t_id,
+            None => return Ok(current_id),
         };
-        self.storage.save_refs(vec![child_refs]).await.unwrap();
-        commit_id
+
+        let current_commit: Option<Commit> = self
+            .storage
+            .get_commit_by_hash(&current_id)
+            .await?
+            .map(|c| c.into());
+        let child_commit = match current_commit {
+            Some(current) if current.tree_id.to_plain_str() == t_id => None,
+            current => Some(Commit::subdir_commit(
+                root_commit.to_data()?,
+                Hash::new_from_str(&t_id),
+                current.map(|c| vec![c.id]).unwrap_or_default(),
+            )),
+        };
+        let commit_id = child_commit
+            .as_ref()
+            .map(|c| c.id.to_plain_str())
+            .unwrap_or_else(|| current_id.clone());
+
+        let txn = self.storage.get_connection().begin().await?;
+        let swapped = current_id == commit_id
+            || self
+                .storage
+                .compare_and_swap_ref(&txn, path_str, &refs.ref_name, &current_id, &commit_id)
+                .await?;
+        if swapped {
+            if let Some(child_commit) = child_commit {
+                self.storage
+                    .save_commits(Some(&txn), vec![child_commit.convert_to_model(repo_path)])
+                    .await?;
+            }
+            self.storage
+                .save_subrepo_commits(
+                    &txn,
+                    vec![new_subrepo_commit(
+                        path_str,
+                        &commit_id,
+                        &refs.repo_path,
+                        &refs.ref_git_id,
+                    )],
+                )
+                .await?;
+            txn.commit().await?;
+        } else {
+            // moved by a concurrent push or fetch, the next fetch follows it
+            txn.rollback().await?;
+        }
+        Ok(commit_id)
     }
 
-    // find search_dir's tree id from a provided tree
+    // find search_dir's tree id from a provided tree, None if the directory doesn't exist
     #[async_recursion]
     pub async fn search_dir_from_tree<'a>(
         &self,
         tree_id: &str,
         mut relative_path: Components<'async_recursion>,
-    ) -> String {
+    ) -> Result<Option<String>, GitError> {
         let root_tree: Tree = self
             .storage
             .get_obj_data_by_id(tree_id)
-            .await
-            .unwrap()
-            .unwrap()
+            .await?
+            .ok_or_else(|| missing_object(tree_id))?
             .into();
-        if let Some(Component::Normal(search_dir)) = relative_path.next() {
-            let t_id = root_tree
-                .tree_items
-                .iter()
-                .find(|item| item.name == search_dir.to_str().unwrap())
-                .unwrap()
-                .id
-                .to_plain_str();
-            self.search_dir_from_tree(&t_id, relative_path).await
-        } else {
-            root_tree.id.to_plain_str()
+        match relative_path.next() {
+            Some(Component::Normal(search_dir)) => {
+                let t_id = match root_tree.tree_items.iter().find(|item| {
+                    item.mode == TreeItemMode::Tree && item.name == search_dir.to_str().unwrap()
+                }) {
+                    Some(item) => item.id.to_plain_str(),
+                    None => return Ok(None),
+                };
+                self.search_dir_from_tree(&t_id, relative_path).await
+            }
+            Some(_) => self.search_dir_from_tree(tree_id, relative_path).await,
+            None => Ok(Some(root_tree.id.to_plain_str())),
         }
     }
 }
@@ -384,9 +896,9 @@ pub async fn save_node_from_mr(
     mr_id: i64,
     repo_path: &Path,
 ) -> Result<(), anyhow::Error> {
-    let tree_map: HashMap<Hash, Tree> = get_objects_from_mr(storage.clone(), mr_id, "tree").await;
-    let blob_map: HashMap<Hash, Blob> = get_objects_from_mr(storage.clone(), mr_id, "blob").await;
-    let commits: Vec<Commit> = get_objects_vec_from_mr(storage.clone(), mr_id, "commit").await;
+    let tree_map: HashMap<Hash, Tree> = get_objects_from_mr(storage.clone(), mr_id, "tree").await?;
+    let blob_map: HashMap<Hash, Blob> = get_objects_from_mr(storage.clone(), mr_id, "blob").await?;
+    let commits: Vec<Commit> = get_objects_vec_from_mr(storage.clone(), mr_id, "commit").await?;
     let builder = NodeBuilder {
         storage: storage.clone(),
         tree_map,
@@ -394,13 +906,13 @@ pub async fn save_node_from_mr(
         repo_path: repo_path.to_path_buf(),
         commits,
     };
-    let nodes = builder.build_node_tree().await.unwrap();
+    let nodes = builder.build_node_tree().await?;
     storage
         .get_connection()
         .transaction::<_, (), DbErr>(|txn| {
             Box::pin(async move {
-                builder.save_nodes(Some(txn), nodes).await.unwrap();
-                builder.save_commits(Some(txn)).await.unwrap();
+                builder.save_nodes(Some(txn), nodes).await.map_err(db_err)?;
+                builder.save_commits(Some(txn)).await.map_err(db_err)?;
                 Ok(())
             })
         })
@@ -449,10 +961,7 @@ pub async fn save_node_from_git_obj(
             link: Set(m.link.clone()),
         })
         .collect();
-    storage
-        .save_obj_data(None, git_obj_active_model)
-        .await
-        .unwrap();
+    storage.save_obj_data(None, git_obj_active_model).await?;
 
     let repo = NodeBuilder {
         storage: storage.clone(),
@@ -461,13 +970,13 @@ pub async fn save_node_from_git_obj(
         repo_path: repo_path.to_path_buf(),
         commits: commits.clone(),
     };
-    let nodes = repo.build_node_tree().await.unwrap();
+    let nodes = repo.build_node_tree().await?;
     storage
         .get_connection()
         .transaction::<_, (), DbErr>(|txn| {
             Box::pin(async move {
-                repo.save_nodes(Some(txn), nodes).await.unwrap();
-                repo.save_commits(Some(txn)).await.unwrap();
+                repo.save_nodes(Some(txn), nodes).await.map_err(db_err)?;
+                repo.save_commits(Some(txn)).await.map_err(db_err)?;
                 Ok(())
             })
         })
@@ -492,8 +1001,7 @@ pub async fn save_node_from_git_obj(
 
     let mut refs = storage
         .get_all_refs_by_path(repo_path.to_str().unwrap())
-        .await
-        .unwrap();
+        .await?;
     if refs.is_empty() {
         let child_refs = refs::ActiveModel {
             id: NotSet,
@@ -503,11 +1011,11 @@ pub async fn save_node_from_git_obj(
             created_at: Set(chrono::Utc::now().naive_utc()),
             updated_at: Set(chrono::Utc::now().naive_utc()),
         };
-        storage.save_refs(vec![child_refs]).await.unwrap();
+        storage.save_refs(vec![child_refs]).await?;
     } else if let Some(r) = refs.pop() {
         storage
             .update_refs(r.ref_git_id, commit_id.clone(), repo_path)
-            .await;
+            .await?;
     }
 
     Ok(())
@@ -517,16 +1025,39 @@ pub async fn get_objects_from_mr<T: ObjectT>(
     storage: Arc<dyn ObjectStorage>,
     mr_id: i64,
     object_type: &str,
-) -> HashMap<Hash, T> {
+) -> Result<HashMap<Hash, T>, MegaError> {
     let git_ids = storage
         .get_mr_objects_by_type(mr_id, object_type)
-        .await
-        .unwrap()
+        .await?
         .iter()
         .map(|model| model.git_id.clone())
         .collect();
-    let models = storage.get_obj_data_by_ids(git_ids).await.unwrap();
-    convert_model_to_map(models)
+    let models = storage.get_obj_data_by_ids(git_ids).await?;
+    Ok(convert_model_to_map(models))
+}
+
+// the node tables are written in a sea-orm transaction, which fails with a `DbErr`
+fn db_err(err: MegaError) -> DbErr {
+    DbErr::Custom(err.to_string())
+}
+
+/// The error of an object missing from storage although a stored object refers to it.
+pub fn missing_object(id: &str) -> GitError {
+    GitError::StorageError(StorageError::NotFound(format!("object {}", id)))
+}
+
+/// The branch HEAD points to among the refs of a repo: `master`, else `main`, else the first
+/// branch by name.
+pub fn head_ref(refs_list: &[refs::Model]) -> Option<&refs::Model> {
+    let branch = |name: &str| refs_list.iter().find(|r| r.ref_name == name);
+    branch("refs/heads/master")
+        .or_else(|| branch("refs/heads/main"))
+        .or_else(|| {
+            refs_list
+                .iter()
+                .filter(|r| r.ref_name.starts_with("refs/heads/"))
+                .min_by(|a, b| a.ref_name.cmp(&b.ref_name))
+        })
 }
 
 pub fn convert_model_to_map<T: ObjectT>(models: Vec<objects::Model>) -> HashMap<Hash, T> {
@@ -545,15 +1076,14 @@ pub async fn get_objects_vec_from_mr<T: ObjectT>(
     storage: Arc<dyn ObjectStorage>,
     mr_id: i64,
     object_type: &str,
-) -> Vec<T> {
+) -> Result<Vec<T>, MegaError> {
     let git_ids = storage
         .get_mr_objects_by_type(mr_id, object_type)
-        .await
-        .unwrap()
+        .await?
         .iter()
         .map(|model| model.git_id.clone())
         .collect();
-    let models = storage.get_obj_data_by_ids(git_ids).await.unwrap();
+    let models = storage.get_obj_data_by_ids(git_ids).await?;
     let result = models
         .iter()
         .map(|model| {
@@ -563,5 +1093,264 @@ pub async fn get_objects_vec_from_mr<T: ObjectT>(
             obj
         })
         .collect();
-    result
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use sea_orm::{DatabaseConnection, DatabaseTransaction, Set};
+    use tokio_test::block_on;
+
+    use common::config::StorageConfig;
+    use common::errors::MegaError;
+    use entity::{commit, objects, refs};
+    use storage::driver::database::sqlite_storage::SqliteStorage;
+    use storage::driver::database::storage::ObjectStorage;
+
+    use crate::hash::Hash;
+    use crate::internal::object::commit::Commit;
+    use crate::internal::object::meta::Meta;
+    use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use crate::internal::object::ObjectT;
+    use crate::internal::ObjectType;
+    use crate::protocol::filter::ObjectFilter;
+    use crate::protocol::{PackProtocol, RefCommand};
+
+    fn object(object_type: ObjectType, data: Vec<u8>) -> (Hash, objects::ActiveModel) {
+        let id = Meta::calculate_id(object_type, &data);
+        let model = objects::ActiveModel {
+            id: Set(0),
+            git_id: Set(id.to_plain_str()),
+            object_type: Set(object_type.to_string()),
+            data: Set(data),
+            link: Set(None),
+        };
+        (id, model)
+    }
+
+    fn tree(items: &[(&str, TreeItemMode, Hash)]) -> (Hash, objects::ActiveModel) {
+        let tree = Tree {
+            id: Hash::default(),
+            tree_items: items
+                .iter()
+                .map(|(name, mode, id)| TreeItem::new(*mode, *id, name.to_string()))
+                .collect(),
+        };
+        object(ObjectType::Tree, tree.to_data().unwrap())
+    }
+
+    // counts the objects loaded from a SQLite storage
+    struct CountingStorage {
+        inner: SqliteStorage,
+        loaded: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ObjectStorage for CountingStorage {
+        fn get_connection(&self) -> &DatabaseConnection {
+            self.inner.get_connection()
+        }
+
+        fn get_config(&self) -> &StorageConfig {
+            self.inner.get_config()
+        }
+
+        async fn save_obj_data_to_db(
+            &self,
+            txn: Option<&DatabaseTransaction>,
+            obj_data: Vec<objects::ActiveModel>,
+        ) -> Result<bool, MegaError> {
+            self.inner.save_obj_data_to_db(txn, obj_data).await
+        }
+
+        async fn search_refs(&self, path_str: &str) -> Result<Vec<refs::Model>, MegaError> {
+            self.inner.search_refs(path_str).await
+        }
+
+        async fn search_commits(&self, path_str: &str) -> Result<Vec<commit::Model>, MegaError> {
+            self.inner.search_commits(path_str).await
+        }
+
+        async fn get_commit_by_hashes(
+            &self,
+            hashes: Vec<String>,
+        ) -> Result<Vec<commit::Model>, MegaError> {
+            self.inner.get_commit_by_hashes(hashes).await
+        }
+
+        async fn get_obj_data_by_ids(
+            &self,
+            git_ids: Vec<String>,
+        ) -> Result<Vec<objects::Model>, MegaError> {
+            self.loaded.fetch_add(git_ids.len(), Ordering::SeqCst);
+            self.inner.get_obj_data_by_ids(git_ids).await
+        }
+    }
+
+    // saves the objects and a master branch of /repo with a commit of each of `roots`, the
+    // last one at the tip
+    async fn save_repo(storage: &SqliteStorage, models: Vec<objects::ActiveModel>, roots: &[Hash]) {
+        for (i, mut model) in models.into_iter().enumerate() {
+            model.id = Set(i as i64 + 1);
+            storage
+                .save_obj_data_to_db(None, vec![model])
+                .await
+                .unwrap();
+        }
+        let mut tip: Option<Hash> = None;
+        for (i, root) in roots.iter().enumerate() {
+            let parent: String = tip.iter().map(|p| format!("parent {}\n", p)).collect();
+            let signature = format!("Alice <alice@example.com> {} +0000", 1700000000 + i);
+            let data = format!(
+                "tree {}\n{}author {}\ncommitter {}\n\ncommit {}\n",
+                root, parent, signature, signature, i
+            );
+            let mut commit = Commit::new_from_data(data.into_bytes());
+            commit.id = Meta::calculate_id(ObjectType::Commit, &commit.to_data().unwrap());
+            storage
+                .save_commits(None, vec![commit.convert_to_model(Path::new("/repo"))])
+                .await
+                .unwrap();
+            tip = Some(commit.id);
+        }
+        let master = RefCommand::new(
+            common::utils::ZERO_ID.to_owned(),
+            tip.unwrap().to_plain_str(),
+            String::from("refs/heads/master"),
+        );
+        storage
+            .save_refs(vec![master.convert_to_model("/repo")])
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_want_objects_are_reachable() {
+        block_on(async {
+            let storage = Arc::new(SqliteStorage::memory().await.unwrap());
+            let (file, file_model) = object(ObjectType::Blob, b"file".to_vec());
+            let (a, a_model) = tree(&[("file.txt", TreeItemMode::Blob, file)]);
+            let (secret, secret_model) = tree(&[("key", TreeItemMode::Blob, file)]);
+            let (root, root_model) = tree(&[
+                ("a", TreeItemMode::Tree, a),
+                ("secret", TreeItemMode::Tree, secret),
+            ]);
+            let (orphan, orphan_model) = tree(&[("orphan.txt", TreeItemMode::Blob, file)]);
+            let models = vec![file_model, a_model, secret_model, root_model, orphan_model];
+            save_repo(&storage, models, &[root]).await;
+
+            let mut mock = PackProtocol::mock();
+            mock.storage = storage.clone();
+            mock.path = PathBuf::from("/repo");
+            mock.denied_dirs = vec![PathBuf::from("/repo/secret")];
+            for (want, reachable) in [(a, true), (file, true), (orphan, false), (secret, false)] {
+                mock.negotiation.want = HashSet::from([want.to_plain_str()]);
+                let objects = mock.collect_full_pack_objects(&mock.path).await;
+                assert_eq!(objects.is_ok(), reachable, "{}", want);
+                if reachable {
+                    assert!(objects.unwrap().contains(&want));
+                }
+            }
+        });
+    }
+    #[test]
+    fn test_want_parent_of_denied_dir() {
+        block_on(async {
+            let storage = Arc::new(SqliteStorage::memory().await.unwrap());
+            let (file, file_model) = object(ObjectType::Blob, b"file".to_vec());
+            let (key, key_model) = object(ObjectType::Blob, b"key".to_vec());
+            let (secret, secret_model) = tree(&[("key", TreeItemMode::Blob, key)]);
+            let (a, a_model) = tree(&[
+                ("file.txt", TreeItemMode::Blob, file),
+                ("secret", TreeItemMode::Tree, secret),
+            ]);
+            let (root, root_model) = tree(&[("a", TreeItemMode::Tree, a)]);
+            let models = vec![file_model, key_model, secret_model, a_model, root_model];
+            save_repo(&storage, models, &[root]).await;
+
+            let mut mock = PackProtocol::mock();
+            mock.storage = storage.clone();
+            mock.path = PathBuf::from("/repo");
+            mock.denied_dirs = vec![PathBuf::from("/repo/a/secret")];
+            mock.negotiation.want = HashSet::from([a.to_plain_str()]);
+            let objects = mock.collect_full_pack_objects(&mock.path).await.unwrap();
+            assert!(objects.contains(&a) && objects.contains(&file));
+            assert!(!objects.contains(&secret) && !objects.contains(&key));
+        });
+    }
+    #[test]
+    fn test_clone_with_denied_dir() {
+        block_on(async {
+            let storage = Arc::new(SqliteStorage::memory().await.unwrap());
+            let (file, file_model) = object(ObjectType::Blob, b"file".to_vec());
+            let (key, key_model) = object(ObjectType::Blob, b"key".to_vec());
+            let (a, a_model) = tree(&[("file.txt", TreeItemMode::Blob, file)]);
+            let (secret, secret_model) = tree(&[("key", TreeItemMode::Blob, key)]);
+            let (root, root_model) = tree(&[
+                ("a", TreeItemMode::Tree, a),
+                ("secret", TreeItemMode::Tree, secret),
+            ]);
+            let models = vec![file_model, key_model, a_model, secret_model, root_model];
+            save_repo(&storage, models, &[root]).await;
+
+            let mut mock = PackProtocol::mock();
+            mock.storage = storage.clone();
+            mock.path = PathBuf::from("/repo");
+            mock.denied_dirs = vec![PathBuf::from("/repo/secret")];
+            // the parent tree would point to a missing tree
+            let err = mock
+                .collect_full_pack_objects(&mock.path)
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Permission denied, anonymous user can't read /repo/secret, \
+                fetch with --filter to leave it out"
+            );
+            mock.filter = Some(ObjectFilter::BlobLimit(1024));
+            let objects = mock.collect_full_pack_objects(&mock.path).await.unwrap();
+            assert!(objects.contains(&root) && objects.contains(&file));
+            assert!(!objects.contains(&secret) && !objects.contains(&key));
+        });
+    }
+    #[test]
+    fn test_want_objects_walk_the_tips_first() {
+        block_on(async {
+            let storage = Arc::new(CountingStorage {
+                inner: SqliteStorage::memory().await.unwrap(),
+                loaded: AtomicUsize::new(0),
+            });
+            // a history of 50 versions of a file
+            let mut models = vec![];
+            let mut roots = vec![];
+            let mut blobs = vec![];
+            for i in 0..50 {
+                let (blob, blob_model) = object(ObjectType::Blob, format!("v{}", i).into_bytes());
+                let (a, a_model) = tree(&[("file.txt", TreeItemMode::Blob, blob)]);
+                let (root, root_model) = tree(&[("a", TreeItemMode::Tree, a)]);
+                models.extend([blob_model, a_model, root_model]);
+                roots.push(root);
+                blobs.push(blob);
+            }
+            save_repo(&storage.inner, models, &roots).await;
+
+            let mut mock = PackProtocol::mock();
+            mock.storage = storage.clone();
+            mock.path = PathBuf::from("/repo");
+            for (blob, max_loaded) in [(blobs[49], 3), (blobs[0], 150)] {
+                storage.loaded.store(0, Ordering::SeqCst);
+                mock.negotiation.want = HashSet::from([blob.to_plain_str()]);
+                let objects = mock.collect_full_pack_objects(&mock.path).await.unwrap();
+                assert!(objects.contains(&blob));
+                // the wanted blob, then the root and `a` trees of each walked commit
+                assert!(storage.loaded.load(Ordering::SeqCst) <= max_loaded);
+            }
+        });
+    }
 }
@@ -1,8 +1,8 @@
 use std::path::{Path, PathBuf};
 
-use storage::utils::id_generator::{self, generate_id};
 use entity::{commit, node};
 use sea_orm::{ActiveValue::NotSet, Set};
+use storage::utils::id_generator::{self, generate_id};
 
 use crate::{
     hash::Hash,
@@ -22,6 +22,7 @@ use self::nodes::{FileNode, Node, TreeNode};
 
 pub mod conversion;
 pub mod nodes;
+pub mod subrepo;
 /// only blob and tree should implement this trait
 pub trait GitNodeObject {
     fn convert_to_node(
@@ -82,14 +83,14 @@ impl GitNodeObject for Blob {
 }
 
 impl Commit {
-    pub fn subdir_commit(meta: Vec<u8>, tree_id: Hash) -> Commit {
+    pub fn subdir_commit(meta: Vec<u8>, tree_id: Hash, parent_ids: Vec<Hash>) -> Commit {
         let mut c = Commit::new_from_meta(Meta::new_from_data_with_object_type(
             ObjectType::Commit,
             meta,
         ))
         .unwrap();
         c.tree_id = tree_id;
-        c.parent_tree_ids.clear();
+        c.parent_tree_ids = parent_ids;
         c.id = Meta::calculate_id(ObjectType::Commit, &c.to_data().unwrap());
         c
     }
@@ -0,0 +1,712 @@
+//!
+//! Sub-directory repos: any directory below a repo of the monorepo, the trunk, can be cloned as
+//! a repo of its own and pushed back.
+//!
+//! Fetching a sub-directory serves a commit synthesized from the trunk commit, with the
+//! directory as root tree. Pushing to it replays each pushed commit onto the trunk branch of the
+//! same name, with the directory replaced by the pushed tree and the same author, committer and
+//! message. Both directions record the pair of commits in `subrepo_commit`, so that a fetch
+//! after a push serves the pushed commit instead of synthesizing another one.
+//!
+use std::collections::HashMap;
+use std::path::{Component, Path};
+
+use async_recursion::async_recursion;
+use sea_orm::{DatabaseTransaction, Set};
+
+use common::utils::ZERO_ID;
+use entity::{objects, refs, subrepo_commit};
+use storage::utils::id_generator::generate_id;
+
+use crate::errors::GitError;
+use crate::hash::Hash;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::meta::Meta;
+use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+use crate::internal::ObjectType;
+use crate::protocol::hooks::PendingObjects;
+use crate::protocol::{CommandType, PackProtocol, RefCommand, RefsType};
+use crate::structure::conversion::missing_object;
+
+const TRUNK_MOVED: &str = "directory changed in trunk, fetch first";
+const MERGE_REPLAY: &str = "merge commits can't be replayed onto the trunk, rebase them first";
+
+/// The move of a trunk branch replaying the commits pushed to a sub-directory repo.
+pub struct TrunkUpdate {
+    pub trunk_path: String,
+    pub old_id: String,
+    pub new_id: String,
+    // the replayed commits and their rewritten trees, stored with the refs
+    commits: Vec<Commit>,
+    trees: Vec<Tree>,
+    subrepo_commits: Vec<subrepo_commit::ActiveModel>,
+}
+
+impl TrunkUpdate {
+    /// The replayed commits and trees, for the hooks run before they are stored.
+    pub fn pending_objects(&self) -> PendingObjects {
+        PendingObjects {
+            commits: self
+                .commits
+                .iter()
+                .map(|c| (c.id.to_plain_str(), c.clone()))
+                .collect(),
+            trees: self
+                .trees
+                .iter()
+                .map(|t| (t.id.to_plain_str(), t.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl PackProtocol {
+    /// Returns the refs of the closest repo above `repo_path`, which are empty unless
+    /// `repo_path` is a sub-directory repo.
+    pub async fn get_trunk_refs(&self, repo_path: &Path) -> Result<Vec<refs::Model>, GitError> {
+        let refs_list = self
+            .storage
+            .search_refs(repo_path.to_str().unwrap())
+            .await?;
+        let trunk_path = refs_list
+            .iter()
+            .map(|r| Path::new(&r.repo_path))
+            .filter(|p| *p != repo_path && repo_path.starts_with(p))
+            .max_by_key(|p| p.components().count())
+            .map(|p| p.to_path_buf());
+        Ok(match trunk_path {
+            Some(trunk_path) => refs_list
+                .into_iter()
+                .filter(|r| Path::new(&r.repo_path) == trunk_path)
+                .collect(),
+            None => vec![],
+        })
+    }
+
+    /// # Replays the branches pushed to a sub-directory repo onto the trunk.
+    ///
+    /// Nothing is stored yet, the returned updates write the trunk commits and move the trunk
+    /// branches along with the sub-directory refs in `apply_ref_commands`. A branch is rejected
+    /// if the trunk doesn't have it, if the directory changed in trunk since the pushed history
+    /// was fetched, or if the pushed history has merge commits.
+    pub async fn prepare_trunk_updates(
+        &mut self,
+    ) -> Result<HashMap<String, TrunkUpdate>, GitError> {
+        let mut updates = HashMap::new();
+        let trunk_refs = self.get_trunk_refs(&self.path).await?;
+        if trunk_refs.is_empty() {
+            return Ok(updates);
+        }
+        let mut commands = std::mem::take(&mut self.command_list);
+        for command in commands.iter_mut().filter(|c| {
+            c.is_ok() && c.refs_type == RefsType::Branch && c.command_type != CommandType::Delete
+        }) {
+            let trunk_ref = match trunk_refs.iter().find(|r| r.ref_name == command.ref_name) {
+                Some(trunk_ref) => trunk_ref,
+                None => {
+                    command.failed(format!(
+                        "{} doesn't exist in {}",
+                        command.ref_name, trunk_refs[0].repo_path
+                    ));
+                    continue;
+                }
+            };
+            match self.replay_onto_trunk(trunk_ref, command).await {
+                Ok(Ok(update)) => {
+                    updates.insert(command.ref_name.clone(), update);
+                }
+                Ok(Err(msg)) => command.failed(msg),
+                Err(err) => {
+                    self.command_list = commands;
+                    return Err(err);
+                }
+            }
+        }
+        self.command_list = commands;
+        Ok(updates)
+    }
+
+    /// # Moves the trunk branch of a sub-directory ref swapped in `txn`.
+    ///
+    /// The replayed commits and trees are written in `txn` too. If the trunk branch moved
+    /// meanwhile, the sub-directory ref is swapped back and false is returned.
+    pub async fn apply_trunk_update(
+        &self,
+        txn: &DatabaseTransaction,
+        command: &RefCommand,
+        update: &TrunkUpdate,
+    ) -> Result<bool, GitError> {
+        if update.old_id != update.new_id
+            && !self
+                .storage
+                .compare_and_swap_ref(
+                    txn,
+                    &update.trunk_path,
+                    &command.ref_name,
+                    &update.old_id,
+                    &update.new_id,
+                )
+                .await?
+        {
+            self.storage
+                .compare_and_swap_ref(
+                    txn,
+                    self.path.to_str().unwrap(),
+                    &command.ref_name,
+                    &command.new_id,
+                    &command.old_id,
+                )
+                .await?;
+            return Ok(false);
+        }
+        let tree_models = update
+            .trees
+            .iter()
+            .map(|tree| {
+                Ok(objects::ActiveModel {
+                    id: Set(generate_id()),
+                    git_id: Set(tree.id.to_plain_str()),
+                    object_type: Set(String::from("tree")),
+                    data: Set(tree.to_data()?),
+                    link: Set(None),
+                })
+            })
+            .collect::<Result<_, GitError>>()?;
+        self.storage.save_obj_data(Some(txn), tree_models).await?;
+        let trunk_path = Path::new(&update.trunk_path);
+        let commit_models = update
+            .commits
+            .iter()
+            .map(|c| c.convert_to_model(trunk_path))
+            .collect();
+        self.storage.save_commits(Some(txn), commit_models).await?;
+        self.storage
+            .save_subrepo_commits(txn, update.subrepo_commits.clone())
+            .await?;
+        Ok(true)
+    }
+
+    // creates a trunk commit for each pushed commit, following the parents back to the last
+    // commit that was fetched from the trunk, the inner error rejects the branch
+    async fn replay_onto_trunk(
+        &self,
+        trunk_ref: &refs::Model,
+        command: &RefCommand,
+    ) -> Result<Result<TrunkUpdate, String>, GitError> {
+        let repo_path = self.path.to_str().unwrap();
+        let relative: Vec<String> = self
+            .path
+            .strip_prefix(&trunk_ref.repo_path)
+            .unwrap()
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+
+        let mut pushed = vec![];
+        let mut next = Some(command.new_id.clone());
+        let base = loop {
+            let id = match next {
+                Some(id) => id,
+                None => break None,
+            };
+            if id == command.old_id
+                || self
+                    .storage
+                    .get_subrepo_commit(repo_path, &id)
+                    .await?
+                    .is_some()
+            {
+                break self.load_commit(&id).await?;
+            }
+            let commit = match self.load_commit(&id).await? {
+                Some(commit) => commit,
+                None => return Ok(Err(format!("missing commit {}", id))),
+            };
+            if commit.parent_tree_ids.len() > 1 {
+                return Ok(Err(String::from(MERGE_REPLAY)));
+            }
+            next = commit.parent_tree_ids.first().map(|p| p.to_plain_str());
+            pushed.push(commit);
+        };
+
+        let trunk_commit = match self.load_commit(&trunk_ref.ref_git_id).await? {
+            Some(commit) => commit,
+            None => return Ok(Err(format!("missing commit {}", trunk_ref.ref_git_id))),
+        };
+        let trunk_dir = self
+            .search_dir_from_tree(
+                &trunk_commit.tree_id.to_plain_str(),
+                self.path
+                    .strip_prefix(&trunk_ref.repo_path)
+                    .unwrap()
+                    .components(),
+            )
+            .await?;
+        if trunk_dir != base.map(|c| c.tree_id.to_plain_str()) {
+            return Ok(Err(String::from(TRUNK_MOVED)));
+        }
+
+        let mut trees = HashMap::new();
+        let mut commits = vec![];
+        let mut subrepo_commits = vec![];
+        let mut root = trunk_commit.tree_id;
+        let mut parent = trunk_commit.id;
+        for commit in pushed.into_iter().rev() {
+            root = self
+                .replace_subtree(Some(root), &relative, commit.tree_id, &mut trees)
+                .await?;
+            let mut replayed = Commit {
+                id: Hash::default(),
+                tree_id: root,
+                parent_tree_ids: vec![parent],
+                author: commit.author.clone(),
+                committer: commit.committer.clone(),
+                message: commit.message.clone(),
+            };
+            replayed.id = Meta::calculate_id(ObjectType::Commit, &replayed.to_data()?);
+            parent = replayed.id;
+            commits.push(replayed);
+            subrepo_commits.push(new_subrepo_commit(
+                repo_path,
+                &commit.id.to_plain_str(),
+                &trunk_ref.repo_path,
+                &parent.to_plain_str(),
+            ));
+        }
+
+        Ok(Ok(TrunkUpdate {
+            trunk_path: trunk_ref.repo_path.clone(),
+            old_id: trunk_ref.ref_git_id.clone(),
+            new_id: parent.to_plain_str(),
+            commits,
+            trees: trees.into_values().collect(),
+            subrepo_commits,
+        }))
+    }
+
+    // writes `subtree` at `path` below a tree, creating the missing directories, and returns
+    // the id of the rewritten tree, the rewritten trees are added to `trees`, where the trees
+    // rewritten for the previous commits are read from
+    #[async_recursion]
+    async fn replace_subtree(
+        &self,
+        tree_id: Option<Hash>,
+        path: &[String],
+        subtree: Hash,
+        trees: &mut HashMap<Hash, Tree>,
+    ) -> Result<Hash, GitError> {
+        let mut tree: Tree = match tree_id {
+            Some(id) if trees.contains_key(&id) => trees[&id].clone(),
+            Some(id) => self
+                .storage
+                .get_obj_data_by_id(&id.to_plain_str())
+                .await?
+                .ok_or_else(|| missing_object(&id.to_plain_str()))?
+                .into(),
+            None => Tree {
+                id: Hash::default(),
+                tree_items: vec![],
+            },
+        };
+        let (name, rest) = path.split_first().unwrap();
+        let position = tree.tree_items.iter().position(|item| &item.name == name);
+        let child = if rest.is_empty() {
+            subtree
+        } else {
+            let current = position
+                .map(|p| &tree.tree_items[p])
+                .filter(|item| item.mode == TreeItemMode::Tree)
+                .map(|item| item.id);
+            self.replace_subtree(current, rest, subtree, trees).await?
+        };
+        match position {
+            Some(p) => {
+                tree.tree_items[p].mode = TreeItemMode::Tree;
+                tree.tree_items[p].id = child;
+            }
+            None => {
+                tree.tree_items
+                    .push(TreeItem::new(TreeItemMode::Tree, child, name.clone()));
+                sort_tree_items(&mut tree.tree_items);
+            }
+        }
+        tree.id = Meta::calculate_id(ObjectType::Tree, &tree.to_data()?);
+        let id = tree.id;
+        trees.insert(id, tree);
+        Ok(id)
+    }
+
+    async fn load_commit(&self, id: &str) -> Result<Option<Commit>, GitError> {
+        if id == ZERO_ID {
+            return Ok(None);
+        }
+        Ok(self
+            .storage
+            .get_commit_by_hash(id)
+            .await?
+            .map(|model| model.into()))
+    }
+}
+
+pub fn new_subrepo_commit(
+    repo_path: &str,
+    commit_id: &str,
+    trunk_path: &str,
+    trunk_commit_id: &str,
+) -> subrepo_commit::ActiveModel {
+    subrepo_commit::ActiveModel {
+        id: sea_orm::NotSet,
+        repo_path: Set(repo_path.to_owned()),
+        commit_id: Set(commit_id.to_owned()),
+        trunk_path: Set(trunk_path.to_owned()),
+        trunk_commit_id: Set(trunk_commit_id.to_owned()),
+        created_at: Set(chrono::Utc::now().naive_utc()),
+    }
+}
+
+// git sorts tree entries by name, comparing directories as if their name ended with '/'
+fn sort_tree_items(items: &mut [TreeItem]) {
+    let key = |item: &TreeItem| {
+        let mut name = item.name.as_bytes().to_vec();
+        if item.mode == TreeItemMode::Tree {
+            name.push(b'/');
+        }
+        name
+    };
+    items.sort_by_key(key);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use sea_orm::{ActiveModelTrait, NotSet, Set};
+    use tokio_test::block_on;
+
+    use common::config::HooksConfig;
+    use entity::{objects, path_owner, protected_ref};
+    use storage::driver::database::sqlite_storage::SqliteStorage;
+    use storage::driver::database::storage::ObjectStorage;
+
+    use crate::hash::Hash;
+    use crate::internal::object::commit::Commit;
+    use crate::internal::object::meta::Meta;
+    use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use crate::internal::object::ObjectT;
+    use crate::internal::ObjectType;
+    use crate::protocol::hooks::ReceiveHooks;
+    use crate::protocol::{PackProtocol, RefCommand};
+    use crate::structure::subrepo::sort_tree_items;
+
+    fn tree(name: &str, mode: TreeItemMode, id: Hash) -> Tree {
+        let mut tree = Tree {
+            id: Hash::default(),
+            tree_items: vec![TreeItem::new(mode, id, name.to_owned())],
+        };
+        tree.id = Meta::calculate_id(ObjectType::Tree, &tree.to_data().unwrap());
+        tree
+    }
+
+    // a directory holding a single file
+    fn dir(content: &str) -> Tree {
+        let blob_id = Meta::calculate_id(ObjectType::Blob, &content.as_bytes().to_vec());
+        tree("file.txt", TreeItemMode::Blob, blob_id)
+    }
+
+    fn commit(tree: &Tree, parents: &[String], message: &str) -> Commit {
+        let parents: String = parents.iter().map(|p| format!("parent {}\n", p)).collect();
+        let signature = "Alice <alice@example.com> 1700000000 +0000";
+        let data = format!(
+            "tree {}\n{}author {}\ncommitter {}\n\n{}\n",
+            tree.id, parents, signature, signature, message
+        );
+        let mut commit = Commit::new_from_data(data.into_bytes());
+        commit.id = Meta::calculate_id(ObjectType::Commit, &commit.to_data().unwrap());
+        commit
+    }
+
+    async fn save_trees(storage: &SqliteStorage, trees: &[&Tree]) {
+        let models = trees
+            .iter()
+            .enumerate()
+            .map(|(i, tree)| objects::ActiveModel {
+                id: Set(i as i64 + 1),
+                git_id: Set(tree.id.to_plain_str()),
+                object_type: Set(String::from("tree")),
+                data: Set(tree.to_data().unwrap()),
+                link: Set(None),
+            })
+            .collect();
+        storage.save_obj_data_to_db(None, models).await.unwrap();
+    }
+
+    #[test]
+    fn test_subrepo_follows_trunk_head() {
+        block_on(async {
+            let storage = Arc::new(SqliteStorage::memory().await.unwrap());
+            let (a0, a1) = (dir("v0"), dir("v1"));
+            let (projects0, projects1) = (
+                tree("a", TreeItemMode::Tree, a0.id),
+                tree("a", TreeItemMode::Tree, a1.id),
+            );
+            let root0 = tree("projects", TreeItemMode::Tree, projects0.id);
+            let root1 = tree("projects", TreeItemMode::Tree, projects1.id);
+            save_trees(
+                &storage,
+                &[&a0, &a1, &projects0, &projects1, &root0, &root1],
+            )
+            .await;
+            let (main, feature) = (commit(&root0, &[], "main"), commit(&root1, &[], "feature"));
+            // the feature branch comes first in storage
+            for (trunk, ref_name) in [(&feature, "refs/heads/feature"), (&main, "refs/heads/main")]
+            {
+                storage
+                    .save_commits(None, vec![trunk.convert_to_model(Path::new("/"))])
+                    .await
+                    .unwrap();
+                let command = RefCommand::new(
+                    common::utils::ZERO_ID.to_owned(),
+                    trunk.id.to_plain_str(),
+                    ref_name.to_owned(),
+                );
+                storage
+                    .save_refs(vec![command.convert_to_model("/")])
+                    .await
+                    .unwrap();
+            }
+
+            let mut mock = PackProtocol::mock();
+            mock.storage = storage.clone();
+            let head = mock
+                .get_head_object_id(Path::new("/projects/a"))
+                .await
+                .unwrap();
+            let head_commit: Commit = storage
+                .get_commit_by_hash(&head)
+                .await
+                .unwrap()
+                .unwrap()
+                .into();
+            assert_eq!(head_commit.tree_id, a0.id);
+            // fetching again reads the recorded commit
+            assert_eq!(
+                mock.get_head_object_id(Path::new("/projects/a"))
+                    .await
+                    .unwrap(),
+                head
+            );
+            let commits = storage
+                .get_all_commits_by_path("/projects/a")
+                .await
+                .unwrap();
+            assert_eq!(commits.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_push_to_subrepo_checks_trunk_policy() {
+        block_on(async {
+            let storage = Arc::new(SqliteStorage::memory().await.unwrap());
+            let (a0, a1) = (dir("v0"), dir("v1"));
+            let projects = tree("a", TreeItemMode::Tree, a0.id);
+            let root = tree("projects", TreeItemMode::Tree, projects.id);
+            save_trees(&storage, &[&a0, &a1, &projects, &root]).await;
+            let trunk = commit(&root, &[], "trunk");
+            storage
+                .save_commits(None, vec![trunk.convert_to_model(Path::new("/"))])
+                .await
+                .unwrap();
+            let main = RefCommand::new(
+                common::utils::ZERO_ID.to_owned(),
+                trunk.id.to_plain_str(),
+                String::from("refs/heads/main"),
+            );
+            storage
+                .save_refs(vec![main.convert_to_model("/")])
+                .await
+                .unwrap();
+            let now = chrono::Utc::now().naive_utc();
+            protected_ref::ActiveModel {
+                id: NotSet,
+                repo_path: Set(String::from("/")),
+                ref_pattern: Set(String::from("refs/heads/main")),
+                created_at: Set(now),
+                updated_at: Set(now),
+            }
+            .insert(storage.get_connection())
+            .await
+            .unwrap();
+            path_owner::ActiveModel {
+                id: NotSet,
+                path: Set(String::from("/projects/a")),
+                owners: Set(String::from("bob")),
+                created_at: Set(now),
+                updated_at: Set(now),
+            }
+            .insert(storage.get_connection())
+            .await
+            .unwrap();
+
+            let mut mock = PackProtocol::mock();
+            mock.storage = storage.clone();
+            mock.path = PathBuf::from("/projects/a");
+            mock.hooks = ReceiveHooks::with_policy(&HooksConfig::default());
+            let fetched = mock
+                .get_head_object_id(Path::new("/projects/a"))
+                .await
+                .unwrap();
+            let pushed = commit(&a1, std::slice::from_ref(&fetched), "edit");
+            storage
+                .save_commits(None, vec![pushed.convert_to_model(&mock.path)])
+                .await
+                .unwrap();
+
+            // the sub-directory has no rule of its own, the trunk's rules apply
+            for (user, status) in [
+                (
+                    "alice",
+                    "ng refs/heads/main /: changes to /projects/a require review from bob",
+                ),
+                ("bob", "ok refs/heads/main"),
+            ] {
+                mock.user = Some(user.to_owned());
+                mock.command_list = vec![RefCommand::new(
+                    fetched.clone(),
+                    pushed.id.to_plain_str(),
+                    String::from("refs/heads/main"),
+                )];
+                mock.check_ref_commands().await.unwrap();
+                let mut output = vec![];
+                mock.run_receive_hooks(&mut output).await;
+                mock.apply_ref_commands(&mut output).await.unwrap();
+                assert_eq!(mock.command_list[0].get_status(), status);
+                // the rejected replay left nothing behind
+                let trunk_commits = storage.get_all_commits_by_path("/").await.unwrap();
+                assert_eq!(trunk_commits.len(), if user == "bob" { 2 } else { 1 });
+            }
+
+            let merge = commit(&a1, &[pushed.id.to_plain_str(), fetched.clone()], "merge");
+            storage
+                .save_commits(None, vec![merge.convert_to_model(&mock.path)])
+                .await
+                .unwrap();
+            mock.command_list = vec![RefCommand::new(
+                pushed.id.to_plain_str(),
+                merge.id.to_plain_str(),
+                String::from("refs/heads/main"),
+            )];
+            mock.check_ref_commands().await.unwrap();
+            mock.apply_ref_commands(&mut vec![]).await.unwrap();
+            assert_eq!(
+                mock.command_list[0].get_status(),
+                "ng refs/heads/main merge commits can't be replayed onto the trunk, rebase them first"
+            );
+
+            let trunk_ref = storage.get_all_refs_by_path("/").await.unwrap().remove(0);
+            let replayed = storage
+                .get_commit_by_hash(&trunk_ref.ref_git_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(replayed.pid, vec![trunk.id.to_plain_str()]);
+            let dir = mock
+                .search_dir_from_tree(&replayed.tree, Path::new("projects/a").components())
+                .await
+                .unwrap();
+            assert_eq!(dir, Some(a1.id.to_plain_str()));
+        });
+    }
+
+    #[test]
+    fn test_push_several_commits_to_subrepo() {
+        block_on(async {
+            let storage = Arc::new(SqliteStorage::memory().await.unwrap());
+            let (a0, a1, a2) = (dir("v0"), dir("v1"), dir("v2"));
+            let projects = tree("a", TreeItemMode::Tree, a0.id);
+            let root = tree("projects", TreeItemMode::Tree, projects.id);
+            save_trees(&storage, &[&a0, &a1, &a2, &projects, &root]).await;
+            let trunk = commit(&root, &[], "trunk");
+            storage
+                .save_commits(None, vec![trunk.convert_to_model(Path::new("/"))])
+                .await
+                .unwrap();
+            let main = RefCommand::new(
+                common::utils::ZERO_ID.to_owned(),
+                trunk.id.to_plain_str(),
+                String::from("refs/heads/main"),
+            );
+            storage
+                .save_refs(vec![main.convert_to_model("/")])
+                .await
+                .unwrap();
+
+            let mut mock = PackProtocol::mock();
+            mock.storage = storage.clone();
+            mock.path = PathBuf::from("/projects/a");
+            let fetched = mock
+                .get_head_object_id(Path::new("/projects/a"))
+                .await
+                .unwrap();
+            let first = commit(&a1, std::slice::from_ref(&fetched), "first");
+            let second = commit(&a2, &[first.id.to_plain_str()], "second");
+            storage
+                .save_commits(
+                    None,
+                    vec![
+                        first.convert_to_model(&mock.path),
+                        second.convert_to_model(&mock.path),
+                    ],
+                )
+                .await
+                .unwrap();
+            mock.command_list = vec![RefCommand::new(
+                fetched,
+                second.id.to_plain_str(),
+                String::from("refs/heads/main"),
+            )];
+            mock.check_ref_commands().await.unwrap();
+            mock.apply_ref_commands(&mut vec![]).await.unwrap();
+            assert_eq!(mock.command_list[0].get_status(), "ok refs/heads/main");
+
+            // each pushed commit is replayed on top of the previous one
+            let trunk_ref = storage.get_all_refs_by_path("/").await.unwrap().remove(0);
+            let mut replayed = vec![];
+            let mut next = trunk_ref.ref_git_id;
+            while next != trunk.id.to_plain_str() {
+                let commit = storage.get_commit_by_hash(&next).await.unwrap().unwrap();
+                next = commit.pid[0].clone();
+                replayed.push(commit);
+            }
+            let mut dirs = vec![];
+            for commit in &replayed {
+                let dir = mock
+                    .search_dir_from_tree(&commit.tree, Path::new("projects/a").components())
+                    .await
+                    .unwrap();
+                dirs.push(dir);
+            }
+            assert_eq!(
+                dirs,
+                vec![Some(a2.id.to_plain_str()), Some(a1.id.to_plain_str())]
+            );
+        });
+    }
+
+    #[test]
+    fn test_sort_tree_items() {
+        let item = |mode, name: &str| TreeItem::new(mode, Hash::default(), name.to_owned());
+        let mut items = vec![
+            item(TreeItemMode::Blob, "foo.txt"),
+            item(TreeItemMode::Tree, "foo"),
+            item(TreeItemMode::Blob, "foo-bar"),
+            item(TreeItemMode::Blob, "Makefile"),
+        ];
+        sort_tree_items(&mut items);
+        let names: Vec<&str> = items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["Makefile", "foo-bar", "foo.txt", "foo"]);
+    }
+}
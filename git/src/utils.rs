@@ -203,10 +203,12 @@ pub fn write_offset_encoding(number: u64) -> Vec<u8> {
     num.push((number & 0x7f) as u8);
     number >>= 7;
 
-    // Encode the remaining bits in subsequent bytes
+    // Encode the remaining bits in subsequent bytes, minus one as `read_offset_encoding` adds
+    // it back for each byte
     while number > 0 {
+        number -= 1;
         // Set the most significant bit to indicate continuation
-        num.push(((number & 0x7f) - 1) as u8 | 0x80);
+        num.push((number & 0x7f) as u8 | 0x80);
         number >>= 7;
     }
 
@@ -388,6 +390,19 @@ mod test {
     use crate::utils::*;
     use std::env;
 
+    #[test]
+    fn test_offset_encoding() {
+        for offset in [0, 127, 128, 16511, 16512, 1 << 20] {
+            let data = write_offset_encoding(offset);
+            let mut consume = 0;
+            assert_eq!(
+                read_offset_encoding(&mut data.as_slice(), &mut consume).unwrap(),
+                offset
+            );
+            assert_eq!(consume, data.len());
+        }
+    }
+
     #[test]
     fn test_get_env_value(){
         env::set_var("GIT_INTERNAL_DECODE_STORAGE_TQUEUE_SIZE", "10");
@@ -4,7 +4,9 @@ use anyhow::Result;
 use redis::{Connection, ConnectionInfo, FromRedisValue, IntoConnectionInfo, ToRedisArgs};
 
 use crate::connector::Connector;
-use crate::utils;
+
+const DEFAULT_ADDR: &str = "redis://127.0.0.1:6379";
+
 pub struct RedisClient<K, V> {
     conn: RefCell<Connection>,
     k: PhantomData<K>,
@@ -50,8 +52,16 @@ where
     }
 
     fn new() -> RedisClient<K, V> {
-        let mut addr: String= String::new();
-        utils::get_env_number("REDIS_CONFIG", &mut addr);
+        Self::open(DEFAULT_ADDR)
+    }
+}
+impl<K, V> RedisClient<K, V>
+where
+    K: ToRedisArgs,
+    V: ToRedisArgs + FromRedisValue,
+{
+    /// Connects to the redis server at `addr`, such as `redis://127.0.0.1:6379`.
+    pub fn open(addr: &str) -> RedisClient<K, V> {
         let config :ConnectionInfo  = addr.into_connection_info().unwrap();
         let mut c = Self::new_client(config).unwrap();
 
@@ -73,12 +83,7 @@ where
             v: PhantomData,
         }
     }
-}
-impl<K, V> RedisClient<K, V>
-where
-    K: ToRedisArgs,
-    V: ToRedisArgs + FromRedisValue,
-{
+
     fn new_client(info: ConnectionInfo) -> Result<Connection> {
         
         let client = redis::Client::open(info)?;
@@ -16,8 +16,12 @@ impl<C> KVCache<C>
         C: Connector,
 {
     pub fn new() -> Self {
+        Self::with_connector(C::new())
+    }
+
+    pub fn with_connector(con: C) -> Self {
         KVCache {
-            con: RefCell::new(C::new()),
+            con: RefCell::new(con),
         }
     }
 
@@ -5,12 +5,12 @@
 //!
 //!
 
-use storage::driver::database::storage::ObjectStorage;
 use git::protocol::{PackProtocol, Protocol};
 use std::path::PathBuf;
-use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::node::ClientParas;
+
 pub mod network;
 pub mod node;
 pub mod peer;
@@ -19,9 +19,14 @@ pub mod internal;
 
 pub mod nostr;
 
-fn get_pack_protocol(path: &str, storage: Arc<dyn ObjectStorage>) -> PackProtocol {
+fn get_pack_protocol(path: &str, client_paras: &ClientParas) -> PackProtocol {
     let path = del_ends_str(path, ".git");
-    PackProtocol::new(PathBuf::from(path), storage, Protocol::P2p)
+    PackProtocol::new(
+        PathBuf::from(path),
+        client_paras.storage.clone(),
+        Protocol::P2p,
+        &client_paras.config,
+    )
 }
 
 pub fn get_repo_full_path(repo_name: &str) -> String {
@@ -264,7 +264,7 @@ pub async fn git_upload_pack_event_handler(
     event: request_response::Event<GitUploadPackReq, GitUploadPackRes>,
 ) {
     match event {
-        request_response::Event::Message { message, .. } => match message {
+        request_response::Event::Message { peer, message } => match message {
             request_response::Message::Request {
                 request, channel, ..
             } => {
@@ -312,8 +312,17 @@ pub async fn git_upload_pack_event_handler(
                         return;
                     }
                     let path = get_repo_full_path(repo_name);
-                    let mut pack_protocol = get_pack_protocol(&path, client_paras.storage.clone());
-                    let old_object_id = pack_protocol.get_head_object_id(Path::new(&path)).await;
+                    let mut pack_protocol = get_pack_protocol(&path, client_paras);
+                    // the peer is recorded in the audit log as the client
+                    pack_protocol.client_addr = Some(peer.to_string());
+                    let old_object_id =
+                        match pack_protocol.get_head_object_id(Path::new(&path)).await {
+                            Ok(object_id) => object_id,
+                            Err(e) => {
+                                tracing::error!("{}", e);
+                                return;
+                            }
+                        };
                     tracing::info!(
                         "new_object_id:{}; old_object_id:{}",
                         object_id.clone(),
@@ -375,8 +384,14 @@ pub async fn git_info_refs_event_handler(
                 tracing::info!("path: {}", path);
                 let git_ids_they_have = request.1;
                 tracing::info!("git_ids_they_have: {:?}", git_ids_they_have);
-                let pack_protocol = get_pack_protocol(&path, client_paras.storage.clone());
-                let ref_git_id = pack_protocol.get_head_object_id(Path::new(&path)).await;
+                let pack_protocol = get_pack_protocol(&path, client_paras);
+                let ref_git_id = match pack_protocol.get_head_object_id(Path::new(&path)).await {
+                    Ok(object_id) => object_id,
+                    Err(e) => {
+                        tracing::error!("{}", e);
+                        return;
+                    }
+                };
                 let mut git_obj_ids = get_all_git_obj_ids(&path, client_paras).await;
                 if !git_ids_they_have.is_empty() {
                     git_obj_ids.retain(|id| !git_ids_they_have.contains(id));
@@ -405,7 +420,7 @@ pub async fn git_info_refs_event_handler(
                         return;
                     }
                     let path = get_repo_full_path(repo_name);
-                    let pack_protocol = get_pack_protocol(&path, client_paras.storage.clone());
+                    let pack_protocol = get_pack_protocol(&path, client_paras);
                     //generate want and have collection
                     let mut want: HashSet<String> = HashSet::new();
                     let mut have: HashSet<String> = HashSet::new();
@@ -496,7 +511,7 @@ pub async fn git_object_event_handler(
                 let git_ids = request.1;
                 tracing::info!("path: {}", path);
                 tracing::info!("git_ids: {:?}", git_ids);
-                let pack_protocol = get_pack_protocol(&path, client_paras.storage.clone());
+                let pack_protocol = get_pack_protocol(&path, client_paras);
                 let git_obj_models = match pack_protocol.storage.get_obj_data_by_ids(git_ids).await
                 {
                     Ok(models) => models,
@@ -583,10 +598,17 @@ pub async fn git_object_event_handler(
                                     obj_model_list.len()
                                 );
                                 let path = get_repo_full_path(repo_name);
-                                let pack_protocol =
-                                    get_pack_protocol(&path, client_paras.storage.clone());
-                                let object_id =
-                                    pack_protocol.get_head_object_id(Path::new(&path)).await;
+                                let pack_protocol = get_pack_protocol(&path, client_paras);
+                                let object_id = match pack_protocol
+                                    .get_head_object_id(Path::new(&path))
+                                    .await
+                                {
+                                    Ok(object_id) => object_id,
+                                    Err(e) => {
+                                        tracing::error!("{}", e);
+                                        return;
+                                    }
+                                };
                                 //update repoInfo
                                 let kad_query_id = swarm
                                     .behaviour_mut()
@@ -657,8 +679,14 @@ async fn git_upload_pack_handler(
     want: HashSet<String>,
     have: HashSet<String>,
 ) -> Result<(Vec<u8>, String), String> {
-    let pack_protocol = get_pack_protocol(path, client_paras.storage.clone());
-    let object_id = pack_protocol.get_head_object_id(Path::new(path)).await;
+    let pack_protocol = get_pack_protocol(path, client_paras);
+    let object_id = match pack_protocol.get_head_object_id(Path::new(path)).await {
+        Ok(object_id) => object_id,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return Err(e.to_string());
+        }
+    };
     if object_id == *utils::ZERO_ID {
         return Err("Repository not found".to_string());
     }
@@ -691,7 +719,7 @@ async fn git_upload_pack_handler(
 }
 
 async fn get_all_git_obj_ids(path: &str, client_paras: &mut ClientParas) -> Vec<String> {
-    let pack_protocol = get_pack_protocol(path, client_paras.storage.clone());
+    let pack_protocol = get_pack_protocol(path, client_paras);
     let mut git_ids: Vec<String> = Vec::new();
     if let Ok(commit_models) = pack_protocol.storage.get_all_commits_by_path(path).await {
         commit_models.iter().for_each(|model| {
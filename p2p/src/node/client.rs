@@ -17,6 +17,7 @@ use libp2p::{
 };
 use tokio::sync::Mutex;
 
+use common::config::Config;
 use common::enums::DataSource;
 use entity::objects::Model;
 use storage::driver::database;
@@ -29,6 +30,7 @@ use crate::node::input_command;
 use crate::node::ClientParas;
 
 pub async fn run(
+    config: &Config,
     sk: SecretKey,
     p2p_address: String,
     bootstrap_node: String,
@@ -99,12 +101,13 @@ pub async fn run(
     swarm.listen_on(p2p_address.parse()?)?;
 
     tracing::info!("Connect to database");
-    let storage = database::init(&data_source).await;
+    let storage = database::init(config, &data_source).await;
     let mut client_paras = ClientParas {
         cookie: None,
         rendezvous_point: None,
         bootstrap_node_addr: None,
         storage,
+        config: config.clone(),
         key_pair,
         pending_git_upload_package: HashMap::new(),
         pending_git_pull: HashMap::new(),
@@ -33,8 +33,14 @@ impl CmdHandler {
             }
             let path = get_repo_full_path(repo_name);
             let pack_protocol: git::protocol::PackProtocol =
-                get_pack_protocol(&path, client_paras.storage.clone());
-            let object_id = pack_protocol.get_head_object_id(Path::new(&path)).await;
+                get_pack_protocol(&path, &client_paras);
+            let object_id = match pack_protocol.get_head_object_id(Path::new(&path)).await {
+                Ok(object_id) => object_id,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            };
             if object_id == *utils::ZERO_ID {
                 eprintln!("Repository not found");
                 return;
@@ -109,8 +115,14 @@ impl CmdHandler {
         };
         let path = get_repo_full_path(repo_name);
         let mut client_paras = self.client_paras.lock().await;
-        let pack_protocol = get_pack_protocol(&path, client_paras.storage.clone());
-        let object_id = pack_protocol.get_head_object_id(Path::new(&path)).await;
+        let pack_protocol = get_pack_protocol(&path, &client_paras);
+        let object_id = match pack_protocol.get_head_object_id(Path::new(&path)).await {
+            Ok(object_id) => object_id,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
         if object_id == *utils::ZERO_ID {
             eprintln!("local repo not found");
             return;
@@ -193,8 +205,14 @@ impl CmdHandler {
 
         let path = get_repo_full_path(repo_name);
         let client_paras = self.client_paras.lock().await;
-        let pack_protocol = get_pack_protocol(&path, client_paras.storage.clone());
-        let object_id = pack_protocol.get_head_object_id(Path::new(&path)).await;
+        let pack_protocol = get_pack_protocol(&path, &client_paras);
+        let object_id = match pack_protocol.get_head_object_id(Path::new(&path)).await {
+            Ok(object_id) => object_id,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
 
         let git_event = GitEvent {
             peer_id,
@@ -226,8 +244,14 @@ impl CmdHandler {
 
         let path = get_repo_full_path(repo_name);
         let client_paras = self.client_paras.lock().await;
-        let pack_protocol = get_pack_protocol(&path, client_paras.storage.clone());
-        let object_id = pack_protocol.get_head_object_id(Path::new(&path)).await;
+        let pack_protocol = get_pack_protocol(&path, &client_paras);
+        let object_id = match pack_protocol.get_head_object_id(Path::new(&path)).await {
+            Ok(object_id) => object_id,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
 
         let git_event = GitEvent {
             peer_id: swarm.local_peer_id().to_string(),
@@ -258,8 +282,14 @@ impl CmdHandler {
 
         let path = get_repo_full_path(repo_name);
         let client_paras = self.client_paras.lock().await;
-        let pack_protocol = get_pack_protocol(&path, client_paras.storage.clone());
-        let object_id = pack_protocol.get_head_object_id(Path::new(&path)).await;
+        let pack_protocol = get_pack_protocol(&path, &client_paras);
+        let object_id = match pack_protocol.get_head_object_id(Path::new(&path)).await {
+            Ok(object_id) => object_id,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
 
         let git_event = GitEvent {
             peer_id: swarm.local_peer_id().to_string(),
@@ -17,6 +17,7 @@ use libp2p::{identity, Multiaddr, PeerId};
 use secp256k1::KeyPair;
 use serde::{Deserialize, Serialize};
 
+use common::config::Config;
 use entity::objects::Model;
 use storage::driver::database::storage::ObjectStorage;
 
@@ -50,6 +51,7 @@ pub struct ClientParas {
     pub rendezvous_point: Option<PeerId>,
     pub bootstrap_node_addr: Option<Multiaddr>,
     pub storage: Arc<dyn ObjectStorage>,
+    pub config: Config,
     pub key_pair: KeyPair,
     pub pending_git_upload_package: HashMap<OutboundRequestId, String>,
     pub pending_git_pull: HashMap<OutboundRequestId, String>,
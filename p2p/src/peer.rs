@@ -8,6 +8,7 @@ use clap::Args;
 use libp2p::identity;
 use libp2p::identity::secp256k1::SecretKey;
 
+use common::config::Config;
 use common::model::CommonOptions;
 
 use crate::node::client;
@@ -39,7 +40,10 @@ pub struct P2pOptions {
 }
 
 /// run as a p2p node
-pub async fn run(options: &P2pOptions) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn run(
+    config: &Config,
+    options: &P2pOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
     let P2pOptions {
         common: CommonOptions { host, data_source },
         custom:
@@ -66,7 +70,14 @@ pub async fn run(options: &P2pOptions) -> Result<(), Box<dyn std::error::Error>>
     if *relay_server {
         relay_server::run(local_key, p2p_address)?;
     } else {
-        client::run(sk, p2p_address, bootstrap_node.clone(), *data_source).await?;
+        client::run(
+            config,
+            sk,
+            p2p_address,
+            bootstrap_node.clone(),
+            *data_source,
+        )
+        .await?;
     }
     Ok(())
 }
@@ -3,40 +3,17 @@
 //!
 //!
 //!
+use std::path::PathBuf;
+
 use clap::{Arg, ArgMatches, Command};
-use config as c;
-use serde::Deserialize;
 
 use crate::commands::{builtin, builtin_exec};
 use common::errors::{MegaError, MegaResult};
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct Config {}
-
-impl Config {
-    pub fn new(path: &str) -> Result<Self, c::ConfigError> {
-        let builder = c::Config::builder().add_source(c::File::new(path, c::FileFormat::Toml));
-        let config = builder.build().unwrap();
-
-        Config::from_config(&config)
-    }
-
-    pub fn from_config(config: &c::Config) -> Result<Self, c::ConfigError> {
-        config.get::<Self>(env!("CARGO_PKG_NAME"))
-    }
-
-    pub fn default() -> Self {
-        Config {}
-    }
-}
+pub(crate) use common::config::Config;
 
 pub fn parse() -> MegaResult {
     let matches = cli().try_get_matches().unwrap_or_else(|e| e.exit());
-    let mut config = Config::default();
-
-    if let Some(c) = matches.get_one::<String>("config").cloned() {
-        config = Config::new(c.as_str()).unwrap()
-    }
 
     let (cmd, subcommand_args) = match matches.subcommand() {
         Some((cmd, args)) => (cmd, args),
@@ -46,6 +23,13 @@ pub fn parse() -> MegaResult {
         }
     };
 
+    // fails with every missing or invalid key before running anything
+    let path = matches.get_one::<String>("config").map(PathBuf::from);
+    let config = Config::load(path.as_deref()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
     exec_subcommand(config, cmd, subcommand_args)
 }
 
@@ -59,7 +43,8 @@ fn cli() -> Command {
             Arg::new("config")
                 .short('c')
                 .long("config")
-                .help("Sets a config file work directory"),
+                .global(true)
+                .help("Sets the TOML config file, overridden by the environment variables"),
         )
 }
 
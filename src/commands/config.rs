@@ -0,0 +1,53 @@
+//!
+//! `mega config check` validates the configuration file and the environment overrides.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches, Subcommand};
+
+use common::enums::DataSource;
+use common::errors::MegaResult;
+
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct ConfigOptions {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum ConfigCommand {
+    /// Checks the configuration, the config file is given with --config
+    Check {
+        /// Also checks that the database of this data source is configured
+        #[arg(short, long, value_enum)]
+        data_source: Option<DataSource>,
+    },
+}
+
+pub fn cli() -> Command {
+    ConfigOptions::augment_args_for_update(
+        Command::new("config").about("Validate the configuration of mega"),
+    )
+}
+
+/// The configuration is loaded before any command runs, so an invalid one never gets here.
+pub(crate) fn exec(config: Config, args: &ArgMatches) -> MegaResult {
+    let options = ConfigOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    match options.command {
+        ConfigCommand::Check { data_source } => {
+            if let Some(data_source) = data_source {
+                if let Err(e) = config.check_data_source(&data_source) {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            println!("the configuration is valid");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {}
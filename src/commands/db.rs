@@ -0,0 +1,27 @@
+//!
+//! `mega db` creates and upgrades the schema of the database.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use common::errors::MegaResult;
+use gateway::db::{exec_db_command, DbOptions};
+
+use crate::cli::Config;
+
+pub fn cli() -> Command {
+    DbOptions::augment_args_for_update(
+        Command::new("db").about("Migrate the schema of the database"),
+    )
+}
+
+#[tokio::main]
+pub(crate) async fn exec(config: Config, args: &ArgMatches) -> MegaResult {
+    let options = DbOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    config.check_data_source(&options.data_source)?;
+    exec_db_command(&config, &options).await
+}
+
+#[cfg(test)]
+mod tests {}
@@ -16,12 +16,13 @@ pub fn cli() -> Command {
 }
 
 #[tokio::main]
-pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+pub(crate) async fn exec(config: Config, args: &ArgMatches) -> MegaResult {
     let server_matchers = InitOptions::from_arg_matches(args)
         .map_err(|err| err.exit())
         .unwrap();
     println!("{server_matchers:#?}");
-    init::init_dir(&server_matchers).await.unwrap();
+    config.check_data_source(&server_matchers.data_source)?;
+    init::init_dir(&config, &server_matchers).await.unwrap();
     Ok(())
 }
 
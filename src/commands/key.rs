@@ -0,0 +1,27 @@
+//!
+//! `mega key` manages the SSH public keys the ssh service authenticates users with.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use common::errors::MegaResult;
+use gateway::key_registry::{exec_key_command, KeyOptions};
+
+use crate::cli::Config;
+
+pub fn cli() -> Command {
+    KeyOptions::augment_args_for_update(
+        Command::new("key").about("Manage the SSH public keys of the users"),
+    )
+}
+
+#[tokio::main]
+pub(crate) async fn exec(config: Config, args: &ArgMatches) -> MegaResult {
+    let options = KeyOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    config.check_data_source(&options.data_source)?;
+    exec_key_command(&config, &options).await
+}
+
+#[cfg(test)]
+mod tests {}
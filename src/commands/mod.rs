@@ -3,8 +3,12 @@
 //!
 //!
 //!
+mod config;
+mod db;
 mod init;
+mod key;
 mod service;
+mod token;
 
 use clap::{ArgMatches, Command};
 
@@ -13,15 +17,23 @@ use common::errors::MegaResult;
 
 pub fn builtin() -> Vec<Command> {
     vec![
+        config::cli(),
+        db::cli(),
         init::cli(),
+        key::cli(),
         service::cli(),
+        token::cli(),
     ]
 }
 
 pub(crate) fn builtin_exec(cmd: &str) -> Option<fn(Config, &ArgMatches) -> MegaResult> {
     let f = match cmd {
+        "config" => config::exec,
+        "db" => db::exec,
         "init" => init::exec,
+        "key" => key::exec,
         "service" => service::exec,
+        "token" => token::exec,
         _ => return None,
     };
 
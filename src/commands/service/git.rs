@@ -0,0 +1,28 @@
+//!
+//! Serves anonymous read-only fetches over the git daemon protocol, on `git://` URLs.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use common::errors::MegaResult;
+use gateway::git_server::start_server;
+use gateway::git_server::GitOptions;
+
+use crate::cli::Config;
+use crate::commands::service::git;
+
+pub fn cli() -> Command {
+    GitOptions::augment_args_for_update(Command::new("git").about("Start Git daemon server"))
+}
+
+pub(crate) async fn exec(config: Config, args: &ArgMatches) -> MegaResult {
+    let server_matchers = GitOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    println!("{server_matchers:#?}");
+    config.check_data_source(&server_matchers.common.data_source)?;
+    git::start_server(&config, &server_matchers).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {}
@@ -9,19 +9,26 @@ use common::errors::MegaResult;
 use gateway::https_server::{self, HttpOptions};
 
 use crate::cli::Config;
+use crate::commands::service::supervisor::Supervisor;
 
 pub fn cli() -> Command {
     HttpOptions::augment_args_for_update(Command::new("https").about("Start Git HTTPS server"))
 }
 
-pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+pub(crate) async fn exec(config: Config, args: &ArgMatches) -> MegaResult {
     let server_matchers = HttpOptions::from_arg_matches(args)
         .map_err(|err| err.exit())
         .unwrap();
 
     println!("{server_matchers:#?}");
-    https_server::start_server(&server_matchers).await;
-    Ok(())
+    config.check_data_source(&server_matchers.common.data_source)?;
+    // supervised for the readiness of /readyz and the graceful shutdown
+    let mut supervisor = Supervisor::new(&config.service);
+    supervisor.add("http", move || {
+        let (config, http) = (config.clone(), server_matchers.clone());
+        async move { https_server::start_server(&config, &http).await }
+    });
+    supervisor.run().await
 }
 
 #[cfg(test)]
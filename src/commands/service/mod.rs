@@ -9,20 +9,28 @@ use common::errors::MegaResult;
 
 use crate::cli::Config;
 
+mod git;
 mod https;
 mod p2p;
 mod ssh;
 mod start;
+mod supervisor;
 
 pub fn cli() -> Command {
-    let subcommands = vec![https::cli(), ssh::cli(), p2p::cli(), start::cli()];
+    let subcommands = vec![
+        https::cli(),
+        ssh::cli(),
+        git::cli(),
+        p2p::cli(),
+        start::cli(),
+    ];
     Command::new("service")
-        .about("Start different kinds of server: for example https, ssh, p2p")
+        .about("Start different kinds of server: for example https, ssh, git, p2p")
         .subcommands(subcommands)
 }
 
 #[tokio::main]
-pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+pub(crate) async fn exec(config: Config, args: &ArgMatches) -> MegaResult {
     let (cmd, subcommand_args) = match args.subcommand() {
         Some((cmd, args)) => (cmd, args),
         _ => {
@@ -31,10 +39,11 @@ pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
         }
     };
     match cmd {
-        "https" => https::exec(_config, subcommand_args).await,
-        "ssh" => ssh::exec(_config, subcommand_args).await,
-        "p2p" => p2p::exec(_config, subcommand_args).await,
-        "start" => start::exec(_config, subcommand_args).await,
+        "https" => https::exec(config, subcommand_args).await,
+        "ssh" => ssh::exec(config, subcommand_args).await,
+        "git" => git::exec(config, subcommand_args).await,
+        "p2p" => p2p::exec(config, subcommand_args).await,
+        "start" => start::exec(config, subcommand_args).await,
         _ => Ok(()),
     }
 }
@@ -14,12 +14,13 @@ pub fn cli() -> Command {
     peer::P2pOptions::augment_args_for_update(Command::new("p2p").about("Start p2p node"))
 }
 
-pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+pub(crate) async fn exec(config: Config, args: &ArgMatches) -> MegaResult {
     let server_matchers = peer::P2pOptions::from_arg_matches(args)
         .map_err(|err| err.exit())
         .unwrap();
     println!("{server_matchers:#?}");
-    peer::run(&server_matchers).await.unwrap();
+    config.check_data_source(&server_matchers.common.data_source)?;
+    peer::run(&config, &server_matchers).await.unwrap();
     Ok(())
 }
 
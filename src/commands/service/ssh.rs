@@ -16,12 +16,13 @@ pub fn cli() -> Command {
     SshOptions::augment_args_for_update(Command::new("ssh").about("Start Git SSH server"))
 }
 
-pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+pub(crate) async fn exec(config: Config, args: &ArgMatches) -> MegaResult {
     let server_matchers = SshOptions::from_arg_matches(args)
         .map_err(|err| err.exit())
         .unwrap();
     println!("{server_matchers:#?}");
-    ssh::start_server(&server_matchers).await;
+    config.check_data_source(&server_matchers.common.data_source)?;
+    ssh::start_server(&config, &server_matchers).await;
     Ok(())
 }
 
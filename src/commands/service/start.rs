@@ -2,18 +2,21 @@ use clap::{ArgMatches, Args, Command, FromArgMatches, ValueEnum};
 
 use common::{errors::MegaResult, model::CommonOptions};
 use gateway::{
+    git_server::{self, GitCustom, GitOptions},
     https_server::{self, HttpCustom, HttpOptions},
     ssh_server::{self, SshCustom, SshOptions},
 };
 use p2p::peer::{self, P2pCustom, P2pOptions};
 
 use crate::cli::Config;
+use crate::commands::service::supervisor::Supervisor;
 
 #[derive(Debug, PartialEq, Clone, ValueEnum)]
 enum ServiceType {
     Http,
     Https,
     Ssh,
+    Git,
     P2p,
 }
 
@@ -30,6 +33,9 @@ pub struct StartOptions {
     #[clap(flatten)]
     pub ssh: SshCustom,
 
+    #[clap(flatten)]
+    pub git: GitCustom,
+
     #[clap(flatten)]
     pub p2p: P2pCustom,
 }
@@ -40,50 +46,69 @@ pub fn cli() -> Command {
     )
 }
 
-pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+pub(crate) async fn exec(config: Config, args: &ArgMatches) -> MegaResult {
     let server_matchers = StartOptions::from_arg_matches(args)
         .map_err(|err| err.exit())
         .unwrap();
 
     println!("{server_matchers:#?}");
+    config.check_data_source(&server_matchers.common.data_source)?;
 
     let service_type = server_matchers.service;
+    let http_port = server_matchers.http.http_port;
+    let mut supervisor = Supervisor::new(&config.service);
 
-    let http_server = if service_type.contains(&ServiceType::Http)
-        || service_type.contains(&ServiceType::Https)
-    {
+    if service_type.contains(&ServiceType::Http) || service_type.contains(&ServiceType::Https) {
         let http = HttpOptions {
             common: server_matchers.common.clone(),
             custom: server_matchers.http,
         };
-        tokio::spawn(async move { https_server::start_server(&http).await })
-    } else {
-        tokio::task::spawn(async {})
-    };
-
-    let ssh_server = if service_type.contains(&ServiceType::Ssh) {
-        let ssh = SshOptions {
+        let config = config.clone();
+        supervisor.add("http", move || {
+            let (config, http) = (config.clone(), http.clone());
+            async move { https_server::start_server(&config, &http).await }
+        });
+    }
+
+    if service_type.contains(&ServiceType::Ssh) {
+        let mut ssh = SshOptions {
             common: server_matchers.common.clone(),
             custom: server_matchers.ssh,
         };
-        tokio::spawn(async move { ssh_server::start_server(&ssh).await })
-    } else {
-        tokio::task::spawn(async {})
-    };
-
-    let p2p_server = if service_type.contains(&ServiceType::P2p) {
+        // LFS clients authenticated over ssh are sent to the http service started along
+        ssh.custom
+            .lfs_url
+            .get_or_insert_with(|| format!("http://{}:{}", server_matchers.common.host, http_port));
+        let config = config.clone();
+        supervisor.add("ssh", move || {
+            let (config, ssh) = (config.clone(), ssh.clone());
+            async move { ssh_server::start_server(&config, &ssh).await }
+        });
+    }
+
+    if service_type.contains(&ServiceType::Git) {
+        let git = GitOptions {
+            common: server_matchers.common.clone(),
+            custom: server_matchers.git,
+        };
+        let config = config.clone();
+        supervisor.add("git", move || {
+            let (config, git) = (config.clone(), git.clone());
+            async move { git_server::start_server(&config, &git).await }
+        });
+    }
+
+    if service_type.contains(&ServiceType::P2p) {
         let p2p = P2pOptions {
             common: server_matchers.common.clone(),
             custom: server_matchers.p2p,
         };
-        tokio::spawn(async move {
-            peer::run(&p2p).await.unwrap();
-        })
-    } else {
-        tokio::task::spawn(async {})
-    };
-
-    let _ = tokio::join!(http_server, ssh_server, p2p_server);
-
-    Ok(())
+        let config = config.clone();
+        supervisor.add("p2p", move || {
+            let (config, p2p) = (config.clone(), p2p.clone());
+            async move { peer::run(&config, &p2p).await.unwrap() }
+        });
+    }
+
+    supervisor.run().await
 }
@@ -0,0 +1,179 @@
+//!
+//! Runs the services of `mega service start` side by side. A service that panics or stops by
+//! itself is restarted or shuts down all the others, following `service.on_failure`.
+//!
+//! SIGINT and SIGTERM shut down gracefully: the process stops being ready, new pushes are
+//! refused, and the listeners are closed once the pushes in progress are done or
+//! `service.shutdown_timeout` has expired. A second signal closes them right away.
+//!
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use tokio::task::{Id, JoinSet};
+
+use common::config::{OnFailure, ServiceConfig};
+use common::errors::{MegaError, MegaResult};
+use common::lifecycle::lifecycle;
+
+type StartFn = Box<dyn Fn() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+struct Service {
+    name: &'static str,
+    start: StartFn,
+    restarts: u32,
+}
+
+pub(crate) struct Supervisor {
+    config: ServiceConfig,
+    services: Vec<Service>,
+}
+
+impl Supervisor {
+    pub(crate) fn new(config: &ServiceConfig) -> Self {
+        Supervisor {
+            config: config.clone(),
+            services: vec![],
+        }
+    }
+
+    /// Adds a service, `start` is called again each time it is restarted.
+    pub(crate) fn add<F, Fut>(&mut self, name: &'static str, start: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.services.push(Service {
+            name,
+            start: Box::new(move || Box::pin(start())),
+            restarts: 0,
+        });
+    }
+
+    /// # Runs the services until a shutdown signal or a failure that isn't restarted.
+    ///
+    /// Returns an error naming the failed service in the latter case.
+    pub(crate) async fn run(mut self) -> MegaResult {
+        if self.services.is_empty() {
+            return Ok(());
+        }
+        let mut tasks = JoinSet::new();
+        let mut running: HashMap<Id, usize> = HashMap::new();
+        for (index, service) in self.services.iter().enumerate() {
+            let id = tasks.spawn((service.start)()).id();
+            running.insert(id, index);
+        }
+        lifecycle().set_ready(true);
+
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+        let failure = loop {
+            tokio::select! {
+                _ = &mut shutdown => break None,
+                Some(result) = tasks.join_next_with_id() => {
+                    let (id, reason) = match result {
+                        Ok((id, ())) => (id, "stopped"),
+                        Err(e) if e.is_panic() => (e.id(), "panicked"),
+                        Err(e) => (e.id(), "was cancelled"),
+                    };
+                    let index = running.remove(&id).unwrap();
+                    let service = &mut self.services[index];
+                    tracing::error!("the {} service {}", service.name, reason);
+                    if self.config.on_failure == OnFailure::Restart
+                        && service.restarts < self.config.max_restarts
+                    {
+                        service.restarts += 1;
+                        tracing::warn!(
+                            "restarting the {} service ({}/{})",
+                            service.name,
+                            service.restarts,
+                            self.config.max_restarts
+                        );
+                        // backs off so that a service failing at startup doesn't spin
+                        let delay = Duration::from_secs(u64::from(service.restarts));
+                        let start = (service.start)();
+                        let id = tasks
+                            .spawn(async move {
+                                tokio::time::sleep(delay).await;
+                                start.await
+                            })
+                            .id();
+                        running.insert(id, index);
+                        continue;
+                    }
+                    break Some(format!("the {} service {}", service.name, reason));
+                }
+            }
+        };
+
+        lifecycle().stop();
+        let deadline = Duration::from_secs(self.config.shutdown_timeout);
+        tracing::info!(
+            "shutting down, waiting up to {:?} for {} pushes in progress",
+            deadline,
+            lifecycle().in_flight()
+        );
+        tokio::select! {
+            drained = lifecycle().drain(deadline) => if !drained {
+                tracing::warn!(
+                    "{} pushes still in progress, closing the listeners",
+                    lifecycle().in_flight()
+                );
+            },
+            _ = shutdown_signal() => tracing::warn!("signal received again, closing the listeners"),
+        }
+        tasks.shutdown().await;
+
+        match failure {
+            Some(reason) => Err(MegaError::new(anyhow::anyhow!(reason), 1)),
+            None => Ok(()),
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use common::config::{OnFailure, ServiceConfig};
+
+    use crate::commands::service::supervisor::Supervisor;
+
+    #[tokio::test]
+    async fn test_restart_then_fail() {
+        let config = ServiceConfig {
+            shutdown_timeout: 0,
+            on_failure: OnFailure::Restart,
+            max_restarts: 1,
+        };
+        let starts = Arc::new(AtomicU32::new(0));
+        let mut supervisor = Supervisor::new(&config);
+        let counter = starts.clone();
+        supervisor.add("crashing", move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { panic!("crash") }
+        });
+        supervisor.add("idle", std::future::pending);
+
+        let err = supervisor.run().await.unwrap_err();
+        assert_eq!(err.to_string(), "the crashing service panicked");
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,27 @@
+//!
+//! `mega token` manages the access tokens the http service authenticates users with.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use common::errors::MegaResult;
+use gateway::token_registry::{exec_token_command, TokenOptions};
+
+use crate::cli::Config;
+
+pub fn cli() -> Command {
+    TokenOptions::augment_args_for_update(
+        Command::new("token").about("Manage the access tokens of the users"),
+    )
+}
+
+#[tokio::main]
+pub(crate) async fn exec(config: Config, args: &ArgMatches) -> MegaResult {
+    let options = TokenOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    config.check_data_source(&options.data_source)?;
+    exec_token_command(&config, &options).await
+}
+
+#[cfg(test)]
+mod tests {}
@@ -13,3 +13,12 @@ pub mod refs;
 pub mod issue;
 pub mod repo_directory;
 pub mod pull_request;
+pub mod protected_ref;
+pub mod path_owner;
+pub mod dir_permission;
+pub mod subrepo_commit;
+pub mod users;
+pub mod ssh_keys;
+pub mod access_token;
+pub mod audit_log;
+pub mod pack_index;
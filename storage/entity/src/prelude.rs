@@ -10,3 +10,12 @@ pub use crate::node::Entity as Node;
 pub use crate::refs::Entity as Refs;
 pub use crate::repo_directory::Entity as RepoDirectory;
 pub use crate::pull_request::Entity as PullRequest;
+pub use crate::protected_ref::Entity as ProtectedRef;
+pub use crate::path_owner::Entity as PathOwner;
+pub use crate::dir_permission::Entity as DirPermission;
+pub use crate::subrepo_commit::Entity as SubrepoCommit;
+pub use crate::users::Entity as Users;
+pub use crate::ssh_keys::Entity as SshKeys;
+pub use crate::access_token::Entity as AccessToken;
+pub use crate::audit_log::Entity as AuditLog;
+pub use crate::pack_index::Entity as PackIndex;
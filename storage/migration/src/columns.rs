@@ -0,0 +1,66 @@
+//!
+//! The column and index definitions shared by the migrations.
+//!
+use sea_orm_migration::prelude::*;
+
+/// Creates the indexes that don't exist yet, MySQL has no CREATE INDEX IF NOT EXISTS.
+pub async fn create_indexes(
+    manager: &SchemaManager<'_>,
+    indexes: Vec<(String, &'static str, IndexCreateStatement)>,
+) -> Result<(), DbErr> {
+    for (table, name, index) in indexes {
+        if !manager.has_index(&table, name).await? {
+            manager.create_index(index).await?;
+        }
+    }
+    Ok(())
+}
+
+/// An index of a lookup, with its name and the name of its table.
+pub fn index<T: Iden + 'static, const N: usize>(
+    name: &'static str,
+    table: T,
+    cols: [T; N],
+) -> (String, &'static str, IndexCreateStatement) {
+    let mut index = Index::create();
+    index.name(name).table(Alias::new(table.to_string()));
+    for col in cols {
+        index.col(col);
+    }
+    (table.to_string(), name, index)
+}
+
+pub fn serial<T: IntoIden>(column: T) -> ColumnDef {
+    ColumnDef::new(column)
+        .integer()
+        .not_null()
+        .auto_increment()
+        .primary_key()
+        .to_owned()
+}
+
+pub fn big_serial<T: IntoIden>(column: T) -> ColumnDef {
+    ColumnDef::new(column)
+        .big_integer()
+        .not_null()
+        .auto_increment()
+        .primary_key()
+        .to_owned()
+}
+
+/// The ids generated by mega or given by the forge.
+pub fn big_id<T: IntoIden>(column: T) -> ColumnDef {
+    ColumnDef::new(column)
+        .big_integer()
+        .not_null()
+        .primary_key()
+        .to_owned()
+}
+
+pub fn timestamp<T: IntoIden>(column: T) -> ColumnDef {
+    ColumnDef::new(column).date_time().not_null().to_owned()
+}
+
+pub fn unique(name: &str) -> IndexCreateStatement {
+    Index::create().name(name).unique().to_owned()
+}
@@ -0,0 +1,36 @@
+//!
+//! The migrations of the schema, the only definition of the tables for every backend. They are
+//! applied in order by `mega db migrate` and `mega init` and recorded in the `seaql_migrations`
+//! table, so that each one runs once per database.
+//!
+//! A change of the schema is a new migration appended to `Migrator::migrations`, whose `down`
+//! undoes it for `mega db rollback`, and the entities in `storage/entity` are updated to match.
+//! The initial migration, which adopts the tables of the former SQL dumps, can't be rolled back.
+//!
+pub use sea_orm_migration::prelude::*;
+
+mod columns;
+mod m20231106_000001_init;
+mod m20240301_000001_pack_index;
+mod m20240302_000001_ref_policies;
+mod m20240302_000002_subrepo_commit;
+mod m20240302_000003_users;
+mod m20240302_000004_audit_log;
+mod m20240310_000001_refs_unique;
+
+pub struct Migrator;
+
+#[async_trait::async_trait]
+impl MigratorTrait for Migrator {
+    fn migrations() -> Vec<Box<dyn MigrationTrait>> {
+        vec![
+            Box::new(m20231106_000001_init::Migration),
+            Box::new(m20240301_000001_pack_index::Migration),
+            Box::new(m20240302_000001_ref_policies::Migration),
+            Box::new(m20240302_000002_subrepo_commit::Migration),
+            Box::new(m20240302_000003_users::Migration),
+            Box::new(m20240302_000004_audit_log::Migration),
+            Box::new(m20240310_000001_refs_unique::Migration),
+        ]
+    }
+}
@@ -0,0 +1,416 @@
+//!
+//! The tables of the entities in `storage/entity` that were created by hand from the SQL dumps
+//! before the migrations, so the tables and indexes that already exist are left as they are.
+//! The tables added since have migrations of their own.
+//!
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::DatabaseBackend;
+
+use crate::columns::{big_id, big_serial, create_indexes, index, serial, timestamp, unique};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for table in tables(manager.get_database_backend()) {
+            manager.create_table(table).await?;
+        }
+        create_indexes(manager, indexes()).await
+    }
+
+    // the tables may have been adopted from a database older than the migrations, with data
+    // that dropping them would lose
+    async fn down(&self, _manager: &SchemaManager) -> Result<(), DbErr> {
+        Err(DbErr::Migration(String::from(
+            "the initial tables may predate the migrations and are never dropped",
+        )))
+    }
+}
+
+fn tables(backend: DatabaseBackend) -> Vec<TableCreateStatement> {
+    // Postgres keeps the parent ids of a commit in an array, the other backends in a JSON array
+    let mut pid = ColumnDef::new(Commit::Pid);
+    match backend {
+        DatabaseBackend::Postgres => pid.array(ColumnType::Text),
+        DatabaseBackend::MySql | DatabaseBackend::Sqlite => pid.text().not_null(),
+    };
+    vec![
+        Table::create()
+            .table(Commit::Table)
+            .if_not_exists()
+            .col(&mut serial(Commit::Id))
+            .col(ColumnDef::new(Commit::GitId).string_len(40).not_null())
+            .col(ColumnDef::new(Commit::Tree).string_len(40).not_null())
+            .col(&mut pid)
+            .col(ColumnDef::new(Commit::RepoPath).string_len(512).not_null())
+            .col(ColumnDef::new(Commit::Author).text())
+            .col(ColumnDef::new(Commit::Committer).text())
+            .col(ColumnDef::new(Commit::Content).text())
+            .col(&mut timestamp(Commit::CreatedAt))
+            .col(&mut timestamp(Commit::UpdatedAt))
+            .index(unique("uniq_c_git_id").col(Commit::GitId))
+            .to_owned(),
+        Table::create()
+            .table(Node::Table)
+            .if_not_exists()
+            .col(&mut big_serial(Node::Id))
+            .col(ColumnDef::new(Node::NodeId).big_integer().not_null())
+            .col(ColumnDef::new(Node::GitId).string_len(40).not_null())
+            .col(ColumnDef::new(Node::LastCommit).string_len(40).not_null())
+            .col(ColumnDef::new(Node::NodeType).string_len(16).not_null())
+            .col(ColumnDef::new(Node::Name).string_len(128))
+            .col(ColumnDef::new(Node::Mode).binary().not_null())
+            .col(ColumnDef::new(Node::ContentSha).string_len(40))
+            .col(ColumnDef::new(Node::Size).integer().not_null())
+            .col(ColumnDef::new(Node::RepoPath).string_len(512).not_null())
+            .col(ColumnDef::new(Node::FullPath).text().not_null())
+            .col(&mut timestamp(Node::CreatedAt))
+            .col(&mut timestamp(Node::UpdatedAt))
+            .index(unique("uniq_n_git_id").col(Node::GitId))
+            .to_owned(),
+        Table::create()
+            .table(Refs::Table)
+            .if_not_exists()
+            .col(&mut serial(Refs::Id))
+            .col(ColumnDef::new(Refs::RepoPath).string_len(512).not_null())
+            .col(ColumnDef::new(Refs::RefName).text().not_null())
+            .col(ColumnDef::new(Refs::RefGitId).string_len(40).not_null())
+            .col(&mut timestamp(Refs::CreatedAt))
+            .col(&mut timestamp(Refs::UpdatedAt))
+            .to_owned(),
+        Table::create()
+            .table(Mr::Table)
+            .if_not_exists()
+            .col(&mut big_id(Mr::Id))
+            .col(ColumnDef::new(Mr::MrId).big_integer().not_null())
+            .col(ColumnDef::new(Mr::GitId).string_len(40).not_null())
+            .col(ColumnDef::new(Mr::ObjectType).string_len(16).not_null())
+            .col(&mut timestamp(Mr::CreatedAt))
+            .to_owned(),
+        Table::create()
+            .table(Objects::Table)
+            .if_not_exists()
+            .col(&mut big_id(Objects::Id))
+            .col(ColumnDef::new(Objects::GitId).string_len(40).not_null())
+            .col(
+                ColumnDef::new(Objects::ObjectType)
+                    .string_len(16)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(Objects::Data)
+                    .blob(BlobSize::Long)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(Objects::Link).string_len(512))
+            .index(unique("uniq_o_git_id").col(Objects::GitId))
+            .to_owned(),
+        Table::create()
+            .table(MrInfo::Table)
+            .if_not_exists()
+            .col(&mut serial(MrInfo::Id))
+            .col(ColumnDef::new(MrInfo::MrId).big_integer().not_null())
+            .col(ColumnDef::new(MrInfo::MrMsg).string_len(255).not_null())
+            .col(&mut timestamp(MrInfo::MrDate))
+            .col(&mut timestamp(MrInfo::CreatedAt))
+            .col(&mut timestamp(MrInfo::UpdatedAt))
+            .to_owned(),
+        Table::create()
+            .table(Locks::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(Locks::Id)
+                    .string_len(200)
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(Locks::Data).text().not_null())
+            .to_owned(),
+        Table::create()
+            .table(Meta::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(Meta::Oid)
+                    .string_len(64)
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(Meta::Size).big_integer().not_null())
+            .col(ColumnDef::new(Meta::Exist).boolean().not_null())
+            .to_owned(),
+        Table::create()
+            .table(Issue::Table)
+            .if_not_exists()
+            .col(&mut big_id(Issue::Id))
+            .col(ColumnDef::new(Issue::Number).big_integer().not_null())
+            .col(ColumnDef::new(Issue::Title).string_len(255).not_null())
+            .col(ColumnDef::new(Issue::SenderName).string_len(255).not_null())
+            .col(ColumnDef::new(Issue::SenderId).big_integer().not_null())
+            .col(ColumnDef::new(Issue::State).string_len(255).not_null())
+            .col(&mut timestamp(Issue::CreatedAt))
+            .col(&mut timestamp(Issue::UpdatedAt))
+            .col(ColumnDef::new(Issue::ClosedAt).date_time())
+            .col(ColumnDef::new(Issue::RepoPath).text().not_null())
+            .col(ColumnDef::new(Issue::RepoId).big_integer().not_null())
+            .to_owned(),
+        Table::create()
+            .table(RepoDirectory::Table)
+            .if_not_exists()
+            .col(&mut serial(RepoDirectory::Id))
+            .col(
+                ColumnDef::new(RepoDirectory::Pid)
+                    .integer()
+                    .not_null()
+                    .default(0),
+            )
+            .col(
+                ColumnDef::new(RepoDirectory::Name)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(RepoDirectory::IsRepo).boolean().not_null())
+            .col(
+                ColumnDef::new(RepoDirectory::FullPath)
+                    .string_len(512)
+                    .not_null(),
+            )
+            .col(&mut timestamp(RepoDirectory::CreatedAt))
+            .col(&mut timestamp(RepoDirectory::UpdatedAt))
+            .index(unique("uniq_d_full_path").col(RepoDirectory::FullPath))
+            .to_owned(),
+        Table::create()
+            .table(PullRequest::Table)
+            .if_not_exists()
+            .col(&mut big_id(PullRequest::Id))
+            .col(ColumnDef::new(PullRequest::Number).big_integer().not_null())
+            .col(
+                ColumnDef::new(PullRequest::Title)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PullRequest::State)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(&mut timestamp(PullRequest::CreatedAt))
+            .col(&mut timestamp(PullRequest::UpdatedAt))
+            .col(ColumnDef::new(PullRequest::ClosedAt).date_time())
+            .col(ColumnDef::new(PullRequest::MergedAt).date_time())
+            .col(ColumnDef::new(PullRequest::MergeCommitSha).string_len(200))
+            .col(ColumnDef::new(PullRequest::RepoPath).text().not_null())
+            .col(ColumnDef::new(PullRequest::RepoId).big_integer().not_null())
+            .col(
+                ColumnDef::new(PullRequest::SenderName)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PullRequest::SenderId)
+                    .big_integer()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PullRequest::UserName)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(PullRequest::UserId).big_integer().not_null())
+            .col(
+                ColumnDef::new(PullRequest::CommitsUrl)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PullRequest::PatchUrl)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PullRequest::HeadLabel)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PullRequest::HeadRef)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PullRequest::BaseLabel)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PullRequest::BaseRef)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .to_owned(),
+    ]
+}
+
+// the indexes of the lookups, with their name and the name of their table
+fn indexes() -> Vec<(String, &'static str, IndexCreateStatement)> {
+    vec![
+        index("idx_c_tree", Commit::Table, [Commit::Tree]),
+        index("idx_c_repo_path", Commit::Table, [Commit::RepoPath]),
+        index("idx_node_name", Node::Table, [Node::Name]),
+        index("idx_node_repo_path", Node::Table, [Node::RepoPath]),
+        index("idx_refs_repo_path", Refs::Table, [Refs::RepoPath]),
+        index("idx_mr_hash", Mr::Table, [Mr::GitId]),
+        index("idx_mr_id", Mr::Table, [Mr::MrId, Mr::ObjectType]),
+        index("idx_info_mr_id", MrInfo::Table, [MrInfo::MrId]),
+        index("idx_dir_pid", RepoDirectory::Table, [RepoDirectory::Pid]),
+    ]
+}
+
+#[derive(DeriveIden)]
+enum Commit {
+    Table,
+    Id,
+    GitId,
+    Tree,
+    Pid,
+    RepoPath,
+    Author,
+    Committer,
+    Content,
+    CreatedAt,
+    UpdatedAt,
+}
+
+// the variants are the columns
+#[allow(clippy::enum_variant_names)]
+#[derive(DeriveIden)]
+enum Node {
+    Table,
+    Id,
+    NodeId,
+    GitId,
+    LastCommit,
+    NodeType,
+    Name,
+    Mode,
+    ContentSha,
+    Size,
+    RepoPath,
+    FullPath,
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[derive(DeriveIden)]
+enum Refs {
+    Table,
+    Id,
+    RepoPath,
+    RefName,
+    RefGitId,
+    CreatedAt,
+    UpdatedAt,
+}
+
+// the variants are the columns
+#[allow(clippy::enum_variant_names)]
+#[derive(DeriveIden)]
+enum Mr {
+    Table,
+    Id,
+    MrId,
+    GitId,
+    ObjectType,
+    CreatedAt,
+}
+
+#[derive(DeriveIden)]
+enum Objects {
+    Table,
+    Id,
+    GitId,
+    ObjectType,
+    Data,
+    Link,
+}
+
+#[derive(DeriveIden)]
+enum MrInfo {
+    Table,
+    Id,
+    MrId,
+    MrMsg,
+    MrDate,
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[derive(DeriveIden)]
+enum Locks {
+    Table,
+    Id,
+    Data,
+}
+
+#[derive(DeriveIden)]
+enum Meta {
+    Table,
+    Oid,
+    Size,
+    Exist,
+}
+
+#[derive(DeriveIden)]
+enum Issue {
+    Table,
+    Id,
+    Number,
+    Title,
+    SenderName,
+    SenderId,
+    State,
+    CreatedAt,
+    UpdatedAt,
+    ClosedAt,
+    RepoPath,
+    RepoId,
+}
+
+#[derive(DeriveIden)]
+enum RepoDirectory {
+    Table,
+    Id,
+    Pid,
+    Name,
+    IsRepo,
+    FullPath,
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[derive(DeriveIden)]
+enum PullRequest {
+    Table,
+    Id,
+    Number,
+    Title,
+    State,
+    CreatedAt,
+    UpdatedAt,
+    ClosedAt,
+    MergedAt,
+    MergeCommitSha,
+    RepoPath,
+    RepoId,
+    SenderName,
+    SenderId,
+    UserName,
+    UserId,
+    CommitsUrl,
+    PatchUrl,
+    HeadLabel,
+    HeadRef,
+    BaseLabel,
+    BaseRef,
+}
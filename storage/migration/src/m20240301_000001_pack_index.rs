@@ -0,0 +1,60 @@
+//!
+//! The `pack_index` table of the pack store, see `storage.obj_store`: the pack and the offset
+//! in it of every object received while the packs are kept as they are.
+//!
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PackIndex::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(PackIndex::GitId)
+                            .string_len(40)
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(PackIndex::PackId).string_len(40).not_null())
+                    .col(
+                        ColumnDef::new(PackIndex::PackOffset)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        if !manager.has_index("pack_index", "idx_pi_pack_id").await? {
+            manager
+                .create_index(
+                    Index::create()
+                        .name("idx_pi_pack_id")
+                        .table(PackIndex::Table)
+                        .col(PackIndex::PackId)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PackIndex::Table).if_exists().to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum PackIndex {
+    Table,
+    GitId,
+    PackId,
+    PackOffset,
+}
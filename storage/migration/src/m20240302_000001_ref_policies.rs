@@ -0,0 +1,145 @@
+//!
+//! The policies checked on push, see `git::protocol::policy`: the protected refs, the owners
+//! of paths and the permissions of users on directories.
+//!
+use sea_orm_migration::prelude::*;
+
+use crate::columns::{create_indexes, index, serial, timestamp, unique};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ProtectedRef::Table)
+                    .if_not_exists()
+                    .col(&mut serial(ProtectedRef::Id))
+                    .col(
+                        ColumnDef::new(ProtectedRef::RepoPath)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ProtectedRef::RefPattern)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(&mut timestamp(ProtectedRef::CreatedAt))
+                    .col(&mut timestamp(ProtectedRef::UpdatedAt))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(PathOwner::Table)
+                    .if_not_exists()
+                    .col(&mut serial(PathOwner::Id))
+                    .col(ColumnDef::new(PathOwner::Path).string_len(512).not_null())
+                    .col(ColumnDef::new(PathOwner::Owners).text().not_null())
+                    .col(&mut timestamp(PathOwner::CreatedAt))
+                    .col(&mut timestamp(PathOwner::UpdatedAt))
+                    .index(unique("uniq_po_path").col(PathOwner::Path))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(DirPermission::Table)
+                    .if_not_exists()
+                    .col(&mut serial(DirPermission::Id))
+                    .col(
+                        ColumnDef::new(DirPermission::Path)
+                            .string_len(512)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(DirPermission::UserName)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(DirPermission::Permission)
+                            .string_len(16)
+                            .not_null(),
+                    )
+                    .col(&mut timestamp(DirPermission::CreatedAt))
+                    .col(&mut timestamp(DirPermission::UpdatedAt))
+                    .index(
+                        unique("uniq_dp_path_user")
+                            .col(DirPermission::Path)
+                            .col(DirPermission::UserName),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        create_indexes(
+            manager,
+            vec![index(
+                "idx_pr_repo_path",
+                ProtectedRef::Table,
+                [ProtectedRef::RepoPath],
+            )],
+        )
+        .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(
+                Table::drop()
+                    .table(DirPermission::Table)
+                    .if_exists()
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_table(Table::drop().table(PathOwner::Table).if_exists().to_owned())
+            .await?;
+        manager
+            .drop_table(
+                Table::drop()
+                    .table(ProtectedRef::Table)
+                    .if_exists()
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum ProtectedRef {
+    Table,
+    Id,
+    RepoPath,
+    RefPattern,
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[derive(DeriveIden)]
+enum PathOwner {
+    Table,
+    Id,
+    Path,
+    Owners,
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[derive(DeriveIden)]
+enum DirPermission {
+    Table,
+    Id,
+    Path,
+    UserName,
+    Permission,
+    CreatedAt,
+    UpdatedAt,
+}
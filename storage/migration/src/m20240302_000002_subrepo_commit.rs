@@ -0,0 +1,83 @@
+//!
+//! The pairs of a sub-directory repo commit and of the trunk commit it was synthesized from or
+//! replayed onto, see `git::structure::subrepo`.
+//!
+use sea_orm_migration::prelude::*;
+
+use crate::columns::{create_indexes, index, serial, timestamp, unique};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(SubrepoCommit::Table)
+                    .if_not_exists()
+                    .col(&mut serial(SubrepoCommit::Id))
+                    .col(
+                        ColumnDef::new(SubrepoCommit::RepoPath)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(SubrepoCommit::CommitId)
+                            .string_len(40)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(SubrepoCommit::TrunkPath)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(SubrepoCommit::TrunkCommitId)
+                            .string_len(40)
+                            .not_null(),
+                    )
+                    .col(&mut timestamp(SubrepoCommit::CreatedAt))
+                    .index(
+                        unique("uniq_sc_path_trunk_commit")
+                            .col(SubrepoCommit::RepoPath)
+                            .col(SubrepoCommit::TrunkCommitId),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        create_indexes(
+            manager,
+            vec![index(
+                "idx_sc_path_commit",
+                SubrepoCommit::Table,
+                [SubrepoCommit::RepoPath, SubrepoCommit::CommitId],
+            )],
+        )
+        .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(
+                Table::drop()
+                    .table(SubrepoCommit::Table)
+                    .if_exists()
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum SubrepoCommit {
+    Table,
+    Id,
+    RepoPath,
+    CommitId,
+    TrunkPath,
+    TrunkCommitId,
+    CreatedAt,
+}
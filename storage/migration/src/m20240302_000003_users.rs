@@ -0,0 +1,130 @@
+//!
+//! The users, with the public keys they authenticate with over ssh and their access tokens for
+//! the http service.
+//!
+use sea_orm_migration::prelude::*;
+
+use crate::columns::{create_indexes, index, serial, timestamp, unique};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Users::Table)
+                    .if_not_exists()
+                    .col(&mut serial(Users::Id))
+                    .col(ColumnDef::new(Users::Name).string_len(255).not_null())
+                    .col(&mut timestamp(Users::CreatedAt))
+                    .col(&mut timestamp(Users::UpdatedAt))
+                    .index(unique("uniq_u_name").col(Users::Name))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(SshKeys::Table)
+                    .if_not_exists()
+                    .col(&mut serial(SshKeys::Id))
+                    .col(ColumnDef::new(SshKeys::UserId).integer().not_null())
+                    .col(ColumnDef::new(SshKeys::Title).string_len(255).not_null())
+                    .col(ColumnDef::new(SshKeys::SshKey).text().not_null())
+                    .col(
+                        ColumnDef::new(SshKeys::Fingerprint)
+                            .string_len(64)
+                            .not_null(),
+                    )
+                    .col(&mut timestamp(SshKeys::CreatedAt))
+                    .index(unique("uniq_sk_fingerprint").col(SshKeys::Fingerprint))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(AccessToken::Table)
+                    .if_not_exists()
+                    .col(&mut serial(AccessToken::Id))
+                    .col(ColumnDef::new(AccessToken::UserId).integer().not_null())
+                    .col(ColumnDef::new(AccessToken::Name).string_len(255).not_null())
+                    .col(
+                        ColumnDef::new(AccessToken::TokenHash)
+                            .string_len(64)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AccessToken::Scopes)
+                            .string_len(64)
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(AccessToken::ExpiresAt).date_time())
+                    .col(&mut timestamp(AccessToken::CreatedAt))
+                    .index(unique("uniq_at_token_hash").col(AccessToken::TokenHash))
+                    .to_owned(),
+            )
+            .await?;
+        create_indexes(
+            manager,
+            vec![
+                index("idx_sk_user_id", SshKeys::Table, [SshKeys::UserId]),
+                index("idx_at_user_id", AccessToken::Table, [AccessToken::UserId]),
+            ],
+        )
+        .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(
+                Table::drop()
+                    .table(AccessToken::Table)
+                    .if_exists()
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_table(Table::drop().table(SshKeys::Table).if_exists().to_owned())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Users::Table).if_exists().to_owned())
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Users {
+    Table,
+    Id,
+    Name,
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[derive(DeriveIden)]
+enum SshKeys {
+    Table,
+    Id,
+    UserId,
+    Title,
+    SshKey,
+    Fingerprint,
+    CreatedAt,
+}
+
+#[derive(DeriveIden)]
+enum AccessToken {
+    Table,
+    Id,
+    UserId,
+    Name,
+    TokenHash,
+    Scopes,
+    ExpiresAt,
+    CreatedAt,
+}
@@ -0,0 +1,70 @@
+//!
+//! The audit log of the pushes, ref updates, LFS writes and lock operations.
+//!
+use sea_orm_migration::prelude::*;
+
+use crate::columns::{big_serial, create_indexes, index, timestamp};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(AuditLog::Table)
+                    .if_not_exists()
+                    .col(&mut big_serial(AuditLog::Id))
+                    .col(ColumnDef::new(AuditLog::Action).string_len(32).not_null())
+                    .col(ColumnDef::new(AuditLog::Actor).string_len(255))
+                    .col(ColumnDef::new(AuditLog::Protocol).string_len(16).not_null())
+                    .col(
+                        ColumnDef::new(AuditLog::RepoPath)
+                            .string_len(512)
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(AuditLog::Target).text().not_null())
+                    .col(ColumnDef::new(AuditLog::OldId).string_len(64))
+                    .col(ColumnDef::new(AuditLog::NewId).string_len(64))
+                    .col(ColumnDef::new(AuditLog::Result).text().not_null())
+                    .col(ColumnDef::new(AuditLog::ClientAddr).string_len(128))
+                    .col(&mut timestamp(AuditLog::CreatedAt))
+                    .to_owned(),
+            )
+            .await?;
+        create_indexes(
+            manager,
+            vec![
+                index("idx_al_repo_path", AuditLog::Table, [AuditLog::RepoPath]),
+                index("idx_al_actor", AuditLog::Table, [AuditLog::Actor]),
+                index("idx_al_created_at", AuditLog::Table, [AuditLog::CreatedAt]),
+            ],
+        )
+        .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AuditLog::Table).if_exists().to_owned())
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum AuditLog {
+    Table,
+    Id,
+    Action,
+    Actor,
+    Protocol,
+    RepoPath,
+    Target,
+    OldId,
+    NewId,
+    Result,
+    ClientAddr,
+    CreatedAt,
+}
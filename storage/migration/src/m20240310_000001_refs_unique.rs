@@ -0,0 +1,59 @@
+//!
+//! A unique index on the `refs` by repo path and name, so that two pushes creating the same ref
+//! can't both insert it: the second insert conflicts and its push is rejected as stale. The
+//! duplicates left by earlier races are removed first, keeping the last one written.
+//!
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, DatabaseBackend};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if manager.has_index("refs", "uniq_refs_path_name").await? {
+            return Ok(());
+        }
+        manager
+            .get_connection()
+            .execute_unprepared(
+                "DELETE FROM refs WHERE id NOT IN \
+                 (SELECT id FROM (SELECT MAX(id) AS id FROM refs GROUP BY repo_path, ref_name) AS kept)",
+            )
+            .await?;
+        let mut index = Index::create()
+            .name("uniq_refs_path_name")
+            .table(Refs::Table)
+            .col(Refs::RepoPath)
+            .unique()
+            .to_owned();
+        // MySQL can only index a prefix of a TEXT column
+        match manager.get_database_backend() {
+            DatabaseBackend::MySql => index.col((Refs::RefName, 255)),
+            _ => index.col(Refs::RefName),
+        };
+        manager.create_index(index).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if !manager.has_index("refs", "uniq_refs_path_name").await? {
+            return Ok(());
+        }
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("uniq_refs_path_name")
+                    .table(Refs::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Refs {
+    Table,
+    RepoPath,
+    RefName,
+}
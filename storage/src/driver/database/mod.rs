@@ -1,56 +1,76 @@
-use std::{env, sync::Arc, time::Duration};
+use std::{sync::Arc, time::Duration};
 
-use sea_orm::{ConnectOptions, Database};
+use sea_orm::{ConnectOptions, Database, DatabaseConnection};
 use tracing::log;
 
-use self::{mysql_storage::MysqlStorage, pg_storage::PgStorage, storage::ObjectStorage};
+use self::{
+    mysql_storage::MysqlStorage, pg_storage::PgStorage, sqlite_storage::SqliteStorage,
+    storage::ObjectStorage,
+};
 use crate::utils::id_generator;
+use common::config::{Config, DbConfig};
 use common::enums::DataSource;
 
 pub mod mysql_storage;
 pub mod pg_storage;
+pub mod sqlite_storage;
 pub mod storage;
 
-pub async fn init(data_source: &DataSource) -> Arc<dyn ObjectStorage> {
+pub async fn init(config: &Config, data_source: &DataSource) -> Arc<dyn ObjectStorage> {
     id_generator::set_up_options().unwrap();
 
-    let db_url = match data_source {
-        DataSource::Mysql => {
-            env::var("MEGA_DB_MYSQL_URL").expect("DATABASE_URL is not set in .env file")
-        }
-        DataSource::Postgres => {
-            env::var("MEGA_DB_POSTGRESQL_URL").expect("DATABASE_URL is not set in .env file")
-        }
-    };
-
-    let max_connections = env::var("MEGA_DB_MAX_CONNECTIONS")
-        .expect("MEGA_DB_MAX_CONNECTIONS not configured")
-        .parse::<u32>()
-        .unwrap();
-    let min_connections = env::var("MEGA_DB_MIN_CONNECTIONS")
-        .expect("MEGA_DB_MAX_CONNECTIONS not configured")
-        .parse::<u32>()
-        .unwrap();
+    let db_config = &config.database;
+    let db_url = db_config
+        .url(data_source)
+        .unwrap_or_else(|| panic!("{} is not configured", DbConfig::url_key(data_source)));
     let mut opt = ConnectOptions::new(db_url.to_owned());
-    // max_connections is properly for double size of the cpu core
-    opt.max_connections(max_connections)
-        .min_connections(min_connections)
-        .acquire_timeout(Duration::from_secs(30))
+    opt.acquire_timeout(Duration::from_secs(30))
         .connect_timeout(Duration::from_secs(20))
-        .idle_timeout(Duration::from_secs(8))
-        .max_lifetime(Duration::from_secs(8))
-        .sqlx_logging(
-            env::var("MEGA_DB_SQLX_LOGGING")
-                .unwrap()
-                .parse::<bool>()
-                .unwrap(),
-        )
+        .sqlx_logging(db_config.sqlx_logging)
         .sqlx_logging_level(log::LevelFilter::Debug);
+    if *data_source == DataSource::Sqlite && sqlite_storage::is_memory(db_url) {
+        // the in-memory database is dropped with its connection, keep a single one open
+        opt.max_connections(1).min_connections(1);
+    } else {
+        // max_connections is properly for double size of the cpu core
+        opt.max_connections(db_config.max_connections)
+            .min_connections(db_config.min_connections)
+            .idle_timeout(Duration::from_secs(8))
+            .max_lifetime(Duration::from_secs(8));
+    }
     let connection = Database::connect(opt)
         .await
         .expect("Database connection failed");
+    let config = config.storage.clone();
     match data_source {
-        DataSource::Mysql => Arc::new(MysqlStorage { connection }),
-        DataSource::Postgres => Arc::new(PgStorage { connection }),
+        DataSource::Mysql => Arc::new(MysqlStorage { connection, config }),
+        DataSource::Postgres => Arc::new(PgStorage { connection, config }),
+        DataSource::Sqlite => {
+            let storage = SqliteStorage { connection, config };
+            storage
+                .bootstrap()
+                .await
+                .expect("SQLite schema bootstrap failed");
+            Arc::new(storage)
+        }
+    }
+}
+
+/// The number of open and idle connections of the pool, `None` if the connection has no pool.
+pub fn pool_stats(connection: &DatabaseConnection) -> Option<(u32, usize)> {
+    match connection {
+        DatabaseConnection::SqlxPostgresPoolConnection(_) => {
+            let pool = connection.get_postgres_connection_pool();
+            Some((pool.size(), pool.num_idle()))
+        }
+        DatabaseConnection::SqlxMySqlPoolConnection(_) => {
+            let pool = connection.get_mysql_connection_pool();
+            Some((pool.size(), pool.num_idle()))
+        }
+        DatabaseConnection::SqlxSqlitePoolConnection(_) => {
+            let pool = connection.get_sqlite_connection_pool();
+            Some((pool.size(), pool.num_idle()))
+        }
+        _ => None,
     }
 }
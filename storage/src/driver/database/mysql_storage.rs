@@ -21,16 +21,18 @@ use sea_orm::TryIntoModel;
 use crate::driver::database::storage::ObjectStorage;
 
 use crate::driver::database::storage::batch_save_model;
+use common::config::StorageConfig;
 use common::errors::MegaError;
 
 #[derive(Debug, Default)]
 pub struct MysqlStorage {
     pub connection: DatabaseConnection,
+    pub config: StorageConfig,
 }
 
 impl MysqlStorage {
-    pub fn new(connection: DatabaseConnection) -> MysqlStorage {
-        MysqlStorage { connection }
+    pub fn new(connection: DatabaseConnection, config: StorageConfig) -> MysqlStorage {
+        MysqlStorage { connection, config }
     }
 }
 
@@ -40,6 +42,10 @@ impl ObjectStorage for MysqlStorage {
         &self.connection
     }
 
+    fn get_config(&self) -> &StorageConfig {
+        &self.config
+    }
+
     /// Asynchronously saves object data to the database, splitting it into multiple batches if necessary.
     ///
     /// This function takes an optional transaction `txn`, a database connection `conn`, and a vector of `git_obj::ActiveModel` objects.
@@ -59,10 +65,10 @@ impl ObjectStorage for MysqlStorage {
         txn: Option<&DatabaseTransaction>,
         obj_data: Vec<objects::ActiveModel>,
     ) -> Result<bool, MegaError> {
-        let packet_size = obj_data
-            .iter()
-            .map(|model| model.clone().try_into_model().unwrap().data.len())
-            .sum::<usize>();
+        let mut packet_size = 0;
+        for model in &obj_data {
+            packet_size += model.clone().try_into_model()?.data.len();
+        }
 
         if packet_size > 0xDF_FF_FF {
             let mut batch_obj = Vec::new();
@@ -133,3 +139,73 @@ async fn conditional_batch_save_model(
         None => batch_save_model(conn, obj_data.to_vec()).await,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{DatabaseBackend, MockDatabase, MockExecResult, Set, TransactionTrait};
+
+    use common::config::StorageConfig;
+    use common::utils::ZERO_ID;
+    use entity::objects;
+
+    use crate::driver::database::mysql_storage::MysqlStorage;
+    use crate::driver::database::storage::ObjectStorage;
+
+    const COMMIT_ID: &str = "8f19cdbd7e5f3f9d2c3a4f6e0a4ae2e0b0e9a5b1";
+
+    fn exec_result(rows_affected: u64) -> MockExecResult {
+        MockExecResult {
+            last_insert_id: 0,
+            rows_affected,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_ignore() {
+        let connection = MockDatabase::new(DatabaseBackend::MySql)
+            .append_exec_results([exec_result(1), exec_result(1), exec_result(0)])
+            .into_connection();
+        let storage = MysqlStorage::new(connection, StorageConfig::default());
+        let object = objects::ActiveModel {
+            id: Set(1),
+            git_id: Set(COMMIT_ID.to_owned()),
+            object_type: Set(String::from("commit")),
+            data: Set(b"tree".to_vec()),
+            link: Set(None),
+        };
+        storage
+            .save_obj_data_to_db(None, vec![object])
+            .await
+            .unwrap();
+
+        let txn = storage.get_connection().begin().await.unwrap();
+        assert!(storage
+            .compare_and_swap_ref(
+                &txn,
+                "/projects/mega",
+                "refs/heads/main",
+                ZERO_ID,
+                COMMIT_ID
+            )
+            .await
+            .unwrap());
+        // a concurrent push created the ref first
+        assert!(!storage
+            .compare_and_swap_ref(
+                &txn,
+                "/projects/mega",
+                "refs/heads/main",
+                ZERO_ID,
+                COMMIT_ID
+            )
+            .await
+            .unwrap());
+        txn.commit().await.unwrap();
+
+        let log = format!("{:?}", storage.connection.into_transaction_log());
+        assert_eq!(log.matches("INSERT IGNORE INTO `objects`").count(), 1);
+        assert_eq!(log.matches("INSERT IGNORE INTO `refs`").count(), 2);
+        assert!(!log.contains("ON DUPLICATE KEY"));
+        assert!(!log.contains("DO NOTHING"));
+    }
+}
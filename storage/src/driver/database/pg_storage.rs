@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use common::config::StorageConfig;
 use common::errors::MegaError;
 use entity::{commit, objects, refs};
 use sea_orm::{
@@ -11,11 +12,12 @@ use crate::driver::{database::storage::batch_save_model, database::storage::Obje
 #[derive(Debug, Default)]
 pub struct PgStorage {
     pub connection: DatabaseConnection,
+    pub config: StorageConfig,
 }
 
 impl PgStorage {
-    pub fn new(connection: DatabaseConnection) -> PgStorage {
-        PgStorage { connection }
+    pub fn new(connection: DatabaseConnection, config: StorageConfig) -> PgStorage {
+        PgStorage { connection, config }
     }
 }
 
@@ -25,6 +27,10 @@ impl ObjectStorage for PgStorage {
         &self.connection
     }
 
+    fn get_config(&self) -> &StorageConfig {
+        &self.config
+    }
+
     async fn save_obj_data_to_db(
         &self,
         txn: Option<&DatabaseTransaction>,
@@ -55,3 +61,46 @@ impl ObjectStorage for PgStorage {
             .await?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{DatabaseBackend, MockDatabase, MockExecResult, TransactionTrait};
+
+    use common::config::StorageConfig;
+    use common::utils::ZERO_ID;
+
+    use crate::driver::database::pg_storage::PgStorage;
+    use crate::driver::database::storage::ObjectStorage;
+
+    const COMMIT_ID: &str = "8f19cdbd7e5f3f9d2c3a4f6e0a4ae2e0b0e9a5b1";
+
+    #[tokio::test]
+    async fn test_create_ref_conflict() {
+        let connection = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_exec_results([1, 0].map(|rows_affected| MockExecResult {
+                last_insert_id: 0,
+                rows_affected,
+            }))
+            .into_connection();
+        let storage = PgStorage::new(connection, StorageConfig::default());
+        let txn = storage.get_connection().begin().await.unwrap();
+        for created in [true, false] {
+            let swapped = storage
+                .compare_and_swap_ref(
+                    &txn,
+                    "/projects/mega",
+                    "refs/heads/main",
+                    ZERO_ID,
+                    COMMIT_ID,
+                )
+                .await
+                .unwrap();
+            assert_eq!(swapped, created);
+        }
+        txn.commit().await.unwrap();
+
+        let log = format!("{:?}", storage.connection.into_transaction_log());
+        assert_eq!(log.matches(r#"INSERT INTO \"refs\""#).count(), 2);
+        assert_eq!(log.matches("DO NOTHING").count(), 2);
+    }
+}
@@ -0,0 +1,405 @@
+//!
+//! The SQLite storage, for single-node deployments and for the tests, which get a private
+//! in-memory database with `SqliteStorage::memory`.
+//!
+//! SQLite has no array type, so the parent ids of the `commit` table are kept as a JSON array
+//! and the commits are read and written through `CommitRow` instead of the entity.
+//!
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use sea_orm::sea_query::{OnConflict, Query, SimpleExpr};
+use sea_orm::{
+    ColumnTrait, ConnectOptions, ConnectionTrait, Database, DatabaseBackend, DatabaseConnection,
+    DatabaseTransaction, EntityTrait, FromQueryResult, QueryFilter, Statement,
+};
+
+use common::config::StorageConfig;
+use common::errors::{MegaError, StorageError};
+use entity::{commit, objects, refs};
+use migration::{Migrator, MigratorTrait};
+
+use crate::driver::database::storage::{batch_save_model, ObjectStorage};
+
+#[derive(Debug, Default)]
+pub struct SqliteStorage {
+    pub connection: DatabaseConnection,
+    pub config: StorageConfig,
+}
+
+impl SqliteStorage {
+    pub fn new(connection: DatabaseConnection, config: StorageConfig) -> SqliteStorage {
+        SqliteStorage { connection, config }
+    }
+
+    /// # Opens a private in-memory database with the schema created.
+    ///
+    /// The database lives as long as its only connection, it is dropped with the storage.
+    pub async fn memory() -> Result<SqliteStorage, MegaError> {
+        let mut opt = ConnectOptions::new("sqlite::memory:");
+        opt.max_connections(1)
+            .min_connections(1)
+            .sqlx_logging(false);
+        let storage = SqliteStorage::new(Database::connect(opt).await?, StorageConfig::default());
+        storage.bootstrap().await?;
+        Ok(storage)
+    }
+
+    /// Applies the pending migrations, so that a new database file is ready to use.
+    pub async fn bootstrap(&self) -> Result<(), MegaError> {
+        // the readers don't block the writer
+        self.connection
+            .execute_unprepared("PRAGMA journal_mode = WAL;")
+            .await?;
+        Migrator::up(&self.connection, None).await?;
+        Ok(())
+    }
+}
+
+/// Whether `url` opens an in-memory database, which only one connection can see.
+pub fn is_memory(url: &str) -> bool {
+    url.contains(":memory:") || url.contains("mode=memory")
+}
+
+#[async_trait]
+impl ObjectStorage for SqliteStorage {
+    fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    fn get_config(&self) -> &StorageConfig {
+        &self.config
+    }
+
+    async fn save_obj_data_to_db(
+        &self,
+        txn: Option<&DatabaseTransaction>,
+        obj_data: Vec<objects::ActiveModel>,
+    ) -> Result<bool, MegaError> {
+        match txn {
+            Some(txn) => batch_save_model(txn, obj_data).await?,
+            None => batch_save_model(self.get_connection(), obj_data).await?,
+        }
+        Ok(true)
+    }
+
+    async fn search_refs(&self, path_str: &str) -> Result<Vec<refs::Model>, MegaError> {
+        Ok(refs::Entity::find()
+            .from_raw_sql(Statement::from_sql_and_values(
+                DatabaseBackend::Sqlite,
+                r#"SELECT * FROM refs where ? LIKE repo_path || '%'"#,
+                [path_str.into()],
+            ))
+            .all(&self.connection)
+            .await?)
+    }
+
+    async fn search_commits(&self, path_str: &str) -> Result<Vec<commit::Model>, MegaError> {
+        let rows = commit::Entity::find()
+            .from_raw_sql(Statement::from_sql_and_values(
+                DatabaseBackend::Sqlite,
+                r#"SELECT * FROM "commit" where ? LIKE repo_path || '%'"#,
+                [path_str.into()],
+            ))
+            .into_model::<CommitRow>()
+            .all(&self.connection)
+            .await?;
+        rows.into_iter().map(commit::Model::try_from).collect()
+    }
+
+    async fn get_commit_by_hash(&self, hash: &str) -> Result<Option<commit::Model>, MegaError> {
+        commit::Entity::find()
+            .filter(commit::Column::GitId.eq(hash))
+            .into_model::<CommitRow>()
+            .one(&self.connection)
+            .await?
+            .map(commit::Model::try_from)
+            .transpose()
+    }
+
+    async fn get_commit_by_hashes(
+        &self,
+        hashes: Vec<String>,
+    ) -> Result<Vec<commit::Model>, MegaError> {
+        let mut commits = Vec::new();
+        for chunk in hashes.chunks(1000) {
+            let rows = commit::Entity::find()
+                .filter(commit::Column::GitId.is_in(chunk))
+                .into_model::<CommitRow>()
+                .all(&self.connection)
+                .await?;
+            for row in rows {
+                commits.push(row.try_into()?);
+            }
+        }
+        Ok(commits)
+    }
+
+    async fn get_all_commits_by_path(
+        &self,
+        repo_path: &str,
+    ) -> Result<Vec<commit::Model>, MegaError> {
+        let rows = commit::Entity::find()
+            .filter(commit::Column::RepoPath.eq(repo_path))
+            .into_model::<CommitRow>()
+            .all(&self.connection)
+            .await?;
+        rows.into_iter().map(commit::Model::try_from).collect()
+    }
+
+    async fn save_commits(
+        &self,
+        txn: Option<&DatabaseTransaction>,
+        commits: Vec<commit::ActiveModel>,
+    ) -> Result<bool, MegaError> {
+        for chunk in commits.chunks(1000) {
+            let mut insert = Query::insert();
+            insert
+                .into_table(commit::Entity)
+                .columns([
+                    commit::Column::GitId,
+                    commit::Column::Tree,
+                    commit::Column::Pid,
+                    commit::Column::RepoPath,
+                    commit::Column::Author,
+                    commit::Column::Committer,
+                    commit::Column::Content,
+                    commit::Column::CreatedAt,
+                    commit::Column::UpdatedAt,
+                ])
+                .on_conflict(OnConflict::new().do_nothing().to_owned());
+            for model in chunk {
+                insert
+                    .values(commit_values(model.clone())?)
+                    .map_err(|e| StorageError::Serialization(e.to_string()))?;
+            }
+            let statement = DatabaseBackend::Sqlite.build(&insert);
+            match txn {
+                Some(txn) => txn.execute(statement).await?,
+                None => self.connection.execute(statement).await?,
+            };
+        }
+        Ok(true)
+    }
+}
+
+/// A row of the `commit` table, with the parent ids as a JSON array.
+#[derive(Debug, FromQueryResult)]
+struct CommitRow {
+    id: i32,
+    git_id: String,
+    tree: String,
+    pid: String,
+    repo_path: String,
+    author: Option<String>,
+    committer: Option<String>,
+    content: Option<String>,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+}
+
+impl TryFrom<CommitRow> for commit::Model {
+    type Error = MegaError;
+
+    fn try_from(row: CommitRow) -> Result<Self, Self::Error> {
+        let pid = serde_json::from_str(&row.pid)
+            .map_err(|e| StorageError::Serialization(format!("commit {}: {}", row.git_id, e)))?;
+        Ok(commit::Model {
+            id: row.id,
+            git_id: row.git_id,
+            tree: row.tree,
+            pid,
+            repo_path: row.repo_path,
+            author: row.author,
+            committer: row.committer,
+            content: row.content,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        })
+    }
+}
+
+// the values of the columns inserted by `save_commits`, in the same order
+fn commit_values(mut model: commit::ActiveModel) -> Result<Vec<SimpleExpr>, MegaError> {
+    let not_set = || StorageError::Serialization(String::from("commit has unset columns"));
+    let pid = model.pid.take().ok_or_else(not_set)?;
+    let pid =
+        serde_json::to_string(&pid).map_err(|e| StorageError::Serialization(e.to_string()))?;
+    let values = [
+        model.git_id.into_value(),
+        model.tree.into_value(),
+        Some(pid.into()),
+        model.repo_path.into_value(),
+        model.author.into_value(),
+        model.committer.into_value(),
+        model.content.into_value(),
+        model.created_at.into_value(),
+        model.updated_at.into_value(),
+    ];
+    let values: Option<Vec<_>> = values
+        .into_iter()
+        .map(|v| v.map(SimpleExpr::from))
+        .collect();
+    Ok(values.ok_or_else(not_set)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use sea_orm::{NotSet, Set, TransactionTrait};
+
+    use common::utils::ZERO_ID;
+    use entity::{commit, refs};
+    use migration::{Migrator, MigratorTrait, SchemaManager};
+
+    use crate::driver::database::sqlite_storage::SqliteStorage;
+    use crate::driver::database::storage::ObjectStorage;
+
+    const PARENT_ID: &str = "7bdc783132575d5b3e78400ace9971970ff43a18";
+    const COMMIT_ID: &str = "8f19cdbd7e5f3f9d2c3a4f6e0a4ae2e0b0e9a5b1";
+
+    fn commit_model(git_id: &str, pid: Vec<String>) -> commit::ActiveModel {
+        commit::ActiveModel {
+            id: NotSet,
+            git_id: Set(git_id.to_owned()),
+            tree: Set(String::from("4b825dc642cb6eb9a060e54bf8d69288fbee4904")),
+            pid: Set(pid),
+            repo_path: Set(String::from("/projects/mega")),
+            author: Set(None),
+            committer: Set(None),
+            content: Set(Some(String::from("init"))),
+            created_at: Set(Utc::now().naive_utc()),
+            updated_at: Set(Utc::now().naive_utc()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage() {
+        let storage = SqliteStorage::memory().await.unwrap();
+        // bootstrapping twice keeps the tables
+        storage.bootstrap().await.unwrap();
+
+        let commits = vec![
+            commit_model(PARENT_ID, vec![]),
+            commit_model(COMMIT_ID, vec![PARENT_ID.to_owned()]),
+        ];
+        storage.save_commits(None, commits.clone()).await.unwrap();
+        // the commits already saved are skipped
+        storage.save_commits(None, commits).await.unwrap();
+
+        let commit = storage
+            .get_commit_by_hash(COMMIT_ID)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(commit.pid, vec![PARENT_ID]);
+        let commits = storage
+            .get_commit_by_hashes(vec![PARENT_ID.to_owned(), COMMIT_ID.to_owned()])
+            .await
+            .unwrap();
+        assert_eq!(commits.len(), 2);
+        assert_eq!(
+            storage
+                .search_commits("/projects/mega/src")
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(storage.search_commits("/docs").await.unwrap().is_empty());
+
+        storage
+            .save_refs(vec![refs::ActiveModel {
+                id: NotSet,
+                repo_path: Set(String::from("/projects/mega")),
+                ref_name: Set(String::from("refs/heads/main")),
+                ref_git_id: Set(COMMIT_ID.to_owned()),
+                created_at: Set(Utc::now().naive_utc()),
+                updated_at: Set(Utc::now().naive_utc()),
+            }])
+            .await
+            .unwrap();
+        let refs = storage.search_refs("/projects/mega/src").await.unwrap();
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].ref_git_id, COMMIT_ID);
+    }
+
+    #[tokio::test]
+    async fn test_create_ref_conflict() {
+        let storage = SqliteStorage::memory().await.unwrap();
+        let txn = storage.get_connection().begin().await.unwrap();
+        assert!(storage
+            .compare_and_swap_ref(
+                &txn,
+                "/projects/mega",
+                "refs/heads/main",
+                ZERO_ID,
+                COMMIT_ID
+            )
+            .await
+            .unwrap());
+        // a second create of the same ref is stale instead of a duplicate row
+        assert!(!storage
+            .compare_and_swap_ref(
+                &txn,
+                "/projects/mega",
+                "refs/heads/main",
+                ZERO_ID,
+                PARENT_ID
+            )
+            .await
+            .unwrap());
+        txn.commit().await.unwrap();
+
+        let refs = storage.search_refs("/projects/mega").await.unwrap();
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].ref_git_id, COMMIT_ID);
+    }
+
+    #[tokio::test]
+    async fn test_migrations() {
+        let storage = SqliteStorage::memory().await.unwrap();
+        let conn = storage.get_connection();
+        let manager = SchemaManager::new(conn);
+        assert!(Migrator::get_pending_migrations(conn)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(manager.has_table("audit_log").await.unwrap());
+        assert!(manager.has_index("commit", "idx_c_tree").await.unwrap());
+        assert!(manager
+            .has_index("pack_index", "idx_pi_pack_id")
+            .await
+            .unwrap());
+        assert!(manager
+            .has_index("refs", "uniq_refs_path_name")
+            .await
+            .unwrap());
+
+        Migrator::down(conn, Some(1)).await.unwrap();
+        assert!(!manager
+            .has_index("refs", "uniq_refs_path_name")
+            .await
+            .unwrap());
+        Migrator::down(conn, Some(4)).await.unwrap();
+        for table in ["audit_log", "users", "subrepo_commit", "dir_permission"] {
+            assert!(!manager.has_table(table).await.unwrap());
+        }
+        Migrator::down(conn, Some(1)).await.unwrap();
+        assert!(!manager.has_table("pack_index").await.unwrap());
+        // the initial tables may predate the migrations and are kept
+        assert!(Migrator::down(conn, Some(1)).await.is_err());
+        assert!(manager.has_table("commit").await.unwrap());
+        assert_eq!(
+            Migrator::get_pending_migrations(conn).await.unwrap().len(),
+            6
+        );
+
+        Migrator::up(conn, None).await.unwrap();
+        assert!(manager.has_table("audit_log").await.unwrap());
+        assert!(manager.has_table("pack_index").await.unwrap());
+        assert_eq!(
+            Migrator::get_applied_migrations(conn).await.unwrap().len(),
+            7
+        );
+    }
+}
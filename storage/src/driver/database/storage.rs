@@ -4,13 +4,17 @@
 
 extern crate common;
 
-use std::env;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
 
 use async_trait::async_trait;
+use chrono::NaiveDateTime;
 
+use entity::access_token;
+use entity::audit_log;
 use entity::commit;
+use entity::dir_permission;
 use entity::issue;
 use entity::locks;
 use entity::meta;
@@ -18,32 +22,49 @@ use entity::mr;
 use entity::mr_info;
 use entity::node;
 use entity::objects;
+use entity::pack_index;
+use entity::path_owner;
+use entity::protected_ref;
 use entity::pull_request;
 use entity::refs;
+use entity::ssh_keys;
+use entity::subrepo_commit;
+use entity::users;
 
 use entity::repo_directory;
-use sea_orm::sea_query::OnConflict;
+use sea_orm::sea_query::{Expr, InsertStatement, OnConflict};
 use sea_orm::ActiveModelTrait;
 use sea_orm::ColumnTrait;
+use sea_orm::Condition;
 use sea_orm::ConnectionTrait;
 use sea_orm::DatabaseConnection;
 use sea_orm::DatabaseTransaction;
+use sea_orm::DbBackend;
 use sea_orm::DbErr;
 use sea_orm::EntityTrait;
 use sea_orm::IntoActiveModel;
+use sea_orm::NotSet;
 use sea_orm::QueryFilter;
+use sea_orm::QueryOrder;
 use sea_orm::QuerySelect;
+use sea_orm::QueryTrait;
 use sea_orm::Set;
+use sea_orm::Statement;
 use sea_orm::TryIntoModel;
 
-use common::errors::MegaError;
+use common::config::StorageConfig;
+use common::errors::{MegaError, StorageError};
+use common::utils::ZERO_ID;
 
 use crate::driver::file_storage;
+use crate::driver::pack_store::{apply_deltas, PackStore, Resolved};
 
 #[async_trait]
 pub trait ObjectStorage: Send + Sync {
     fn get_connection(&self) -> &DatabaseConnection;
 
+    fn get_config(&self) -> &StorageConfig;
+
     async fn save_mr_objects(
         &self,
         txn: Option<&DatabaseTransaction>,
@@ -61,21 +82,16 @@ pub trait ObjectStorage: Send + Sync {
         txn: Option<&DatabaseTransaction>,
         mut obj_data: Vec<objects::ActiveModel>,
     ) -> Result<bool, MegaError> {
-        let threshold = env::var("MEGA_BIG_OBJ_THRESHOLD_SIZE")
-            .expect("MEGA_BIG_OBJ_THRESHOLD_SIZE not configured")
-            .parse::<usize>()
-            .unwrap();
-
-        let fs_storage = file_storage::init("git-objects".to_owned()).await;
+        let threshold = self.get_config().big_obj_threshold;
+        let fs_storage = file_storage::init(self.get_config(), "git-objects".to_owned()).await;
 
         let mut new_obj_data: Vec<objects::ActiveModel> = Vec::new();
         for model in obj_data.iter_mut() {
-            let mut obj = model.clone().try_into_model().unwrap();
+            let mut obj = model.clone().try_into_model()?;
             if obj.data.len() / 1024 > threshold {
                 let path = fs_storage
                     .put(&obj.git_id, obj.data.len() as i64, &obj.data)
-                    .await
-                    .unwrap();
+                    .await?;
                 obj.link = Some(path);
                 obj.data.clear();
             }
@@ -99,15 +115,13 @@ pub trait ObjectStorage: Send + Sync {
             .filter(mr::Column::MrId.eq(mr_id))
             .filter(mr::Column::ObjectType.eq(object_type))
             .all(self.get_connection())
-            .await
-            .unwrap())
+            .await?)
     }
 
     async fn save_mr_info(&self, mr_info: mr_info::ActiveModel) -> Result<bool, MegaError> {
         mr_info::Entity::insert(mr_info)
             .exec(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         Ok(true)
     }
 
@@ -115,10 +129,10 @@ pub trait ObjectStorage: Send + Sync {
         Ok(mr_info::Entity::find()
             .filter(mr_info::Column::MrId.is_in(mr_ids))
             .all(self.get_connection())
-            .await
-            .unwrap())
+            .await?)
     }
 
+    /// Reads objects from the `objects` table, or from the pack store for those not there.
     async fn get_obj_data_by_ids(
         &self,
         git_ids: Vec<String>,
@@ -127,18 +141,27 @@ pub trait ObjectStorage: Send + Sync {
             batch_query_by_columns::<objects::Entity, objects::Column>(
                 self.get_connection(),
                 objects::Column::GitId,
-                git_ids,
+                git_ids.clone(),
             )
-            .await
-            .unwrap();
-        let fs_storage = file_storage::init("git-objects".to_owned()).await;
+            .await?;
+        let fs_storage = file_storage::init(self.get_config(), "git-objects".to_owned()).await;
 
         for obj in objs.iter_mut() {
             if obj.link.is_some() {
-                let data = fs_storage.get(&obj.git_id).await.unwrap();
+                let data = fs_storage.get(&obj.git_id).await?;
                 obj.data = data.to_vec();
             }
         }
+        if objs.len() < git_ids.len() {
+            let found: HashSet<&str> = objs.iter().map(|o| o.git_id.as_str()).collect();
+            let missing = git_ids
+                .iter()
+                .filter(|id| !found.contains(id.as_str()))
+                .cloned()
+                .collect();
+            let packed = self.get_packed_obj_data(missing).await?;
+            objs.extend(packed);
+        }
         Ok(objs)
     }
 
@@ -146,18 +169,135 @@ pub trait ObjectStorage: Send + Sync {
         let obj = objects::Entity::find()
             .filter(objects::Column::GitId.eq(git_id))
             .one(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
 
         if let Some(mut model) = obj {
             if model.link.is_some() {
-                let fs_storage = file_storage::init("git-objects".to_owned()).await;
-                let data = fs_storage.get(&model.git_id).await.unwrap();
+                let fs_storage =
+                    file_storage::init(self.get_config(), "git-objects".to_owned()).await;
+                let data = fs_storage.get(&model.git_id).await?;
                 model.data = data.to_vec();
             }
             return Ok(Some(model));
         }
-        Ok(None)
+        Ok(self
+            .get_packed_obj_data(vec![git_id.to_owned()])
+            .await?
+            .pop())
+    }
+
+    /// # The size of the objects of `git_ids`, without reading their content.
+    ///
+    /// The size of an object of the `objects` table is its length in the database or in the
+    /// file storage, the size of a packed object is read from the header of its entry. The
+    /// objects that aren't found are left out.
+    async fn get_obj_sizes(&self, git_ids: Vec<String>) -> Result<HashMap<String, u64>, MegaError> {
+        let length = match self.get_connection().get_database_backend() {
+            DbBackend::MySql => "CAST(LENGTH(data) AS SIGNED)",
+            _ => "CAST(LENGTH(data) AS BIGINT)",
+        };
+        let fs_storage = file_storage::init(self.get_config(), "git-objects".to_owned()).await;
+        let mut sizes = HashMap::new();
+        for chunk in git_ids.chunks(1000) {
+            let rows: Vec<(String, Option<String>, i64)> = objects::Entity::find()
+                .select_only()
+                .column(objects::Column::GitId)
+                .column(objects::Column::Link)
+                .column_as(Expr::cust(length), "size")
+                .filter(objects::Column::GitId.is_in(chunk))
+                .into_tuple()
+                .all(self.get_connection())
+                .await?;
+            for (git_id, link, size) in rows {
+                let size = match link {
+                    Some(_) => {
+                        let meta = fs_storage.metadata(&git_id).await?;
+                        meta.ok_or_else(|| StorageError::NotFound(format!("object {}", git_id)))?
+                            .size
+                    }
+                    None => size as u64,
+                };
+                sizes.insert(git_id, size);
+            }
+        }
+        let missing: Vec<String> = git_ids
+            .into_iter()
+            .filter(|id| !sizes.contains_key(id))
+            .collect();
+        let index = self.get_pack_index(missing).await?;
+        if !index.is_empty() {
+            let packs = PackStore::init(self.get_config()).await;
+            for row in index {
+                let size = packs
+                    .object_size(&row.pack_id, row.pack_offset as u64)
+                    .await?;
+                sizes.insert(row.git_id, size);
+            }
+        }
+        Ok(sizes)
+    }
+
+    async fn save_pack_index(
+        &self,
+        txn: Option<&DatabaseTransaction>,
+        index: Vec<pack_index::ActiveModel>,
+    ) -> Result<(), MegaError> {
+        match txn {
+            Some(txn) => batch_save_model(txn, index).await,
+            None => batch_save_model(self.get_connection(), index).await,
+        }
+    }
+
+    /// The pack and offset of the objects kept in the pack store, among `git_ids`.
+    async fn get_pack_index(
+        &self,
+        git_ids: Vec<String>,
+    ) -> Result<Vec<pack_index::Model>, MegaError> {
+        batch_query_by_columns::<pack_index::Entity, pack_index::Column>(
+            self.get_connection(),
+            pack_index::Column::GitId,
+            git_ids,
+        )
+        .await
+    }
+
+    /// # Reads the objects of `git_ids` kept in the pack store.
+    ///
+    /// The deltas are resolved from the packs, the base of a delta against an object of
+    /// another pack or of the `objects` table is read with `get_obj_data_by_id`. The objects
+    /// that aren't in the pack store are left out.
+    async fn get_packed_obj_data(
+        &self,
+        git_ids: Vec<String>,
+    ) -> Result<Vec<objects::Model>, MegaError> {
+        let index = self.get_pack_index(git_ids).await?;
+        if index.is_empty() {
+            return Ok(vec![]);
+        }
+        let packs = PackStore::init(self.get_config()).await;
+        let mut objs = Vec::with_capacity(index.len());
+        for row in index {
+            let (object_type, data) = match packs
+                .resolve(&row.pack_id, row.pack_offset as u64)
+                .await?
+            {
+                Resolved::Object(object_type, data) => (object_type, data.to_vec()),
+                Resolved::RefDelta { base_id, deltas } => {
+                    let base = self.get_obj_data_by_id(&base_id).await?.ok_or_else(|| {
+                        StorageError::NotFound(format!("base {} of object {}", base_id, row.git_id))
+                    })?;
+                    (base.object_type, apply_deltas(&base.data, deltas)?)
+                }
+            };
+            objs.push(objects::Model {
+                id: 0,
+                git_id: row.git_id,
+                object_type,
+                data,
+                link: None,
+            });
+        }
+        Ok(objs)
     }
 
     async fn get_all_refs_by_path(&self, repo_path: &str) -> Result<Vec<refs::Model>, MegaError> {
@@ -165,16 +305,30 @@ pub trait ObjectStorage: Send + Sync {
         Ok(refs::Entity::find()
             .filter(refs::Column::RepoPath.eq(repo_path))
             .all(self.get_connection())
-            .await
-            .unwrap())
+            .await?)
+    }
+
+    async fn get_refs_by_prefixes(
+        &self,
+        repo_path: &str,
+        prefixes: Vec<String>,
+    ) -> Result<Vec<refs::Model>, MegaError> {
+        let mut condition = Condition::any();
+        for prefix in prefixes {
+            condition = condition.add(refs::Column::RefName.starts_with(&prefix));
+        }
+        Ok(refs::Entity::find()
+            .filter(refs::Column::RepoPath.eq(repo_path))
+            .filter(condition)
+            .all(self.get_connection())
+            .await?)
     }
 
     async fn get_commit_by_hash(&self, hash: &str) -> Result<Option<commit::Model>, MegaError> {
         Ok(commit::Entity::find()
             .filter(commit::Column::GitId.eq(hash))
             .one(self.get_connection())
-            .await
-            .unwrap())
+            .await?)
     }
 
     async fn get_commit_by_hashes(
@@ -186,8 +340,7 @@ pub trait ObjectStorage: Send + Sync {
             commit::Column::GitId,
             hashes,
         )
-        .await
-        .unwrap())
+        .await?)
     }
 
     async fn get_all_commits_by_path(
@@ -197,8 +350,7 @@ pub trait ObjectStorage: Send + Sync {
         let commits: Vec<commit::Model> = commit::Entity::find()
             .filter(commit::Column::RepoPath.eq(repo_path))
             .all(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         Ok(commits)
     }
 
@@ -209,25 +361,33 @@ pub trait ObjectStorage: Send + Sync {
     async fn save_refs(&self, save_models: Vec<refs::ActiveModel>) -> Result<bool, MegaError> {
         refs::Entity::insert_many(save_models)
             .exec(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         Ok(true)
     }
 
-    async fn update_refs(&self, old_id: String, new_id: String, path: &Path) {
+    async fn update_refs(
+        &self,
+        old_id: String,
+        new_id: String,
+        path: &Path,
+    ) -> Result<(), MegaError> {
         let ref_data: Option<refs::Model> = refs::Entity::find()
-            .filter(refs::Column::RefGitId.eq(old_id))
+            .filter(refs::Column::RefGitId.eq(&old_id))
             .filter(refs::Column::RepoPath.eq(path.to_str().unwrap()))
             .one(self.get_connection())
-            .await
-            .unwrap();
-        let mut ref_data: refs::ActiveModel = ref_data.unwrap().into();
+            .await?;
+        let mut ref_data: refs::ActiveModel = ref_data
+            .ok_or_else(|| {
+                StorageError::NotFound(format!("ref at {} in {}", old_id, path.display()))
+            })?
+            .into();
         ref_data.ref_git_id = Set(new_id);
         ref_data.updated_at = Set(chrono::Utc::now().naive_utc());
-        ref_data.update(self.get_connection()).await.unwrap();
+        ref_data.update(self.get_connection()).await?;
+        Ok(())
     }
 
-    async fn delete_refs(&self, old_id: String, path: &Path) {
+    async fn delete_refs(&self, old_id: String, path: &Path) -> Result<(), MegaError> {
         let delete_ref = refs::ActiveModel {
             ref_git_id: Set(old_id),
             repo_path: Set(path.to_str().unwrap().to_owned()),
@@ -235,8 +395,60 @@ pub trait ObjectStorage: Send + Sync {
         };
         refs::Entity::delete(delete_ref)
             .exec(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
+        Ok(())
+    }
+
+    /// # Moves a ref from `old_id` to `new_id` inside `txn`, if it still points to `old_id`.
+    ///
+    /// `ZERO_ID` as `old_id` creates the ref and as `new_id` deletes it. Returns false without
+    /// writing anything if the ref changed since `old_id` was read, so that concurrent pushes
+    /// can't overwrite each other.
+    async fn compare_and_swap_ref(
+        &self,
+        txn: &DatabaseTransaction,
+        repo_path: &str,
+        ref_name: &str,
+        old_id: &str,
+        new_id: &str,
+    ) -> Result<bool, MegaError> {
+        let same_ref = Condition::all()
+            .add(refs::Column::RepoPath.eq(repo_path))
+            .add(refs::Column::RefName.eq(ref_name));
+        if old_id == ZERO_ID {
+            let now = chrono::Utc::now().naive_utc();
+            let insert = refs::Entity::insert(refs::ActiveModel {
+                id: NotSet,
+                repo_path: Set(repo_path.to_owned()),
+                ref_name: Set(ref_name.to_owned()),
+                ref_git_id: Set(new_id.to_owned()),
+                created_at: Set(now),
+                updated_at: Set(now),
+            })
+            .into_query();
+            // no row is inserted if the ref exists, or a concurrent push just created it
+            return Ok(insert_ignore(txn, insert).await? == 1);
+        }
+        let same_value = same_ref.add(refs::Column::RefGitId.eq(old_id));
+        let rows_affected = if new_id == ZERO_ID {
+            refs::Entity::delete_many()
+                .filter(same_value)
+                .exec(txn)
+                .await?
+                .rows_affected
+        } else {
+            refs::Entity::update_many()
+                .col_expr(refs::Column::RefGitId, Expr::value(new_id))
+                .col_expr(
+                    refs::Column::UpdatedAt,
+                    Expr::value(chrono::Utc::now().naive_utc()),
+                )
+                .filter(same_value)
+                .exec(txn)
+                .await?
+                .rows_affected
+        };
+        Ok(rows_affected == 1)
     }
 
     async fn get_nodes_by_hashes(
@@ -248,24 +460,21 @@ pub trait ObjectStorage: Send + Sync {
             node::Column::GitId,
             hashes,
         )
-        .await
-        .unwrap())
+        .await?)
     }
 
     async fn get_node_by_hash(&self, hash: &str) -> Result<Option<node::Model>, MegaError> {
         Ok(node::Entity::find()
             .filter(node::Column::GitId.eq(hash))
             .one(self.get_connection())
-            .await
-            .unwrap())
+            .await?)
     }
 
     async fn get_node_by_path(&self, path: &Path) -> Result<Vec<node::Model>, MegaError> {
         Ok(node::Entity::find()
             .filter(node::Column::RepoPath.eq(path.to_str().unwrap()))
             .all(self.get_connection())
-            .await
-            .unwrap())
+            .await?)
     }
     async fn get_nodes(&self) -> Result<Vec<node::Model>, MegaError> {
         Ok(node::Entity::find()
@@ -276,8 +485,7 @@ pub trait ObjectStorage: Send + Sync {
                 node::Column::FullPath,
             ])
             .all(self.get_connection())
-            .await
-            .unwrap())
+            .await?)
     }
 
     async fn save_nodes(
@@ -305,69 +513,65 @@ pub trait ObjectStorage: Send + Sync {
                 .map(|_| true),
         }
     }
-    async fn search_root_node_by_path(&self, repo_path: &Path) -> Option<node::Model> {
+    async fn search_root_node_by_path(
+        &self,
+        repo_path: &Path,
+    ) -> Result<Option<node::Model>, MegaError> {
         tracing::debug!("file_name: {:?}", repo_path.file_name());
         let res = node::Entity::find()
             .filter(node::Column::Name.eq(repo_path.file_name().unwrap().to_str().unwrap()))
             .one(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         if let Some(res) = res {
-            Some(res)
+            Ok(Some(res))
         } else {
-            node::Entity::find()
+            Ok(node::Entity::find()
                 // .filter(node::Column::Path.eq(repo_path.to_str().unwrap()))
                 .filter(node::Column::Name.eq(""))
                 .one(self.get_connection())
-                .await
-                .unwrap()
+                .await?)
         }
     }
 
     async fn get_meta_by_id(&self, oid: String) -> Result<Option<meta::Model>, MegaError> {
         let result = meta::Entity::find_by_id(oid)
             .one(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         Ok(result)
     }
 
     async fn delete_meta_by_id(&self, oid: String) -> Result<(), MegaError> {
         meta::Entity::delete_by_id(oid)
             .exec(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         Ok(())
     }
 
     async fn get_lock_by_id(&self, refspec: &str) -> Result<Option<locks::Model>, MegaError> {
         let result = locks::Entity::find_by_id(refspec)
             .one(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         Ok(result)
     }
 
-    async fn delete_lock_by_id(&self, id: String) {
+    async fn delete_lock_by_id(&self, id: String) -> Result<(), MegaError> {
         locks::Entity::delete_by_id(id)
             .exec(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
+        Ok(())
     }
 
     async fn save_issue(&self, issue: issue::ActiveModel) -> Result<bool, MegaError> {
         issue::Entity::insert(issue)
             .exec(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         Ok(true)
     }
 
     async fn update_issue(&self, issue: issue::ActiveModel) -> Result<bool, MegaError> {
         issue::Entity::update(issue)
             .exec(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         Ok(true)
     }
 
@@ -375,26 +579,25 @@ pub trait ObjectStorage: Send + Sync {
         Ok(issue::Entity::find()
             .filter(issue::Column::Id.eq(id))
             .one(self.get_connection())
-            .await
-            .unwrap())
+            .await?)
     }
 
     async fn init_repo_dir(&self) -> Result<(), MegaError> {
-        let pid = if let Some(root) = self.get_directory_by_full_path("/").await.unwrap() {
+        let pid = if let Some(root) = self.get_directory_by_full_path("/").await? {
             root.id
         } else {
             let root = repo_directory::new(0, "root", "/");
             self.save_directory(root).await?
         };
 
-        let init_dirs = env::var("MEGA_INIT_DIRS").unwrap();
         let mut model_vec = Vec::new();
-        for str in init_dirs.split(',') {
-            let path = PathBuf::from(str);
+        for dir in &self.get_config().init_dirs {
+            let dir = dir.trim_start_matches('/');
+            let path = PathBuf::from(dir);
             model_vec.push(repo_directory::new(
                 pid,
                 path.file_name().unwrap().to_str().unwrap(),
-                &format!("/{}", str),
+                &format!("/{}", dir),
             ));
         }
         repo_directory::Entity::insert_many(model_vec)
@@ -409,34 +612,35 @@ pub trait ObjectStorage: Send + Sync {
                     .to_owned(),
             )
             .exec(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         Ok(())
     }
 
     async fn save_directory(&self, model: repo_directory::ActiveModel) -> Result<i32, MegaError> {
         Ok(repo_directory::Entity::insert(model)
             .exec(self.get_connection())
-            .await
-            .unwrap()
+            .await?
             .last_insert_id)
     }
 
     async fn get_directory_by_full_path(
         &self,
         path: &str,
-    ) -> Result<Option<repo_directory::Model>, DbErr> {
-        repo_directory::Entity::find()
+    ) -> Result<Option<repo_directory::Model>, MegaError> {
+        Ok(repo_directory::Entity::find()
             .filter(repo_directory::Column::FullPath.eq(path))
             .one(self.get_connection())
-            .await
+            .await?)
     }
 
-    async fn get_directory_by_pid(&self, pid: i32) -> Result<Vec<repo_directory::Model>, DbErr> {
-        repo_directory::Entity::find()
+    async fn get_directory_by_pid(
+        &self,
+        pid: i32,
+    ) -> Result<Vec<repo_directory::Model>, MegaError> {
+        Ok(repo_directory::Entity::find()
             .filter(repo_directory::Column::Pid.eq(pid))
             .all(self.get_connection())
-            .await
+            .await?)
     }
     async fn save_pull_request(
         &self,
@@ -444,8 +648,7 @@ pub trait ObjectStorage: Send + Sync {
     ) -> Result<bool, MegaError> {
         pull_request::Entity::insert(pull_request)
             .exec(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         Ok(true)
     }
 
@@ -455,8 +658,7 @@ pub trait ObjectStorage: Send + Sync {
     ) -> Result<bool, MegaError> {
         pull_request::Entity::update(pull_request)
             .exec(self.get_connection())
-            .await
-            .unwrap();
+            .await?;
         Ok(true)
     }
 
@@ -467,9 +669,237 @@ pub trait ObjectStorage: Send + Sync {
         Ok(pull_request::Entity::find()
             .filter(pull_request::Column::Id.eq(id))
             .one(self.get_connection())
-            .await
-            .unwrap())
+            .await?)
+    }
+
+    async fn get_protected_refs(
+        &self,
+        repo_path: &str,
+    ) -> Result<Vec<protected_ref::Model>, MegaError> {
+        Ok(protected_ref::Entity::find()
+            .filter(protected_ref::Column::RepoPath.eq(repo_path))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Returns the owner rules of `path`, its ancestors and its descendants.
+    async fn get_path_owners(&self, path: &str) -> Result<Vec<path_owner::Model>, MegaError> {
+        let mut condition = Condition::any()
+            .add(path_owner::Column::Path.starts_with(format!("{}/", path.trim_end_matches('/'))));
+        for ancestor in Path::new(path).ancestors() {
+            condition = condition.add(path_owner::Column::Path.eq(ancestor.to_str().unwrap()));
+        }
+        Ok(path_owner::Entity::find()
+            .filter(condition)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Returns the permission rows of `path`, its ancestors and its descendants.
+    async fn get_dir_permissions(
+        &self,
+        path: &str,
+    ) -> Result<Vec<dir_permission::Model>, MegaError> {
+        let mut condition = Condition::any().add(
+            dir_permission::Column::Path.starts_with(format!("{}/", path.trim_end_matches('/'))),
+        );
+        for ancestor in Path::new(path).ancestors() {
+            condition = condition.add(dir_permission::Column::Path.eq(ancestor.to_str().unwrap()));
+        }
+        Ok(dir_permission::Entity::find()
+            .filter(condition)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    async fn save_subrepo_commits(
+        &self,
+        txn: &DatabaseTransaction,
+        models: Vec<subrepo_commit::ActiveModel>,
+    ) -> Result<(), MegaError> {
+        batch_save_model(txn, models).await
+    }
+
+    /// Finds the trunk commit a commit of the sub-directory repo `repo_path` was integrated as.
+    async fn get_subrepo_commit(
+        &self,
+        repo_path: &str,
+        commit_id: &str,
+    ) -> Result<Option<subrepo_commit::Model>, MegaError> {
+        Ok(subrepo_commit::Entity::find()
+            .filter(subrepo_commit::Column::RepoPath.eq(repo_path))
+            .filter(subrepo_commit::Column::CommitId.eq(commit_id))
+            .one(self.get_connection())
+            .await?)
+    }
+
+    /// Finds the commit of the sub-directory repo `repo_path` matching a trunk commit.
+    async fn get_subrepo_commit_by_trunk(
+        &self,
+        repo_path: &str,
+        trunk_commit_id: &str,
+    ) -> Result<Option<subrepo_commit::Model>, MegaError> {
+        Ok(subrepo_commit::Entity::find()
+            .filter(subrepo_commit::Column::RepoPath.eq(repo_path))
+            .filter(subrepo_commit::Column::TrunkCommitId.eq(trunk_commit_id))
+            .one(self.get_connection())
+            .await?)
+    }
+
+    async fn save_user(&self, user: users::ActiveModel) -> Result<i32, MegaError> {
+        Ok(users::Entity::insert(user)
+            .exec(self.get_connection())
+            .await?
+            .last_insert_id)
+    }
+
+    async fn get_user_by_name(&self, name: &str) -> Result<Option<users::Model>, MegaError> {
+        Ok(users::Entity::find()
+            .filter(users::Column::Name.eq(name))
+            .one(self.get_connection())
+            .await?)
+    }
+
+    async fn get_user_by_id(&self, id: i32) -> Result<Option<users::Model>, MegaError> {
+        Ok(users::Entity::find_by_id(id)
+            .one(self.get_connection())
+            .await?)
+    }
+
+    async fn save_ssh_key(&self, ssh_key: ssh_keys::ActiveModel) -> Result<i32, MegaError> {
+        Ok(ssh_keys::Entity::insert(ssh_key)
+            .exec(self.get_connection())
+            .await?
+            .last_insert_id)
+    }
+
+    async fn list_ssh_keys(&self, user_id: i32) -> Result<Vec<ssh_keys::Model>, MegaError> {
+        Ok(ssh_keys::Entity::find()
+            .filter(ssh_keys::Column::UserId.eq(user_id))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Deletes a key of the user, returns false if the user has no such key.
+    async fn revoke_ssh_key(&self, user_id: i32, key_id: i32) -> Result<bool, MegaError> {
+        let res = ssh_keys::Entity::delete_many()
+            .filter(ssh_keys::Column::UserId.eq(user_id))
+            .filter(ssh_keys::Column::Id.eq(key_id))
+            .exec(self.get_connection())
+            .await?;
+        Ok(res.rows_affected == 1)
+    }
+
+    async fn get_ssh_key_by_fingerprint(
+        &self,
+        fingerprint: &str,
+    ) -> Result<Option<ssh_keys::Model>, MegaError> {
+        Ok(ssh_keys::Entity::find()
+            .filter(ssh_keys::Column::Fingerprint.eq(fingerprint))
+            .one(self.get_connection())
+            .await?)
     }
+
+    async fn save_access_token(&self, token: access_token::ActiveModel) -> Result<i32, MegaError> {
+        Ok(access_token::Entity::insert(token)
+            .exec(self.get_connection())
+            .await?
+            .last_insert_id)
+    }
+
+    async fn list_access_tokens(
+        &self,
+        user_id: i32,
+    ) -> Result<Vec<access_token::Model>, MegaError> {
+        Ok(access_token::Entity::find()
+            .filter(access_token::Column::UserId.eq(user_id))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Deletes a token of the user, returns false if the user has no such token.
+    async fn revoke_access_token(&self, user_id: i32, token_id: i32) -> Result<bool, MegaError> {
+        let res = access_token::Entity::delete_many()
+            .filter(access_token::Column::UserId.eq(user_id))
+            .filter(access_token::Column::Id.eq(token_id))
+            .exec(self.get_connection())
+            .await?;
+        Ok(res.rows_affected == 1)
+    }
+
+    async fn get_access_token_by_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<access_token::Model>, MegaError> {
+        Ok(access_token::Entity::find()
+            .filter(access_token::Column::TokenHash.eq(token_hash))
+            .one(self.get_connection())
+            .await?)
+    }
+
+    /// # Appends entries to the audit log.
+    ///
+    /// Within `txn`, the entries are only kept if the changes they describe are committed.
+    /// The audit log is append-only, there is no API to update or delete its entries.
+    async fn save_audit_logs(
+        &self,
+        txn: Option<&DatabaseTransaction>,
+        logs: Vec<audit_log::ActiveModel>,
+    ) -> Result<(), MegaError> {
+        if logs.is_empty() {
+            return Ok(());
+        }
+        let insert = audit_log::Entity::insert_many(logs);
+        match txn {
+            Some(txn) => insert.exec(txn).await?,
+            None => insert.exec(self.get_connection()).await?,
+        };
+        Ok(())
+    }
+
+    /// Returns the entries of the audit log matching `query`, newest first.
+    async fn get_audit_logs(
+        &self,
+        query: &AuditLogQuery,
+    ) -> Result<Vec<audit_log::Model>, MegaError> {
+        let mut condition = Condition::all();
+        if let Some(repo_path) = &query.repo_path {
+            condition = condition.add(audit_log::Column::RepoPath.eq(repo_path));
+        }
+        if let Some(actor) = &query.actor {
+            condition = condition.add(audit_log::Column::Actor.eq(actor));
+        }
+        if let Some(action) = &query.action {
+            condition = condition.add(audit_log::Column::Action.eq(action));
+        }
+        if let Some(since) = query.since {
+            condition = condition.add(audit_log::Column::CreatedAt.gte(since));
+        }
+        if let Some(until) = query.until {
+            condition = condition.add(audit_log::Column::CreatedAt.lt(until));
+        }
+        if let Some(before_id) = query.before_id {
+            condition = condition.add(audit_log::Column::Id.lt(before_id));
+        }
+        Ok(audit_log::Entity::find()
+            .filter(condition)
+            .order_by_desc(audit_log::Column::Id)
+            .limit(query.limit)
+            .all(self.get_connection())
+            .await?)
+    }
+}
+
+/// The filters of `ObjectStorage::get_audit_logs`, `before_id` pages through the entries.
+#[derive(Debug, Default)]
+pub struct AuditLogQuery {
+    pub repo_path: Option<String>,
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub before_id: Option<i64>,
+    pub limit: u64,
 }
 
 /// Performs batch saving of models in the database.
@@ -506,15 +936,39 @@ where
     let mut results = Vec::new();
     for chunk in save_models.chunks(1000) {
         // notice that sqlx not support packets larger than 16MB now
-        let res = E::insert_many(chunk.iter().cloned())
-            .on_conflict(OnConflict::new().do_nothing().to_owned())
-            .exec(connection);
-        results.push(res);
+        let insert = E::insert_many(chunk.iter().cloned()).into_query();
+        results.push(insert_ignore(connection, insert));
+    }
+    for res in futures::future::join_all(results).await {
+        res?;
     }
-    futures::future::join_all(results).await;
     Ok(())
 }
 
+/// Executes `insert`, skipping the rows which collide with a unique key, and returns the number
+/// of inserted rows.
+pub async fn insert_ignore(
+    connection: &impl ConnectionTrait,
+    insert: InsertStatement,
+) -> Result<u64, DbErr> {
+    let stmt = insert_ignore_statement(connection.get_database_backend(), insert);
+    Ok(connection.execute(stmt).await?.rows_affected())
+}
+
+/// Builds `insert` for `backend` so that the rows colliding with a unique key are skipped.
+///
+/// sea-query renders `ON CONFLICT DO NOTHING` as `ON DUPLICATE KEY DO NOTHING` on MySQL, which
+/// isn't valid there, so MySQL gets an `INSERT IGNORE` instead.
+pub fn insert_ignore_statement(backend: DbBackend, mut insert: InsertStatement) -> Statement {
+    if backend == DbBackend::MySql {
+        let mut stmt = backend.build(&insert);
+        stmt.sql = stmt.sql.replacen("INSERT", "INSERT IGNORE", 1);
+        return stmt;
+    }
+    insert.on_conflict(OnConflict::new().do_nothing().to_owned());
+    backend.build(&insert)
+}
+
 async fn batch_query_by_columns<T, C>(
     connection: &DatabaseConnection,
     column: C,
@@ -530,8 +984,7 @@ where
             T::find()
                 .filter(column.is_in(chunk))
                 .all(connection)
-                .await
-                .unwrap(),
+                .await?,
         );
     }
     Ok(result)
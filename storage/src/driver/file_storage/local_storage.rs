@@ -1,14 +1,19 @@
 use std::fs;
-use std::io::prelude::*;
-use std::path;
-use std::path::PathBuf;
+use std::io::{self, ErrorKind, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use async_trait::async_trait;
 use bytes::Bytes;
+use chrono::DateTime;
+use futures::{StreamExt, TryStreamExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
 
 use common::errors::MegaError;
 
-use crate::driver::file_storage::FileStorage;
+use crate::driver::file_storage::{not_found, ByteStream, FileStorage, ObjectMeta, ObjectPage};
 
 #[derive(Default)]
 pub struct LocalStorage {
@@ -27,15 +32,119 @@ impl LocalStorage {
         fs::create_dir_all(&base_path).expect("Create directory failed!");
         LocalStorage { base_path }
     }
+
+    fn path(&self, object_id: &str) -> PathBuf {
+        self.base_path.join(self.transform_path(object_id))
+    }
+
+    async fn open(&self, object_id: &str) -> Result<tokio::fs::File, MegaError> {
+        tokio::fs::File::open(self.path(object_id))
+            .await
+            .map_err(|e| match e.kind() {
+                ErrorKind::NotFound => not_found(object_id),
+                _ => MegaError::from(e),
+            })
+    }
+
+    /// Writes the chunks to a hidden file next to the object, which replaces the object once
+    /// they are all written.
+    async fn write(&self, object_id: &str, mut stream: ByteStream) -> Result<u64, MegaError> {
+        let path = self.path(object_id);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        let temp = temp_path(&path);
+        let written = async {
+            let mut file = tokio::fs::File::create(&temp).await?;
+            let mut size = 0;
+            while let Some(chunk) = stream.try_next().await? {
+                file.write_all(&chunk).await?;
+                size += chunk.len() as u64;
+            }
+            file.sync_all().await?;
+            Ok::<u64, MegaError>(size)
+        }
+        .await;
+        match written {
+            Ok(size) => {
+                tokio::fs::rename(&temp, &path).await?;
+                Ok(size)
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp).await;
+                Err(e)
+            }
+        }
+    }
+
+    // appends the objects under `dir`, whose ids start with `prefix`, in the order of their
+    // ids until one more than `limit` are found
+    fn walk(
+        dir: &Path,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+        objects: &mut Vec<ObjectMeta>,
+    ) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            if objects.len() > limit {
+                break;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // the objects being written
+            if name.starts_with('.') {
+                continue;
+            }
+            let id = format!("{}{}", prefix, name);
+            if entry.file_type()?.is_dir() {
+                // all the objects of the directory come before `after`
+                if let Some(after) = after {
+                    if id.as_str() < after.get(..id.len()).unwrap_or(after) {
+                        continue;
+                    }
+                }
+                LocalStorage::walk(&entry.path(), &id, after, limit, objects)?;
+            } else if after.is_none_or(|after| id.as_str() > after) {
+                let meta = entry.metadata()?;
+                objects.push(ObjectMeta {
+                    object_id: id,
+                    size: meta.len(),
+                    last_modified: meta.modified().ok().map(DateTime::from),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().unwrap().to_string_lossy();
+    path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
 }
 
 #[async_trait]
 impl FileStorage for LocalStorage {
-    async fn get(&self, object_id: &str) -> Result<Bytes, MegaError> {
-        let path = path::Path::new(&self.base_path).join(self.transform_path(object_id));
-        let mut file = fs::File::open(&path).unwrap_or_else(|_| panic!("Open file:{:?} failed!", path));
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
+    async fn get_stream(&self, object_id: &str) -> Result<ByteStream, MegaError> {
+        let file = self.open(object_id).await?;
+        Ok(ReaderStream::new(file).map_err(MegaError::from).boxed())
+    }
+
+    async fn get_range(&self, object_id: &str, range: Range<u64>) -> Result<Bytes, MegaError> {
+        let mut file = self.open(object_id).await?;
+        let len = file.metadata().await?.len();
+        let (start, end) = (range.start.min(len), range.end.min(len));
+        if start >= end {
+            return Ok(Bytes::new());
+        }
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut buffer = vec![0; (end - start) as usize];
+        file.read_exact(&mut buffer).await?;
         Ok(Bytes::from(buffer))
     }
 
@@ -45,22 +154,67 @@ impl FileStorage for LocalStorage {
         size: i64,
         body_content: &[u8],
     ) -> Result<String, MegaError> {
-        let path = path::Path::new(&self.base_path).join(self.transform_path(object_id));
-        let dir = path.parent().unwrap();
-        fs::create_dir_all(dir).expect("Create directory failed!");
-
-        let mut file = fs::File::create(&path).expect("Open file failed");
-        let lenght_written = file.write(body_content).expect("Write file failed");
-        if lenght_written as i64 != size {
+        if body_content.len() as i64 != size {
             return Err(MegaError::with_message("size not correct"));
         }
-        Ok(path.to_str().unwrap().to_string())
+        let chunk = Bytes::copy_from_slice(body_content);
+        self.write(
+            object_id,
+            futures::stream::once(async { Ok(chunk) }).boxed(),
+        )
+        .await?;
+        Ok(self.path(object_id).to_str().unwrap().to_string())
+    }
+
+    async fn put_stream(&self, object_id: &str, stream: ByteStream) -> Result<u64, MegaError> {
+        self.write(object_id, stream).await
+    }
+
+    async fn metadata(&self, object_id: &str) -> Result<Option<ObjectMeta>, MegaError> {
+        match tokio::fs::metadata(self.path(object_id)).await {
+            Ok(meta) if meta.is_file() => Ok(Some(ObjectMeta {
+                object_id: object_id.to_owned(),
+                size: meta.len(),
+                last_modified: meta.modified().ok().map(DateTime::from),
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, object_id: &str) -> Result<(), MegaError> {
+        match tokio::fs::remove_file(self.path(object_id)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
     }
 
-    fn exist(&self, object_id: &str) -> bool {
-        let path = path::Path::new(&self.base_path).join(self.transform_path(object_id));
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<ObjectPage, MegaError> {
+        let mut objects = vec![];
+        LocalStorage::walk(&self.base_path, "", after, limit, &mut objects)?;
+        let next = if objects.len() > limit {
+            objects.truncate(limit);
+            objects.last().map(|object| object.object_id.clone())
+        } else {
+            None
+        };
+        Ok(ObjectPage { objects, next })
+    }
 
-        path::Path::exists(&path)
+    async fn check(&self) -> Result<(), MegaError> {
+        match fs::metadata(&self.base_path) {
+            Ok(meta) if meta.is_dir() && !meta.permissions().readonly() => Ok(()),
+            Ok(_) => Err(MegaError::with_message(&format!(
+                "{} is not a writable directory",
+                self.base_path.display()
+            ))),
+            Err(e) => Err(MegaError::with_message(&format!(
+                "{}: {}",
+                self.base_path.display(),
+                e
+            ))),
+        }
     }
 }
 
@@ -68,7 +222,11 @@ impl FileStorage for LocalStorage {
 mod tests {
     use std::{env, path::PathBuf};
 
-    use crate::driver::file_storage::{local_storage::{MetaObject, LocalStorage}, FileStorage};
+    use crate::driver::file_storage::{
+        local_storage::{LocalStorage, MetaObject},
+        tests::check_contract,
+        FileStorage,
+    };
 
     // #[test]
     #[tokio::test]
@@ -90,6 +248,14 @@ mod tests {
             .await
             .is_ok());
 
-        assert!(local_storage.exist(&meta.oid));
+        assert!(local_storage.exist(&meta.oid).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_local_storage_contract() {
+        let base = env::temp_dir().join(format!("mega-local-storage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        check_contract(&LocalStorage::init(base.clone())).await;
+        std::fs::remove_dir_all(base).unwrap();
     }
 }
@@ -1,12 +1,22 @@
-use std::{
-    env,
-    path::{self, PathBuf},
-    sync::Arc,
-};
+//!
+//! The file storage keeps the content of the LFS objects and of the git objects larger than
+//! `storage.big_obj_threshold`, either in a local directory or in an S3-compatible bucket, as
+//! chosen by `storage.obj_storage_type`.
+//!
+//! An object is addressed by its id, a hex hash, and stored under a path derived from it by
+//! `FileStorage::transform_path`, so that no directory or prefix holds too many entries.
+//!
+use std::ops::Range;
+use std::{path, sync::Arc};
 
 use async_trait::async_trait;
-use bytes::Bytes;
-use common::errors::MegaError;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+
+use common::config::{ObjStorageType, StorageConfig};
+use common::errors::{MegaError, StorageError};
 
 use crate::driver::file_storage::local_storage::LocalStorage;
 
@@ -16,10 +26,50 @@ pub mod local_storage;
 pub mod remote_storage;
 pub mod s3_service;
 
+#[cfg(test)]
+mod s3_stand_in;
+
+/// The chunks of an object read or written by the streaming methods of `FileStorage`.
+pub type ByteStream = BoxStream<'static, Result<Bytes, MegaError>>;
+
+/// The metadata of a stored object.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectMeta {
+    pub object_id: String,
+    pub size: u64,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// A page of the objects returned by `FileStorage::list`.
+#[derive(Debug, Default)]
+pub struct ObjectPage {
+    pub objects: Vec<ObjectMeta>,
+    /// The `after` of the next page, `None` if this one is the last.
+    pub next: Option<String>,
+}
+
 #[async_trait]
 pub trait FileStorage: Sync + Send {
-    async fn get(&self, object_id: &str) -> Result<Bytes, MegaError>;
+    /// Reads a whole object, `StorageError::NotFound` if it doesn't exist.
+    async fn get(&self, object_id: &str) -> Result<Bytes, MegaError> {
+        let chunks: Vec<Bytes> = self.get_stream(object_id).await?.try_collect().await?;
+        let mut data = BytesMut::new();
+        for chunk in chunks {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data.freeze())
+    }
 
+    /// Reads an object chunk by chunk, `StorageError::NotFound` if it doesn't exist.
+    async fn get_stream(&self, object_id: &str) -> Result<ByteStream, MegaError>;
+
+    /// # Reads the bytes of `range` of an object.
+    ///
+    /// The range is cut at the end of the object, so the returned bytes are shorter than the
+    /// range, or empty, if it goes past the end.
+    async fn get_range(&self, object_id: &str, range: Range<u64>) -> Result<Bytes, MegaError>;
+
+    /// Writes an object and returns its location, which fails if it isn't `size` bytes long.
     async fn put(
         &self,
         object_id: &str,
@@ -27,15 +77,30 @@ pub trait FileStorage: Sync + Send {
         body_content: &[u8],
     ) -> Result<String, MegaError>;
 
-    fn exist(&self, object_id: &str) -> bool;
+    /// # Writes an object from its chunks and returns its size.
+    ///
+    /// The object is only visible once all the chunks are written, a failed write leaves the
+    /// previous content, if any.
+    async fn put_stream(&self, object_id: &str, stream: ByteStream) -> Result<u64, MegaError>;
 
-    async fn list(&self) {
-        unreachable!("not implement")
+    async fn exist(&self, object_id: &str) -> Result<bool, MegaError> {
+        Ok(self.metadata(object_id).await?.is_some())
     }
 
-    async fn delete(&self) {
-        unreachable!("not implement")
-    }
+    /// The size and modification time of an object, `None` if it doesn't exist.
+    async fn metadata(&self, object_id: &str) -> Result<Option<ObjectMeta>, MegaError>;
+
+    /// Deletes an object, deleting an object that doesn't exist isn't an error.
+    async fn delete(&self, object_id: &str) -> Result<(), MegaError>;
+
+    /// # Lists the objects in the order of their ids.
+    ///
+    /// A page holds at most `limit` objects whose ids come after `after`, the `next` of a page
+    /// is the `after` of the following one.
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<ObjectPage, MegaError>;
+
+    /// Checks that the backend can be reached, for the readiness endpoint of the gateway.
+    async fn check(&self) -> Result<(), MegaError>;
 
     fn transform_path(&self, path: &str) -> String {
         if path.len() < 5 {
@@ -51,17 +116,108 @@ pub trait FileStorage: Sync + Send {
     }
 }
 
-pub async fn init(path: String) -> Arc<dyn FileStorage> {
-    let storage_type = env::var("MEGA_OBJ_STORAGR_TYPE").unwrap();
-    match storage_type.as_str() {
-        "LOCAL" => {
-            let mut base_path = PathBuf::from(env::var("MEGA_OBJ_LOCAL_PATH").unwrap());
-            base_path.push(path);
-            Arc::new(LocalStorage::init(base_path))
-        }
-        "REMOTE" => Arc::new(RemoteStorage::init(path).await),
-        _ => unreachable!(
-            "Not supported config, MEGA_OBJ_STORAGR_TYPE should be 'LOCAL' or 'REMOTE'"
-        ),
+// the id of the object stored under a path given by `transform_path`
+fn object_id(path: &str) -> String {
+    path.split(['/', '\\']).collect()
+}
+
+fn not_found(object_id: &str) -> MegaError {
+    StorageError::NotFound(format!("object {}", object_id)).into()
+}
+
+pub async fn init(config: &StorageConfig, path: String) -> Arc<dyn FileStorage> {
+    match config.obj_storage_type {
+        ObjStorageType::Local => Arc::new(LocalStorage::init(config.obj_local_path.join(path))),
+        ObjStorageType::Remote => Arc::new(RemoteStorage::init(config, path).await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{stream, StreamExt};
+
+    use common::errors::{MegaError, StorageError};
+
+    use crate::driver::file_storage::{ByteStream, FileStorage};
+
+    const OID_A: &str = "1f0e4ae8b41d4a5bc8b6bd4e1f4b1d8fd6e41d0b3c2a5e7f9081726354a6b7c8";
+    const OID_B: &str = "5e1c309dae7f45e0f39b1bf3ac3cd9db12e7d689b5e4f1c0a1b2c3d4e5f60718";
+    const OID_C: &str = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3a94a8fe5ccb19ba61c4c0873";
+
+    fn chunks(chunks: &[&'static [u8]]) -> ByteStream {
+        let chunks: Vec<_> = chunks.iter().map(|c| Ok(Bytes::from_static(c))).collect();
+        stream::iter(chunks).boxed()
+    }
+
+    /// # Checks the contract of `FileStorage` on an empty storage.
+    ///
+    /// Shared by the tests of the backends.
+    pub(crate) async fn check_contract(storage: &dyn FileStorage) {
+        assert!(!storage.exist(OID_A).await.unwrap());
+        assert!(storage.metadata(OID_A).await.unwrap().is_none());
+        assert!(matches!(
+            storage.get(OID_A).await.unwrap_err().storage_error(),
+            Some(StorageError::NotFound(_))
+        ));
+        assert!(storage.get_stream(OID_A).await.is_err());
+        // deleting a missing object is fine
+        storage.delete(OID_A).await.unwrap();
+
+        storage.put(OID_A, 12, b"test content").await.unwrap();
+        assert!(storage.put(OID_C, 3, b"test content").await.is_err());
+        let size = storage
+            .put_stream(OID_B, chunks(&[b"streamed ", b"in ", b"chunks"]))
+            .await
+            .unwrap();
+        assert_eq!(size, 18);
+        // a failed stream leaves no object
+        let failed = stream::iter(vec![
+            Ok(Bytes::from_static(b"partial")),
+            Err(MegaError::with_message("client went away")),
+        ])
+        .boxed();
+        assert!(storage.put_stream(OID_C, failed).await.is_err());
+        assert!(!storage.exist(OID_C).await.unwrap());
+
+        assert!(storage.exist(OID_A).await.unwrap());
+        assert_eq!(storage.get(OID_B).await.unwrap(), "streamed in chunks");
+        let streamed: Vec<Bytes> = storage
+            .get_stream(OID_A)
+            .await
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect()
+            .await;
+        assert_eq!(streamed.concat(), b"test content");
+        let meta = storage.metadata(OID_B).await.unwrap().unwrap();
+        assert_eq!(meta.object_id, OID_B);
+        assert_eq!(meta.size, 18);
+        assert!(meta.last_modified.is_some());
+
+        assert_eq!(storage.get_range(OID_A, 5..12).await.unwrap(), "content");
+        assert_eq!(storage.get_range(OID_A, 0..4).await.unwrap(), "test");
+        // the range is cut at the end of the object
+        assert_eq!(storage.get_range(OID_A, 10..100).await.unwrap(), "nt");
+        assert!(storage.get_range(OID_A, 12..20).await.unwrap().is_empty());
+        assert!(storage.get_range(OID_A, 3..3).await.unwrap().is_empty());
+        assert!(storage.get_range(OID_C, 0..4).await.is_err());
+
+        storage.put(OID_C, 3, b"abc").await.unwrap();
+        let page = storage.list(None, 2).await.unwrap();
+        let ids: Vec<_> = page.objects.iter().map(|o| o.object_id.as_str()).collect();
+        assert_eq!(ids, [OID_A, OID_B]);
+        assert_eq!(page.objects[1].size, 18);
+        assert_eq!(page.next.as_deref(), Some(OID_B));
+        let page = storage.list(page.next.as_deref(), 2).await.unwrap();
+        let ids: Vec<_> = page.objects.iter().map(|o| o.object_id.as_str()).collect();
+        assert_eq!(ids, [OID_C]);
+        assert_eq!(page.next, None);
+
+        storage.delete(OID_B).await.unwrap();
+        assert!(!storage.exist(OID_B).await.unwrap());
+        let page = storage.list(None, 10).await.unwrap();
+        assert_eq!(page.objects.len(), 2);
+        assert_eq!(page.next, None);
     }
 }
@@ -1,82 +1,457 @@
-use std::env;
+//!
+//! The file storage in a bucket of an S3-compatible service, such as AWS S3, MinIO or OBS.
+//!
+//! `put_stream` sends the objects larger than `PART_SIZE` as multipart uploads, so that only a
+//! part is held in memory at a time.
+//!
+use std::ops::Range;
 
 use async_trait::async_trait;
 use aws_config::{meta::region::RegionProviderChain, BehaviorVersion};
 use aws_sdk_s3::{
     config::{Credentials, Region},
+    error::{DisplayErrorContext, SdkError},
+    primitives::ByteStream as S3ByteStream,
+    types::{
+        BucketLocationConstraint, CompletedMultipartUpload, CompletedPart,
+        CreateBucketConfiguration,
+    },
     Client,
 };
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use chrono::DateTime;
+use futures::{stream, StreamExt, TryStreamExt};
 
-use common::errors::MegaError;
+use common::config::StorageConfig;
+use common::errors::{MegaError, StorageError};
 
 use crate::driver::file_storage::s3_service;
-use crate::driver::file_storage::FileStorage;
+use crate::driver::file_storage::{
+    not_found, object_id, ByteStream, FileStorage, ObjectMeta, ObjectPage,
+};
+
+/// The size of the parts of a multipart upload, S3 requires at least 5 MiB but for the last.
+const PART_SIZE: usize = 8 * 1024 * 1024;
 
 pub struct RemoteStorage {
     pub region: Region,
     pub client: Client,
     pub bucket_name: String,
+    /// The URL of the bucket, which the locations returned by `put` start with.
+    pub bucket_url: String,
 }
 
 impl RemoteStorage {
-    pub async fn init(bucket_name: String) -> RemoteStorage {
-        let region = env::var("MEGA_OBJ_REMOTE_REGION").unwrap();
-        let endpoint = env::var("MEGA_OBJ_REMOTE_ENDPOINT").unwrap();
+    /// # Connects to the bucket `bucket_name`, which is created if it doesn't exist.
+    ///
+    /// A bucket that can't be created is only logged, the requests fail until it can be
+    /// reached, as reported by `check`.
+    pub async fn init(config: &StorageConfig, bucket_name: String) -> RemoteStorage {
+        // checked when the configuration is loaded
+        let region = config.obj_remote_region.clone().unwrap();
+        let endpoint = config.obj_remote_endpoint.clone().unwrap();
 
         let region_provider = RegionProviderChain::first_try(Region::new(region));
         let region = region_provider.region().await.unwrap();
 
-        let shared_config = aws_config::defaults(BehaviorVersion::latest())
+        let mut loader = aws_config::defaults(BehaviorVersion::latest())
             .region(region_provider)
-            .credentials_provider(Credentials::new("AK", "SK", None, None, "mega"))
-            .endpoint_url(endpoint)
-            .load()
-            .await;
+            .endpoint_url(&endpoint);
+        if let (Some(access_key), Some(secret_key)) =
+            (&config.obj_remote_access_key, &config.obj_remote_secret_key)
+        {
+            loader = loader
+                .credentials_provider(Credentials::new(access_key, secret_key, None, None, "mega"));
+        }
+        let shared_config = loader.load().await;
+        let s3_config = aws_sdk_s3::config::Builder::from(&shared_config)
+            .force_path_style(config.obj_remote_path_style)
+            .build();
 
-        let client = Client::new(&shared_config);
-        s3_service::create_bucket(&client, &bucket_name, region.as_ref())
-            .await
-            .unwrap();
-        RemoteStorage {
+        let endpoint = endpoint.trim_end_matches('/');
+        let bucket_url = match endpoint.split_once("://") {
+            _ if config.obj_remote_path_style => format!("{}/{}", endpoint, bucket_name),
+            Some((scheme, host)) => format!("{}://{}.{}", scheme, bucket_name, host),
+            None => format!("https://{}.{}", bucket_name, endpoint),
+        };
+        let storage = RemoteStorage {
             region,
-            client,
+            client: Client::from_conf(s3_config),
             bucket_name,
+            bucket_url,
+        };
+        if let Err(e) = storage.create_bucket().await {
+            tracing::error!("bucket {}: {}", storage.bucket_name, e);
         }
+        storage
     }
+
+    async fn create_bucket(&self) -> Result<(), MegaError> {
+        if self.check().await.is_ok() {
+            return Ok(());
+        }
+        let mut request = self.client.create_bucket().bucket(&self.bucket_name);
+        // us-east-1 is the default location, which can't be given
+        if self.region.as_ref() != "us-east-1" {
+            let constraint = BucketLocationConstraint::from(self.region.as_ref());
+            request = request.create_bucket_configuration(
+                CreateBucketConfiguration::builder()
+                    .location_constraint(constraint)
+                    .build(),
+            );
+        }
+        match request.send().await {
+            Err(SdkError::ServiceError(e))
+                if e.err().is_bucket_already_owned_by_you()
+                    || e.err().is_bucket_already_exists() =>
+            {
+                Ok(())
+            }
+            res => res.map(|_| ()).map_err(request_error),
+        }
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &mut Vec<CompletedPart>,
+        data: Bytes,
+    ) -> Result<(), MegaError> {
+        let part_number = parts.len() as i32 + 1;
+        let res = self
+            .client
+            .upload_part()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(S3ByteStream::from(data))
+            .send()
+            .await
+            .map_err(request_error)?;
+        parts.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(res.e_tag)
+                .build(),
+        );
+        Ok(())
+    }
+
+    // sends the chunks as parts of `PART_SIZE`, or as a single object if there are fewer
+    async fn upload(
+        &self,
+        key: &str,
+        stream: &mut ByteStream,
+        upload_id: &mut Option<String>,
+    ) -> Result<u64, MegaError> {
+        let mut buffer = BytesMut::new();
+        let mut parts = vec![];
+        let mut size = 0;
+        while let Some(chunk) = stream.try_next().await? {
+            size += chunk.len() as u64;
+            buffer.extend_from_slice(&chunk);
+            while buffer.len() >= PART_SIZE {
+                if upload_id.is_none() {
+                    let res = self
+                        .client
+                        .create_multipart_upload()
+                        .bucket(&self.bucket_name)
+                        .key(key)
+                        .send()
+                        .await
+                        .map_err(request_error)?;
+                    *upload_id = res.upload_id;
+                }
+                let part = buffer.split_to(PART_SIZE).freeze();
+                let id = upload_id.as_deref().unwrap_or_default();
+                self.upload_part(key, id, &mut parts, part).await?;
+            }
+        }
+        let Some(id) = upload_id.as_deref() else {
+            s3_service::upload_object_from_content(&self.client, &self.bucket_name, &buffer, key)
+                .await
+                .map_err(request_error)?;
+            return Ok(size);
+        };
+        if !buffer.is_empty() {
+            self.upload_part(key, id, &mut parts, buffer.freeze())
+                .await?;
+        }
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(request_error)?;
+        Ok(size)
+    }
+}
+
+// an error of a request, a missing object is handled by the callers
+fn request_error<E, R>(e: SdkError<E, R>) -> MegaError
+where
+    E: std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    let message = DisplayErrorContext(&e).to_string();
+    match e {
+        SdkError::ServiceError(_) => StorageError::Other(message),
+        _ => StorageError::Connection(message),
+    }
+    .into()
+}
+
+fn modified(time: Option<&aws_sdk_s3::primitives::DateTime>) -> Option<DateTime<chrono::Utc>> {
+    time.and_then(|time| DateTime::from_timestamp(time.secs(), time.subsec_nanos()))
 }
 
 #[async_trait]
 impl FileStorage for RemoteStorage {
-    async fn get(&self, object_id: &str) -> Result<Bytes, MegaError> {
+    async fn get_stream(&self, object_id: &str) -> Result<ByteStream, MegaError> {
         let key = self.transform_path(object_id);
         let res = s3_service::download_object(&self.client, &self.bucket_name, &key)
             .await
-            .unwrap();
-        let data = res.body.collect().await.expect("error reading data");
+            .map_err(|e| match e.as_service_error() {
+                Some(err) if err.is_no_such_key() => not_found(object_id),
+                _ => request_error(e),
+            })?;
+        let chunks = stream::unfold(res.body, |mut body| async move {
+            let chunk = body.next().await?;
+            let chunk = chunk.map_err(|e| StorageError::Connection(e.to_string()).into());
+            Some((chunk, body))
+        });
+        Ok(chunks.boxed())
+    }
+
+    async fn get_range(&self, object_id: &str, range: Range<u64>) -> Result<Bytes, MegaError> {
+        if range.is_empty() {
+            // the object must exist all the same
+            return match self.exist(object_id).await? {
+                true => Ok(Bytes::new()),
+                false => Err(not_found(object_id)),
+            };
+        }
+        let res = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(self.transform_path(object_id))
+            .range(format!("bytes={}-{}", range.start, range.end - 1))
+            .send()
+            .await;
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                return match e.as_service_error() {
+                    Some(err) if err.is_no_such_key() => Err(not_found(object_id)),
+                    // the range starts past the end of the object
+                    _ if e.raw_response().map(|r| r.status().as_u16()) == Some(416) => {
+                        Ok(Bytes::new())
+                    }
+                    _ => Err(request_error(e)),
+                };
+            }
+        };
+        let data = res
+            .body
+            .collect()
+            .await
+            .map_err(|e| StorageError::Connection(e.to_string()))?;
         Ok(data.into_bytes())
     }
 
     async fn put(
         &self,
         object_id: &str,
-        _size: i64,
+        size: i64,
         body_content: &[u8],
     ) -> Result<String, MegaError> {
+        if body_content.len() as i64 != size {
+            return Err(MegaError::with_message("size not correct"));
+        }
         let key = self.transform_path(object_id);
         s3_service::upload_object_from_content(&self.client, &self.bucket_name, body_content, &key)
             .await
-            .unwrap();
-        let url = format!(
-            "https://{}.obs.{}.myhuaweicloud.com/{}",
-            self.bucket_name,
-            self.region.as_ref(),
-            key
-        );
-        Ok(url)
+            .map_err(request_error)?;
+        Ok(format!("{}/{}", self.bucket_url, key))
+    }
+
+    async fn put_stream(&self, object_id: &str, mut stream: ByteStream) -> Result<u64, MegaError> {
+        let key = self.transform_path(object_id);
+        let mut upload_id = None;
+        let res = self.upload(&key, &mut stream, &mut upload_id).await;
+        if let (Err(_), Some(upload_id)) = (&res, upload_id) {
+            // drops the parts already sent
+            let _ = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket_name)
+                .key(&key)
+                .upload_id(upload_id)
+                .send()
+                .await;
+        }
+        res
+    }
+
+    async fn metadata(&self, object_id: &str) -> Result<Option<ObjectMeta>, MegaError> {
+        let res = self
+            .client
+            .head_object()
+            .bucket(&self.bucket_name)
+            .key(self.transform_path(object_id))
+            .send()
+            .await;
+        match res {
+            Ok(res) => Ok(Some(ObjectMeta {
+                object_id: object_id.to_owned(),
+                size: res.content_length.unwrap_or_default() as u64,
+                last_modified: modified(res.last_modified.as_ref()),
+            })),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+            Err(e) => Err(request_error(e)),
+        }
+    }
+
+    async fn delete(&self, object_id: &str) -> Result<(), MegaError> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket_name)
+            .key(self.transform_path(object_id))
+            .send()
+            .await
+            .map_err(request_error)?;
+        Ok(())
+    }
+
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<ObjectPage, MegaError> {
+        let res = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket_name)
+            .max_keys(limit.min(i32::MAX as usize) as i32)
+            .set_start_after(after.map(|after| self.transform_path(after)))
+            .send()
+            .await
+            .map_err(request_error)?;
+        let objects: Vec<ObjectMeta> = res
+            .contents()
+            .iter()
+            .filter_map(|object| {
+                Some(ObjectMeta {
+                    object_id: object_id(object.key()?),
+                    size: object.size.unwrap_or_default() as u64,
+                    last_modified: modified(object.last_modified.as_ref()),
+                })
+            })
+            .collect();
+        let next = match res.is_truncated {
+            Some(true) => objects.last().map(|object| object.object_id.clone()),
+            _ => None,
+        };
+        Ok(ObjectPage { objects, next })
+    }
+
+    async fn check(&self) -> Result<(), MegaError> {
+        self.client
+            .head_bucket()
+            .bucket(&self.bucket_name)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| MegaError::with_message(&DisplayErrorContext(&e).to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use common::config::{ObjStorageType, StorageConfig};
+
+    use crate::driver::file_storage::remote_storage::RemoteStorage;
+    use crate::driver::file_storage::s3_stand_in::S3StandIn;
+    use crate::driver::file_storage::tests::check_contract;
+    use crate::driver::file_storage::FileStorage;
+
+    fn credential(var: &str) -> String {
+        env::var(var).unwrap_or_else(|_| String::from("minioadmin"))
+    }
+
+    /// # Runs the contract of `FileStorage` against an S3-compatible service.
+    ///
+    /// The in-process `S3StandIn` is used unless `MEGA_TEST_S3_ENDPOINT` points to a MinIO,
+    /// whose credentials are then given by `MEGA_TEST_S3_ACCESS_KEY` and
+    /// `MEGA_TEST_S3_SECRET_KEY`.
+    #[tokio::test]
+    async fn test_remote_storage_contract() {
+        let (endpoint, _stand_in) = match env::var("MEGA_TEST_S3_ENDPOINT") {
+            Ok(endpoint) => (endpoint, None),
+            Err(_) => {
+                let stand_in = S3StandIn::start().await;
+                (stand_in.endpoint(), Some(stand_in))
+            }
+        };
+        let config = StorageConfig {
+            obj_storage_type: ObjStorageType::Remote,
+            obj_remote_region: Some(String::from("us-east-1")),
+            obj_remote_endpoint: Some(endpoint),
+            obj_remote_access_key: Some(credential("MEGA_TEST_S3_ACCESS_KEY")),
+            obj_remote_secret_key: Some(credential("MEGA_TEST_S3_SECRET_KEY")),
+            obj_remote_path_style: true,
+            ..Default::default()
+        };
+        let bucket = format!("mega-test-{}", std::process::id());
+        let storage = RemoteStorage::init(&config, bucket).await;
+        storage.check().await.unwrap();
+        check_contract(&storage).await;
     }
 
-    fn exist(&self, _object_id: &str) -> bool {
-        todo!()
+    #[tokio::test]
+    async fn test_remote_storage_multipart() {
+        let stand_in = S3StandIn::start().await;
+        let config = StorageConfig {
+            obj_storage_type: ObjStorageType::Remote,
+            obj_remote_region: Some(String::from("cn-east-3")),
+            obj_remote_endpoint: Some(stand_in.endpoint()),
+            obj_remote_access_key: Some(String::from("minioadmin")),
+            obj_remote_secret_key: Some(String::from("minioadmin")),
+            obj_remote_path_style: true,
+            ..Default::default()
+        };
+        let storage = RemoteStorage::init(&config, String::from("lfs-files")).await;
+        // init again on an existing bucket
+        let storage = RemoteStorage::init(&config, storage.bucket_name).await;
+
+        let oid = "6ae8a75555209fd6c44157c0aed8016e763ff435a19cf186f76863140143ff72";
+        let data: Vec<u8> = (0..20 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let chunks: Vec<_> = data
+            .chunks(1024 * 1024)
+            .map(|chunk| Ok(bytes::Bytes::copy_from_slice(chunk)))
+            .collect();
+        let size = storage
+            .put_stream(
+                oid,
+                futures::StreamExt::boxed(futures::stream::iter(chunks)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(stand_in.multipart_uploads(), 1);
+        assert_eq!(storage.get(oid).await.unwrap(), data);
+        let range = 9 * 1024 * 1024 - 10..9 * 1024 * 1024 + 10;
+        assert_eq!(
+            storage.get_range(oid, range.clone()).await.unwrap(),
+            data[range.start as usize..range.end as usize]
+        );
     }
 }
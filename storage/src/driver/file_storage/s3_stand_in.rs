@@ -0,0 +1,262 @@
+//!
+//! A stand-in for MinIO in the tests of `RemoteStorage`: an in-memory S3 service answering the
+//! requests the storage sends, with path-style addressing and no authentication.
+//!
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{DefaultBodyLimit, Path, Query, State};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::any;
+use axum::Router;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+
+type Params = Query<HashMap<String, String>>;
+
+struct Object {
+    data: Bytes,
+    modified: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct Store {
+    buckets: HashMap<String, BTreeMap<String, Object>>,
+    uploads: HashMap<String, BTreeMap<i32, Bytes>>,
+    multipart_uploads: usize,
+}
+
+type Shared = Arc<Mutex<Store>>;
+
+pub struct S3StandIn {
+    port: u16,
+    store: Shared,
+}
+
+impl S3StandIn {
+    /// Serves an empty store on a free port of the loopback.
+    pub async fn start() -> S3StandIn {
+        let store = Shared::default();
+        let app = Router::new()
+            .route("/:bucket", any(bucket))
+            .route("/:bucket/", any(bucket))
+            .route("/:bucket/*key", any(object))
+            .layer(DefaultBodyLimit::disable())
+            .with_state(store.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        S3StandIn { port, store }
+    }
+
+    pub fn endpoint(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// The number of multipart uploads completed.
+    pub fn multipart_uploads(&self) -> usize {
+        self.store.lock().unwrap().multipart_uploads
+    }
+}
+
+fn error(status: StatusCode, code: &str) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>{}</Code><Message>{}</Message></Error>",
+        code, code
+    );
+    (status, [("content-type", "application/xml")], body).into_response()
+}
+
+fn xml(body: String) -> Response {
+    let body = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>{}", body);
+    ([("content-type", "application/xml")], body).into_response()
+}
+
+fn http_date(time: &DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+// the payload of a body sent with `aws-chunked` encoding, without the signatures and trailers
+fn decode_chunked(body: &[u8]) -> Bytes {
+    let mut data = BytesMut::new();
+    let mut rest = body;
+    while let Some(end) = rest.windows(2).position(|w| w == b"\r\n") {
+        let header = std::str::from_utf8(&rest[..end]).unwrap();
+        let size = usize::from_str_radix(header.split(';').next().unwrap().trim(), 16).unwrap();
+        if size == 0 {
+            break;
+        }
+        data.extend_from_slice(&rest[end + 2..end + 2 + size]);
+        rest = &rest[end + 2 + size + 2..];
+    }
+    data.freeze()
+}
+
+async fn bucket(
+    State(store): State<Shared>,
+    Path(bucket): Path<String>,
+    Query(params): Params,
+    method: Method,
+) -> Response {
+    let mut store = store.lock().unwrap();
+    match method {
+        Method::HEAD if store.buckets.contains_key(&bucket) => StatusCode::OK.into_response(),
+        Method::HEAD => StatusCode::NOT_FOUND.into_response(),
+        Method::PUT if store.buckets.contains_key(&bucket) => {
+            error(StatusCode::CONFLICT, "BucketAlreadyOwnedByYou")
+        }
+        Method::PUT => {
+            store.buckets.insert(bucket, BTreeMap::new());
+            StatusCode::OK.into_response()
+        }
+        Method::GET => {
+            let Some(objects) = store.buckets.get(&bucket) else {
+                return error(StatusCode::NOT_FOUND, "NoSuchBucket");
+            };
+            let max_keys: usize = params
+                .get("max-keys")
+                .map_or(1000, |max_keys| max_keys.parse().unwrap());
+            let after = params.get("start-after").cloned().unwrap_or_default();
+            let mut keys = objects.range::<String, _>((
+                std::ops::Bound::Excluded(&after),
+                std::ops::Bound::Unbounded,
+            ));
+            let mut contents = String::new();
+            let mut count = 0;
+            for (key, object) in keys.by_ref().take(max_keys) {
+                count += 1;
+                contents.push_str(&format!(
+                    "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>\"{}\"</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                    key,
+                    object.modified.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                    sha256::digest(&object.data[..]),
+                    object.data.len()
+                ));
+            }
+            let truncated = keys.next().is_some();
+            xml(format!(
+                "<ListBucketResult><Name>{}</Name><Prefix></Prefix><KeyCount>{}</KeyCount><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>{}</ListBucketResult>",
+                bucket, count, max_keys, truncated, contents
+            ))
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+async fn object(
+    State(store): State<Shared>,
+    Path((bucket, key)): Path<(String, String)>,
+    Query(params): Params,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let mut store = store.lock().unwrap();
+    let store = &mut *store;
+    let Some(objects) = store.buckets.get_mut(&bucket) else {
+        return error(StatusCode::NOT_FOUND, "NoSuchBucket");
+    };
+    let body = match headers.get("content-encoding") {
+        Some(encoding) if encoding.to_str().unwrap().contains("aws-chunked") => {
+            decode_chunked(&body)
+        }
+        _ => body,
+    };
+    let upload_id = params.get("uploadId").cloned();
+    match (method, upload_id) {
+        (Method::POST, None) if params.contains_key("uploads") => {
+            let upload_id = format!("upload-{}", store.uploads.len() + 1);
+            store.uploads.insert(upload_id.clone(), BTreeMap::new());
+            xml(format!(
+                "<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                bucket, key, upload_id
+            ))
+        }
+        (Method::PUT, Some(upload_id)) => {
+            let Some(parts) = store.uploads.get_mut(&upload_id) else {
+                return error(StatusCode::NOT_FOUND, "NoSuchUpload");
+            };
+            let part_number = params["partNumber"].parse().unwrap();
+            let etag = format!("\"{}\"", sha256::digest(&body[..]));
+            parts.insert(part_number, body);
+            ([("etag", etag)], "").into_response()
+        }
+        (Method::POST, Some(upload_id)) => {
+            let Some(parts) = store.uploads.remove(&upload_id) else {
+                return error(StatusCode::NOT_FOUND, "NoSuchUpload");
+            };
+            let data: Vec<u8> = parts.into_values().flat_map(|part| part.to_vec()).collect();
+            objects.insert(
+                key.clone(),
+                Object {
+                    data: Bytes::from(data),
+                    modified: Utc::now(),
+                },
+            );
+            store.multipart_uploads += 1;
+            xml(format!(
+                "<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><ETag>\"multipart\"</ETag></CompleteMultipartUploadResult>",
+                bucket, key
+            ))
+        }
+        (Method::DELETE, Some(upload_id)) => {
+            store.uploads.remove(&upload_id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        (Method::PUT, None) => {
+            let etag = format!("\"{}\"", sha256::digest(&body[..]));
+            objects.insert(
+                key,
+                Object {
+                    data: body,
+                    modified: Utc::now(),
+                },
+            );
+            ([("etag", etag)], "").into_response()
+        }
+        (Method::DELETE, None) => {
+            objects.remove(&key);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        (Method::HEAD, None) => match objects.get(&key) {
+            Some(object) => (
+                [
+                    ("content-length", object.data.len().to_string()),
+                    ("last-modified", http_date(&object.modified)),
+                ],
+                "",
+            )
+                .into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        (Method::GET, None) => {
+            let Some(object) = objects.get(&key) else {
+                return error(StatusCode::NOT_FOUND, "NoSuchKey");
+            };
+            let len = object.data.len();
+            let Some(range) = headers.get("range") else {
+                return (
+                    [("last-modified", http_date(&object.modified))],
+                    object.data.clone(),
+                )
+                    .into_response();
+            };
+            // bytes=<first>-<last>, the last byte is cut at the end of the object
+            let range = range.to_str().unwrap().trim_start_matches("bytes=");
+            let (first, last) = range.split_once('-').unwrap();
+            let first: usize = first.parse().unwrap();
+            let last = last.parse::<usize>().unwrap().min(len.saturating_sub(1));
+            if first >= len {
+                return error(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange");
+            }
+            (
+                StatusCode::PARTIAL_CONTENT,
+                [("content-range", format!("bytes {}-{}/{}", first, last, len))],
+                object.data.slice(first..last + 1),
+            )
+                .into_response()
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
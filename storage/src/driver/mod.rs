@@ -1,2 +1,3 @@
 pub mod database;
 pub mod file_storage;
+pub mod pack_store;
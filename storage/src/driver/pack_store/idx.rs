@@ -0,0 +1,169 @@
+//!
+//! The `.idx` files of the packs, in the version 2 format of git, see
+//! https://git-scm.com/docs/pack-format#_version_2_pack_idx_files_support_packs_larger_than_4_gib_and
+//!
+//! They list every object of a pack with its offset, so that a pack can be checked with
+//! `git verify-pack` and its objects known without reading the database.
+//!
+use crc::{Crc, CRC_32_ISO_HDLC};
+use sha1::{Digest, Sha1};
+
+use common::errors::{MegaError, StorageError};
+
+const MAGIC: &[u8; 4] = b"\xfftOc";
+const VERSION: u32 = 2;
+// the offsets above take an entry of the table of 64-bit offsets
+const LARGE_OFFSET: u64 = 0x8000_0000;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// An object of a pack.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdxEntry {
+    pub id: [u8; 20],
+    pub offset: u64,
+    /// The CRC-32 of the entry as it is in the pack, compressed.
+    pub crc32: u32,
+}
+
+impl IdxEntry {
+    pub fn git_id(&self) -> String {
+        hex::encode(self.id)
+    }
+}
+
+/// The index of a pack, its entries sorted by id.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackIdx {
+    pub entries: Vec<IdxEntry>,
+    /// The checksum at the end of the pack.
+    pub pack_checksum: [u8; 20],
+}
+
+impl PackIdx {
+    /// # Indexes the objects of `pack`.
+    ///
+    /// `objects` are the ids of the objects with the offset of their entry, `entry_offsets` the
+    /// offsets of all the entries of the pack, in order, which bound the entries for their CRC.
+    pub fn new(
+        pack: &[u8],
+        entry_offsets: &[u64],
+        objects: &[(String, u64)],
+    ) -> Result<PackIdx, MegaError> {
+        let corrupt = |message: &str| StorageError::Serialization(format!("pack: {}", message));
+        if pack.len() < 32 {
+            return Err(corrupt("too short").into());
+        }
+        let data_end = (pack.len() - 20) as u64;
+        let mut entries = Vec::with_capacity(objects.len());
+        for (git_id, offset) in objects {
+            let i = entry_offsets
+                .binary_search(offset)
+                .map_err(|_| corrupt(&format!("no entry at {}", offset)))?;
+            let end = entry_offsets.get(i + 1).copied().unwrap_or(data_end);
+            if end > data_end {
+                return Err(corrupt(&format!("entry at {} past the end", offset)).into());
+            }
+            let id = hex::decode(git_id)
+                .ok()
+                .and_then(|id| <[u8; 20]>::try_from(id).ok())
+                .ok_or_else(|| corrupt(&format!("invalid id {}", git_id)))?;
+            entries.push(IdxEntry {
+                id,
+                offset: *offset,
+                crc32: CRC32.checksum(&pack[*offset as usize..end as usize]),
+            });
+        }
+        entries.sort_by_key(|e| e.id);
+        entries.dedup_by(|a, b| a.id == b.id);
+        Ok(PackIdx {
+            entries,
+            pack_checksum: pack[pack.len() - 20..].try_into().unwrap(),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(1072 + self.entries.len() * 28);
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&VERSION.to_be_bytes());
+        let mut count = 0;
+        for first in 0..=255u8 {
+            count += self.entries[count..]
+                .iter()
+                .take_while(|e| e.id[0] == first)
+                .count();
+            data.extend_from_slice(&(count as u32).to_be_bytes());
+        }
+        for entry in &self.entries {
+            data.extend_from_slice(&entry.id);
+        }
+        for entry in &self.entries {
+            data.extend_from_slice(&entry.crc32.to_be_bytes());
+        }
+        let mut large = vec![];
+        for entry in &self.entries {
+            let offset = if entry.offset < LARGE_OFFSET {
+                entry.offset as u32
+            } else {
+                large.push(entry.offset);
+                (LARGE_OFFSET as u32) | (large.len() as u32 - 1)
+            };
+            data.extend_from_slice(&offset.to_be_bytes());
+        }
+        for offset in large {
+            data.extend_from_slice(&offset.to_be_bytes());
+        }
+        data.extend_from_slice(&self.pack_checksum);
+        let checksum = Sha1::digest(&data);
+        data.extend_from_slice(&checksum);
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<PackIdx, MegaError> {
+        let corrupt = |message: &str| StorageError::Serialization(format!("idx: {}", message));
+        if data.len() < 1072 + 40 || &data[..4] != MAGIC {
+            return Err(corrupt("not an idx file").into());
+        }
+        if u32_at(data, 4) != VERSION {
+            return Err(corrupt("unsupported version").into());
+        }
+        let (content, checksum) = data.split_at(data.len() - 20);
+        if Sha1::digest(content).as_slice() != checksum {
+            return Err(corrupt("checksum mismatch").into());
+        }
+        let count = u32_at(data, 8 + 255 * 4) as usize;
+        let ids = 1032;
+        let crcs = ids + count * 20;
+        let offsets = crcs + count * 4;
+        let large = offsets + count * 4;
+        if content.len() < large + 20 {
+            return Err(corrupt("truncated").into());
+        }
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let offset = u32_at(data, offsets + i * 4) as u64;
+            let offset = if offset & LARGE_OFFSET == 0 {
+                offset
+            } else {
+                let at = large + (offset & !LARGE_OFFSET) as usize * 8;
+                if at + 8 > content.len() - 20 {
+                    return Err(corrupt("truncated").into());
+                }
+                u64::from_be_bytes(data[at..at + 8].try_into().unwrap())
+            };
+            entries.push(IdxEntry {
+                id: data[ids + i * 20..ids + (i + 1) * 20].try_into().unwrap(),
+                offset,
+                crc32: u32_at(data, crcs + i * 4),
+            });
+        }
+        Ok(PackIdx {
+            entries,
+            pack_checksum: content[content.len() - 20..].try_into().unwrap(),
+        })
+    }
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
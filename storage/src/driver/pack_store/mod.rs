@@ -0,0 +1,572 @@
+//!
+//! The pack store keeps the received packs as they are, with their `.idx`, in the file storage,
+//! when `storage.obj_store` is `PACK`. The database only holds the `pack_index` table, the pack
+//! and the offset of each object, instead of a row of the `objects` table with its content.
+//!
+//! A pack is named after its checksum, as git does, and an object is read by inflating its
+//! entry and applying the deltas down to its base, see `ObjectStorage::get_packed_obj_data`.
+//! The objects resolved along a delta chain are cached, as they are likely bases of the next
+//! objects read.
+//!
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use flate2::{Decompress, FlushDecompress, Status};
+
+use common::config::StorageConfig;
+use common::errors::{MegaError, StorageError};
+
+use crate::driver::file_storage::{self, FileStorage};
+
+pub mod idx;
+
+use self::idx::PackIdx;
+
+// the bytes read at once from a pack, most entries fit in a single read
+const READ_WINDOW: u64 = 64 * 1024;
+
+// the size of the objects cached by a store, the cache is dropped once it is reached
+const CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// The kind of an entry of a pack.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EntryKind {
+    /// An object of a type, `commit`, `tree`, `blob` or `tag`.
+    Object(&'static str),
+    /// A delta against the entry at an offset of the same pack.
+    OfsDelta(u64),
+    /// A delta against an object given by its id.
+    RefDelta(String),
+}
+
+/// An entry of a pack, with its data inflated.
+#[derive(Debug)]
+pub struct PackEntry {
+    pub kind: EntryKind,
+    pub data: Vec<u8>,
+}
+
+/// An object read from the pack store, or the deltas to apply to an object of another pack.
+pub enum Resolved {
+    Object(String, Arc<Vec<u8>>),
+    /// The deltas of a chain ending with a `RefDelta`, the last one applies to `base_id`.
+    RefDelta {
+        base_id: String,
+        deltas: Vec<Vec<u8>>,
+    },
+}
+
+type CachedObject = (String, Arc<Vec<u8>>);
+
+// the objects cached by their pack and offset, with their total size
+#[derive(Default)]
+struct Cache {
+    size: usize,
+    objects: HashMap<(String, u64), CachedObject>,
+}
+
+pub struct PackStore {
+    storage: Arc<dyn FileStorage>,
+    cache: Mutex<Cache>,
+}
+
+impl PackStore {
+    pub async fn init(config: &StorageConfig) -> PackStore {
+        PackStore::new(file_storage::init(config, String::from("git-packs")).await)
+    }
+
+    pub fn new(storage: Arc<dyn FileStorage>) -> PackStore {
+        PackStore {
+            storage,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// # Writes a pack and returns its id, the hex of its checksum.
+    ///
+    /// Writing a pack that is already stored replaces it with the same content.
+    pub async fn put_pack(&self, pack: &[u8]) -> Result<String, MegaError> {
+        if pack.len() < 32 || !pack.starts_with(b"PACK") {
+            return Err(StorageError::Serialization(String::from("not a pack")).into());
+        }
+        let pack_id = hex::encode(&pack[pack.len() - 20..]);
+        self.storage
+            .put(&pack_name(&pack_id), pack.len() as i64, pack)
+            .await?;
+        Ok(pack_id)
+    }
+
+    pub async fn put_index(&self, pack_id: &str, idx: &PackIdx) -> Result<(), MegaError> {
+        let data = idx.encode();
+        self.storage
+            .put(&idx_name(pack_id), data.len() as i64, &data)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_pack(&self, pack_id: &str) -> Result<Bytes, MegaError> {
+        self.storage.get(&pack_name(pack_id)).await
+    }
+
+    pub async fn get_index(&self, pack_id: &str) -> Result<PackIdx, MegaError> {
+        PackIdx::decode(&self.storage.get(&idx_name(pack_id)).await?)
+    }
+
+    /// Reads and inflates the entry at `offset` of a pack.
+    pub async fn read_entry(&self, pack_id: &str, offset: u64) -> Result<PackEntry, MegaError> {
+        let name = pack_name(pack_id);
+        let corrupt = |message: &str| -> MegaError {
+            StorageError::Serialization(format!("pack {} at {}: {}", pack_id, offset, message))
+                .into()
+        };
+        let window = self
+            .storage
+            .get_range(&name, offset..offset + READ_WINDOW)
+            .await?;
+        let (kind, size, header_len) =
+            parse_header(&window, offset).ok_or_else(|| corrupt("invalid entry header"))?;
+
+        let mut input = window.slice(header_len..);
+        let mut read_to = offset + window.len() as u64;
+        let mut inflate = Decompress::new(true);
+        let mut data = Vec::with_capacity(size + 1);
+        loop {
+            let before = inflate.total_in();
+            let status = inflate
+                .decompress_vec(&input, &mut data, FlushDecompress::None)
+                .map_err(|e| corrupt(&e.to_string()))?;
+            let consumed = (inflate.total_in() - before) as usize;
+            input = input.slice(consumed..);
+            if status == Status::StreamEnd {
+                break;
+            }
+            if data.len() > size {
+                return Err(corrupt("larger than its size"));
+            }
+            if data.len() == data.capacity() {
+                data.reserve(4096);
+            }
+            if input.is_empty() {
+                let more = self
+                    .storage
+                    .get_range(&name, read_to..read_to + READ_WINDOW)
+                    .await?;
+                if more.is_empty() {
+                    return Err(corrupt("truncated"));
+                }
+                read_to += more.len() as u64;
+                input = more;
+            } else if consumed == 0 && status == Status::BufError {
+                return Err(corrupt("can't be inflated"));
+            }
+        }
+        if data.len() != size {
+            return Err(corrupt("size mismatch"));
+        }
+        Ok(PackEntry { kind, data })
+    }
+
+    /// # The size of the object at `offset` of a pack, without applying the deltas of its chain.
+    ///
+    /// The size of an object is in the header of its entry, the size of the result of a delta
+    /// is at the start of the delta, after the size of its base.
+    pub async fn object_size(&self, pack_id: &str, offset: u64) -> Result<u64, MegaError> {
+        let corrupt = |message: &str| -> MegaError {
+            StorageError::Serialization(format!("pack {} at {}: {}", pack_id, offset, message))
+                .into()
+        };
+        let window = self
+            .storage
+            .get_range(&pack_name(pack_id), offset..offset + READ_WINDOW)
+            .await?;
+        let (kind, size, _) =
+            parse_header(&window, offset).ok_or_else(|| corrupt("invalid entry header"))?;
+        if let EntryKind::Object(_) = kind {
+            return Ok(size as u64);
+        }
+        let delta = self.read_entry(pack_id, offset).await?.data;
+        let mut bytes = delta.iter().copied();
+        delta_size(&mut bytes)
+            .and_then(|_| delta_size(&mut bytes))
+            .ok_or_else(|| corrupt("invalid delta header"))
+    }
+
+    /// # Reads the object at `offset` of a pack, applying the deltas of its chain.
+    ///
+    /// A chain ending with a delta against an object of another pack, or of the `objects`
+    /// table, is returned as `Resolved::RefDelta` for the caller to find the base.
+    pub async fn resolve(&self, pack_id: &str, offset: u64) -> Result<Resolved, MegaError> {
+        let mut deltas = vec![];
+        let mut offset = offset;
+        let (object_type, mut data) = loop {
+            if let Some((object_type, data)) = self.cached(pack_id, offset) {
+                break (object_type, data);
+            }
+            let entry = self.read_entry(pack_id, offset).await?;
+            match entry.kind {
+                EntryKind::Object(object_type) => {
+                    break (object_type.to_owned(), Arc::new(entry.data));
+                }
+                EntryKind::OfsDelta(base_offset) => {
+                    deltas.push((offset, entry.data));
+                    offset = base_offset;
+                }
+                EntryKind::RefDelta(base_id) => {
+                    deltas.push((offset, entry.data));
+                    let deltas = deltas.into_iter().map(|(_, delta)| delta).collect();
+                    return Ok(Resolved::RefDelta { base_id, deltas });
+                }
+            }
+        };
+        while let Some((offset, delta)) = deltas.pop() {
+            data = Arc::new(apply_delta(&data, delta)?);
+            self.cache(pack_id, offset, &object_type, &data);
+        }
+        Ok(Resolved::Object(object_type, data))
+    }
+
+    fn cached(&self, pack_id: &str, offset: u64) -> Option<CachedObject> {
+        let cache = self.cache.lock().unwrap();
+        cache.objects.get(&(pack_id.to_owned(), offset)).cloned()
+    }
+
+    fn cache(&self, pack_id: &str, offset: u64, object_type: &str, data: &Arc<Vec<u8>>) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.size + data.len() > CACHE_SIZE {
+            *cache = Cache::default();
+        }
+        cache.size += data.len();
+        cache.objects.insert(
+            (pack_id.to_owned(), offset),
+            (object_type.to_owned(), data.clone()),
+        );
+    }
+}
+
+/// Applies the deltas of `Resolved::RefDelta` to the content of their base.
+pub fn apply_deltas(base: &[u8], mut deltas: Vec<Vec<u8>>) -> Result<Vec<u8>, MegaError> {
+    let mut data = base.to_vec();
+    while let Some(delta) = deltas.pop() {
+        data = apply_delta(&data, delta)?;
+    }
+    Ok(data)
+}
+
+fn apply_delta(base: &Vec<u8>, delta: Vec<u8>) -> Result<Vec<u8>, MegaError> {
+    delta::decode(&mut Cursor::new(delta), base)
+        .map_err(|e| StorageError::Serialization(format!("delta: {}", e)).into())
+}
+
+/// # Parses the header of the entry at `offset` of a pack, at the start of `data`.
+///
+/// Returns the kind of the entry, the size of its inflated data and the length of the header.
+pub fn parse_header(data: &[u8], offset: u64) -> Option<(EntryKind, usize, usize)> {
+    let mut bytes = data.iter().copied();
+    let mut byte = bytes.next()?;
+    let type_number = (byte >> 4) & 0x07;
+    let mut size = (byte & 0x0f) as usize;
+    let mut shift = 4;
+    let mut len = 1;
+    while byte & 0x80 != 0 {
+        byte = bytes.next()?;
+        size |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        shift += 7;
+        len += 1;
+    }
+    let kind = match type_number {
+        1 => EntryKind::Object("commit"),
+        2 => EntryKind::Object("tree"),
+        3 => EntryKind::Object("blob"),
+        4 => EntryKind::Object("tag"),
+        6 => {
+            // the distance to the base, with an offset added for each continuation byte
+            byte = bytes.next()?;
+            len += 1;
+            let mut distance = (byte & 0x7f) as u64;
+            while byte & 0x80 != 0 {
+                byte = bytes.next()?;
+                len += 1;
+                distance = ((distance + 1).checked_shl(7)?) | (byte & 0x7f) as u64;
+            }
+            EntryKind::OfsDelta(offset.checked_sub(distance)?)
+        }
+        7 => {
+            let base_id = data.get(len..len + 20)?;
+            len += 20;
+            EntryKind::RefDelta(hex::encode(base_id))
+        }
+        _ => return None,
+    };
+    Some((kind, size, len))
+}
+
+// a size of the header of a delta, 7 bits a byte from the least significant ones
+fn delta_size(bytes: &mut impl Iterator<Item = u8>) -> Option<u64> {
+    let mut size = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = bytes.next()?;
+        size |= ((byte & 0x7f) as u64).checked_shl(shift)?;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some(size);
+        }
+    }
+}
+
+/// The number of objects announced in the header of a pack.
+pub fn object_count(pack: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(pack.get(8..12)?.try_into().ok()?))
+}
+
+/// The entries of a pack, between its header and its checksum.
+pub fn entries(pack: &Bytes) -> Bytes {
+    pack.slice(12..pack.len() - 20)
+}
+
+fn pack_name(pack_id: &str) -> String {
+    format!("{}.pack", pack_id)
+}
+
+fn idx_name(pack_id: &str) -> String {
+    format!("{}.idx", pack_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Write;
+
+    use flate2::{write::ZlibEncoder, Compression};
+    use sea_orm::Set;
+    use sha1::{Digest, Sha1};
+
+    use entity::{objects, pack_index};
+
+    use crate::driver::database::sqlite_storage::SqliteStorage;
+    use crate::driver::database::storage::ObjectStorage;
+    use crate::driver::file_storage::local_storage::LocalStorage;
+    use crate::driver::pack_store::idx::PackIdx;
+    use crate::driver::pack_store::{parse_header, EntryKind, PackStore, Resolved};
+
+    fn git_id(object_type: &str, data: &[u8]) -> String {
+        let mut hash = Sha1::new();
+        hash.update(format!("{} {}\0", object_type, data.len()));
+        hash.update(data);
+        hex::encode(hash.finalize())
+    }
+
+    // the header, the base of a delta and the deflated data of an entry
+    fn entry(type_number: u8, size: usize, base: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut entry = vec![(type_number << 4) | (size & 0x0f) as u8];
+        let mut rest = size >> 4;
+        while rest > 0 {
+            *entry.last_mut().unwrap() |= 0x80;
+            entry.push((rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+        entry.extend_from_slice(base);
+        let mut encoder = ZlibEncoder::new(entry, Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn ofs_distance(mut distance: u64) -> Vec<u8> {
+        let mut bytes = vec![(distance & 0x7f) as u8];
+        distance >>= 7;
+        while distance > 0 {
+            distance -= 1;
+            bytes.push(0x80 | (distance & 0x7f) as u8);
+            distance >>= 7;
+        }
+        bytes.reverse();
+        bytes
+    }
+
+    fn pack(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut pack = b"PACK\0\0\0\x02".to_vec();
+        pack.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        for entry in entries {
+            pack.extend_from_slice(entry);
+        }
+        let checksum = Sha1::digest(&pack);
+        pack.extend_from_slice(&checksum);
+        pack
+    }
+
+    // data that doesn't compress, so that its entry spans several reads
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u32 = 7;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pack_idx() {
+        let data = pack(&[entry(3, 4, &[], b"blob"), entry(3, 4, &[], b"more")]);
+        let objects = [(git_id("blob", b"more"), 17), (git_id("blob", b"blob"), 12)];
+        let idx = PackIdx::new(&data, &[12, 17], &objects).unwrap();
+        assert_eq!(idx.entries.len(), 2);
+        assert!(idx.entries[0].id < idx.entries[1].id);
+        assert_eq!(PackIdx::decode(&idx.encode()).unwrap(), idx);
+        assert!(PackIdx::new(&data, &[12, 17], &[(objects[0].0.clone(), 13)]).is_err());
+
+        // the offsets from 2GiB are in the table of 64-bit offsets
+        let mut large = idx.clone();
+        large.entries[1].offset = 5 << 32;
+        let encoded = large.encode();
+        assert_eq!(encoded.len(), idx.encode().len() + 8);
+        assert_eq!(PackIdx::decode(&encoded).unwrap(), large);
+
+        let mut corrupt = encoded;
+        corrupt[1100] ^= 1;
+        assert!(PackIdx::decode(&corrupt).is_err());
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let header = [0xe5, 0x0a, 0x91, 0x2e];
+        assert_eq!(
+            parse_header(&header, 5000),
+            Some((EntryKind::OfsDelta(5000 - 2350), 165, 4))
+        );
+        assert_eq!(parse_header(&header[..3], 5000), None);
+        assert_eq!(ofs_distance(2350), [0x91, 0x2e]);
+    }
+
+    #[tokio::test]
+    async fn test_pack_store() {
+        let base = env::temp_dir().join(format!("mega-pack-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+
+        let large = noise(200 * 1024);
+        let mut changed = large.clone();
+        changed.extend_from_slice(b"appended");
+        let edited = b"fn main() {}\n".repeat(20);
+        let mut edited_again = edited.clone();
+        edited_again.extend_from_slice(b"// edited\n");
+        let in_db = b"kept in the objects table\n".repeat(4);
+
+        let mut entries = vec![entry(3, large.len(), &[], &large)];
+        let delta = delta::encode(&large, &changed);
+        let distance = ofs_distance(entries[0].len() as u64);
+        entries.push(entry(6, delta.len(), &distance, &delta));
+        let delta = delta::encode(&in_db, &edited);
+        let base_id = hex::decode(git_id("blob", &in_db)).unwrap();
+        entries.push(entry(7, delta.len(), &base_id, &delta));
+        let delta = delta::encode(&edited, &edited_again);
+        let distance = ofs_distance(entries[2].len() as u64);
+        entries.push(entry(6, delta.len(), &distance, &delta));
+        let data = pack(&entries);
+        let mut offsets = vec![12];
+        for entry in &entries[..entries.len() - 1] {
+            offsets.push(offsets.last().unwrap() + entry.len() as u64);
+        }
+
+        let packs = PackStore::new(std::sync::Arc::new(LocalStorage::init(
+            base.join("git-packs"),
+        )));
+        let pack_id = packs.put_pack(&data).await.unwrap();
+        assert_eq!(pack_id, hex::encode(&data[data.len() - 20..]));
+        assert!(packs.put_pack(b"not a pack").await.is_err());
+        match packs.resolve(&pack_id, offsets[1]).await.unwrap() {
+            Resolved::Object(object_type, object) => {
+                assert_eq!(object_type, "blob");
+                assert_eq!(*object, changed);
+            }
+            Resolved::RefDelta { .. } => panic!("the base is in the pack"),
+        }
+        assert!(matches!(
+            packs.resolve(&pack_id, offsets[3]).await.unwrap(),
+            Resolved::RefDelta { deltas, .. } if deltas.len() == 2
+        ));
+        assert!(packs.read_entry(&pack_id, offsets[0] + 1).await.is_err());
+
+        let ids = [
+            git_id("blob", &large),
+            git_id("blob", &changed),
+            git_id("blob", &edited),
+            git_id("blob", &edited_again),
+        ];
+        let objects: Vec<_> = ids.iter().cloned().zip(offsets.clone()).collect();
+        let idx = PackIdx::new(&data, &offsets, &objects).unwrap();
+        packs.put_index(&pack_id, &idx).await.unwrap();
+        assert_eq!(packs.get_index(&pack_id).await.unwrap(), idx);
+        assert_eq!(packs.get_pack(&pack_id).await.unwrap(), data);
+
+        let mut storage = SqliteStorage::memory().await.unwrap();
+        storage.config.obj_local_path = base.clone();
+        let index = objects
+            .iter()
+            .map(|(git_id, offset)| pack_index::ActiveModel {
+                git_id: Set(git_id.clone()),
+                pack_id: Set(pack_id.clone()),
+                pack_offset: Set(*offset as i64),
+            })
+            .collect();
+        storage.save_pack_index(None, index).await.unwrap();
+        storage
+            .save_obj_data_to_db(
+                None,
+                vec![objects::ActiveModel {
+                    id: Set(1),
+                    git_id: Set(git_id("blob", &in_db)),
+                    object_type: Set(String::from("blob")),
+                    data: Set(in_db.clone()),
+                    link: Set(None),
+                }],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            packs.object_size(&pack_id, offsets[0]).await.unwrap(),
+            large.len() as u64
+        );
+        assert_eq!(
+            packs.object_size(&pack_id, offsets[3]).await.unwrap(),
+            edited_again.len() as u64
+        );
+        let sizes = storage
+            .get_obj_sizes(vec![
+                ids[1].clone(),
+                ids[2].clone(),
+                git_id("blob", &in_db),
+                git_id("blob", b"missing"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(sizes.len(), 3);
+        assert_eq!(sizes[&ids[1]], changed.len() as u64);
+        assert_eq!(sizes[&ids[2]], edited.len() as u64);
+        assert_eq!(sizes[&git_id("blob", &in_db)], in_db.len() as u64);
+
+        let mut read = storage
+            .get_obj_data_by_ids(vec![
+                ids[1].clone(),
+                ids[3].clone(),
+                git_id("blob", &in_db),
+                git_id("blob", b"missing"),
+            ])
+            .await
+            .unwrap();
+        read.sort_by_key(|o| o.data.len());
+        let data: Vec<_> = read.iter().map(|o| o.data.clone()).collect();
+        assert_eq!(data, [in_db, edited_again, changed]);
+        let read = storage.get_obj_data_by_id(&ids[2]).await.unwrap().unwrap();
+        assert_eq!((read.object_type.as_str(), read.data), ("blob", edited));
+        assert!(storage
+            .get_obj_data_by_id(&git_id("blob", b"missing"))
+            .await
+            .unwrap()
+            .is_none());
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+}